@@ -0,0 +1,117 @@
+//! Read path benchmarks over synthetic sparse and stream-optimized images.
+//!
+//! Run with `cargo bench`; the images are created in the system temporary
+//! directory on first use. To measure a change, save a baseline before it
+//! with `cargo bench -- --save-baseline before` and compare after it with
+//! `cargo bench -- --baseline before`.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use vmdk::clone::CloneOptions;
+use vmdk::create::VmdkBuilder;
+use vmdk::stream::{CompressionOptions, StreamOptimizedWriter};
+use vmdk::Vmdk;
+
+/// Size of the benchmark disks
+const CAPACITY: u64 = 64 << 20;
+const GRAIN: usize = 64 << 10;
+const RANDOM_READS: usize = 256;
+
+/// Disk contents: every other grain allocated, holding compressible data
+fn contents() -> Vec<u8> {
+    let mut data = vec![0u8; CAPACITY as usize];
+    let mut state = 0x2545f4914f6cdd1du64;
+    for grain in data.chunks_mut(GRAIN).step_by(2) {
+        for (i, b) in grain.iter_mut().enumerate() {
+            if i % 64 == 0 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+            }
+            *b = (state >> (i % 8 * 8)) as u8 & 0x0f;
+        }
+    }
+    data
+}
+
+/// Paths of the sparse and stream-optimized images, created if missing
+fn images() -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join("vmdk-bench");
+    let sparse = dir.join("sparse.vmdk");
+    let stream = dir.join("stream.vmdk");
+    if sparse.exists() && stream.exists() {
+        return (sparse, stream);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let data = contents();
+    VmdkBuilder::new(CAPACITY).import(&sparse, &data[..]).unwrap().close().unwrap();
+    let mut writer = StreamOptimizedWriter::new(File::create(&stream).unwrap(), CAPACITY, &CompressionOptions::new()).unwrap();
+    std::io::Write::write_all(&mut writer, &data).unwrap();
+    writer.finish().unwrap();
+    (sparse, stream)
+}
+
+/// Offsets of 4 KiB reads spread over the disk, the same on every run
+fn random_offsets() -> Vec<u64> {
+    let mut state = 0x9e3779b97f4a7c15u64;
+    (0..RANDOM_READS)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) % (CAPACITY / 4096) * 4096
+        })
+        .collect()
+}
+
+fn sequential(vmdk: &mut Vmdk) {
+    let mut buf = vec![0u8; 1 << 20];
+    let mut offset = 0;
+    while offset < vmdk.size() {
+        offset += vmdk.read_at(offset, &mut buf).unwrap() as u64;
+    }
+}
+
+fn bench_reads(c: &mut Criterion, name: &str, path: &Path) {
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+
+    group.throughput(Throughput::Bytes(CAPACITY));
+    group.bench_function("sequential", |b| {
+        let mut vmdk = Vmdk::new(path).unwrap();
+        b.iter(|| sequential(&mut vmdk))
+    });
+
+    let offsets = random_offsets();
+    group.throughput(Throughput::Bytes((RANDOM_READS * 4096) as u64));
+    group.bench_function("random_4k", |b| {
+        let mut vmdk = Vmdk::new(path).unwrap();
+        let mut buf = [0u8; 4096];
+        b.iter(|| {
+            for &offset in &offsets {
+                vmdk.read_at(offset, &mut buf).unwrap();
+            }
+        })
+    });
+
+    group.throughput(Throughput::Bytes(CAPACITY));
+    group.bench_function("export_raw", |b| {
+        let mut vmdk = Vmdk::new(path).unwrap();
+        let out = path.with_extension("raw");
+        b.iter(|| {
+            let _ = std::fs::remove_file(&out);
+            vmdk.export_raw(&out, &CloneOptions::new()).unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    let (sparse, stream) = images();
+    bench_reads(c, "sparse", &sparse);
+    bench_reads(c, "stream_optimized", &stream);
+}
+
+criterion_group!(read, benches);
+criterion_main!(read);
@@ -0,0 +1,679 @@
+//! Analysis of how a disk is laid out in its files.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use byteorder::{LittleEndian, ReadBytesExt};
+use failure::Error;
+use log::info;
+
+use crate::audit::AuditOperation;
+use crate::extent::{append, grain_table, other_grain_bytes, read_table, set_gte, Allocation, Backing, Extent};
+use crate::lba::LbaMapper;
+use crate::storage::Storage;
+use crate::{ExtentHeader, Vmdk, VmdkError, FLAG_COMPRESSED, FLAG_MARKERS, SECTOR_SIZE};
+
+/// How scattered the grains of a disk are, from `Vmdk::fragmentation`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fragmentation {
+    /// Grains stored in sparse extents
+    pub allocated_grains: u64,
+    /// Runs of grains that follow each other both in logical order and in
+    /// the file, so they can be read sequentially
+    pub runs: u64,
+    /// Grains stored before the grain logically preceding them
+    pub out_of_order: u64,
+    /// Filesystem extents the extent files occupy, where the platform
+    /// reports them
+    pub file_extents: Option<u64>,
+}
+
+impl Fragmentation {
+    /// Average number of grains per run, 1 when every grain needs a seek
+    pub fn average_run_length(&self) -> f64 {
+        if self.runs == 0 {
+            return 0.0;
+        }
+        self.allocated_grains as f64 / self.runs as f64
+    }
+}
+
+/// Space used by one extent, as part of `Usage`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtentUsage {
+    /// The extent file, `None` for ZERO extents
+    pub path: Option<PathBuf>,
+    /// Bytes of the disk the extent covers
+    pub virtual_size: u64,
+    /// Bytes of the disk the extent stores data for: all of them for flat
+    /// extents, the allocated grains for sparse ones
+    pub allocated_bytes: u64,
+    /// Grains stored in the extent, 0 unless sparse
+    pub allocated_grains: u64,
+    /// Grains marked as reading zeros without being stored
+    pub zero_grains: u64,
+    /// Length of the extent file, 0 for devices
+    pub file_size: u64,
+}
+
+/// Space used by a disk, from `Vmdk::usage`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub virtual_size: u64,
+    /// Sum of `ExtentUsage::allocated_bytes`
+    pub allocated_bytes: u64,
+    /// Sum of `ExtentUsage::zero_grains`
+    pub zero_grains: u64,
+    /// Sum of `ExtentUsage::file_size`
+    pub file_size: u64,
+    pub extents: Vec<ExtentUsage>,
+}
+
+/// What an unreferenced region of an extent file likely held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// A grain-sized area among the data no grain table points to, such as
+    /// a grain dropped by `sparsify`
+    OrphanedGrain,
+    /// Any other gap, e.g. padding between metadata and data
+    Slack,
+}
+
+/// A region of a sparse extent file that no metadata refers to. Deleted
+/// or overwritten data often survives in such regions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnreferencedRegion {
+    /// The extent file
+    pub path: PathBuf,
+    /// Byte offset in the file
+    pub offset: u64,
+    /// Length in bytes
+    pub length: u64,
+    pub kind: RegionKind,
+}
+
+impl UnreferencedRegion {
+    /// Open a reader over exactly the bytes of the region, in the local
+    /// file at `path`
+    pub fn reader(&self) -> Result<impl Read, Error> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        Ok(file.take(self.length))
+    }
+}
+
+/// Bins of `EntropyReport::histogram`, each half a bit per byte wide
+pub const ENTROPY_BINS: usize = 16;
+
+/// Entropy of the data of a disk, from `Vmdk::entropy`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EntropyReport {
+    /// Blocks holding data per entropy range: bin `i` counts blocks with
+    /// `i / 2` to `(i + 1) / 2` bits per byte
+    pub histogram: [u64; ENTROPY_BINS],
+    /// Byte ranges of the disk, as (offset, length), whose blocks reach
+    /// the threshold. Encrypted or compressed data lies close to 8 bits per
+    /// byte.
+    pub high_entropy: Vec<(u64, u64)>,
+}
+
+/// Shannon entropy of `data` in bits per byte, from 0 to 8
+pub fn shannon_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0u64; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+impl Vmdk {
+    /// Compute the entropy of every grain-sized block of the disk holding
+    /// data, including data of parents, and list the ranges whose entropy
+    /// is at least `threshold` bits per byte
+    pub fn entropy(&mut self, threshold: f64) -> Result<EntropyReport, Error> {
+        let mut report = EntropyReport::default();
+        let mut buf = Vec::new();
+
+        for entry in self.map()?.into_iter().filter(|e| e.data) {
+            let end = entry.offset + entry.length;
+            let mut offset = entry.offset;
+            while offset < end {
+                let block = self.grain_bytes_at(offset)?;
+                let len = std::cmp::min(block - offset % block, end - offset);
+                buf.resize(std::cmp::max(buf.len(), len.try_into()?), 0);
+                let data = &mut buf[..len as usize];
+                self.read_at(offset, data)?;
+                let entropy = shannon_entropy(data);
+                let bin = std::cmp::min((entropy * 2.0) as usize, ENTROPY_BINS - 1);
+                report.histogram[bin] += 1;
+
+                if entropy >= threshold {
+                    match report.high_entropy.last_mut() {
+                        Some(last) if last.0 + last.1 == offset => last.1 += len,
+                        _ => report.high_entropy.push((offset, len)),
+                    }
+                }
+                offset += len;
+            }
+        }
+        Ok(report)
+    }
+
+    /// List the regions of the sparse extent files of this disk that no
+    /// header, descriptor, grain directory, grain table or grain covers, in
+    /// file order. Parents are not included.
+    pub fn unreferenced_regions(&mut self) -> Result<Vec<UnreferencedRegion>, Error> {
+        let mut regions = Vec::new();
+        for extent in self.extents.iter_mut() {
+            let (path, mut file, header) = match (&extent.path, extent.backing.get_mut()?) {
+                (Some(path), Backing::Sparse { file, header }) => (path, file.get()?, header),
+                _ => continue,
+            };
+            let file_sectors = file.seek(SeekFrom::End(0))? / SECTOR_SIZE;
+            let mut used = used_sectors(&mut *file, header, file_sectors)?;
+            used.sort_unstable();
+
+            let mut push = |start: u64, end: u64| {
+                if start >= end {
+                    return;
+                }
+                let grain = header.grain_size.0;
+                let orphaned = start >= header.overhead.0 && (end - start).is_multiple_of(grain);
+                let (kind, step) = if orphaned { (RegionKind::OrphanedGrain, grain) } else { (RegionKind::Slack, end - start) };
+                let mut sector = start;
+                while sector < end {
+                    regions.push(UnreferencedRegion {
+                        path: path.clone(),
+                        offset: sector * SECTOR_SIZE,
+                        length: step * SECTOR_SIZE,
+                        kind,
+                    });
+                    sector += step;
+                }
+            };
+            let mut covered = 0;
+            for (start, end) in used {
+                if start > covered {
+                    push(covered, std::cmp::min(start, file_sectors));
+                }
+                covered = std::cmp::max(covered, end);
+            }
+            if covered < file_sectors {
+                push(covered, file_sectors);
+            }
+        }
+        Ok(regions)
+    }
+
+    /// Report how much of the disk is allocated and how much space its
+    /// extent files take, overall and per extent. Parents are not included.
+    pub fn usage(&mut self) -> Result<Usage, Error> {
+        let mut usage = Usage { virtual_size: self.size(), ..Default::default() };
+        for extent in self.extents.iter_mut() {
+            let mut ext = ExtentUsage {
+                path: extent.path.clone(),
+                virtual_size: extent.size(),
+                allocated_bytes: 0,
+                allocated_grains: 0,
+                zero_grains: 0,
+                file_size: 0,
+            };
+            match extent.backing.get_mut()? {
+                Backing::Sparse { file, header } => {
+                    let mut file = file.get()?;
+                    for gte in grain_table(&mut *file, header)? {
+                        match gte {
+                            0 => (),
+                            1 => ext.zero_grains += 1,
+                            _ => ext.allocated_grains += 1,
+                        }
+                    }
+                    // The last grain may extend past the end of the extent
+                    let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+                    ext.allocated_bytes = std::cmp::min(ext.allocated_grains * grain_bytes, ext.virtual_size);
+                    ext.file_size = file.size()?;
+                }
+                Backing::Flat { file } => {
+                    ext.allocated_bytes = ext.virtual_size;
+                    ext.file_size = file.get()?.size()?;
+                }
+                Backing::Other(reader) => {
+                    let grain_bytes = other_grain_bytes(&**reader);
+                    let mut offset = 0;
+                    while offset < ext.virtual_size {
+                        let len = std::cmp::min(grain_bytes, ext.virtual_size - offset);
+                        match reader.allocation(offset, len)? {
+                            Allocation::Data => {
+                                ext.allocated_grains += 1;
+                                ext.allocated_bytes += len;
+                            }
+                            Allocation::Zero => ext.zero_grains += 1,
+                            Allocation::Unallocated => (),
+                        }
+                        offset += len;
+                    }
+                }
+                Backing::Zero => (),
+            }
+            usage.allocated_bytes += ext.allocated_bytes;
+            usage.zero_grains += ext.zero_grains;
+            usage.file_size += ext.file_size;
+            usage.extents.push(ext);
+        }
+        Ok(usage)
+    }
+
+    /// Measure how far the order of grains in the sparse extents of this
+    /// disk is from their logical order. Parents are not included.
+    pub fn fragmentation(&mut self) -> Result<Fragmentation, Error> {
+        let mut report = Fragmentation { file_extents: Some(0), ..Default::default() };
+        for extent in self.extents.iter_mut() {
+            let file = match extent.backing.get_mut()? {
+                Backing::Sparse { file, header } => {
+                    let mut prev: Option<u64> = None;
+                    for sector in grain_table(&mut *file.get()?, header)?.into_iter().filter(|&gte| gte > 1).map(u64::from) {
+                        report.allocated_grains += 1;
+                        match prev {
+                            Some(prev) if sector == prev + header.grain_size.0 => (),
+                            _ => report.runs += 1,
+                        }
+                        if prev.map(|prev| sector < prev).unwrap_or(false) {
+                            report.out_of_order += 1;
+                        }
+                        prev = Some(sector);
+                    }
+                    file
+                }
+                Backing::Flat { file } => file,
+                Backing::Zero | Backing::Other(_) => continue,
+            };
+            report.file_extents = match (report.file_extents, file.get()?.as_file().and_then(file_extents)) {
+                (Some(total), Some(n)) => Some(total + n),
+                _ => None,
+            };
+        }
+        Ok(report)
+    }
+
+    /// Rewrite the grains of the sparse extents of this disk in logical
+    /// order, right after the metadata, so the disk reads sequentially.
+    /// Grains are first copied to the end of each file and then back, so
+    /// the disk stays consistent throughout, but each file temporarily
+    /// grows by the size of its data. The disk must be open for writing.
+    pub fn defragment(&mut self) -> Result<(), Error> {
+        for extent in &self.extents {
+            extent.check_writable()?;
+        }
+        let result = self.extents.iter_mut().try_for_each(defragment_extent);
+        self.audit(AuditOperation::Defragment, &result);
+        result?;
+        info!("Defragmented {}", self.path.display());
+        Ok(())
+    }
+}
+
+fn defragment_extent(extent: &mut Extent) -> Result<(), Error> {
+    match extent.backing.get()? {
+        Backing::Sparse { header, .. } if header.flags & FLAG_COMPRESSED == 0 => (),
+        _ => return Ok(()),
+    }
+    extent.mark_dirty()?;
+    let (mut guard, header) = match extent.backing.get_mut()? {
+        Backing::Sparse { file, header } => (file.get()?, header),
+        _ => unreachable!(),
+    };
+    let file = &mut *guard;
+    let grain_sectors = header.grain_size.0;
+    let grains: Vec<(u64, u64)> = grain_table(file, header)?
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, gte)| gte > 1)
+        .map(|(grain, gte)| (grain as u64, u64::from(gte)))
+        .collect();
+
+    // Pack the grains after the metadata, around grain tables allocated
+    // among the data and grain directories VirtualBox places after it
+    let metadata = metadata_sectors(file, header)?;
+    let mut targets = Vec::with_capacity(grains.len());
+    let mut cursor = header.overhead.0;
+    for _ in &grains {
+        while let Some(&(_, end)) = metadata.iter().find(|&&(start, end)| start < cursor + grain_sectors && cursor < end) {
+            cursor = end;
+        }
+        targets.push(cursor);
+        cursor += grain_sectors;
+    }
+    if grains.iter().map(|g| g.1).eq(targets.iter().copied()) {
+        return Ok(());
+    }
+    let file_end = file.seek(SeekFrom::End(0))?.div_ceil(SECTOR_SIZE);
+    if cursor > file_end {
+        return Err(VmdkError::InvalidArgument("not enough room in the file to pack the grains".to_owned()).into());
+    }
+
+    // Data is synced before the grain tables point to it, so the old copy
+    // stays valid until then
+    let mut buf = vec![0u8; (grain_sectors * SECTOR_SIZE).try_into()?];
+    let mut copies = Vec::with_capacity(grains.len());
+    for &(_, sector) in &grains {
+        file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        file.read_exact(&mut buf)?;
+        copies.push(append(file, &buf)?);
+    }
+    file.sync_data()?;
+    for (&(grain, _), &copy) in grains.iter().zip(&copies) {
+        set_gte(file, header, grain, copy)?;
+    }
+    file.sync_data()?;
+
+    for (&copy, &target) in copies.iter().zip(&targets) {
+        file.seek(SeekFrom::Start(copy * SECTOR_SIZE))?;
+        file.read_exact(&mut buf)?;
+        file.seek(SeekFrom::Start(target * SECTOR_SIZE))?;
+        file.write_all(&buf)?;
+    }
+    file.sync_data()?;
+    for (&(grain, _), &target) in grains.iter().zip(&targets) {
+        set_gte(file, header, grain, target)?;
+    }
+    file.sync_data()?;
+
+    let end = metadata.iter().map(|m| m.1).chain(Some(cursor)).max().unwrap_or(cursor);
+    file.set_len(end * SECTOR_SIZE)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Sector ranges of the grain tables of both grain directories
+fn grain_tables(file: &mut dyn Storage, header: &ExtentHeader) -> Result<Vec<(u64, u64)>, Error> {
+    let gtes_per_gt = u64::from(header.gtes_per_gt);
+    let num_gts = header.capacity.0.div_ceil(header.grain_size.0).div_ceil(gtes_per_gt);
+    let gt_sectors = (gtes_per_gt * 4).div_ceil(SECTOR_SIZE);
+    let mut tables = Vec::new();
+    for &gd in &[header.gd_offset.0, header.rgd_offset.0] {
+        if gd == 0 {
+            continue;
+        }
+        let entries = read_table(file, gd, num_gts)?;
+        tables.extend(entries.into_iter().filter(|&gt| gt != 0).map(|gt| (u64::from(gt), u64::from(gt) + gt_sectors)));
+    }
+    Ok(tables)
+}
+
+/// Sector ranges of the header, descriptor, grain directories and grain
+/// tables of a sparse extent
+fn metadata_sectors(file: &mut dyn Storage, header: &ExtentHeader) -> Result<Vec<(u64, u64)>, Error> {
+    let gd_sectors = LbaMapper::from(header).gd_sectors();
+    let mut metadata = vec![(0, 1)];
+    if header.desc_offset.0 != 0 {
+        metadata.push((header.desc_offset.0, header.desc_offset.0 + header.desc_size.0));
+    }
+    for &gd in &[header.gd_offset.0, header.rgd_offset.0] {
+        if gd != 0 {
+            metadata.push((gd, gd + gd_sectors));
+        }
+    }
+    metadata.extend(grain_tables(file, header)?);
+    Ok(metadata)
+}
+
+/// Sector ranges of a sparse extent that its metadata refers to
+fn used_sectors(file: &mut dyn Storage, header: &ExtentHeader, file_sectors: u64) -> Result<Vec<(u64, u64)>, Error> {
+    let markers = header.flags & FLAG_MARKERS != 0;
+    let gd_sectors = (header.capacity.0.div_ceil(header.grain_size.0).div_ceil(u64::from(header.gtes_per_gt)) * 4)
+        .div_ceil(SECTOR_SIZE);
+    let mut used = vec![(0, 1), (header.desc_offset.0, header.desc_offset.0 + header.desc_size.0)];
+    for &gd in &[header.gd_offset.0, header.rgd_offset.0] {
+        if gd != 0 {
+            // Stream-optimized metadata is preceded by a marker sector
+            used.push((if markers { gd - 1 } else { gd }, gd + gd_sectors));
+        }
+    }
+    for (start, end) in grain_tables(file, header)? {
+        used.push((if markers { start - 1 } else { start }, end));
+    }
+    if markers && file_sectors >= 3 {
+        // Footer marker, footer and end-of-stream marker
+        used.push((file_sectors - 3, file_sectors));
+    }
+
+    for sector in grain_table(file, header)?.into_iter().filter(|&gte| gte > 1).map(u64::from) {
+        let sectors = if header.flags & FLAG_COMPRESSED != 0 {
+            // Grain marker: logical sector, then the size of the data
+            file.seek(SeekFrom::Start(sector * SECTOR_SIZE + 8))?;
+            (12 + u64::from(file.read_u32::<LittleEndian>()?)).div_ceil(SECTOR_SIZE)
+        } else {
+            header.grain_size.0
+        };
+        used.push((sector, sector + sectors));
+    }
+    Ok(used)
+}
+
+/// Number of filesystem extents backing `file`
+#[cfg(target_os = "linux")]
+fn file_extents(file: &File) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    /// `struct fiemap` without the trailing extent array
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+    }
+    const FS_IOC_FIEMAP: u64 = 0xc020_660b;
+    const FIEMAP_FLAG_SYNC: u32 = 1;
+
+    let mut map = Fiemap {
+        start: 0,
+        length: u64::MAX,
+        flags: FIEMAP_FLAG_SYNC,
+        mapped_extents: 0,
+        extent_count: 0,
+        reserved: 0,
+    };
+    // SAFETY: with `extent_count` 0 the kernel only counts the extents and
+    // writes nothing past the header
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map as *mut Fiemap) };
+    if ret == 0 {
+        Some(u64::from(map.mapped_extents))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn file_extents(_file: &File) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{header, header_bytes, scratch_dir, SparseImage};
+    use crate::SectorType;
+    use crate::VmdkOpenOptions;
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(shannon_entropy(&[7; 100]), 0.0);
+        assert_eq!(shannon_entropy(&[0, 1, 0, 1]), 1.0);
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(shannon_entropy(&all), 8.0);
+
+        let dir = scratch_dir("entropy");
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xa1).grain(2, 0);
+        let mut bytes = image.build();
+        // Fill the last grain in the file with every byte value
+        let len = bytes.len();
+        for (i, b) in bytes[len - 128 * 512..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        std::fs::write(&path, bytes).unwrap();
+
+        let report = Vmdk::new(&path).unwrap().entropy(7.5).unwrap();
+        assert_eq!(report.histogram[0], 1);
+        assert_eq!(report.histogram[ENTROPY_BINS - 1], 1);
+        assert_eq!(report.high_entropy, vec![(2 * 128 * 512, 128 * 512)]);
+
+        // Blocks follow the grains of the disk
+        let path = dir.join("small.vmdk");
+        std::fs::write(&path, SparseImage::new(1024, 16).monolithic("small.vmdk").grain(1, 0xa1).grain(2, 0xa2).build()).unwrap();
+        let report = Vmdk::new(&path).unwrap().entropy(7.5).unwrap();
+        assert_eq!(report.histogram[0], 2);
+    }
+
+    #[test]
+    fn test_usage() {
+        let dir = scratch_dir("usage");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xa1).grain(3, 0xa3).build();
+        // Grain 5 reads as zeros
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        let gd = header.gd_offset.bytes() as usize;
+        let gt = u32::from_le_bytes(image[gd..gd + 4].try_into().unwrap()) as usize * SECTOR_SIZE as usize;
+        image[gt + 20..gt + 24].copy_from_slice(&1u32.to_le_bytes());
+        std::fs::write(&path, &image).unwrap();
+        let mut vmdk = Vmdk::new(&path).unwrap();
+
+        let usage = vmdk.usage().unwrap();
+        assert_eq!(usage.virtual_size, 1024 * 512);
+        assert_eq!(usage.allocated_bytes, 2 * 128 * 512);
+        assert_eq!(usage.zero_grains, 1);
+        assert_eq!(usage.extents.len(), 1);
+        assert_eq!(usage.extents[0].allocated_grains, 2);
+        assert_eq!(usage.file_size, std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn test_unreferenced_regions() {
+        let dir = scratch_dir("unreferenced");
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xa1).grain(2, 0).grain(3, 0xa3);
+        std::fs::write(&path, image.build()).unwrap();
+        let grain = 128 * 512;
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        let orphans = |vmdk: &mut Vmdk| {
+            let regions = vmdk.unreferenced_regions().unwrap();
+            regions.into_iter().filter(|r| r.kind == RegionKind::OrphanedGrain).collect::<Vec<_>>()
+        };
+        assert!(orphans(&mut vmdk).is_empty());
+        assert_eq!(vmdk.sparsify().unwrap(), 1);
+
+        let orphans = orphans(&mut vmdk);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].length, grain);
+        let mut data = Vec::new();
+        orphans[0].reader().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![0u8; grain as usize]);
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(file[orphans[0].offset as usize - 1], 0xa1);
+    }
+
+    #[test]
+    fn test_unreferenced_regions_past_the_end() {
+        let dir = scratch_dir("unreferenced-past-end");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xa1).build();
+        let len = image.len() as u64 / 512;
+        // Two grains stored past the end of the file, apart
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        let gd = header.gd_offset.bytes() as usize;
+        let gt = u32::from_le_bytes(image[gd..gd + 4].try_into().unwrap()) as usize * SECTOR_SIZE as usize;
+        image[gt + 8..gt + 12].copy_from_slice(&(len as u32 + 1000).to_le_bytes());
+        image[gt + 12..gt + 16].copy_from_slice(&(len as u32 + 2000).to_le_bytes());
+        std::fs::write(&path, &image).unwrap();
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        let regions = vmdk.unreferenced_regions().unwrap();
+        assert!(regions.iter().all(|r| r.offset + r.length <= len * SECTOR_SIZE));
+    }
+
+    #[test]
+    fn test_defragment() {
+        let dir = scratch_dir("defragment");
+        let path = dir.join("disk.vmdk");
+        // Stored in the file as 3, 1, 2
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(3, 0xa3).grain(1, 0xa1).grain(2, 0xa2);
+        std::fs::write(&path, image.build()).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        let mut before = vec![0u8; 1024 * 512];
+        vmdk.read_at(0, &mut before).unwrap();
+        let report = vmdk.fragmentation().unwrap();
+        assert_eq!((report.allocated_grains, report.runs, report.out_of_order), (3, 2, 1));
+        assert_eq!(report.average_run_length(), 1.5);
+
+        vmdk.defragment().unwrap();
+        let report = vmdk.fragmentation().unwrap();
+        assert_eq!((report.allocated_grains, report.runs, report.out_of_order), (3, 1, 0));
+        let mut after = vec![0u8; 1024 * 512];
+        vmdk.read_at(0, &mut after).unwrap();
+        assert_eq!(after, before);
+        vmdk.close().unwrap();
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert!(Vmdk::new(&path).unwrap().check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_defragment_directories_after_data() {
+        let dir = scratch_dir("defragment-directories");
+        let path = dir.join("disk.vmdk");
+        // Grains 3, 1 and 2 stored around the redundant grain table and
+        // directory, the grain table and directory last
+        let descriptor = "# Disk DescriptorFile\nversion=1\nCID=12345678\nparentCID=ffffffff\n\
+             createType=\"monolithicSparse\"\n\n# Extent description\nRW 1024 SPARSE \"disk.vmdk\"\n";
+        let mut image = header_bytes(&ExtentHeader {
+            rgd_offset: SectorType(260),
+            gd_offset: SectorType(644),
+            overhead: SectorType(128),
+            ..header(1024, 128, 1, 1)
+        });
+        image.extend_from_slice(descriptor.as_bytes());
+        image.resize(645 * 512, 0);
+        for (sector, byte) in [(128, 0xa3), (384, 0xa1), (512, 0xa2)] {
+            image[sector * 512..(sector + 128) * 512].fill(byte);
+        }
+        for (gt, gd) in [(256, 260), (640, 644)] {
+            image[gd * 512..gd * 512 + 4].copy_from_slice(&(gt as u32).to_le_bytes());
+            for (grain, sector) in [(1, 384u32), (2, 512), (3, 128)] {
+                image[gt * 512 + grain * 4..gt * 512 + grain * 4 + 4].copy_from_slice(&sector.to_le_bytes());
+            }
+        }
+        std::fs::write(&path, &image).unwrap();
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        let mut before = vec![0u8; 1024 * 512];
+        vmdk.read_at(0, &mut before).unwrap();
+        vmdk.defragment().unwrap();
+        assert_eq!(vmdk.fragmentation().unwrap().out_of_order, 0);
+        vmdk.close().unwrap();
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 645 * 512);
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert!(vmdk.check().unwrap().problems.is_empty());
+        let mut after = vec![0u8; 1024 * 512];
+        vmdk.read_at(0, &mut after).unwrap();
+        assert_eq!(after, before);
+    }
+}
@@ -0,0 +1,172 @@
+//! Recording what is done to disks.
+//!
+//! A disk opened with `VmdkOpenOptions::audit` reports every operation
+//! changing its files to an `AuditSink` as it completes, successfully or
+//! not: writes of data, rewrites of the descriptor, and maintenance such
+//! as `sparsify`, `defragment` and `repair`, so the log accounts for every
+//! change made to an image through the crate.
+
+use std::fmt::{self, Debug, Display};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use failure::Error;
+use log::warn;
+
+/// An operation changing the files of a disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AuditOperation {
+    /// `length` bytes of data written at byte `offset` of the disk
+    Write { offset: u64, length: u64 },
+    /// `length` bytes at byte `offset` of the disk made to read as zeros
+    WriteZeroes { offset: u64, length: u64 },
+    /// The descriptor was replaced, such as to change the CID or a parent
+    Descriptor,
+    /// `Vmdk::sparsify` deallocated `grains` zero grains
+    Sparsify { grains: u64 },
+    /// `Vmdk::defragment` rewrote the grains in order
+    Defragment,
+    /// `Vmdk::repair` fixed `repaired` problems
+    Repair { repaired: usize },
+    /// `Vmdk::resize` grew the disk to `size` bytes
+    Resize { size: u64 },
+}
+
+impl Display for AuditOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditOperation::Write { offset, length } => write!(f, "write {} bytes at {}", length, offset),
+            AuditOperation::WriteZeroes { offset, length } => write!(f, "zero {} bytes at {}", length, offset),
+            AuditOperation::Descriptor => write!(f, "rewrite descriptor"),
+            AuditOperation::Sparsify { grains } => write!(f, "sparsify {} grains", grains),
+            AuditOperation::Defragment => write!(f, "defragment"),
+            AuditOperation::Repair { repaired } => write!(f, "repair {} problems", repaired),
+            AuditOperation::Resize { size } => write!(f, "resize to {} bytes", size),
+        }
+    }
+}
+
+/// One entry of the audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditEvent {
+    /// When the operation completed
+    pub time: SystemTime,
+    /// Descriptor file of the disk changed
+    pub path: PathBuf,
+    pub operation: AuditOperation,
+    /// Why the operation failed, in which case it may have been carried
+    /// out in part
+    pub error: Option<String>,
+}
+
+impl Display for AuditEvent {
+    /// `<seconds since the epoch> <path> <operation>`, followed by
+    /// `failed: <error>` for failures
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(f, "{}.{:06} {} {}", time.as_secs(), time.subsec_micros(), self.path.display(), self.operation)?;
+        if let Some(error) = &self.error {
+            write!(f, " failed: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the audit log of disks opened with `VmdkOpenOptions::audit`
+pub trait AuditSink: Debug + Send + Sync {
+    /// Record `event`. Called from the thread performing the operation,
+    /// which continues regardless.
+    fn record(&self, event: &AuditEvent);
+}
+
+/// Appends each event as a line of text, as formatted by `Display`, e.g.
+/// to a file opened for appending
+impl<W: Write + Debug + Send> AuditSink for Mutex<W> {
+    fn record(&self, event: &AuditEvent) {
+        let mut writer = self.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(writer, "{}", event).and_then(|_| writer.flush()) {
+            warn!("Writing audit log failed: {}", e);
+        }
+    }
+}
+
+/// Report `operation` on the disk at `path` to `sink`, if any, with the
+/// outcome of `result`
+pub(crate) fn record<T>(sink: Option<&dyn AuditSink>, path: &Path, operation: AuditOperation, result: &Result<T, Error>) {
+    if let Some(sink) = sink {
+        sink.record(&AuditEvent {
+            time: SystemTime::now(),
+            path: path.to_owned(),
+            operation,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::testutil::{scratch_dir, SparseImage};
+    use crate::VmdkOpenOptions;
+
+    /// Collects the events in memory
+    #[derive(Debug, Default, Clone)]
+    struct Events(Arc<Mutex<Vec<AuditEvent>>>);
+
+    impl AuditSink for Events {
+        fn record(&self, event: &AuditEvent) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_audit() {
+        let dir = scratch_dir("audit");
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(3, 0).build()).unwrap();
+
+        let events = Events::default();
+        let mut vmdk = VmdkOpenOptions::new().write(true).audit(events.clone()).open(&path).unwrap();
+        vmdk.write_at(1000, &[0xb1; 24]).unwrap();
+        assert_eq!(vmdk.write_at(1024 * 512, &[1]).unwrap(), 0);
+        vmdk.write_at(1024 * 512 - 4, &[0xb2; 8]).unwrap();
+        assert_eq!(vmdk.sparsify().unwrap(), 1);
+        vmdk.close().unwrap();
+
+        let recorded = events.0.lock().unwrap().clone();
+        let operations: Vec<_> = recorded.iter().map(|e| e.operation.clone()).collect();
+        assert_eq!(
+            operations,
+            [
+                AuditOperation::Descriptor,
+                AuditOperation::Write { offset: 1000, length: 24 },
+                AuditOperation::Write { offset: 1024 * 512 - 4, length: 4 },
+                AuditOperation::Sparsify { grains: 1 },
+            ]
+        );
+        assert!(recorded.iter().all(|e| e.path == path && e.error.is_none()));
+        assert!(recorded.windows(2).all(|w| w[0].time <= w[1].time));
+
+        // Refused writes are recorded as failed
+        let events = Events::default();
+        let mut vmdk = VmdkOpenOptions::new().audit(events.clone()).open(&path).unwrap();
+        assert!(vmdk.write_at(0, &[1]).is_err());
+        let recorded = events.0.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 1);
+        assert!(recorded[0].error.is_some());
+
+        let log = Mutex::new(Vec::new());
+        log.record(&AuditEvent {
+            time: UNIX_EPOCH + std::time::Duration::from_millis(1500),
+            path: path.clone(),
+            operation: AuditOperation::Write { offset: 512, length: 8 },
+            error: Some("denied".to_owned()),
+        });
+        let line = String::from_utf8(log.into_inner().unwrap()).unwrap();
+        assert_eq!(line, format!("1.500000 {} write 8 bytes at 512 failed: denied\n", path.display()));
+    }
+}
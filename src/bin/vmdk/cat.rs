@@ -0,0 +1,40 @@
+//! `vmdk cat`
+
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use failure::Error;
+use vmdk::open_any;
+
+const CHUNK: usize = 1 << 20;
+
+pub fn run(image: &Path, offset: u64, length: Option<u64>) -> Result<(), Error> {
+    let mut image = open_any(image)?;
+    let size = image.size()?;
+    let end = match length {
+        Some(length) => std::cmp::min(offset.saturating_add(length), size),
+        None => size,
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut buf = vec![0u8; CHUNK];
+    let mut pos = offset;
+
+    while pos < end {
+        let n = std::cmp::min(end - pos, CHUNK as u64) as usize;
+        let n = image.read_at(pos, &mut buf[..n])?;
+        if n == 0 {
+            break;
+        }
+        match out.write_all(&buf[..n]) {
+            Ok(()) => (),
+            // The reader, e.g. `head`, has seen enough
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        pos += n as u64;
+    }
+    match out.flush() {
+        Err(ref e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
@@ -0,0 +1,92 @@
+//! `vmdk check`
+
+use std::path::Path;
+use std::process;
+use failure::Error;
+use serde_json::json;
+use vmdk::check::Severity;
+use vmdk::{Vmdk, VmdkOpenOptions};
+
+/// Exit status when errors are left, as `qemu-img check` uses
+const EXIT_ERRORS: i32 = 2;
+/// Exit status when only warnings are left
+const EXIT_WARNINGS: i32 = 3;
+
+pub fn run(image: &Path, repair: bool, json: bool) -> Result<(), Error> {
+    let (report, vmdk) = if repair {
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(image)?;
+        (vmdk.repair()?, vmdk)
+    } else {
+        let mut vmdk = Vmdk::new(image)?;
+        (vmdk.check()?, vmdk)
+    };
+    vmdk.close()?;
+
+    let severity = |s| match s {
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    };
+    if json {
+        let problems: Vec<_> = report
+            .problems
+            .iter()
+            .map(|p| {
+                json!({
+                    "extent": p.extent.as_ref().map(|e| e.display().to_string()),
+                    "severity": severity(p.severity),
+                    "description": p.description,
+                    "repairable": p.repairable,
+                    "repaired": p.repaired,
+                })
+            })
+            .collect();
+        let out = json!({
+            "filename": image.display().to_string(),
+            "problems": problems,
+            "errors": report.count(Severity::Error),
+            "warnings": report.count(Severity::Warning),
+            "divergences": report.divergences.iter().map(|d| json!({
+                "extent": d.extent.as_ref().map(|e| e.display().to_string()),
+                "grain": d.grain,
+                "primary": d.primary,
+                "redundant": d.redundant,
+            })).collect::<Vec<_>>(),
+            "allocated-grains": report.allocated_grains,
+            "total-grains": report.total_grains,
+        });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+    } else {
+        for p in &report.problems {
+            let extent = p.extent.as_ref().map(|e| e.display().to_string()).unwrap_or_default();
+            let state = match (p.repaired, p.repairable) {
+                (true, _) => " (repaired)",
+                (false, true) => " (repairable)",
+                (false, false) => "",
+            };
+            println!("{}: {}: {}{}", severity(p.severity), extent, p.description, state);
+        }
+        if report.is_clean() {
+            println!("No errors were found on the image.");
+        } else {
+            println!(
+                "{} errors and {} warnings were found on the image.",
+                report.count(Severity::Error),
+                report.count(Severity::Warning)
+            );
+        }
+        let percent = if report.total_grains == 0 {
+            0.0
+        } else {
+            report.allocated_grains as f64 * 100.0 / report.total_grains as f64
+        };
+        println!("{}/{} = {:.2}% allocated grains", report.allocated_grains, report.total_grains, percent);
+    }
+
+    if report.count(Severity::Error) > 0 {
+        process::exit(EXIT_ERRORS);
+    }
+    if report.count(Severity::Warning) > 0 {
+        process::exit(EXIT_WARNINGS);
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! `vmdk convert`
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use clap::ValueEnum;
+use failure::Error;
+use indicatif::{ProgressBar, ProgressStyle};
+use vmdk::clone::CloneOptions;
+use vmdk::descriptor::DiskType;
+use vmdk::export::{Qcow2Image, VhdImage};
+use vmdk::formats::ExtentRead;
+use vmdk::{Vmdk, VmdkError};
+
+const CHUNK: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum InputFormat {
+    Vmdk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Raw,
+    Qcow2,
+    Vhd,
+    #[value(name = "streamoptimized")]
+    StreamOptimized,
+    #[value(name = "monolithicsparse")]
+    MonolithicSparse,
+    #[value(name = "monolithicflat")]
+    MonolithicFlat,
+    #[value(name = "twogbmaxextentsparse")]
+    TwoGbMaxExtentSparse,
+    #[value(name = "twogbmaxextentflat")]
+    TwoGbMaxExtentFlat,
+}
+
+impl OutputFormat {
+    fn disk_type(self) -> Option<DiskType> {
+        match self {
+            OutputFormat::StreamOptimized => Some(DiskType::StreamOptimized),
+            OutputFormat::MonolithicSparse => Some(DiskType::MonolithicSparse),
+            OutputFormat::MonolithicFlat => Some(DiskType::MonolithicFlat),
+            OutputFormat::TwoGbMaxExtentSparse => Some(DiskType::TwoGbMaxExtentSparse),
+            OutputFormat::TwoGbMaxExtentFlat => Some(DiskType::TwoGbMaxExtentFlat),
+            OutputFormat::Raw | OutputFormat::Qcow2 | OutputFormat::Vhd => None,
+        }
+    }
+}
+
+pub fn run(
+    _input_format: InputFormat,
+    output_format: OutputFormat,
+    input: &Path,
+    output: &Path,
+    progress: bool,
+    verify: bool,
+) -> Result<(), Error> {
+    let mut vmdk = Vmdk::new(input)?;
+    let mut options = CloneOptions::new();
+    if progress {
+        options.progress(progress_bar());
+    }
+    match (output_format, output_format.disk_type()) {
+        (_, Some(create_type)) => vmdk.clone_to(output, options.create_type(create_type))?,
+        (OutputFormat::Qcow2, None) => vmdk.export_qcow2(output, &options)?,
+        (OutputFormat::Vhd, None) => vmdk.export_vhd(output, &options)?,
+        (_, None) => vmdk.export_raw(output, &options)?,
+    }
+
+    if verify {
+        verify_output(&mut vmdk, output_format, output, progress)?;
+    }
+    Ok(())
+}
+
+fn progress_bar() -> ProgressBar {
+    let bar = ProgressBar::new(0);
+    if let Ok(style) = ProgressStyle::with_template("{msg} [{bar:40}] {bytes}/{total_bytes} ({eta})") {
+        bar.set_style(style.progress_chars("=> "));
+    }
+    bar
+}
+
+enum Converted {
+    Raw(File),
+    Image(Box<dyn ExtentRead>),
+    Vmdk(Box<Vmdk>),
+}
+
+/// Fail unless `output` reads back the same as `vmdk`
+fn verify_output(vmdk: &mut Vmdk, format: OutputFormat, output: &Path, progress: bool) -> Result<(), Error> {
+    let bar = if progress { progress_bar() } else { ProgressBar::hidden() };
+    bar.set_message("verifying");
+    bar.set_length(vmdk.size());
+
+    let mut converted = match format {
+        OutputFormat::Raw => Converted::Raw(File::open(output)?),
+        OutputFormat::Qcow2 => Converted::Image(Box::new(Qcow2Image::open(output)?)),
+        OutputFormat::Vhd => Converted::Image(Box::new(VhdImage::open(output)?)),
+        _ => Converted::Vmdk(Box::new(Vmdk::new(output)?)),
+    };
+    let size = match &converted {
+        Converted::Raw(_) => None,
+        Converted::Image(image) => Some(image.size()),
+        Converted::Vmdk(converted) => Some(converted.size()),
+    };
+    if size.is_some_and(|size| size != vmdk.size()) {
+        return Err(VmdkError::InvalidArgument("output size differs from input".to_owned()).into());
+    }
+
+    let mut expected = vec![0u8; CHUNK];
+    let mut actual = vec![0u8; CHUNK];
+    let mut pos = 0;
+    while pos < vmdk.size() {
+        let n = std::cmp::min(vmdk.size() - pos, CHUNK as u64) as usize;
+        vmdk.read_at(pos, &mut expected[..n])?;
+        match &mut converted {
+            Converted::Raw(file) => file.read_exact(&mut actual[..n])?,
+            Converted::Image(image) => image.read_at(pos, &mut actual[..n])?,
+            Converted::Vmdk(converted) => {
+                converted.read_at(pos, &mut actual[..n])?;
+            }
+        }
+        if expected[..n] != actual[..n] {
+            let at = expected[..n].iter().zip(&actual[..n]).position(|(a, b)| a != b).unwrap_or(0);
+            return Err(VmdkError::InvalidArgument(format!("output differs at byte {}", pos + at as u64)).into());
+        }
+        pos += n as u64;
+        bar.set_position(pos);
+    }
+    bar.finish_and_clear();
+    Ok(())
+}
@@ -0,0 +1,10 @@
+//! `vmdk create`
+
+use std::path::Path;
+use failure::Error;
+use vmdk::create::VmdkBuilder;
+
+pub fn run(size: u64, create_type: &str, grain_size: u64, image: &Path) -> Result<(), Error> {
+    let vmdk = VmdkBuilder::new(size).create_type(create_type.parse()?).grain_size(grain_size).create(image)?;
+    vmdk.close()
+}
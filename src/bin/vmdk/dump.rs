@@ -0,0 +1,27 @@
+//! `vmdk dump`
+
+use std::fs::File;
+use std::path::Path;
+use clap::ValueEnum;
+use failure::Error;
+use vmdk::debug::{self, Region};
+
+/// Metadata region to dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DumpRegion {
+    Header,
+    Descriptor,
+    Gd,
+    Rgd,
+}
+
+pub fn run(image: &Path, region: DumpRegion) -> Result<(), Error> {
+    let region = match region {
+        DumpRegion::Header => Region::Header,
+        DumpRegion::Descriptor => Region::Descriptor,
+        DumpRegion::Gd => Region::GrainDirectory,
+        DumpRegion::Rgd => Region::RedundantGrainDirectory,
+    };
+    print!("{}", debug::dump(File::open(image)?, region)?);
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! `vmdk info`
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use failure::Error;
+use serde_json::{json, Value};
+use vmdk::descriptor::NO_PARENT_CID;
+use vmdk::{human_size, ExtentHeader, Vmdk};
+
+use crate::chain_paths;
+
+/// Bytes of the disk stored in the disk itself, in its parents, and
+/// reading as zeros
+struct Usage {
+    own: u64,
+    parents: u64,
+    zero: u64,
+}
+
+pub fn run(image: &Path, json: bool) -> Result<(), Error> {
+    let mut vmdk = Vmdk::new(image)?;
+    let text = vmdk.raw_descriptor().trim_matches(char::from(0)).to_owned();
+    let desc = vmdk.descriptor.clone();
+
+    let mut usage = Usage { own: 0, parents: 0, zero: 0 };
+    for entry in vmdk.map()? {
+        match (entry.data, entry.depth) {
+            (false, _) => usage.zero += entry.length,
+            (true, 0) => usage.own += entry.length,
+            (true, _) => usage.parents += entry.length,
+        }
+    }
+    let disk_size = own_files(&vmdk).iter().filter_map(|f| fs::metadata(f).ok()).map(|m| m.len()).sum();
+
+    if json {
+        let report = json!({
+            "filename": image.display().to_string(),
+            "create-type": desc.create_type.as_str(),
+            "provisioning": desc.provisioning().as_str(),
+            "virtual-size": vmdk.size(),
+            "logical-sector-size": vmdk.logical_sector_size(),
+            "physical-sector-size": vmdk.physical_sector_size(),
+            "disk-size": disk_size,
+            "cid": format!("{:08x}", desc.cid),
+            "parent-cid": format!("{:08x}", desc.parent_cid),
+            "header": vmdk.extent_header.as_ref().map(header_json),
+            "extents": desc.extents.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            "ddb": desc.ddb.iter().map(|(k, v)| (k.to_owned(), Value::from(v))).collect::<serde_json::Map<_, _>>(),
+            "allocation": {
+                "own": usage.own,
+                "parents": usage.parents,
+                "zero": usage.zero,
+            },
+            "chain": chain(&vmdk).iter().map(|(path, cid)| json!({
+                "filename": path.display().to_string(),
+                "cid": format!("{:08x}", cid),
+            })).collect::<Vec<_>>(),
+            "descriptor": text,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("image: {}", image.display());
+    println!("create type: {}", desc.create_type.as_str());
+    println!("provisioning: {}", desc.provisioning().as_str());
+    println!("virtual size: {} ({} bytes)", human_size(vmdk.size()), vmdk.size());
+    println!("disk size: {}", human_size(disk_size));
+    println!("sector size: {} logical, {} physical", vmdk.logical_sector_size(), vmdk.physical_sector_size());
+    println!("CID: {:08x}", desc.cid);
+    if desc.parent_cid != NO_PARENT_CID {
+        println!("parent CID: {:08x}", desc.parent_cid);
+    }
+    if let Some(header) = &vmdk.extent_header {
+        println!("header:");
+        println!("    version: {}", header.version);
+        println!("    flags: {:#x}", header.flags);
+        println!("    grain size: {} sectors", header.grain_size.sectors());
+        println!("    GTEs per GT: {}", header.gtes_per_gt);
+        println!("    GD offset: {} sectors", header.gd_offset.sectors());
+        println!("    redundant GD offset: {} sectors", header.rgd_offset.sectors());
+        println!("    overhead: {} sectors", header.overhead.sectors());
+        println!("    compression: {}", header.compress_method);
+        println!("    unclean shutdown: {}", header.dirty_shutdown != 0);
+    }
+    println!("extents:");
+    for extent in &desc.extents {
+        println!("    {}", extent);
+    }
+    println!("disk database:");
+    for (key, value) in desc.ddb.iter() {
+        println!("    {} = {}", key, value);
+    }
+    println!("allocation:");
+    println!("    in this disk: {}", human_size(usage.own));
+    println!("    in parents: {}", human_size(usage.parents));
+    println!("    zero: {}", human_size(usage.zero));
+    let chain = chain(&vmdk);
+    if chain.len() > 1 {
+        println!("chain:");
+        for (depth, (path, cid)) in chain.iter().enumerate() {
+            println!("    {}: {} (CID {:08x})", depth, path.display(), cid);
+        }
+    }
+    Ok(())
+}
+
+fn header_json(header: &ExtentHeader) -> Value {
+    json!({
+        "version": header.version,
+        "flags": header.flags,
+        "capacity": header.capacity.sectors(),
+        "grain-size": header.grain_size.sectors(),
+        "descriptor-offset": header.desc_offset.sectors(),
+        "descriptor-size": header.desc_size.sectors(),
+        "gtes-per-gt": header.gtes_per_gt,
+        "redundant-gd-offset": header.rgd_offset.sectors(),
+        "gd-offset": header.gd_offset.sectors(),
+        "overhead": header.overhead.sectors(),
+        "unclean-shutdown": header.dirty_shutdown != 0,
+        "compression": header.compress_method,
+    })
+}
+
+/// Files of this disk, without those of its parents
+fn own_files(vmdk: &Vmdk) -> Vec<PathBuf> {
+    let mut files = vmdk.component_files();
+    if let Some(parent) = vmdk.parent() {
+        let parents = parent.component_files();
+        files.retain(|f| !parents.contains(f));
+    }
+    files
+}
+
+/// Path and CID of the disk and each of its parents
+fn chain(vmdk: &Vmdk) -> Vec<(PathBuf, u32)> {
+    let mut cids = Vec::new();
+    let mut disk = Some(vmdk);
+    while let Some(d) = disk {
+        cids.push(d.cid());
+        disk = d.parent();
+    }
+    chain_paths(vmdk).into_iter().zip(cids).collect()
+}
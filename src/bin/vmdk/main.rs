@@ -0,0 +1,154 @@
+//! `vmdk`, a command line tool to inspect and manage VMDK disks
+
+use std::path::PathBuf;
+use std::process;
+use clap::{Parser, Subcommand};
+
+use convert::{InputFormat, OutputFormat};
+use dump::DumpRegion;
+use snapshot::SnapshotCommand;
+use failure::Error;
+
+mod cat;
+mod check;
+mod convert;
+mod create;
+mod dump;
+mod info;
+mod map;
+mod snapshot;
+
+#[derive(Parser)]
+#[command(name = "vmdk", version, about = "Inspect and manage VMDK disks")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print header, descriptor, extents, allocation and parent chain
+    Info {
+        image: PathBuf,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write the contents of the virtual disk to stdout
+    Cat {
+        image: PathBuf,
+        /// Start at this byte offset, e.g. 1M
+        #[arg(long, value_parser = parse_size, default_value = "0")]
+        offset: u64,
+        /// Stop after this many bytes instead of at the end of the disk
+        #[arg(long, value_parser = parse_size)]
+        length: Option<u64>,
+    },
+    /// Check the metadata of a disk for consistency
+    Check {
+        image: PathBuf,
+        /// Fix the problems that can be fixed without losing data
+        #[arg(short, long)]
+        repair: bool,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Convert a disk, with all its parents, into a new image
+    Convert {
+        /// Format of the input
+        #[arg(short = 'f', value_enum, default_value = "vmdk")]
+        input_format: InputFormat,
+        /// Format of the output
+        #[arg(short = 'O', value_enum)]
+        output_format: OutputFormat,
+        /// Show a progress bar
+        #[arg(short, long)]
+        progress: bool,
+        /// Compare the output with the input afterwards
+        #[arg(long)]
+        verify: bool,
+        input: PathBuf,
+        output: PathBuf,
+    },
+    /// Create a new, empty disk
+    Create {
+        /// Capacity, e.g. 20G
+        #[arg(short, long, value_parser = parse_size)]
+        size: u64,
+        /// Layout of the disk, e.g. monolithicSparse or twoGbMaxExtentFlat
+        #[arg(short = 't', long = "type", default_value = "monolithicSparse")]
+        create_type: String,
+        /// Sectors per grain of sparse extents, a power of two from 8 to 65536
+        #[arg(short, long, default_value_t = 128)]
+        grain_size: u64,
+        image: PathBuf,
+    },
+    /// Print an annotated hexdump of a metadata region of an extent file,
+    /// even one that fails to open
+    Dump {
+        /// Region to dump
+        #[arg(short, long, value_enum, default_value = "header")]
+        region: DumpRegion,
+        image: PathBuf,
+    },
+    /// Manage delta disks stacked on a disk
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Print which ranges of the disk hold data, and where in the chain
+    Map {
+        image: PathBuf,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    match cli.command {
+        Command::Info { image, json } => info::run(&image, json),
+        Command::Cat { image, offset, length } => cat::run(&image, offset, length),
+        Command::Check { image, repair, json } => check::run(&image, repair, json),
+        Command::Convert { input_format, output_format, progress, verify, input, output } => {
+            convert::run(input_format, output_format, &input, &output, progress, verify)
+        }
+        Command::Create { size, create_type, grain_size, image } => create::run(size, &create_type, grain_size, &image),
+        Command::Dump { region, image } => dump::run(&image, region),
+        Command::Snapshot { command } => snapshot::run(command),
+        Command::Map { image, json } => map::run(&image, json),
+    }
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("vmdk: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Parse a size in bytes with an optional binary suffix, e.g. "20G"
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, shift) = match text.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&text[..text.len() - 1], 10),
+        Some('M') => (&text[..text.len() - 1], 20),
+        Some('G') => (&text[..text.len() - 1], 30),
+        Some('T') => (&text[..text.len() - 1], 40),
+        _ => (text, 0),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("invalid size {:?}", text))?;
+    number.checked_mul(1 << shift).ok_or_else(|| format!("size {:?} too large", text))
+}
+
+/// Paths of the disk and each of its parents, indexed by depth
+pub fn chain_paths(vmdk: &vmdk::Vmdk) -> Vec<PathBuf> {
+    let mut chain = Vec::new();
+    let mut disk = Some(vmdk);
+    while let Some(d) = disk {
+        chain.push(d.path().to_owned());
+        disk = d.parent();
+    }
+    chain
+}
@@ -0,0 +1,44 @@
+//! `vmdk map`
+
+use std::path::Path;
+use failure::Error;
+use serde_json::json;
+use vmdk::Vmdk;
+
+use crate::chain_paths;
+
+pub fn run(image: &Path, json: bool) -> Result<(), Error> {
+    let mut vmdk = Vmdk::new(image)?;
+    let map = vmdk.map()?;
+    let chain = chain_paths(&vmdk);
+
+    if json {
+        let entries: Vec<_> = map
+            .iter()
+            .map(|e| {
+                json!({
+                    "start": e.offset,
+                    "length": e.length,
+                    "depth": e.depth,
+                    "data": e.data,
+                    "zero": !e.data,
+                    "filename": chain[e.depth].display().to_string(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    println!("{:<20}{:<20}{:<8}File", "Offset", "Length", "Depth");
+    for entry in map.iter().filter(|e| e.data) {
+        println!(
+            "{:<#20x}{:<#20x}{:<8}{}",
+            entry.offset,
+            entry.length,
+            entry.depth,
+            chain[entry.depth].display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! `vmdk snapshot`
+
+use std::path::{Path, PathBuf};
+use clap::Subcommand;
+use failure::Error;
+use vmdk::{Vmdk, VmdkOpenOptions};
+
+#[derive(Subcommand)]
+pub enum SnapshotCommand {
+    /// Create a delta disk on top of IMAGE, which must not be written
+    /// afterwards
+    Create {
+        image: PathBuf,
+        /// Path of the delta, by default the next free IMAGE-NNNNNN.vmdk
+        delta: Option<PathBuf>,
+    },
+    /// Write the data of a delta disk into its parent
+    Commit { delta: PathBuf },
+    /// Copy the data of all parents into a delta disk and detach it from
+    /// them
+    Flatten { delta: PathBuf },
+}
+
+pub fn run(command: SnapshotCommand) -> Result<(), Error> {
+    match command {
+        SnapshotCommand::Create { image, delta } => {
+            let delta = match delta {
+                Some(delta) => delta,
+                None => next_delta_path(&image),
+            };
+            let base = Vmdk::new(&image)?;
+            base.snapshot(&delta)?.close()?;
+            println!("{}", delta.display());
+            Ok(())
+        }
+        SnapshotCommand::Commit { delta } => {
+            let mut vmdk = VmdkOpenOptions::new().write(true).open(&delta)?;
+            vmdk.commit()?;
+            vmdk.close()
+        }
+        SnapshotCommand::Flatten { delta } => {
+            let mut vmdk = VmdkOpenOptions::new().write(true).open(&delta)?;
+            vmdk.flatten()?;
+            vmdk.close()
+        }
+    }
+}
+
+/// `disk-000001.vmdk` for `disk.vmdk`, counting up past existing files as
+/// VMware names snapshots
+fn next_delta_path(image: &Path) -> PathBuf {
+    let stem = image.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    (1..)
+        .map(|i| image.with_file_name(format!("{}-{:06}.vmdk", stem, i)))
+        .find(|p| !p.exists())
+        .unwrap_or_default()
+}
@@ -0,0 +1,122 @@
+//! Walking a disk a block at a time, for consumers streaming it elsewhere.
+
+use std::convert::TryFrom;
+use failure::Error;
+
+use crate::extent::Allocation;
+use crate::{Vmdk, VmdkError, SECTOR_SIZE};
+
+/// What backs a block yielded by `Vmdk::blocks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlockStatus {
+    /// At least part of the block is stored in the disk or one of its
+    /// parents
+    Data,
+    /// The block reads as zeros, at least part of it through zeroed grains
+    /// or ZERO extents
+    Zero,
+    /// Nothing in the chain stores any of the block
+    Unallocated,
+}
+
+/// Iterator returned by `Vmdk::blocks`. It ends after the first error.
+pub struct Blocks<'a> {
+    vmdk: &'a mut Vmdk,
+    block_size: u64,
+    offset: u64,
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = Result<(u64, BlockStatus, Option<Vec<u8>>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let size = self.vmdk.size();
+        if offset >= size {
+            return None;
+        }
+        let len = std::cmp::min(self.block_size, size - offset);
+        let block = self.vmdk.read_block(offset, len);
+        // Stop after an error rather than yield it for every block left
+        self.offset = if block.is_ok() { offset + len } else { size };
+        Some(block.map(|(status, data)| (offset, status, data)))
+    }
+}
+
+impl Vmdk {
+    /// Blocks of `block_size` bytes covering the disk in order, the last
+    /// one possibly shorter, with their data unless they read as zeros
+    /// without storing any. `block_size` must be a multiple of the sector
+    /// size; multiples of the grain size keep blocks grain-aligned.
+    pub fn blocks(&mut self, block_size: u64) -> Result<Blocks<'_>, Error> {
+        if block_size == 0 || !block_size.is_multiple_of(SECTOR_SIZE) {
+            return Err(VmdkError::InvalidArgument(format!("block size {} is not a multiple of {}", block_size, SECTOR_SIZE)).into());
+        }
+        Ok(Blocks { vmdk: self, block_size, offset: 0 })
+    }
+
+    fn read_block(&mut self, offset: u64, len: u64) -> Result<(BlockStatus, Option<Vec<u8>>), Error> {
+        let status = self.block_status(offset, len)?;
+        if status != BlockStatus::Data {
+            return Ok((status, None));
+        }
+        let mut data = vec![0u8; usize::try_from(len)?];
+        self.read_at(offset, &mut data)?;
+        Ok((status, Some(data)))
+    }
+
+    /// What backs `len` bytes at `offset`, through the whole chain
+    fn block_status(&mut self, offset: u64, len: u64) -> Result<BlockStatus, Error> {
+        let end = std::cmp::min(offset.saturating_add(len), self.size());
+        let mut status = BlockStatus::Unallocated;
+        for extent in self.extents.iter_mut() {
+            let ext_start = extent.start * SECTOR_SIZE;
+            let (start, stop) = (std::cmp::max(offset, ext_start), std::cmp::min(end, ext_start + extent.size()));
+            if start >= stop {
+                continue;
+            }
+            let found = match extent.allocation(start - ext_start, stop - start)? {
+                Allocation::Data => BlockStatus::Data,
+                Allocation::Zero => BlockStatus::Zero,
+                Allocation::Unallocated => match self.parent.as_mut() {
+                    Some(parent) => parent.block_status(start, stop - start)?,
+                    None => BlockStatus::Unallocated,
+                },
+            };
+            match found {
+                BlockStatus::Data => return Ok(BlockStatus::Data),
+                BlockStatus::Zero => status = BlockStatus::Zero,
+                BlockStatus::Unallocated => (),
+            }
+        }
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+
+    #[test]
+    fn test_blocks() {
+        let dir = scratch_dir("blocks");
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, SparseImage::new(1000, 128).monolithic("disk.vmdk").grain(1, 0xb1).grain(7, 0xb7).build()).unwrap();
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert!(vmdk.blocks(1000).is_err());
+
+        let blocks: Vec<_> = vmdk.blocks(2 * 65536).unwrap().collect::<Result<_, _>>().unwrap();
+        let summary: Vec<_> = blocks.iter().map(|(offset, status, data)| (*offset, *status, data.as_ref().map(|d| d.len()))).collect();
+        assert_eq!(summary, [
+            (0, BlockStatus::Data, Some(131072)),
+            (131072, BlockStatus::Unallocated, None),
+            (262144, BlockStatus::Unallocated, None),
+            (393216, BlockStatus::Data, Some(1000 * 512 - 393216)),
+        ]);
+        let first = blocks[0].2.as_ref().unwrap();
+        assert_eq!((first[0], first[65536]), (0, 0xb1));
+        assert_eq!(blocks[3].2.as_ref().unwrap()[65536..][..512], [0xb7; 512]);
+    }
+}
@@ -0,0 +1,173 @@
+//! Caching of grain data read from sparse extents.
+//!
+//! Only extents opened read-only are cached, such as parents and
+//! stream-optimized disks, so entries never go stale through writes made by
+//! this crate.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Bytes of decompressed grains kept per compressed extent by default
+pub(crate) const DEFAULT_COMPRESSED_CACHE: usize = 1 << 20;
+
+/// Identifies a grain by the extent file holding it and the sector it
+/// starts at, so one cache can serve any number of disks
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GrainKey {
+    pub path: PathBuf,
+    pub sector: u64,
+}
+
+/// Stores the data of grains, decompressed, across reads
+pub trait GrainCache: Debug + Send + Sync {
+    /// The data of the grain at `key`, if cached
+    fn get(&self, key: &GrainKey) -> Option<Arc<[u8]>>;
+
+    /// Cache the data of the grain at `key`. The cache may drop it, or
+    /// others, at any time.
+    fn insert(&self, key: GrainKey, data: Arc<[u8]>);
+}
+
+/// A cache holding up to a number of bytes of grain data, dropping the
+/// least recently used grains first. Clones share their contents.
+#[derive(Debug, Clone)]
+pub struct LruGrainCache(Arc<Mutex<Lru>>);
+
+#[derive(Debug)]
+struct Lru {
+    capacity: usize,
+    size: usize,
+    /// Data and last use of each grain
+    grains: HashMap<GrainKey, (Arc<[u8]>, u64)>,
+    /// Grains by last use
+    order: BTreeMap<u64, GrainKey>,
+    tick: u64,
+}
+
+impl LruGrainCache {
+    /// A cache of at most `capacity` bytes
+    pub fn new(capacity: usize) -> Self {
+        LruGrainCache(Arc::new(Mutex::new(Lru {
+            capacity,
+            size: 0,
+            grains: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        })))
+    }
+
+    /// Bytes of grain data currently cached
+    pub fn size(&self) -> usize {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).size
+    }
+}
+
+impl GrainCache for LruGrainCache {
+    fn get(&self, key: &GrainKey) -> Option<Arc<[u8]>> {
+        let mut lru = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        lru.tick += 1;
+        let tick = lru.tick;
+        let (data, used) = lru.grains.get_mut(key)?;
+        let data = data.clone();
+        let last = std::mem::replace(used, tick);
+        lru.order.remove(&last);
+        lru.order.insert(tick, key.clone());
+        Some(data)
+    }
+
+    fn insert(&self, key: GrainKey, data: Arc<[u8]>) {
+        let mut lru = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if data.len() > lru.capacity {
+            return;
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.size += data.len();
+        if let Some((old, used)) = lru.grains.insert(key.clone(), (data, tick)) {
+            lru.size -= old.len();
+            lru.order.remove(&used);
+        }
+        lru.order.insert(tick, key);
+
+        while lru.size > lru.capacity {
+            let (_, oldest) = match lru.order.pop_first() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some((data, _)) = lru.grains.remove(&oldest) {
+                lru.size -= data.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage, StreamImage};
+    use crate::VmdkOpenOptions;
+
+    fn key(sector: u64) -> GrainKey {
+        GrainKey { path: PathBuf::from("disk.vmdk"), sector }
+    }
+
+    #[test]
+    fn test_lru() {
+        let cache = LruGrainCache::new(1024);
+        cache.insert(key(1), vec![1u8; 512].into());
+        cache.insert(key(2), vec![2u8; 512].into());
+        assert!(cache.get(&key(1)).is_some());
+        cache.insert(key(3), vec![3u8; 512].into());
+        assert_eq!(cache.size(), 1024);
+        assert!(cache.get(&key(2)).is_none());
+        assert_eq!(&cache.get(&key(1)).unwrap()[..], &[1u8; 512][..]);
+        assert!(cache.get(&key(3)).is_some());
+
+        cache.insert(key(4), vec![4u8; 2048].into());
+        assert!(cache.get(&key(4)).is_none());
+    }
+
+    #[test]
+    fn test_grain_cache() {
+        let dir = scratch_dir("grain-cache");
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        std::fs::write(&path, &image).unwrap();
+
+        let cache = LruGrainCache::new(1 << 20);
+        let mut vmdk = VmdkOpenOptions::new().grain_cache(cache.clone()).open(&path).unwrap();
+        let mut buf = [0u8; 512];
+        vmdk.read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(cache.size(), 128 * 512);
+
+        // Served from the cache, not the file
+        let changed: Vec<u8> = image.iter().map(|&b| if b == 0xb1 { 0xb2 } else { b }).collect();
+        std::fs::write(&path, changed).unwrap();
+        vmdk.read_at(129 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+    }
+
+    #[test]
+    fn test_compressed_cache() {
+        let dir = scratch_dir("compressed-cache");
+        let path = dir.join("disk.vmdk");
+        let image = StreamImage::new(1024, 128).grain(0, 0x77).build();
+        std::fs::write(&path, &image).unwrap();
+        let mut cached = VmdkOpenOptions::new().open(&path).unwrap();
+        let mut uncached = VmdkOpenOptions::new().compressed_cache_size(0).open(&path).unwrap();
+        let mut buf = [0u8; 512];
+        cached.read_at(0, &mut buf).unwrap();
+        uncached.read_at(0, &mut buf).unwrap();
+
+        // Break the zlib stream of the first grain, right after its marker
+        let mut broken = image.clone();
+        let data = 128 * 512 + 12;
+        broken[data..data + 16].copy_from_slice(&[0xff; 16]);
+        std::fs::write(&path, broken).unwrap();
+        cached.read_at(512, &mut buf).unwrap();
+        assert_eq!(buf, [0x77; 512]);
+        assert!(uncached.read_at(512, &mut buf).is_err());
+    }
+}
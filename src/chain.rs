@@ -0,0 +1,314 @@
+//! Copying the disks of a VM, whole chains at a time.
+//!
+//! A snapshotted disk is a chain of descriptors and extents linked by
+//! file names, often relative ones. `copy_all` copies every file of a chain
+//! into one directory, pointing the copied descriptors at the copies, or
+//! collapses the chain into a single disk, then checks that the content IDs
+//! of the copy link up as those of the source do. Files are copied as
+//! reflinks where the filesystem supports them, so copies within a Btrfs or
+//! XFS volume complete at once.
+
+use std::fs::{File, OpenOptions};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use failure::Error;
+use log::info;
+
+use crate::clone::CloneOptions;
+use crate::progress::{CancelToken, Monitor, Progress};
+use crate::reflink;
+use crate::{Vmdk, VmdkError};
+
+/// Options for `copy_all`
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    collapse: bool,
+    progress: Option<Arc<dyn Progress>>,
+    cancel: Option<CancelToken>,
+}
+
+impl CopyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write the leaf, with the data of all its parents, as one disk
+    /// without parent instead of copying every file. The copy keeps the
+    /// content ID and UUIDs of the leaf, so VMs using it still match.
+    pub fn collapse(&mut self, collapse: bool) -> &mut Self {
+        self.collapse = collapse;
+        self
+    }
+
+    /// Report progress to, and poll for cancellation from, `progress`
+    pub fn progress<P: Progress + 'static>(&mut self, progress: P) -> &mut Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Stop the copy when `cancel` is cancelled
+    pub fn cancel_token(&mut self, cancel: &CancelToken) -> &mut Self {
+        self.cancel = Some(cancel.clone());
+        self
+    }
+
+    fn monitor(&self) -> Monitor<'_> {
+        Monitor {
+            progress: self.progress.as_deref(),
+            cancel: self.cancel.as_ref(),
+        }
+    }
+}
+
+/// What `copy_all` made
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainCopy {
+    /// Descriptor of the copied leaf, the disk to attach
+    pub disk: PathBuf,
+    /// Every file written
+    pub files: Vec<PathBuf>,
+}
+
+/// Copy the disk at `leaf` and all its parents into `dst_dir`, which must
+/// exist. Files under the directory of `leaf` keep their place relative to
+/// it, others are copied next to it; names in the copied descriptors are
+/// rewritten to relative ones where needed. No file is overwritten, and if
+/// the copy fails or its content IDs do not link up like the source's, the
+/// files written are removed again.
+pub fn copy_all<P: AsRef<Path>, Q: AsRef<Path>>(leaf: P, dst_dir: Q, options: &CopyOptions) -> Result<ChainCopy, Error> {
+    let (leaf, dst_dir) = (leaf.as_ref(), dst_dir.as_ref());
+    let mut source = Vmdk::new(leaf)?;
+    let name = leaf.file_name().ok_or_else(|| VmdkError::InvalidArgument(leaf.display().to_string()))?;
+    let disk = dst_dir.join(name);
+
+    let copied = if options.collapse {
+        collapse(&mut source, &disk, options)
+    } else {
+        copy_files(&source, leaf, dst_dir, options)
+    };
+    let files = copied?;
+    match verify(&source, &disk, options.collapse) {
+        Ok(()) => {
+            info!("Copied {} in {} files to {}", leaf.display(), files.len(), disk.display());
+            Ok(ChainCopy { disk, files })
+        }
+        Err(e) => {
+            remove(&files);
+            Err(e)
+        }
+    }
+}
+
+fn collapse(source: &mut Vmdk, disk: &Path, options: &CopyOptions) -> Result<Vec<PathBuf>, Error> {
+    let mut clone = CloneOptions::new();
+    clone.preserve_cid(true).preserve_uuids(true);
+    if let Some(progress) = &options.progress {
+        clone.progress = Some(progress.clone());
+    }
+    if let Some(cancel) = &options.cancel {
+        clone.cancel_token(cancel);
+    }
+    source.clone_to(disk, &clone)?;
+    Ok(Vmdk::new(disk)?.component_files())
+}
+
+/// Copy every file of the chain of `source`, returning the files written
+fn copy_files(source: &Vmdk, leaf: &Path, dst_dir: &Path, options: &CopyOptions) -> Result<Vec<PathBuf>, Error> {
+    let base = leaf.canonicalize()?.parent().map(Path::to_owned).unwrap_or_default();
+    let mut plan: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for file in source.component_files().iter().filter(|f| f.exists()) {
+        let file = file.canonicalize()?;
+        let dst = destination(&base, &file, dst_dir)?;
+        if plan.iter().any(|(src, _)| *src == file) {
+            continue;
+        }
+        if plan.iter().any(|(_, d)| *d == dst) {
+            return Err(VmdkError::InvalidArgument(format!("two files of the chain copy to {}", dst.display())).into());
+        }
+        plan.push((file, dst));
+    }
+
+    let monitor = options.monitor();
+    monitor.phase("copying");
+    let mut total = 0;
+    for (src, _) in &plan {
+        total += src.metadata()?.len();
+    }
+    let mut written = Vec::new();
+    let copied = copy_planned(&plan, &mut written, monitor, total).and_then(|()| {
+        // Point the copied descriptors at the copies of their files
+        let mut disk = Some(source);
+        while let Some(vmdk) = disk {
+            relink(vmdk, &plan)?;
+            disk = vmdk.parent();
+        }
+        Ok(())
+    });
+    if let Err(e) = copied {
+        remove(&written);
+        return Err(e);
+    }
+    monitor.done(total);
+    Ok(written)
+}
+
+/// Copy the files of `plan`, as reflinks where the filesystem supports
+/// them, keeping holes
+fn copy_planned(plan: &[(PathBuf, PathBuf)], written: &mut Vec<PathBuf>, monitor: Monitor, total: u64) -> Result<(), Error> {
+    let mut done = 0;
+    for (src, dst) in plan {
+        if let Some(dir) = dst.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut input = File::open(src)?;
+        let mut output = OpenOptions::new().write(true).create_new(true).open(dst)?;
+        written.push(dst.clone());
+        let method = reflink::copy_file(&mut input, &mut output, monitor, &mut done, total)?;
+        output.sync_all()?;
+        info!("Copied {} to {} by {:?}", src.display(), dst.display(), method);
+    }
+    Ok(())
+}
+
+/// Rewrite the names in the copy of the descriptor of `vmdk` that no
+/// longer lead to the copies of the files they name
+fn relink(vmdk: &Vmdk, plan: &[(PathBuf, PathBuf)]) -> Result<(), Error> {
+    let copy_of = |path: &Path| -> Option<PathBuf> {
+        let path = path.canonicalize().ok()?;
+        plan.iter().find(|(src, _)| *src == path).map(|(_, dst)| dst.clone())
+    };
+    let desc_copy = copy_of(&vmdk.path).ok_or_else(|| VmdkError::InvalidArgument(vmdk.path.display().to_string()))?;
+    let dir = desc_copy.parent().map(Path::to_owned).unwrap_or_default();
+
+    let names: Vec<Option<String>> = vmdk
+        .extents
+        .iter()
+        .map(|e| e.path.as_deref().and_then(copy_of).map(|copy| relative_name(&dir, &copy)))
+        .collect();
+    let hint = vmdk.parent.as_ref().and_then(|p| copy_of(&p.path)).map(|copy| relative_name(&dir, &copy));
+
+    crate::rewrite_descriptor_file(&desc_copy, |desc| {
+        let mut changed = false;
+        for (extent, name) in desc.extents.iter_mut().zip(&names) {
+            if let (Some(old), Some(new)) = (extent.filename.as_mut(), name) {
+                if old != new {
+                    *old = new.clone();
+                    changed = true;
+                }
+            }
+        }
+        if let (Some(old), Some(new)) = (desc.parent_file_name_hint.as_mut(), hint) {
+            if *old != new {
+                *old = new;
+                changed = true;
+            }
+        }
+        changed
+    })?;
+    Ok(())
+}
+
+/// Where the copy of `file` goes: at the same place relative to `base`
+/// under `dst_dir`, or directly in `dst_dir` if outside `base`
+fn destination(base: &Path, file: &Path, dst_dir: &Path) -> Result<PathBuf, Error> {
+    match file.strip_prefix(base) {
+        Ok(relative) => Ok(dst_dir.join(relative)),
+        Err(_) => {
+            let name = file.file_name().ok_or_else(|| VmdkError::InvalidArgument(file.display().to_string()))?;
+            Ok(dst_dir.join(name))
+        }
+    }
+}
+
+/// Name of `to` relative to the directory `from`, with `/` separators as
+/// descriptors use. Both lie under the same directory.
+fn relative_name(from: &Path, to: &Path) -> String {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts: Vec<String> = vec!["..".to_owned(); from.len() - common];
+    parts.extend(to[common..].iter().map(|c| c.as_os_str().to_string_lossy().into_owned()));
+    parts.join("/")
+}
+
+/// Check that the chain of the copy at `disk` has the content IDs of the
+/// chain of `source`, or is a single disk with the CID of the leaf if
+/// `collapsed`, and that each disk names the CID of its parent
+fn verify(source: &Vmdk, disk: &Path, collapsed: bool) -> Result<(), Error> {
+    let copy = Vmdk::new(disk)?;
+    let mismatch = |what: String| Err(VmdkError::CopyMismatch(format!("{}: {}", disk.display(), what)).into());
+    if copy.descriptor.cid != source.descriptor.cid {
+        return mismatch(format!("CID {:08x}, expected {:08x}", copy.descriptor.cid, source.descriptor.cid));
+    }
+    if collapsed {
+        return match copy.parent {
+            None => Ok(()),
+            Some(_) => mismatch("collapsed copy has a parent".to_owned()),
+        };
+    }
+
+    let (mut src, mut dst) = (Some(source), Some(&copy));
+    while let (Some(s), Some(d)) = (src, dst) {
+        if s.descriptor.cid != d.descriptor.cid {
+            return mismatch(format!("{} has CID {:08x}, expected {:08x}", d.path.display(), d.descriptor.cid, s.descriptor.cid));
+        }
+        if let Some(parent) = d.parent() {
+            if d.descriptor.parent_cid != parent.descriptor.cid {
+                return mismatch(format!("{} does not name the CID of its parent", d.path.display()));
+            }
+        }
+        src = s.parent();
+        dst = d.parent();
+    }
+    if src.is_some() || dst.is_some() {
+        return mismatch("chain of a different length".to_owned());
+    }
+    Ok(())
+}
+
+fn remove(files: &[PathBuf]) {
+    for file in files {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{contents, scratch_dir, SparseImage};
+
+    #[test]
+    fn test_copy_all() {
+        let dir = scratch_dir("chain-copy");
+        let (src, dst) = (dir.join("vm"), dir.join("copy"));
+        for d in &[&src, &dst, &dir.join("base")] {
+            std::fs::create_dir_all(d).unwrap();
+        }
+        let base = dir.join("base").join("base.vmdk");
+        std::fs::write(&base, SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0xb1).grain(2, 0xb2).build())
+            .unwrap();
+        let child = SparseImage::new(1024, 128).child("../base/base.vmdk", 0x12345678).grain(2, 0xc2).build();
+        std::fs::write(src.join("child.vmdk"), child).unwrap();
+        let mut source = Vmdk::new(src.join("child.vmdk")).unwrap();
+        let expected = contents(&mut source);
+
+        let copy = copy_all(src.join("child.vmdk"), &dst, &CopyOptions::new()).unwrap();
+        assert_eq!(copy.disk, dst.join("child.vmdk"));
+        assert_eq!(copy.files, [dst.join("child.vmdk"), dst.join("base.vmdk")]);
+        let mut copied = Vmdk::new(&copy.disk).unwrap();
+        assert_eq!(copied.parent().unwrap().path(), dst.join("base.vmdk"));
+        assert_eq!(contents(&mut copied), expected);
+
+        // Nothing is overwritten, nor left behind
+        assert!(copy_all(src.join("child.vmdk"), &dst, &CopyOptions::new()).is_err());
+        assert!(dst.join("base.vmdk").exists());
+
+        let flat = dir.join("flat");
+        std::fs::create_dir_all(&flat).unwrap();
+        let copy = copy_all(src.join("child.vmdk"), &flat, CopyOptions::new().collapse(true)).unwrap();
+        let mut collapsed = Vmdk::new(&copy.disk).unwrap();
+        assert!(collapsed.parent().is_none());
+        assert_eq!(collapsed.cid(), 0x9abcdef0);
+        assert_eq!(contents(&mut collapsed), expected);
+    }
+}
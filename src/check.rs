@@ -0,0 +1,546 @@
+//! Consistency checks of disks, like `qemu-img check`.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use failure::Error;
+use log::info;
+
+use crate::audit::AuditOperation;
+use crate::extent::{append, read_table, set_dirty_shutdown, Backing, Extent};
+use crate::lba::LbaMapper;
+use crate::storage::Storage;
+use crate::{ExtentHeader, Vmdk, FLAG_COMPRESSED, SECTOR_SIZE};
+
+/// How serious a problem found by `Vmdk::check` is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Severity {
+    /// The disk reads correctly, but something is off
+    Warning,
+    /// Data of the disk may be lost or read wrongly
+    Error,
+}
+
+/// A problem found by `Vmdk::check`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Problem {
+    /// Extent file the problem was found in
+    pub extent: Option<PathBuf>,
+    pub severity: Severity,
+    pub description: String,
+    /// Whether `Vmdk::repair` can fix the problem without losing data
+    pub repairable: bool,
+    /// Whether the problem was fixed
+    pub repaired: bool,
+}
+
+/// A grain whose entries in the primary and redundant grain tables differ,
+/// as left behind by a write torn between the two
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Divergence {
+    pub extent: Option<PathBuf>,
+    pub grain: u64,
+    /// Grain table entries, the sector of the grain or 0 or 1
+    pub primary: u32,
+    pub redundant: u32,
+}
+
+/// Result of `Vmdk::check`
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckReport {
+    pub problems: Vec<Problem>,
+    /// Grains the primary and redundant grain tables disagree on
+    pub divergences: Vec<Divergence>,
+    /// Grains stored in sparse extents
+    pub allocated_grains: u64,
+    /// Grains sparse extents can hold
+    pub total_grains: u64,
+}
+
+impl CheckReport {
+    /// Whether no problem is left unrepaired
+    pub fn is_clean(&self) -> bool {
+        self.problems.iter().all(|p| p.repaired)
+    }
+
+    /// Unrepaired problems of `severity`
+    pub fn count(&self, severity: Severity) -> usize {
+        self.problems.iter().filter(|p| p.severity == severity && !p.repaired).count()
+    }
+}
+
+impl Vmdk {
+    /// Check the metadata of every extent of this disk, without modifying
+    /// it. Parents are not checked.
+    pub fn check(&mut self) -> Result<CheckReport, Error> {
+        self.check_extents(false)
+    }
+
+    /// Check the disk like `check` and fix the problems that can be fixed
+    /// safely: clear a stale unclean-shutdown flag, rebuild grain tables
+    /// lost from the primary grain directory from the redundant one, and
+    /// resynchronize diverging grain table entries: from the redundant
+    /// table where the primary entry fails a check the redundant one
+    /// passes, from the primary table otherwise. The disk must be open for
+    /// writing.
+    pub fn repair(&mut self) -> Result<CheckReport, Error> {
+        for extent in &self.extents {
+            extent.check_writable()?;
+        }
+        let result = self.check_extents(true);
+        let repaired = result.as_ref().map(|r| r.problems.iter().filter(|p| p.repaired).count()).unwrap_or(0);
+        if repaired > 0 || result.is_err() {
+            self.audit(AuditOperation::Repair { repaired }, &result);
+        }
+        result
+    }
+
+    fn check_extents(&mut self, repair: bool) -> Result<CheckReport, Error> {
+        let mut report = CheckReport::default();
+        for extent in self.extents.iter_mut() {
+            let (first, first_divergence) = (report.problems.len(), report.divergences.len());
+            check_extent(extent, repair, &mut report)?;
+            for problem in &mut report.problems[first..] {
+                problem.extent = extent.path.clone();
+            }
+            for divergence in &mut report.divergences[first_divergence..] {
+                divergence.extent = extent.path.clone();
+            }
+        }
+        info!(
+            "Checked {}: {} errors, {} warnings",
+            self.path.display(),
+            report.count(Severity::Error),
+            report.count(Severity::Warning)
+        );
+        Ok(report)
+    }
+}
+
+fn problem(severity: Severity, description: String, repairable: bool) -> Problem {
+    Problem { extent: None, severity, description, repairable, repaired: false }
+}
+
+fn check_extent(extent: &mut Extent, repair: bool, report: &mut CheckReport) -> Result<(), Error> {
+    let set_dirty = extent.is_dirty();
+    let needed = (extent.descriptor.offset + extent.descriptor.sectors) * SECTOR_SIZE;
+    let (mut guard, header) = match extent.backing.get_mut()? {
+        Backing::Sparse { file, header } => (file.get()?, header),
+        Backing::Flat { file } => {
+            let len = file.get()?.size()?;
+            // Devices report no length
+            if len != 0 && len < needed {
+                let description = format!("flat extent holds {} bytes, descriptor expects {}", len, needed);
+                report.problems.push(problem(Severity::Error, description, false));
+            }
+            return Ok(());
+        }
+        Backing::Zero | Backing::Other(_) => return Ok(()),
+    };
+    let file = &mut *guard;
+
+    if header.dirty_shutdown != 0 && !set_dirty {
+        let mut p = problem(Severity::Warning, "extent was not closed cleanly".to_owned(), true);
+        if repair {
+            set_dirty_shutdown(file, header, 0)?;
+            p.repaired = true;
+        }
+        report.problems.push(p);
+    }
+
+    let file_sectors = file.size()? / SECTOR_SIZE;
+    let mapper = LbaMapper::from(&*header);
+    let (gtes_per_gt, num_grains, num_gts) = (mapper.gtes_per_gt(), mapper.num_grains(), mapper.num_gts());
+    let (gt_sectors, gd_sectors) = (mapper.gt_sectors(), mapper.gd_sectors());
+    report.total_grains += num_grains;
+
+    // Grains may start below an overhead too small for the metadata, which
+    // stream-optimized extents keep at their end
+    let needed = mapper.overhead(header.desc_size.0, header.has_redundant_gd());
+    if header.flags & FLAG_COMPRESSED == 0 && header.overhead.0 < needed {
+        let description = format!("metadata overhead of {} sectors is less than the {} its metadata needs", header.overhead.0, needed);
+        report.problems.push(problem(Severity::Warning, description, false));
+    }
+    if header.gd_offset.0 + gd_sectors > file_sectors {
+        let description = format!("grain directory at sector {} lies past the end of the file", header.gd_offset.0);
+        report.problems.push(problem(Severity::Error, description, false));
+        return Ok(());
+    }
+    let gd = read_table(file, header.gd_offset.0, num_gts)?;
+    let redundant = header.has_redundant_gd() && header.rgd_offset.0 + gd_sectors <= file_sectors;
+    let rgd = if redundant { Some(read_table(file, header.rgd_offset.0, num_gts)?) } else { None };
+    let valid_gt = |gt: u32| gt != 0 && u64::from(gt) + gt_sectors <= file_sectors;
+    // Compressed grains are smaller than a grain, but start past the
+    // metadata all the same
+    let grain_end = |gte: u64| if header.flags & FLAG_COMPRESSED != 0 { gte + 1 } else { gte + header.grain_size.0 };
+    // Grains overlapping metadata are caught separately: VirtualBox writes
+    // overhead values that need not match where its grains start
+    let valid_gte = |gte: u32| gte <= 1 || grain_end(u64::from(gte)) <= file_sectors;
+
+    // Metadata grains must not overlap, as (start, end, name), by start
+    let mut metadata = vec![
+        (0, 1, "header".to_owned()),
+        (header.gd_offset.0, header.gd_offset.0 + gd_sectors, "grain directory".to_owned()),
+    ];
+    if header.desc_offset.0 != 0 {
+        metadata.push((header.desc_offset.0, header.desc_offset.0 + header.desc_size.0, "descriptor".to_owned()));
+    }
+    if let Some(rgd) = &rgd {
+        metadata.push((header.rgd_offset.0, header.rgd_offset.0 + gd_sectors, "redundant grain directory".to_owned()));
+        for (i, &rgt) in rgd.iter().enumerate().filter(|&(_, &rgt)| valid_gt(rgt)) {
+            metadata.push((u64::from(rgt), u64::from(rgt) + gt_sectors, format!("redundant grain table {}", i)));
+        }
+    }
+    for (i, &gt) in gd.iter().enumerate().filter(|&(_, &gt)| valid_gt(gt)) {
+        metadata.push((u64::from(gt), u64::from(gt) + gt_sectors, format!("grain table {}", i)));
+    }
+    metadata.sort();
+    // Sectors of the grains seen so far, by start: end and grain table entry
+    let mut owners: BTreeMap<u64, (u64, usize, usize)> = BTreeMap::new();
+
+    for (i, &gt) in gd.iter().enumerate() {
+        let rgt = rgd.as_ref().map(|rgd| rgd[i]).filter(|&rgt| valid_gt(rgt));
+        let mut gt = gt;
+        if !valid_gt(gt) && (gt != 0 || rgt.is_some()) {
+            let description = format!("grain directory entry {} points to invalid grain table {}", i, gt);
+            let mut p = problem(Severity::Error, description, rgt.is_some());
+            if let (true, Some(rgt)) = (repair, rgt) {
+                gt = copy_table(file, header, rgt, header.gd_offset.0, i)?;
+                p.repaired = true;
+            }
+            report.problems.push(p);
+            if !valid_gt(gt) {
+                continue;
+            }
+        }
+        if gt == 0 {
+            continue;
+        }
+
+        let gtes = read_table(file, u64::from(gt), gtes_per_gt)?;
+        let rgtes = match rgd.as_ref().map(|rgd| rgd[i]) {
+            Some(rgt) if valid_gt(rgt) => Some(read_table(file, u64::from(rgt), gtes_per_gt)?),
+            _ => None,
+        };
+        // Entries to restore from the redundant table
+        let mut restore = vec![false; gtes.len()];
+        for (j, &gte) in gtes.iter().enumerate() {
+            let grain = i as u64 * gtes_per_gt + j as u64;
+            if gte <= 1 || grain >= num_grains {
+                continue;
+            }
+            // Repairing the divergence fixes any problem of the entry
+            let resolvable = rgtes.as_ref().is_some_and(|rgtes| rgtes[j] != gte && valid_gte(rgtes[j]));
+            let grain_problem = |description| {
+                let mut p = problem(Severity::Error, description, resolvable);
+                p.repaired = repair && resolvable;
+                p
+            };
+            report.allocated_grains += 1;
+            let gte = u64::from(gte);
+            let end = grain_end(gte);
+            let before = metadata.partition_point(|m| m.0 < end);
+            if let Some((start, _, name)) = metadata[..before].last().filter(|m| m.1 > gte) {
+                let description = format!(
+                    "grain table {} entry {} points to sector {}, overlapping the {} at sector {}",
+                    i, j, gte, name, start
+                );
+                report.problems.push(grain_problem(description));
+                restore[j] = resolvable;
+                continue;
+            }
+            if end > file_sectors {
+                let description = format!("grain {} points to sector {} outside the data area", grain, gte);
+                report.problems.push(grain_problem(description));
+                restore[j] = resolvable;
+                continue;
+            }
+            if gte < header.overhead.0 {
+                let description =
+                    format!("grain {} at sector {} lies within the metadata overhead of {} sectors", grain, gte, header.overhead.0);
+                report.problems.push(problem(Severity::Warning, description, false));
+            }
+            // Grains seen are disjoint, so only the last one starting before
+            // the end of this one can overlap it
+            if let Some((&start, &(_, ti, tj))) = owners.range(..end).next_back().filter(|(_, o)| o.0 > gte) {
+                let description = if start == gte {
+                    format!("grain table {} entry {} and grain table {} entry {} both point to sector {}", ti, tj, i, j, gte)
+                } else {
+                    format!(
+                        "grain table {} entry {} at sector {} overlaps grain table {} entry {} at sector {}",
+                        i, j, gte, ti, tj, start
+                    )
+                };
+                report.problems.push(grain_problem(description));
+                restore[j] = resolvable;
+            } else {
+                owners.insert(gte, (end, i, j));
+            }
+        }
+
+        if let Some(rgd) = &rgd {
+            let rgt = rgd[i];
+            if !valid_gt(rgt) {
+                let description = format!("redundant grain directory entry {} points to invalid grain table {}", i, rgt);
+                let mut p = problem(Severity::Warning, description, true);
+                if repair {
+                    copy_table(file, header, gt, header.rgd_offset.0, i)?;
+                    p.repaired = true;
+                }
+                report.problems.push(p);
+            } else if let Some(rgtes) = &rgtes {
+                let diverging = gtes.iter().zip(rgtes).enumerate().filter(|(_, (gte, rgte))| gte != rgte);
+                for (j, (&gte, &rgte)) in diverging {
+                    let grain = i as u64 * gtes_per_gt + j as u64;
+                    if grain >= num_grains {
+                        continue;
+                    }
+                    report.divergences.push(Divergence { extent: None, grain, primary: gte, redundant: rgte });
+                    // A bad primary entry was reported above, the redundant
+                    // one is kept whenever it can replace it
+                    if !valid_gte(gte) || restore[j] {
+                        if repair && valid_gte(rgte) {
+                            file.seek(SeekFrom::Start(u64::from(gt) * SECTOR_SIZE + j as u64 * 4))?;
+                            file.write_all(&rgte.to_le_bytes())?;
+                        }
+                        continue;
+                    }
+                    let description = format!(
+                        "grain {} is at sector {} in grain table {} but at sector {} in the redundant one",
+                        grain, gte, i, rgte
+                    );
+                    let mut p = problem(Severity::Warning, description, true);
+                    if repair {
+                        file.seek(SeekFrom::Start(u64::from(rgt) * SECTOR_SIZE + j as u64 * 4))?;
+                        file.write_all(&gte.to_le_bytes())?;
+                        p.repaired = true;
+                    }
+                    report.problems.push(p);
+                }
+            }
+        }
+    }
+    if repair {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Append a copy of the grain table at sector `gt` and point entry `index`
+/// of the grain directory at `gd_offset` to it, returning its sector
+fn copy_table(file: &mut dyn Storage, header: &ExtentHeader, gt: u32, gd_offset: u64, index: usize) -> Result<u32, Error> {
+    let gt_bytes = (u64::from(header.gtes_per_gt) * 4).div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+    let mut buf = vec![0u8; gt_bytes.try_into()?];
+    file.seek(SeekFrom::Start(u64::from(gt) * SECTOR_SIZE))?;
+    file.read_exact(&mut buf)?;
+    let copy: u32 = append(file, &buf)?.try_into()?;
+    file.sync_data()?;
+
+    file.seek(SeekFrom::Start(gd_offset * SECTOR_SIZE + index as u64 * 4))?;
+    file.write_all(&copy.to_le_bytes())?;
+    Ok(copy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+    use crate::VmdkOpenOptions;
+
+    #[test]
+    fn test_check_and_repair() {
+        let dir = scratch_dir("check");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        let contents = {
+            std::fs::write(&path, &image).unwrap();
+            let mut buf = vec![0u8; 1024 * 512];
+            Vmdk::new(&path).unwrap().read_at(0, &mut buf).unwrap();
+            buf
+        };
+        assert!(Vmdk::new(&path).unwrap().check().unwrap().problems.is_empty());
+
+        // Unclean shutdown and a primary grain directory entry pointing
+        // past the end of the file
+        image[72] = 1;
+        let gd = (header.gd_offset.0 * SECTOR_SIZE) as usize;
+        image[gd..gd + 4].copy_from_slice(&0xfffffu32.to_le_bytes());
+        std::fs::write(&path, &image).unwrap();
+
+        let report = Vmdk::new(&path).unwrap().check().unwrap();
+        assert_eq!(report.count(Severity::Warning), 1);
+        assert_eq!(report.count(Severity::Error), 1);
+        assert!(report.problems.iter().all(|p| p.repairable && !p.repaired));
+        assert_eq!(report.problems[0].extent.as_deref(), Some(path.as_path()));
+        assert!(Vmdk::new(&path).unwrap().repair().is_err());
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        assert!(vmdk.repair().unwrap().is_clean());
+        vmdk.close().unwrap();
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        let report = vmdk.check().unwrap();
+        assert!(report.problems.is_empty());
+        assert_eq!(report.allocated_grains, 1);
+        let mut buf = vec![0u8; 1024 * 512];
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, contents);
+    }
+
+    #[test]
+    fn test_check_grain_out_of_range() {
+        let dir = scratch_dir("check-grain");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        let gd = (header.gd_offset.0 * SECTOR_SIZE) as usize;
+        let gt = u32::from_le_bytes(image[gd..gd + 4].try_into().unwrap()) as usize * SECTOR_SIZE as usize;
+        image[gt + 4..gt + 8].copy_from_slice(&100_000u32.to_le_bytes());
+        std::fs::write(&path, &image).unwrap();
+
+        let report = Vmdk::new(&path).unwrap().check().unwrap();
+        // The grain is out of range, but the redundant table still has it
+        assert_eq!(report.count(Severity::Error), 1);
+        assert_eq!(report.count(Severity::Warning), 0);
+        assert!(report.problems[0].repairable);
+        assert_eq!(report.divergences.len(), 1);
+        assert_eq!((report.divergences[0].grain, report.divergences[0].primary), (1, 100_000));
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        assert!(vmdk.repair().unwrap().is_clean());
+        vmdk.close().unwrap();
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert!(vmdk.check().unwrap().problems.is_empty());
+        let mut buf = [0u8; 512];
+        vmdk.read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+
+        // Out of range on both sides, nothing to resolve it with
+        let rgd = (header.rgd_offset.0 * SECTOR_SIZE) as usize;
+        let rgt = u32::from_le_bytes(image[rgd..rgd + 4].try_into().unwrap()) as usize * SECTOR_SIZE as usize;
+        image[rgt + 4..rgt + 8].copy_from_slice(&200_000u32.to_le_bytes());
+        std::fs::write(&path, &image).unwrap();
+        let report = Vmdk::new(&path).unwrap().check().unwrap();
+        assert_eq!(report.count(Severity::Error), 1);
+        assert!(!report.problems[0].repairable);
+    }
+
+    #[test]
+    fn test_repair_keeps_redundant_entry() {
+        let dir = scratch_dir("check-restore");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        // The primary entry of grain 1 points at the grain directory, the
+        // redundant one is still right
+        let gd = (header.gd_offset.0 * SECTOR_SIZE) as usize;
+        let gt = u32::from_le_bytes(image[gd..gd + 4].try_into().unwrap()) as usize * SECTOR_SIZE as usize;
+        let grain1 = image[gt + 4..gt + 8].to_vec();
+        image[gt + 4..gt + 8].copy_from_slice(&(header.gd_offset.0 as u32).to_le_bytes());
+        std::fs::write(&path, &image).unwrap();
+
+        let report = Vmdk::new(&path).unwrap().check().unwrap();
+        assert_eq!(report.count(Severity::Error), 1);
+        assert!(report.problems[0].description.contains(&format!("points to sector {}, overlapping", header.gd_offset.0)));
+        assert!(report.problems[0].repairable);
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        assert!(vmdk.repair().unwrap().is_clean());
+        vmdk.close().unwrap();
+        let repaired = std::fs::read(&path).unwrap();
+        assert_eq!(repaired[gt + 4..gt + 8], grain1[..]);
+        let rgd = (header.rgd_offset.0 * SECTOR_SIZE) as usize;
+        let rgt = u32::from_le_bytes(repaired[rgd..rgd + 4].try_into().unwrap()) as usize * SECTOR_SIZE as usize;
+        assert_eq!(repaired[rgt + 4..rgt + 8], grain1[..]);
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert!(vmdk.check().unwrap().problems.is_empty());
+        let mut buf = [0u8; 512];
+        vmdk.read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+    }
+
+    #[test]
+    fn test_check_divergence() {
+        let dir = scratch_dir("check-divergence");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).grain(2, 0xb2).build();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        // A torn write: the redundant table never got grain 2
+        let rgd = (header.rgd_offset.0 * SECTOR_SIZE) as usize;
+        let rgt = u32::from_le_bytes(image[rgd..rgd + 4].try_into().unwrap()) as usize * SECTOR_SIZE as usize;
+        image[rgt + 8..rgt + 12].fill(0);
+        std::fs::write(&path, &image).unwrap();
+
+        let report = Vmdk::new(&path).unwrap().check().unwrap();
+        assert_eq!(report.count(Severity::Warning), 1);
+        let divergence = &report.divergences[0];
+        assert_eq!((divergence.grain, divergence.redundant), (2, 0));
+        assert_eq!(divergence.extent.as_deref(), Some(path.as_path()));
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        assert!(vmdk.repair().unwrap().is_clean());
+        vmdk.close().unwrap();
+        let mut options = VmdkOpenOptions::new();
+        options.redundant_gd(true);
+        let mut vmdk = options.open(&path).unwrap();
+        assert!(vmdk.check().unwrap().divergences.is_empty());
+        let mut buf = [0u8; 512];
+        vmdk.read_at(2 * 128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb2; 512]);
+    }
+
+    #[test]
+    fn test_check_shared_grains() {
+        let dir = scratch_dir("check-shared");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        let gd = (header.gd_offset.0 * SECTOR_SIZE) as usize;
+        let gt_sector = u32::from_le_bytes(image[gd..gd + 4].try_into().unwrap());
+        let gt = gt_sector as usize * SECTOR_SIZE as usize;
+        let grain1 = image[gt + 4..gt + 8].to_vec();
+        // Grain 2 shares the sector of grain 1, grain 3 lies on the grain table
+        image[gt + 8..gt + 12].copy_from_slice(&grain1);
+        image[gt + 12..gt + 16].copy_from_slice(&gt_sector.to_le_bytes());
+        std::fs::write(&path, &image).unwrap();
+
+        let report = Vmdk::new(&path).unwrap().check().unwrap();
+        let errors: Vec<_> = report.problems.iter().filter(|p| p.severity == Severity::Error).collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].description.contains("grain table 0 entry 1 and grain table 0 entry 2"));
+        assert!(errors[1].description.contains("entry 3"));
+        assert!(errors[1].description.contains("overlapping the grain table 0"));
+
+        // Grain 2 starts halfway through grain 1
+        let grain1 = u32::from_le_bytes(grain1.try_into().unwrap());
+        image[gt + 8..gt + 12].copy_from_slice(&(grain1 + 64).to_le_bytes());
+        image[gt + 12..gt + 16].fill(0);
+        image.resize(image.len() + 64 * 512, 0);
+        std::fs::write(&path, &image).unwrap();
+        let report = Vmdk::new(&path).unwrap().check().unwrap();
+        assert_eq!(report.problems.len(), 1);
+        let expected = format!("grain table 0 entry 2 at sector {} overlaps grain table 0 entry 1 at sector {}", grain1 + 64, grain1);
+        assert!(report.problems[0].description.contains(&expected));
+    }
+
+    #[test]
+    fn test_check_overhead() {
+        let dir = scratch_dir("check-overhead");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1);
+        std::fs::write(dir.join("disk.vmdk"), image.build()).unwrap();
+        assert!(Vmdk::new(dir.join("disk.vmdk")).unwrap().check().unwrap().problems.is_empty());
+
+        image.overhead = Some(4);
+        std::fs::write(dir.join("disk.vmdk"), image.build()).unwrap();
+        let report = Vmdk::new(dir.join("disk.vmdk")).unwrap().check().unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].description.starts_with("metadata overhead of 4 sectors is less than the 128"));
+    }
+}
@@ -0,0 +1,541 @@
+//! Copying a disk into a new, independent one.
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use failure::Error;
+use log::info;
+
+use crate::create::VmdkBuilder;
+use crate::descriptor::{DiskType, Provisioning, NULL_UUID};
+use crate::extent::is_zero;
+use crate::progress::{CancelToken, Monitor, Progress};
+use crate::stream::{CompressionOptions, StreamOptimizedWriter, DEFAULT_GRAIN_SIZE};
+use crate::{Vmdk, VmdkError, SECTOR_SIZE};
+
+/// Options for `Vmdk::clone_to`
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    create_type: Option<DiskType>,
+    preserve_cid: bool,
+    preserve_uuids: bool,
+    provisioning: Option<Provisioning>,
+    compression: CompressionOptions,
+    pub(crate) progress: Option<Arc<dyn Progress>>,
+    cancel: Option<CancelToken>,
+}
+
+impl CloneOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Layout of the clone. Defaults to the layout of the source when it
+    /// can be created, `monolithicSparse` otherwise.
+    pub fn create_type(&mut self, create_type: DiskType) -> &mut Self {
+        self.create_type = Some(create_type);
+        self
+    }
+
+    /// Give the clone the content ID of the source instead of a new one
+    pub fn preserve_cid(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_cid = preserve;
+        self
+    }
+
+    /// Keep the image and modification UUIDs of the source instead of
+    /// generating new ones
+    pub fn preserve_uuids(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_uuids = preserve;
+        self
+    }
+
+    /// How the space of the clone is allocated, see
+    /// `VmdkBuilder::provisioning`. Defaults to thin for sparse clones and
+    /// lazy-zeroed for flat ones, whatever the source uses.
+    pub fn provisioning(&mut self, provisioning: Provisioning) -> &mut Self {
+        self.provisioning = Some(provisioning);
+        self
+    }
+
+    /// Compression used for `streamOptimized` clones
+    pub fn compression(&mut self, compression: &CompressionOptions) -> &mut Self {
+        self.compression = compression.clone();
+        self
+    }
+
+    /// Report progress to, and poll for cancellation from, `progress`
+    pub fn progress<P: Progress + 'static>(&mut self, progress: P) -> &mut Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Stop the clone when `cancel` is cancelled
+    pub fn cancel_token(&mut self, cancel: &CancelToken) -> &mut Self {
+        self.cancel = Some(cancel.clone());
+        self
+    }
+
+    pub(crate) fn monitor(&self) -> Monitor<'_> {
+        Monitor {
+            progress: self.progress.as_deref(),
+            cancel: self.cancel.as_ref(),
+        }
+    }
+}
+
+/// Copy `len` bytes at byte `src_off` of `src`, with the data of its
+/// parents, to byte `dst_off` of `dst`, returning how many bytes were
+/// copied, fewer where either disk ends first. The data moves a grain of
+/// `src` at a time; ranges of `src` without data, or holding only zeros,
+/// are zeroed in `dst` with `write_zeroes` instead of being written.
+pub fn copy_range(src: &mut Vmdk, src_off: u64, dst: &mut Vmdk, dst_off: u64, len: u64) -> Result<u64, Error> {
+    let len = std::cmp::min(len, src.size().saturating_sub(src_off));
+    let len = std::cmp::min(len, dst.size().saturating_sub(dst_off));
+    let mut buf = Vec::new();
+    // Start of the range of zeros not yet passed to `dst`
+    let mut zeros = None;
+    let mut done = 0;
+
+    while done < len {
+        // Blocks follow the grains of the source
+        let pos = src_off + done;
+        let block = src.grain_bytes_at(pos)?;
+        let n = std::cmp::min(block - pos % block, len - done);
+        buf.resize(std::cmp::max(buf.len(), n as usize), 0);
+        let buf = &mut buf[..n as usize];
+        let data = src.is_allocated(pos, n)? && {
+            src.read_at(pos, buf)?;
+            !is_zero(buf)
+        };
+        match (data, zeros) {
+            (false, None) => zeros = Some(done),
+            (true, Some(start)) => {
+                dst.write_zeroes(dst_off + start, done - start)?;
+                zeros = None;
+            }
+            _ => (),
+        }
+        if data {
+            dst.write_at(dst_off + done, buf)?;
+        }
+        done += n;
+    }
+    if let Some(start) = zeros {
+        dst.write_zeroes(dst_off + start, done - start)?;
+    }
+
+    Ok(len)
+}
+
+impl Vmdk {
+    /// Copy the disk, with the data of all its parents, into a new disk
+    /// without parent at `path`. Only ranges holding data are copied, so
+    /// sparse disks stay sparse.
+    ///
+    /// If the clone is cancelled or fails after it was created, its files
+    /// are removed again; the source is never modified.
+    pub fn clone_to<P: AsRef<Path>>(&mut self, path: P, options: &CloneOptions) -> Result<(), Error> {
+        let path = path.as_ref();
+        let create_type = match (&options.create_type, &self.descriptor.create_type) {
+            (Some(t), _) => t.clone(),
+            (None, t @ DiskType::MonolithicSparse)
+            | (None, t @ DiskType::MonolithicFlat)
+            | (None, t @ DiskType::TwoGbMaxExtentSparse)
+            | (None, t @ DiskType::TwoGbMaxExtentFlat)
+            | (None, t @ DiskType::StreamOptimized) => t.clone(),
+            (None, _) => DiskType::MonolithicSparse,
+        };
+
+        let builder = self.clone_builder(create_type.clone(), options);
+        info!("Cloning into {} disk {}", create_type.as_str(), path.display());
+
+        if create_type == DiskType::StreamOptimized {
+            return self.clone_to_stream(path, options);
+        }
+
+        let mut clone = builder.create(path)?;
+        let copied = self.copy_allocated(options.monitor(), |offset, data| {
+            clone.write_at(offset, data)?;
+            Ok(())
+        });
+        if let Err(e) = copied {
+            clone.remove();
+            return Err(e);
+        }
+        clone.close()
+    }
+
+    /// Copy a monolithic disk into one split into 2GB extent files at
+    /// `path`, keeping its content ID and UUIDs
+    pub fn split_to<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let create_type = match self.descriptor.create_type {
+            DiskType::MonolithicSparse => DiskType::TwoGbMaxExtentSparse,
+            DiskType::MonolithicFlat => DiskType::TwoGbMaxExtentFlat,
+            ref other => return Err(VmdkError::InvalidArgument(format!("cannot split {} disk", other.as_str())).into()),
+        };
+        let mut options = CloneOptions::new();
+        options.create_type(create_type).preserve_cid(true).preserve_uuids(true);
+        self.clone_to(path, &options)
+    }
+
+    /// Copy a disk split into 2GB extent files into a monolithic one at
+    /// `path`, keeping its content ID and UUIDs
+    pub fn merge_to<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let create_type = match self.descriptor.create_type {
+            DiskType::TwoGbMaxExtentSparse => DiskType::MonolithicSparse,
+            DiskType::TwoGbMaxExtentFlat => DiskType::MonolithicFlat,
+            ref other => return Err(VmdkError::InvalidArgument(format!("cannot merge {} disk", other.as_str())).into()),
+        };
+        let mut options = CloneOptions::new();
+        options.create_type(create_type).preserve_cid(true).preserve_uuids(true);
+        self.clone_to(path, &options)
+    }
+
+    /// Write the contents of the disk, with the data of all its parents,
+    /// to a new raw image at `path`. Ranges without data are left as holes
+    /// where the filesystem supports sparse files. Only `progress` and
+    /// `cancel_token` of `options` apply.
+    ///
+    /// If the export is cancelled or fails, the image is removed again.
+    pub fn export_raw<P: AsRef<Path>>(&mut self, path: P, options: &CloneOptions) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        info!("Exporting raw image {}", path.display());
+
+        let exported = file.set_len(self.size()).map_err(Error::from).and_then(|()| {
+            self.copy_allocated(options.monitor(), |offset, data| {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)?;
+                Ok(())
+            })?;
+            Ok(file.sync_all()?)
+        });
+        if exported.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        exported
+    }
+
+    /// Write the disk, with the data of all its parents, as a
+    /// `streamOptimized` disk named `name` to `writer`, returning it.
+    ///
+    /// The disk is written sequentially, so `writer` can be a pipe or a
+    /// `MultipartWriter` uploading it as it is produced. Besides what
+    /// `writer` buffers, a few grains and 4 bytes per grain of the disk are
+    /// kept in memory. `create_type` and `provisioning` of `options` do not
+    /// apply.
+    pub fn export_stream_optimized<W: Write>(&mut self, writer: W, name: &str, options: &CloneOptions) -> Result<W, Error> {
+        let builder = self.clone_builder(DiskType::StreamOptimized, options);
+        let descriptor = builder.descriptor(name).to_text();
+        let size = self.size();
+        let mut writer = StreamOptimizedWriter::with_descriptor(writer, size, &descriptor, &options.compression)?;
+        let zeros = vec![0u8; (DEFAULT_GRAIN_SIZE * SECTOR_SIZE) as usize];
+        let mut pos = 0;
+        self.copy_allocated(options.monitor(), |offset, data| {
+            // The writer is sequential and leaves zero grains out
+            while pos < offset {
+                let n = std::cmp::min(offset - pos, zeros.len() as u64) as usize;
+                writer.write_all(&zeros[..n])?;
+                pos += n as u64;
+            }
+            writer.write_all(data)?;
+            pos += data.len() as u64;
+            Ok(())
+        })?;
+        while pos < size {
+            let n = std::cmp::min(size - pos, zeros.len() as u64) as usize;
+            writer.write_all(&zeros[..n])?;
+            pos += n as u64;
+        }
+        writer.finish()
+    }
+
+    fn clone_to_stream(&mut self, path: &Path, options: &CloneOptions) -> Result<(), Error> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| VmdkError::InvalidArgument(path.display().to_string()))?
+            .to_string_lossy()
+            .into_owned();
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let finished = self.export_stream_optimized(BufWriter::new(file), &name, options).and_then(|writer| {
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            Ok(())
+        });
+        if finished.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        finished
+    }
+
+    /// Builder of a clone of `create_type`, with the settings of the source
+    /// `options` ask to keep
+    fn clone_builder(&self, create_type: DiskType, options: &CloneOptions) -> VmdkBuilder {
+        let mut ddb = self.descriptor.ddb.clone();
+        if !options.preserve_uuids {
+            ddb.new_image_uuid();
+            if ddb.get("uuid.modification").is_some() {
+                ddb.new_modification_uuid();
+            }
+        }
+        // The clone stands on its own
+        for key in &["uuid.parent", "uuid.parentmodification"] {
+            if ddb.get(key).is_some() {
+                ddb.set(key, NULL_UUID);
+            }
+        }
+
+        let mut builder = VmdkBuilder::new(self.size());
+        builder.create_type(create_type).ddb(ddb);
+        if options.preserve_cid {
+            builder.cid(self.descriptor.cid);
+        }
+        if let Some(provisioning) = options.provisioning {
+            builder.provisioning(provisioning);
+        }
+        builder
+    }
+
+    /// Pass every grain-sized block holding data other than zeros, in
+    /// order, to `copy`
+    pub(crate) fn copy_allocated<F>(&mut self, monitor: Monitor, mut copy: F) -> Result<(), Error>
+    where
+        F: FnMut(u64, &[u8]) -> Result<(), Error>,
+    {
+        let size = self.size();
+        let mut buf = Vec::new();
+        let mut offset = 0;
+        monitor.phase("copying");
+
+        while offset < size {
+            monitor.step(offset, size)?;
+            let block = self.grain_bytes_at(offset)?;
+            let len = std::cmp::min(block - offset % block, size - offset);
+            buf.resize(std::cmp::max(buf.len(), len as usize), 0);
+            if self.is_allocated(offset, len)? {
+                let buf = &mut buf[..len as usize];
+                self.read_at(offset, buf)?;
+                if !is_zero(buf) {
+                    copy(offset, buf)?;
+                }
+            }
+            offset += len;
+        }
+
+        monitor.done(size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::create::SPLIT_EXTENT_SECTORS;
+    use crate::testutil::{contents, scratch_dir, write_chain, SparseImage};
+
+    #[test]
+    fn test_clone_sparse() {
+        let dir = scratch_dir("clone-sparse");
+        write_chain(&dir, 4096);
+        let mut child = Vmdk::new(dir.join("child.vmdk")).unwrap();
+
+        child.clone_to(dir.join("clone.vmdk"), &CloneOptions::new()).unwrap();
+        let mut clone = Vmdk::new(dir.join("clone.vmdk")).unwrap();
+        assert!(clone.parent.is_none());
+        assert_eq!(contents(&mut clone), contents(&mut child));
+        assert_ne!(clone.cid(), child.cid());
+        // Metadata plus the two grains holding data
+        assert_eq!(std::fs::metadata(dir.join("clone.vmdk")).unwrap().len(), 3 * 128 * 512);
+
+        let mut options = CloneOptions::new();
+        options.preserve_cid(true).preserve_uuids(true).create_type(DiskType::MonolithicFlat);
+        let mut base = Vmdk::new(dir.join("base.vmdk")).unwrap();
+        base.clone_to(dir.join("flat.vmdk"), &options).unwrap();
+        let mut flat = Vmdk::new(dir.join("flat.vmdk")).unwrap();
+        assert_eq!(flat.cid(), 0x12345678);
+        assert_eq!(flat.descriptor.ddb.get("adapterType"), Some("ide"));
+        assert_eq!(contents(&mut flat), contents(&mut base));
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let dir = scratch_dir("split-merge");
+        let split_at = SPLIT_EXTENT_SECTORS * SECTOR_SIZE;
+        let mut disk = VmdkBuilder::new(split_at + (1 << 20)).create(dir.join("disk.vmdk")).unwrap();
+        disk.write_at(4096, &[1u8; 512]).unwrap();
+        disk.write_at(split_at - 256, &[2u8; 512]).unwrap();
+        disk.close().unwrap();
+
+        let mut disk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        assert!(disk.merge_to(dir.join("merged.vmdk")).is_err());
+        disk.split_to(dir.join("split.vmdk")).unwrap();
+        let mut split = Vmdk::new(dir.join("split.vmdk")).unwrap();
+        assert_eq!(split.descriptor.create_type, DiskType::TwoGbMaxExtentSparse);
+        assert_eq!(split.descriptor.extents.len(), 2);
+        assert_eq!(split.descriptor.extents[1].filename.as_deref(), Some("split-s002.vmdk"));
+        assert_eq!(split.cid(), disk.cid());
+
+        split.merge_to(dir.join("merged.vmdk")).unwrap();
+        let mut merged = Vmdk::new(dir.join("merged.vmdk")).unwrap();
+        assert_eq!(merged.descriptor.create_type, DiskType::MonolithicSparse);
+        assert_eq!(merged.descriptor.ddb.get("uuid.image"), disk.descriptor.ddb.get("uuid.image"));
+        for vmdk in &mut [&mut split, &mut merged] {
+            let mut buf = [0u8; 1024];
+            vmdk.read_at(split_at - 512, &mut buf).unwrap();
+            assert!(buf[..256].iter().all(|&b| b == 0));
+            assert!(buf[256..768].iter().all(|&b| b == 2));
+            vmdk.read_at(4096, &mut buf).unwrap();
+            assert!(buf[..512].iter().all(|&b| b == 1));
+        }
+    }
+
+    #[test]
+    fn test_clone_provisioning() {
+        let dir = scratch_dir("clone-provisioning");
+        let mut builder = VmdkBuilder::new(1 << 20);
+        builder.create_type(DiskType::MonolithicFlat).provisioning(Provisioning::EagerZeroed);
+        let mut eager = builder.create(dir.join("eager.vmdk")).unwrap();
+        assert_eq!(eager.descriptor.ddb.thin_provisioned(), None);
+
+        eager.clone_to(dir.join("thin.vmdk"), CloneOptions::new().create_type(DiskType::MonolithicSparse)).unwrap();
+        let mut thin = Vmdk::new(dir.join("thin.vmdk")).unwrap();
+        assert_eq!(thin.descriptor.ddb.thin_provisioned(), Some(true));
+        assert_eq!(thin.descriptor.provisioning(), Provisioning::Thin);
+
+        let mut options = CloneOptions::new();
+        options.create_type(DiskType::MonolithicFlat).provisioning(Provisioning::EagerZeroed);
+        thin.clone_to(dir.join("thick.vmdk"), &options).unwrap();
+        let thick = Vmdk::new(dir.join("thick.vmdk")).unwrap();
+        assert_eq!(thick.descriptor.ddb.thin_provisioned(), None);
+        assert_eq!(thick.descriptor.provisioning(), Provisioning::LazyZeroed);
+
+        let delta = thick.snapshot(dir.join("delta.vmdk")).unwrap();
+        assert_eq!(delta.descriptor.provisioning(), Provisioning::Thin);
+    }
+
+    #[test]
+    fn test_clone_progress() {
+        #[derive(Debug, Default)]
+        struct Recorder {
+            updates: Mutex<Vec<(u64, u64)>>,
+            cancel_at: Option<usize>,
+        }
+        impl Progress for Arc<Recorder> {
+            fn update(&self, done: u64, total: u64) {
+                self.updates.lock().unwrap().push((done, total));
+            }
+            fn cancelled(&self) -> bool {
+                self.cancel_at.map(|n| self.updates.lock().unwrap().len() > n).unwrap_or(false)
+            }
+        }
+
+        let dir = scratch_dir("clone-progress");
+        write_chain(&dir, 4096);
+        let mut child = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let recorder = Arc::new(Recorder::default());
+        child.clone_to(dir.join("clone.vmdk"), CloneOptions::new().progress(recorder.clone())).unwrap();
+        let updates = recorder.updates.lock().unwrap();
+        assert_eq!(updates.len(), 33);
+        assert_eq!(updates[1], (65536, 4096 * 512));
+        assert_eq!(updates[32], (4096 * 512, 4096 * 512));
+
+        let recorder = Arc::new(Recorder { cancel_at: Some(4), ..Default::default() });
+        let err = child.clone_to(dir.join("cancelled.vmdk"), CloneOptions::new().progress(recorder)).unwrap_err();
+        match err.downcast::<VmdkError>() {
+            Ok(VmdkError::Cancelled) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(!dir.join("cancelled.vmdk").exists());
+    }
+
+    #[test]
+    fn test_export_raw() {
+        let dir = scratch_dir("export-raw");
+        write_chain(&dir, 4096);
+        let mut child = Vmdk::new(dir.join("child.vmdk")).unwrap();
+
+        child.export_raw(dir.join("disk.img"), &CloneOptions::new()).unwrap();
+        assert_eq!(std::fs::read(dir.join("disk.img")).unwrap(), contents(&mut child));
+        assert!(child.export_raw(dir.join("disk.img"), &CloneOptions::new()).is_err());
+    }
+
+    #[test]
+    fn test_copy_range() {
+        let dir = scratch_dir("copy-range");
+        write_chain(&dir, 4096);
+        let mut child = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let source = contents(&mut child);
+        let size = child.size();
+        let grain = 128 * 512;
+
+        // Only the grains holding data are allocated in the destination
+        let mut dst = VmdkBuilder::new(size).create(dir.join("dst.vmdk")).unwrap();
+        assert_eq!(copy_range(&mut child, 0, &mut dst, 0, size).unwrap(), size);
+        assert_eq!(contents(&mut dst), source);
+        let map = dst.map().unwrap();
+        let data: Vec<_> = map.iter().filter(|e| e.data).map(|e| (e.offset, e.length)).collect();
+        assert_eq!(data, [(grain, 2 * grain)]);
+
+        // Unaligned ranges, zeroing what the source holds no data for and
+        // stopping at the end of the destination
+        let mut dst = VmdkBuilder::new(4 * grain).create(dir.join("unaligned.vmdk")).unwrap();
+        dst.write_at(0, &vec![0xee; 4 * grain as usize]).unwrap();
+        assert_eq!(copy_range(&mut child, 100, &mut dst, 1000, 10 * grain).unwrap(), 4 * grain - 1000);
+        let mut expected = vec![0xee; 1000];
+        expected.extend_from_slice(&source[100..100 + 4 * grain as usize - 1000]);
+        assert_eq!(contents(&mut dst), expected);
+        assert_eq!(copy_range(&mut child, size, &mut dst, 0, grain).unwrap(), 0);
+
+        // Small grains of the source are copied one by one
+        let small = SparseImage::new(1024, 16).monolithic("small.vmdk").grain(1, 0xa1);
+        std::fs::write(dir.join("small.vmdk"), small.build()).unwrap();
+        let mut src = Vmdk::new(dir.join("small.vmdk")).unwrap();
+        let mut dst = VmdkBuilder::new(1024 * 512).grain_size(16).create(dir.join("small-dst.vmdk")).unwrap();
+        copy_range(&mut src, 0, &mut dst, 0, 1024 * 512).unwrap();
+        let map = dst.map().unwrap();
+        let data: Vec<_> = map.iter().filter(|e| e.data).map(|e| (e.offset, e.length)).collect();
+        assert_eq!(data, [(16 * 512, 16 * 512)]);
+        let mut blocks = Vec::new();
+        src.copy_allocated(CloneOptions::new().monitor(), |offset, data| {
+            blocks.push((offset, data.len()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(blocks, [(16 * 512, 16 * 512)]);
+    }
+
+    #[test]
+    fn test_clone_cancel_token() {
+        let dir = scratch_dir("clone-cancel");
+        write_chain(&dir, 4096);
+        let mut child = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+
+        for create_type in &[DiskType::TwoGbMaxExtentFlat, DiskType::StreamOptimized] {
+            let mut options = CloneOptions::new();
+            options.create_type(create_type.clone()).cancel_token(&cancel);
+            assert!(child.clone_to(dir.join("cancelled.vmdk"), &options).is_err());
+            assert!(!dir.join("cancelled.vmdk").exists());
+            assert!(!dir.join("cancelled-f001.vmdk").exists());
+        }
+    }
+
+    #[test]
+    fn test_clone_stream_optimized() {
+        let dir = scratch_dir("clone-stream");
+        write_chain(&dir, 4096);
+        let mut child = Vmdk::new(dir.join("child.vmdk")).unwrap();
+
+        let mut options = CloneOptions::new();
+        options.create_type(DiskType::StreamOptimized);
+        child.clone_to(dir.join("stream.vmdk"), &options).unwrap();
+        let mut stream = Vmdk::new(dir.join("stream.vmdk")).unwrap();
+        assert_eq!(stream.descriptor.create_type, DiskType::StreamOptimized);
+        assert_eq!(stream.descriptor.extents[0].filename.as_deref(), Some("stream.vmdk"));
+        assert_eq!(contents(&mut stream), contents(&mut child));
+    }
+}
@@ -0,0 +1,111 @@
+//! Grain compression.
+//!
+//! Compressed grains are zlib streams (RFC 1950). The DEFLATE implementation
+//! comes from `flate2`; enabling the `zlib-ng` feature switches it to the
+//! zlib-ng backend.
+
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom, Write};
+use byteorder::{LittleEndian, ReadBytesExt};
+use failure::Error;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::{VmdkError, SECTOR_SIZE};
+
+/// `compress_method` of uncompressed extents
+pub const COMPRESSION_NONE: u16 = 0;
+/// `compress_method` of DEFLATE compressed extents
+pub const COMPRESSION_DEFLATE: u16 = 1;
+
+/// Inflate a single compressed grain of `grain_bytes` bytes. Short streams
+/// are padded with zeros, as the tail of a grain may be left out.
+pub fn inflate_grain(data: &[u8], grain_bytes: u64) -> Result<Vec<u8>, Error> {
+    let mut grain = Vec::with_capacity(grain_bytes.try_into()?);
+    ZlibDecoder::new(data).take(grain_bytes).read_to_end(&mut grain)?;
+    grain.resize(grain_bytes.try_into()?, 0);
+    Ok(grain)
+}
+
+/// Compress a grain at `level` (0-9). With `store_incompressible` a grain
+/// that does not shrink is emitted as stored DEFLATE blocks instead, which
+/// every reader can still inflate but costs nothing to decode.
+pub fn deflate_grain(data: &[u8], level: u32, store_incompressible: bool) -> Result<Vec<u8>, Error> {
+    let mut enc = ZlibEncoder::new(Vec::with_capacity(data.len() / 2), Compression::new(level));
+    enc.write_all(data)?;
+    let compressed = enc.finish()?;
+    if store_incompressible && level != 0 && compressed.len() >= data.len() {
+        return deflate_grain(data, 0, false);
+    }
+    Ok(compressed)
+}
+
+/// Read and inflate the compressed grain whose marker starts at `sector`.
+/// The compressed data may span any number of sectors after the marker.
+pub(crate) fn read_compressed_grain<R: Read + Seek + ?Sized>(
+    file: &mut R,
+    sector: u64,
+    grain_bytes: u64,
+) -> Result<(u64, Vec<u8>), Error> {
+    file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    let lba = file.read_u64::<LittleEndian>()?;
+    let size = file.read_u32::<LittleEndian>()?;
+    if size == 0 {
+        return Err(VmdkError::ParseError.into());
+    }
+
+    let mut data = vec![0u8; size as usize];
+    file.read_exact(&mut data)?;
+    Ok((lba, inflate_grain(&data, grain_bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use byteorder::WriteBytesExt;
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f491u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_store_incompressible() {
+        let grain = noise(65536);
+        let compressed = deflate_grain(&grain, 9, false).unwrap();
+        let stored = deflate_grain(&grain, 9, true).unwrap();
+        assert!(compressed.len() > grain.len());
+        assert!(stored.len() <= compressed.len());
+        assert_eq!(inflate_grain(&stored, 65536).unwrap(), grain);
+
+        let zeros = deflate_grain(&[0u8; 65536], 6, true).unwrap();
+        assert!(zeros.len() < 1024);
+    }
+
+    #[test]
+    fn test_compressed_grain_spanning_sectors() {
+        // Incompressible data makes the stream span several sectors
+        let grain = noise(4096);
+        let compressed = deflate_grain(&grain, 9, false).unwrap();
+        assert!(compressed.len() > 3 * SECTOR_SIZE as usize);
+
+        let mut file = vec![0u8; 2 * SECTOR_SIZE as usize];
+        file.write_u64::<LittleEndian>(64).unwrap();
+        file.write_u32::<LittleEndian>(compressed.len() as u32).unwrap();
+        file.extend_from_slice(&compressed);
+
+        let (lba, data) = read_compressed_grain(&mut Cursor::new(file), 2, 8192).unwrap();
+        assert_eq!(lba, 64);
+        assert_eq!(&data[..4096], &grain[..]);
+        assert!(data[4096..].iter().all(|&b| b == 0));
+    }
+}
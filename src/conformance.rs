@@ -0,0 +1,286 @@
+//! How closely a disk follows the published VMDK specification.
+//!
+//! Opening a disk accepts much that VMware's tools do not, and never looks
+//! at padding or the order of descriptor lines at all. `report` lists every
+//! deviation it finds in the files of a disk, for those generating disks
+//! that ESXi and Workstation have to accept.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Error;
+
+use crate::compress::{COMPRESSION_DEFLATE, COMPRESSION_NONE};
+use crate::create::SPLIT_EXTENT_SECTORS;
+use crate::debug::{hex, HEADER_FIELDS, HEADER_LEN};
+use crate::descriptor::{Descriptor, DiskType, ExtentType};
+use crate::diagnostics::{Diagnostic, Diagnostics, Strictness};
+use crate::extent::{Backing, GD_AT_END};
+use crate::{
+    ExtentHeader, Vmdk, FLAG_COMPRESSED, FLAG_MARKERS, FLAG_USE_REDUNDANT_GT, FLAG_VALID_NEWLINE_DETECTION, SECTOR_SIZE,
+};
+
+/// Keys before the extent descriptions, in the order VMware writes them
+const HEADER_KEYS: [&str; 8] =
+    ["version", "encoding", "CID", "parentCID", "isNativeSnapshot", "createType", "parentFileNameHint", "changeTrackPath"];
+
+/// Disk database keys ESXi expects of every disk
+const REQUIRED_DDB_KEYS: [&str; 5] =
+    ["virtualHWVersion", "adapterType", "geometry.cylinders", "geometry.heads", "geometry.sectors"];
+
+/// A deviation from the specification found in one file of a disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Deviation {
+    pub path: PathBuf,
+    /// What deviates, with its byte offset in the file where known
+    pub diagnostic: Diagnostic,
+}
+
+impl fmt::Display for Deviation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.diagnostic)
+    }
+}
+
+/// Result of `report`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConformanceReport {
+    /// Deviations of the descriptor first, then of each sparse extent
+    pub deviations: Vec<Deviation>,
+}
+
+impl ConformanceReport {
+    /// Whether nothing deviates from the specification
+    pub fn conforms(&self) -> bool {
+        self.deviations.is_empty()
+    }
+
+    fn add(&mut self, path: &Path, found: Diagnostics) {
+        let deviations = found.0.into_iter().map(|diagnostic| Deviation { path: path.to_owned(), diagnostic });
+        self.deviations.extend(deviations);
+    }
+}
+
+/// Every deviation of the descriptor and sparse extent headers of `vmdk`
+/// from the specification: values out of range, reserved flags or padding
+/// that is not zero, and descriptor lines missing or out of order. Errors
+/// are what VMware refuses, warnings what it tolerates or rewrites.
+/// Parents are not examined.
+pub fn report(vmdk: &Vmdk) -> Result<ConformanceReport, Error> {
+    let mut report = ConformanceReport::default();
+    // The descriptor ends at the first NUL, the rest being padding
+    let text = vmdk.raw_descriptor().split(char::from(0)).next().unwrap_or_default();
+    let mut found = descriptor_deviations(text, &vmdk.descriptor);
+    found.rebase(vmdk.extent_header.as_ref().map_or(0, |h| h.desc_offset.bytes()));
+    report.add(&vmdk.path, found);
+
+    for extent in &vmdk.extents {
+        let file = match extent.backing.get()? {
+            Backing::Sparse { file, .. } => file.get()?,
+            _ => continue,
+        };
+        let mut sector = vec![0u8; SECTOR_SIZE as usize];
+        file.read_exact_at(&mut sector, 0)?;
+        let header = ExtentHeader::read(&sector[..])?;
+        let mut found = header.diagnose();
+        header_deviations(&header, &sector, &mut found);
+
+        // The embedded descriptor is padded with NULs
+        if let Ok((offset, size)) = header.descriptor_area(file.size()?) {
+            let mut area = vec![0u8; size as usize];
+            file.read_exact_at(&mut area, offset)?;
+            let end = area.iter().position(|&b| b == 0).unwrap_or(area.len());
+            if let Some(i) = area[end..].iter().position(|&b| b != 0) {
+                let at = end + i;
+                found.warning(Some(offset + at as u64), "descriptor padding", hex(&area[at..at + 1]), "NUL bytes");
+            }
+        }
+        report.add(extent.path.as_deref().unwrap_or(&vmdk.path), found);
+    }
+    Ok(report)
+}
+
+/// Deviations of the fields of a sparse extent header not caught when
+/// opening it, from the raw `sector` holding it
+fn header_deviations(header: &ExtentHeader, sector: &[u8], found: &mut Diagnostics) {
+    let field = |name: &str| {
+        let &(_, offset, size) = HEADER_FIELDS.iter().find(|f| f.0 == name).expect("known header field");
+        let bytes = &sector[offset..offset + size];
+        (Some(offset as u64), format!("{} ({})", hex(bytes), LittleEndian::read_uint(bytes, size)))
+    };
+
+    if header.flags & FLAG_VALID_NEWLINE_DETECTION == 0 {
+        let (offset, value) = field("flags");
+        found.warning(offset, "flags", value, "valid newline detection (0x1) set");
+    }
+    let stream = header.flags & (FLAG_COMPRESSED | FLAG_MARKERS);
+    if stream != 0 && stream != FLAG_COMPRESSED | FLAG_MARKERS {
+        let (offset, value) = field("flags");
+        found.error(offset, "flags", value, "compressed (0x10000) and markers (0x20000) set together");
+    }
+    let method = if header.flags & FLAG_COMPRESSED != 0 { COMPRESSION_DEFLATE } else { COMPRESSION_NONE };
+    if header.compress_method != method {
+        let (offset, value) = field("compressAlgorithm");
+        found.error(offset, "compressAlgorithm", value, method.to_string());
+    }
+    if header.flags & FLAG_USE_REDUNDANT_GT != 0 && header.rgd_offset.0 == 0 {
+        let (offset, value) = field("rgdOffset");
+        found.error(offset, "rgdOffset", value, "a redundant grain directory, as flags declare one");
+    }
+    if header.gd_offset.0 == 0 || (header.gd_offset.0 == GD_AT_END && stream == 0) {
+        let (offset, value) = field("gdOffset");
+        found.error(offset, "gdOffset", value, "the sector of the grain directory");
+    }
+    let grain_size = header.grain_size.0;
+    if grain_size != 0 && !header.capacity.0.is_multiple_of(grain_size) {
+        let (offset, value) = field("capacity");
+        found.warning(offset, "capacity", value, format!("a multiple of the grain size of {} sectors", grain_size));
+    }
+    if grain_size != 0 && !header.overhead.0.is_multiple_of(grain_size) {
+        let (offset, value) = field("overHead");
+        found.warning(offset, "overHead", value, format!("a multiple of the grain size of {} sectors", grain_size));
+    }
+    if header.dirty_shutdown > 1 {
+        let (offset, value) = field("uncleanShutdown");
+        found.error(offset, "uncleanShutdown", value, "0 or 1");
+    }
+    if let Some(i) = sector[HEADER_LEN..].iter().position(|&b| b != 0) {
+        let at = HEADER_LEN + i;
+        found.error(Some(at as u64), "pad", hex(&sector[at..at + 1]), "zeros up to the end of the sector");
+    }
+}
+
+/// Deviations of the descriptor `text`, parsed as `desc`, with offsets
+/// relative to its start
+fn descriptor_deviations(text: &str, desc: &Descriptor) -> Diagnostics {
+    // What parsing accepts leniently, such as trailing whitespace
+    let mut found = match Descriptor::with_strictness(text, Strictness::Lenient) {
+        Ok((_, warnings)) => warnings,
+        Err(_) => Diagnostics::default(),
+    };
+
+    // The header keys, then the extents, then the disk database
+    let mut section = 0;
+    let mut last_key: Option<(usize, &str)> = None;
+    let mut start = 0;
+    for raw in text.split_inclusive('\n') {
+        let line = raw.trim();
+        let offset = Some((start + raw.len() - raw.trim_start().len()) as u64);
+        start += raw.len();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let key = line.split('=').next().unwrap_or_default().trim();
+        let line_section = if line.starts_with("RW ") || line.starts_with("RDONLY ") || line.starts_with("NOACCESS ") {
+            1
+        } else if key.starts_with("ddb.") {
+            2
+        } else {
+            0
+        };
+        if line_section < section {
+            let expected = ["header lines before the extents", "extents before the disk database"][line_section];
+            found.warning(offset, "line", line, expected);
+        }
+        section = std::cmp::max(section, line_section);
+
+        if let Some(index) = HEADER_KEYS.iter().position(|k| *k == key) {
+            match last_key {
+                Some((last, previous)) if last > index => found.warning(offset, key, line, format!("{} before {}", key, previous)),
+                _ => last_key = Some((index, key)),
+            }
+        }
+    }
+
+    for key in REQUIRED_DDB_KEYS.iter().filter(|k| desc.ddb.get(k).is_none()) {
+        found.warning(None, format!("ddb.{}", key), "nothing", "a value");
+    }
+
+    let monolithic = matches!(desc.create_type, DiskType::MonolithicSparse | DiskType::MonolithicFlat | DiskType::StreamOptimized);
+    if monolithic && desc.extents.len() != 1 {
+        found.error(None, "extent", format!("{} extents", desc.extents.len()), format!("one extent for {}", desc.create_type));
+    }
+    let extent_type = match desc.create_type {
+        DiskType::MonolithicSparse | DiskType::TwoGbMaxExtentSparse | DiskType::StreamOptimized => Some(ExtentType::Sparse),
+        DiskType::MonolithicFlat | DiskType::TwoGbMaxExtentFlat => Some(ExtentType::Flat),
+        _ => None,
+    };
+    let split = matches!(desc.create_type, DiskType::TwoGbMaxExtentSparse | DiskType::TwoGbMaxExtentFlat);
+    for extent in &desc.extents {
+        if extent_type.is_some_and(|t| t != extent.extent_type) {
+            let expected = format!("{} extents for {}", extent_type.unwrap().as_str(), desc.create_type);
+            found.error(None, "extent", extent.extent_type.as_str(), expected);
+        }
+        if split && extent.sectors > SPLIT_EXTENT_SECTORS {
+            found.error(None, "extent", format!("{} sectors", extent.sectors), format!("at most {} sectors", SPLIT_EXTENT_SECTORS));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::VmdkBuilder;
+    use crate::testutil::{scratch_dir, SparseImage};
+
+    #[test]
+    fn test_conformance_report() {
+        let dir = scratch_dir("conformance");
+        // What the crate creates conforms
+        for create_type in [DiskType::MonolithicSparse, DiskType::TwoGbMaxExtentSparse, DiskType::MonolithicFlat] {
+            let path = dir.join(format!("{}.vmdk", create_type.as_str()));
+            let mut builder = VmdkBuilder::new(1 << 20);
+            builder.create_type(create_type);
+            let vmdk = builder.create(&path).unwrap();
+            let report = report(&vmdk).unwrap();
+            assert!(report.conforms(), "{:?}", report);
+        }
+
+        let mut image = SparseImage::new(1000, 128).monolithic("disk.vmdk");
+        image.descriptor = Some(
+            "# Disk DescriptorFile\nCID=12345678\nversion=1\nparentCID=ffffffff\n\
+             RW 1000 SPARSE \"disk.vmdk\"\ncreateType=\"monolithicSparse\"\nddb.adapterType = \"ide\"\n"
+                .to_owned(),
+        );
+        let mut bytes = image.build();
+        bytes[8] = 0;
+        bytes[100] = 0xaa;
+        let header = ExtentHeader::new(&bytes[..]).unwrap();
+        let padding = (header.desc_offset.bytes() + header.desc_size.bytes() - 1) as usize;
+        bytes[padding] = b'x';
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, &bytes).unwrap();
+
+        let vmdk = Vmdk::new(&path).unwrap();
+        let report = report(&vmdk).unwrap();
+        assert!(report.deviations.iter().all(|d| d.path == path));
+        let found: Vec<_> = report.deviations.iter().map(|d| (d.diagnostic.field.as_str(), d.diagnostic.severity)).collect();
+        use crate::diagnostics::Severity::*;
+        assert_eq!(
+            found,
+            [
+                ("version", Warning),
+                ("line", Warning),
+                ("ddb.virtualHWVersion", Warning),
+                ("ddb.geometry.cylinders", Warning),
+                ("ddb.geometry.heads", Warning),
+                ("ddb.geometry.sectors", Warning),
+                ("flags", Warning),
+                ("capacity", Warning),
+                ("pad", Error),
+                ("descriptor padding", Warning),
+            ]
+        );
+        let base = header.desc_offset.bytes();
+        let offsets: Vec<_> = report.deviations.iter().map(|d| d.diagnostic.offset).collect();
+        assert_eq!(offsets[..2], [Some(base + 35), Some(base + 91)]);
+        assert_eq!(offsets[6..], [Some(8), Some(12), Some(100), Some(padding as u64)]);
+        assert_eq!(
+            report.deviations[1].to_string(),
+            format!("{}: warning: line at byte {}: found createType=\"monolithicSparse\", expected header lines before the extents", path.display(), base + 91)
+        );
+    }
+}
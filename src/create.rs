@@ -0,0 +1,728 @@
+//! Creation of new, empty disks.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+use log::info;
+
+use crate::descriptor::{
+    format_uuid, mix, new_cid, new_uuid, AccessMode, Descriptor, DescriptorBuilder, DiskDatabase, DiskType, ExtentType,
+    NO_PARENT_CID, Provisioning, SECTOR_SIZES,
+};
+use crate::extent::is_zero;
+use crate::lba::LbaMapper;
+use crate::progress::{CancelToken, Monitor, Progress};
+use crate::storage::{Storage, StorageProvider};
+use crate::stream::{DEFAULT_GRAIN_SIZE, DEFAULT_GTES_PER_GT};
+use crate::{
+    ExtentHeader, SectorType, Vmdk, VmdkError, VmdkOpenOptions, EXTENT_MAGIC, FLAG_USE_REDUNDANT_GT,
+    FLAG_VALID_NEWLINE_DETECTION, SECTOR_SIZE,
+};
+
+/// Sectors per extent of `twoGbMaxExtent*` disks, 2047 MiB as VMware uses
+pub const SPLIT_EXTENT_SECTORS: u64 = 4192256;
+
+/// Bytes of zeros written at once when eagerly zeroing flat extents
+const ZERO_CHUNK: usize = 1 << 20;
+
+/// Sectors reserved for the embedded descriptor, as VMware does, so it can
+/// grow when rewritten
+const EMBEDDED_DESCRIPTOR_SECTORS: u64 = 20;
+
+/// Largest grain accepted, 32 MiB. Reads and writes buffer whole grains.
+const MAX_GRAIN_SIZE: u64 = 1 << 16;
+
+/// Creates new disks.
+///
+/// Supports `monolithicSparse`, `monolithicFlat` and the split
+/// `twoGbMaxExtentSparse` and `twoGbMaxExtentFlat` disks. Stream-optimized
+/// disks are written in one pass with `stream::StreamOptimizedWriter`.
+#[derive(Debug, Clone)]
+pub struct VmdkBuilder {
+    /// Capacity in sectors
+    capacity: u64,
+    create_type: DiskType,
+    cid: Option<u32>,
+    seed: Option<u64>,
+    ddb: DiskDatabase,
+    /// File name hint and CID of the parent of a delta disk
+    parent: Option<(String, u32)>,
+    /// Logical and physical sector sizes
+    sector_size: (u64, u64),
+    /// Sectors per grain of sparse extents
+    grain_size: u64,
+    provisioning: Option<Provisioning>,
+    progress: Option<Arc<dyn Progress>>,
+    cancel: Option<CancelToken>,
+    /// Where the files are created, if not the local filesystem
+    storage: Option<Arc<dyn StorageProvider>>,
+}
+
+impl VmdkBuilder {
+    /// A disk of `capacity` bytes, rounded up to whole sectors
+    pub fn new(capacity: u64) -> Self {
+        VmdkBuilder {
+            capacity: capacity.div_ceil(SECTOR_SIZE),
+            create_type: DiskType::MonolithicSparse,
+            cid: None,
+            seed: None,
+            ddb: DiskDatabase::default(),
+            parent: None,
+            sector_size: (512, 512),
+            grain_size: DEFAULT_GRAIN_SIZE,
+            provisioning: None,
+            progress: None,
+            cancel: None,
+            storage: None,
+        }
+    }
+
+    /// Layout of the disk, `monolithicSparse` by default
+    pub fn create_type(&mut self, create_type: DiskType) -> &mut Self {
+        self.create_type = create_type;
+        self
+    }
+
+    /// Use `cid` as content ID instead of a random one
+    pub fn cid(&mut self, cid: u32) -> &mut Self {
+        self.cid = Some(cid);
+        self
+    }
+
+    /// Derive the content ID and image UUID from `seed` instead of picking
+    /// random ones, so the same settings always produce byte-identical
+    /// disks. Values set with `cid` or `ddb` still take precedence. For
+    /// stream-optimized disks, pass `descriptor` to
+    /// `StreamOptimizedWriter::with_descriptor`.
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Disk database entries, overriding the generated adapter type,
+    /// geometry and UUID where they overlap. Keys describing the
+    /// allocation of the disk always follow `provisioning` instead.
+    pub fn ddb(&mut self, ddb: DiskDatabase) -> &mut Self {
+        self.ddb = ddb;
+        self
+    }
+
+    /// Make the disk a delta of the disk with content ID `cid`, found at
+    /// `file_name_hint` relative to the new disk. Only sparse disks can be
+    /// deltas. See `Vmdk::snapshot` for the common case.
+    pub fn parent(&mut self, file_name_hint: &str, cid: u32) -> &mut Self {
+        self.parent = Some((file_name_hint.to_owned(), cid));
+        self
+    }
+
+    /// Declare the sector sizes of the disk, 512 or 4096 bytes each: 512
+    /// and 4096 for a 512e disk, 4096 and 4096 for a 4Kn disk. The capacity
+    /// is rounded up to whole logical sectors.
+    pub fn sector_size(&mut self, logical: u64, physical: u64) -> &mut Self {
+        let per_sector = std::cmp::max(logical / SECTOR_SIZE, 1);
+        self.capacity = self.capacity.div_ceil(per_sector) * per_sector;
+        self.sector_size = (logical, physical);
+        self
+    }
+
+    /// Sectors per grain of sparse extents, a power of two from 8 to 65536,
+    /// 128 by default. Larger grains mean smaller grain tables but more
+    /// space allocated per write.
+    pub fn grain_size(&mut self, sectors: u64) -> &mut Self {
+        self.grain_size = sectors;
+        self
+    }
+
+    /// How the space of the disk is allocated, recorded as
+    /// `ddb.thinProvisioned` for thin disks. Sparse disks are always thin; flat disks are
+    /// lazy-zeroed, only reserving their size, unless `EagerZeroed` has
+    /// them allocated at creation, reporting progress as the "zeroing"
+    /// phase. Where the filesystem can allocate zeroed space at once, as
+    /// with `fallocate` on Linux, it is used rather than writing zeros.
+    pub fn provisioning(&mut self, provisioning: Provisioning) -> &mut Self {
+        self.provisioning = Some(provisioning);
+        self
+    }
+
+    /// Report progress of `import` and eager zeroing to, and poll for
+    /// cancellation from, `progress`
+    pub fn progress<P: Progress + 'static>(&mut self, progress: P) -> &mut Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Stop `import` or eager zeroing when `cancel` is cancelled
+    pub fn cancel_token(&mut self, cancel: &CancelToken) -> &mut Self {
+        self.cancel = Some(cancel.clone());
+        self
+    }
+
+    /// Create the files of the disk through `provider` rather than on the
+    /// local filesystem, and open the disk through it too, see
+    /// `VmdkOpenOptions::storage`
+    pub fn storage<P: StorageProvider + 'static>(&mut self, provider: P) -> &mut Self {
+        self.storage = Some(Arc::new(provider));
+        self
+    }
+
+    /// The descriptor of the disk stored as `name`, from which the names
+    /// of separate extent files are derived
+    pub fn descriptor(&self, name: &str) -> Descriptor {
+        let stem = name.strip_suffix(".vmdk").unwrap_or(name);
+        let mut builder = DescriptorBuilder::new();
+        builder.create_type(self.create_type.clone());
+        let mut split = |extent_type, suffix| {
+            let count = std::cmp::max(self.capacity.div_ceil(SPLIT_EXTENT_SECTORS), 1);
+            for i in 0..count {
+                let sectors = std::cmp::min(SPLIT_EXTENT_SECTORS, self.capacity - i * SPLIT_EXTENT_SECTORS);
+                let file = format!("{}-{}{:03}.vmdk", stem, suffix, i + 1);
+                builder.add_extent(AccessMode::Rw, sectors, extent_type, &file);
+            }
+        };
+        match self.create_type {
+            DiskType::TwoGbMaxExtentFlat => split(ExtentType::Flat, 'f'),
+            DiskType::TwoGbMaxExtentSparse => split(ExtentType::Sparse, 's'),
+            DiskType::MonolithicFlat => {
+                builder.add_extent(AccessMode::Rw, self.capacity, ExtentType::Flat, &format!("{}-flat.vmdk", stem));
+            }
+            _ => {
+                builder.add_extent(AccessMode::Rw, self.capacity, ExtentType::Sparse, name);
+            }
+        }
+
+        let (cid, uuid) = match self.seed {
+            Some(seed) => (seeded_cid(seed), format_uuid(mix(seed ^ 1), mix(seed ^ 2))),
+            None => (new_cid(NO_PARENT_CID), new_uuid()),
+        };
+        let adapter = "lsilogic";
+        builder
+            .cid(self.cid.unwrap_or(cid))
+            .ddb("virtualHWVersion", "4")
+            .ddb("adapterType", adapter)
+            .geometry_for_capacity(self.capacity * SECTOR_SIZE, adapter)
+            .ddb("uuid.image", &uuid);
+        if let Some((hint, cid)) = &self.parent {
+            builder.parent(hint, *cid);
+        }
+        // Thick disks are told apart by their extents
+        if self.provisioning.unwrap_or(self.default_provisioning()) == Provisioning::Thin {
+            builder.ddb("thinProvisioned", "1");
+        }
+        if self.sector_size != (512, 512) {
+            builder
+                .ddb("logicalSectorSize", &self.sector_size.0.to_string())
+                .ddb("physicalSectorSize", &self.sector_size.1.to_string());
+        }
+        for (key, value) in self.ddb.iter().filter(|&(key, _)| key != "thinProvisioned") {
+            builder.ddb(key, value);
+        }
+        builder.build()
+    }
+
+    /// Create the disk at `path`, failing if it exists, and open it for
+    /// writing
+    pub fn create<P: AsRef<Path>>(&self, path: P) -> Result<Vmdk, Error> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .ok_or_else(|| VmdkError::InvalidArgument(path.display().to_string()))?
+            .to_string_lossy()
+            .into_owned();
+        let desc = self.descriptor(&name);
+        if self.parent.is_some() && !matches!(self.create_type, DiskType::MonolithicSparse | DiskType::TwoGbMaxExtentSparse) {
+            return Err(VmdkError::InvalidArgument(format!("{} disk cannot have a parent", self.create_type.as_str())).into());
+        }
+        let (logical, physical) = self.sector_size;
+        if !SECTOR_SIZES.contains(&logical) || !SECTOR_SIZES.contains(&physical) || logical > physical {
+            return Err(VmdkError::InvalidArgument(format!("sector sizes {}/{}", logical, physical)).into());
+        }
+        if !self.grain_size.is_power_of_two() || self.grain_size < 8 || self.grain_size > MAX_GRAIN_SIZE {
+            return Err(VmdkError::InvalidArgument(format!("grain size of {} sectors", self.grain_size)).into());
+        }
+        let flat = self.default_provisioning() != Provisioning::Thin;
+        match self.provisioning {
+            Some(Provisioning::Thin) if flat => {
+                return Err(VmdkError::InvalidArgument("flat disks cannot be thin provisioned".to_owned()).into());
+            }
+            Some(mode @ Provisioning::LazyZeroed) | Some(mode @ Provisioning::EagerZeroed) if !flat => {
+                return Err(VmdkError::InvalidArgument(format!("{} disk cannot be {}", self.create_type.as_str(), mode.as_str())).into());
+            }
+            _ => (),
+        }
+
+        match self.create_type {
+            DiskType::MonolithicSparse => {
+                let mut file = self.create_file(path)?;
+                let (metadata, overhead) = sparse_metadata(self.capacity, self.grain_size, Some(&desc.to_text()))?;
+                file.write_all(&metadata)?;
+                file.set_len(overhead * SECTOR_SIZE)?;
+                file.sync_all()?;
+            }
+            DiskType::MonolithicFlat | DiskType::TwoGbMaxExtentFlat | DiskType::TwoGbMaxExtentSparse => {
+                let mut created = Vec::new();
+                if let Err(e) = self.create_extents(path, &desc, &mut created) {
+                    for file in &created {
+                        let _ = self.remove_file(file);
+                    }
+                    return Err(e);
+                }
+                let mut file = self.create_file(path)?;
+                file.write_all(desc.to_text().as_bytes())?;
+                file.sync_all()?;
+            }
+            ref other => return Err(VmdkError::UnsupportedExtent(other.as_str().to_owned()).into()),
+        }
+        info!("Created {} disk {}", self.create_type.as_str(), path.display());
+
+        let mut options = VmdkOpenOptions::new();
+        options.write(true);
+        options.storage = self.storage.clone();
+        let mut vmdk = options.open(path)?;
+        // Nothing can refer to the content of a disk that did not exist
+        // before, so filling it keeps the chosen CID
+        vmdk.cid_updated = true;
+        Ok(vmdk)
+    }
+
+    fn create_file(&self, path: &Path) -> Result<Box<dyn Storage>, Error> {
+        match &self.storage {
+            Some(provider) => provider.create(path),
+            None => Ok(Box::new(OpenOptions::new().write(true).create_new(true).open(path)?)),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error> {
+        match &self.storage {
+            Some(provider) => provider.remove(path),
+            None => Ok(std::fs::remove_file(path)?),
+        }
+    }
+
+    /// Sparse disks are thin, flat ones lazy-zeroed unless told otherwise
+    fn default_provisioning(&self) -> Provisioning {
+        match self.create_type {
+            DiskType::MonolithicFlat | DiskType::TwoGbMaxExtentFlat => Provisioning::LazyZeroed,
+            _ => Provisioning::Thin,
+        }
+    }
+
+    /// Create the extent files of a disk with a separate descriptor,
+    /// adding each to `created` so they can be removed on failure
+    fn create_extents(&self, path: &Path, desc: &Descriptor, created: &mut Vec<PathBuf>) -> Result<(), Error> {
+        let monitor = Monitor {
+            progress: self.progress.as_deref(),
+            cancel: self.cancel.as_ref(),
+        };
+        let eager = self.provisioning == Some(Provisioning::EagerZeroed);
+        if eager {
+            monitor.phase("zeroing");
+        }
+        let total = desc.capacity() * SECTOR_SIZE;
+        let mut done = 0;
+
+        for extent in &desc.extents {
+            let extent_path = path.with_file_name(extent.filename.as_deref().unwrap_or_default());
+            let mut file = self.create_file(&extent_path)?;
+            created.push(extent_path);
+            let len = extent.sectors * SECTOR_SIZE;
+            match extent.extent_type {
+                ExtentType::Flat if eager => {
+                    monitor.step(done, total)?;
+                    if !file.as_file().map_or(Ok(false), |f| preallocate(f, len))? {
+                        let zeros = vec![0u8; ZERO_CHUNK];
+                        let mut written = 0;
+                        while written < len {
+                            monitor.step(done + written, total)?;
+                            let n = std::cmp::min(len - written, ZERO_CHUNK as u64);
+                            file.write_all(&zeros[..n as usize])?;
+                            written += n;
+                        }
+                    }
+                }
+                ExtentType::Flat => file.set_len(len)?,
+                _ => {
+                    let (metadata, overhead) = sparse_metadata(extent.sectors, self.grain_size, None)?;
+                    file.write_all(&metadata)?;
+                    file.set_len(overhead * SECTOR_SIZE)?;
+                }
+            }
+            file.sync_all()?;
+            done += len;
+        }
+
+        if eager {
+            monitor.done(total);
+        }
+        Ok(())
+    }
+}
+
+/// Content ID derived from `seed`, never `NO_PARENT_CID`
+fn seeded_cid(seed: u64) -> u32 {
+    match mix(seed) as u32 {
+        NO_PARENT_CID => 0,
+        cid => cid,
+    }
+}
+
+impl VmdkBuilder {
+    /// Create the disk at `path` and fill it with raw disk contents read
+    /// from `reader`, leaving grains that hold only zeros unallocated.
+    ///
+    /// If the import is cancelled or fails after the disk was created, its
+    /// files are removed again.
+    pub fn import<P: AsRef<Path>, R: Read>(&self, path: P, reader: R) -> Result<Vmdk, Error> {
+        let mut vmdk = self.create(path)?;
+        match self.fill(&mut vmdk, reader) {
+            Ok(()) => Ok(vmdk),
+            Err(e) => {
+                vmdk.remove();
+                Err(e)
+            }
+        }
+    }
+
+    fn fill<R: Read>(&self, vmdk: &mut Vmdk, mut reader: R) -> Result<(), Error> {
+        let monitor = Monitor {
+            progress: self.progress.as_deref(),
+            cancel: self.cancel.as_ref(),
+        };
+        let mut buf = vec![0u8; (self.grain_size * SECTOR_SIZE).try_into()?];
+        let mut offset = 0;
+        let size = vmdk.size();
+        monitor.phase("importing");
+
+        loop {
+            monitor.step(offset, size)?;
+            let mut len = 0;
+            while len < buf.len() {
+                match reader.read(&mut buf[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            if len == 0 {
+                break;
+            }
+            if offset + len as u64 > size {
+                return Err(VmdkError::InvalidArgument("input larger than the disk".to_owned()).into());
+            }
+            if !is_zero(&buf[..len]) {
+                vmdk.write_at(offset, &buf[..len])?;
+            }
+            offset += len as u64;
+        }
+
+        monitor.done(size);
+        vmdk.flush()
+    }
+}
+
+/// Allocate the first `len` bytes of the empty `file`, which then read as
+/// zeros, without writing them. Returns whether the filesystem supports
+/// it.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> Result<bool, Error> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(true);
+    }
+    // SAFETY: fallocate only acts on the file descriptor passed
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(e.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _len: u64) -> Result<bool, Error> {
+    Ok(false)
+}
+
+/// Header, embedded descriptor and empty grain directories and tables of a
+/// hosted sparse extent of `capacity` sectors in grains of `grain_size`
+/// sectors, with the overhead in sectors the file is to be extended to so
+/// grains start on grain boundaries. Extents of split disks have no
+/// descriptor.
+fn sparse_metadata(capacity: u64, grain_size: u64, descriptor: Option<&str>) -> Result<(Vec<u8>, u64), Error> {
+    let mapper = LbaMapper::new(capacity, grain_size, DEFAULT_GTES_PER_GT);
+    let (num_gts, gt_sectors, gd_sectors) = (mapper.num_gts(), mapper.gt_sectors(), mapper.gd_sectors());
+
+    let (desc_offset, desc_size) = match descriptor {
+        Some(text) => (1, std::cmp::max(EMBEDDED_DESCRIPTOR_SECTORS, (text.len() as u64).div_ceil(SECTOR_SIZE))),
+        None => (0, 0),
+    };
+    let rgd_offset = 1 + desc_size;
+    let gd_offset = rgd_offset + gd_sectors + num_gts * gt_sectors;
+    let overhead = mapper.overhead(desc_size, true);
+
+    let header = ExtentHeader {
+        magic_number: EXTENT_MAGIC,
+        version: 1,
+        flags: FLAG_VALID_NEWLINE_DETECTION | FLAG_USE_REDUNDANT_GT,
+        capacity: SectorType(capacity),
+        grain_size: SectorType(grain_size),
+        desc_offset: SectorType(desc_offset),
+        desc_size: SectorType(desc_size),
+        gtes_per_gt: DEFAULT_GTES_PER_GT,
+        rgd_offset: SectorType(rgd_offset),
+        gd_offset: SectorType(gd_offset),
+        overhead: SectorType(overhead),
+        dirty_shutdown: 0,
+        single_eol_char: b'\n',
+        non_eol_char: b' ',
+        dbl_eol_char: b'\r',
+        dbl_eol_char2: b'\n',
+        compress_method: 0,
+    };
+
+    let end = gd_offset + gd_sectors + num_gts * gt_sectors;
+    let mut out = Vec::with_capacity((end * SECTOR_SIZE).try_into()?);
+    header.write(&mut out)?;
+    out.extend_from_slice(descriptor.unwrap_or_default().as_bytes());
+    out.resize((end * SECTOR_SIZE).try_into()?, 0);
+
+    // Every grain table is preallocated, right after its directory
+    for gd in &[rgd_offset, gd_offset] {
+        let mut entries = Vec::new();
+        for t in 0..num_gts {
+            entries.write_u32::<LittleEndian>((gd + gd_sectors + t * gt_sectors).try_into()?)?;
+        }
+        let start = (gd * SECTOR_SIZE) as usize;
+        out[start..start + entries.len()].copy_from_slice(&entries);
+    }
+
+    Ok((out, overhead))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::scratch_dir;
+
+    #[test]
+    fn test_create_monolithic_sparse() {
+        let dir = scratch_dir("create-sparse");
+        let path = dir.join("new.vmdk");
+        let mut vmdk = VmdkBuilder::new(3 << 20).cid(0x1234abcd).create(&path).unwrap();
+        assert_eq!(vmdk.size(), 3 << 20);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 128 * 512);
+
+        vmdk.write_at(1 << 20, &[0x5a; 4096]).unwrap();
+        vmdk.close().unwrap();
+        assert!(VmdkBuilder::new(3 << 20).create(&path).is_err());
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        let mut buf = vec![0u8; 8192];
+        vmdk.read_at((1 << 20) - 4096, &mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|&b| b == 0));
+        assert!(buf[4096..].iter().all(|&b| b == 0x5a));
+        assert_eq!(vmdk.descriptor.ddb.get("adapterType"), Some("lsilogic"));
+        // 3 MiB as VMware sees it on a SCSI adapter
+        let geometry = crate::descriptor::Geometry { cylinders: 3, heads: 64, sectors: 32 };
+        assert_eq!(vmdk.descriptor.ddb.geometry(), Some(geometry));
+        assert_eq!(vmdk.extent_header.as_ref().unwrap().desc_size.0, EMBEDDED_DESCRIPTOR_SECTORS);
+    }
+
+    #[test]
+    fn test_create_4kn() {
+        let dir = scratch_dir("create-4kn");
+        let path = dir.join("new.vmdk");
+        let mut vmdk = VmdkBuilder::new((1 << 20) + 512).sector_size(4096, 4096).create(&path).unwrap();
+        assert_eq!(vmdk.size(), (1 << 20) + 4096);
+        assert_eq!((vmdk.logical_sector_size(), vmdk.physical_sector_size()), (4096, 4096));
+        assert_eq!(vmdk.write_sectors(256, &[0x4b; 8192]).unwrap(), 1);
+        assert!(vmdk.write_sectors(0, &[0; 512]).is_err());
+        vmdk.close().unwrap();
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        let mut buf = vec![0u8; 8192];
+        assert_eq!(vmdk.read_sectors(255, &mut buf).unwrap(), 2);
+        assert!(buf[..4096].iter().all(|&b| b == 0));
+        assert!(buf[4096..].iter().all(|&b| b == 0x4b));
+        assert!(vmdk.read_sectors(u64::MAX, &mut buf).is_err());
+
+        assert!(VmdkBuilder::new(1 << 20).sector_size(4096, 512).create(dir.join("bad.vmdk")).is_err());
+    }
+
+    #[test]
+    fn test_create_grain_size() {
+        let dir = scratch_dir("create-grain-size");
+        let path = dir.join("new.vmdk");
+        let mut vmdk = VmdkBuilder::new(300 << 20).grain_size(2048).create(&path).unwrap();
+        let header = vmdk.extent_header.clone().unwrap();
+        assert_eq!(header.grain_size.sectors(), 2048);
+        // 300 grains need a single grain table
+        assert_eq!(header.gd_offset.sectors() - header.rgd_offset.sectors(), 1 + 4);
+        assert_eq!(header.overhead.sectors(), 2048);
+
+        vmdk.write_at(5 << 20, &[0x3c; 512]).unwrap();
+        vmdk.close().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * 2048 * 512);
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert!(vmdk.check().unwrap().problems.is_empty());
+        let mut buf = [0u8; 1024];
+        vmdk.read_at((5 << 20) - 512, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0) && buf[512..].iter().all(|&b| b == 0x3c));
+
+        for &bad in &[0, 4, 100, 1 << 30] {
+            assert!(VmdkBuilder::new(1 << 20).grain_size(bad).create(dir.join("bad.vmdk")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_create_monolithic_flat() {
+        let dir = scratch_dir("create-flat");
+        let path = dir.join("new.vmdk");
+        let mut vmdk = VmdkBuilder::new(1 << 20)
+            .create_type(DiskType::MonolithicFlat)
+            .create(&path)
+            .unwrap();
+        vmdk.write_at(512, &[1u8; 512]).unwrap();
+        vmdk.close().unwrap();
+
+        let flat = std::fs::read(dir.join("new-flat.vmdk")).unwrap();
+        assert_eq!(flat.len(), 1 << 20);
+        assert!(flat[512..1024].iter().all(|&b| b == 1));
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("RW 2048 FLAT \"new-flat.vmdk\" 0\n"));
+
+        assert!(VmdkBuilder::new(1 << 20).create_type(DiskType::Vmfs).create(dir.join("vmfs.vmdk")).is_err());
+    }
+
+    #[test]
+    fn test_create_reproducible() {
+        let dirs = [scratch_dir("create-seeded-a"), scratch_dir("create-seeded-b")];
+        let mut builder = VmdkBuilder::new(1 << 20);
+        builder.seed(42);
+        for dir in &dirs {
+            let mut vmdk = builder.create(dir.join("disk.vmdk")).unwrap();
+            vmdk.write_at(0, &[3u8; 512]).unwrap();
+            vmdk.close().unwrap();
+        }
+        VmdkBuilder::new(1 << 20).seed(43).create(dirs[0].join("other.vmdk")).unwrap();
+
+        let a = std::fs::read(dirs[0].join("disk.vmdk")).unwrap();
+        assert!(a == std::fs::read(dirs[1].join("disk.vmdk")).unwrap());
+        let other = Vmdk::new(dirs[0].join("other.vmdk")).unwrap();
+        assert_ne!(other.cid(), Vmdk::new(dirs[0].join("disk.vmdk")).unwrap().cid());
+        assert_eq!(builder.descriptor("x.vmdk"), builder.descriptor("x.vmdk"));
+    }
+
+    #[test]
+    fn test_import_skips_zero_grains() {
+        let dir = scratch_dir("import");
+        let mut raw = vec![0u8; 1 << 20];
+        raw[200_000..200_100].iter_mut().for_each(|b| *b = 9);
+        raw[(1 << 20) - 1] = 1;
+
+        let vmdk = VmdkBuilder::new(1 << 20).import(dir.join("disk.vmdk"), &raw[..]).unwrap();
+        vmdk.close().unwrap();
+        // Metadata and two data grains
+        assert_eq!(std::fs::metadata(dir.join("disk.vmdk")).unwrap().len(), 3 * 128 * 512);
+        let mut vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        let mut buf = vec![0u8; 1 << 20];
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert!(buf == raw);
+
+        assert!(VmdkBuilder::new(4096).import(dir.join("small.vmdk"), &raw[..]).is_err());
+        assert!(!dir.join("small.vmdk").exists());
+        assert!(is_zero(&[0u8; 33]) && !is_zero(&[0, 0, 1]));
+    }
+
+    #[test]
+    fn test_create_provisioning() {
+        let dir = scratch_dir("create-provisioning");
+        let sparse = VmdkBuilder::new(1 << 20).create(dir.join("thin.vmdk")).unwrap();
+        assert_eq!(sparse.descriptor.provisioning(), Provisioning::Thin);
+        let lazy = VmdkBuilder::new(1 << 20).create_type(DiskType::MonolithicFlat).create(dir.join("lazy.vmdk")).unwrap();
+        assert_eq!(lazy.descriptor.provisioning(), Provisioning::LazyZeroed);
+
+        let mut builder = VmdkBuilder::new(3 << 20);
+        builder.create_type(DiskType::MonolithicFlat).provisioning(Provisioning::EagerZeroed);
+        let eager = builder.create(dir.join("eager.vmdk")).unwrap();
+        // Zeroing at creation is not recorded in the descriptor
+        assert_eq!(eager.descriptor.provisioning(), Provisioning::LazyZeroed);
+        assert_eq!(eager.descriptor.ddb.iter().count(), lazy.descriptor.ddb.iter().count());
+        assert!(Vmdk::new(dir.join("eager.vmdk")).unwrap().warnings().is_empty());
+        let data = std::fs::read(dir.join("eager-flat.vmdk")).unwrap();
+        assert_eq!(data.len(), 3 << 20);
+        assert!(is_zero(&data));
+        #[cfg(target_os = "linux")]
+        {
+            // Allocated, whether preallocated or written
+            use std::os::unix::fs::MetadataExt;
+            assert!(std::fs::metadata(dir.join("eager-flat.vmdk")).unwrap().blocks() * 512 >= 3 << 20);
+        }
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert!(builder.cancel_token(&cancel).create(dir.join("cancelled.vmdk")).is_err());
+        assert!(!dir.join("cancelled-flat.vmdk").exists() && !dir.join("cancelled.vmdk").exists());
+
+        let mut thin_flat = VmdkBuilder::new(1 << 20);
+        thin_flat.create_type(DiskType::MonolithicFlat).provisioning(Provisioning::Thin);
+        assert!(thin_flat.create(dir.join("bad.vmdk")).is_err());
+        assert!(VmdkBuilder::new(1 << 20).provisioning(Provisioning::EagerZeroed).create(dir.join("bad.vmdk")).is_err());
+    }
+
+    #[test]
+    fn test_create_split_flat() {
+        let dir = scratch_dir("create-split-flat");
+        let capacity = 3 << 30;
+        let vmdk = VmdkBuilder::new(capacity)
+            .create_type(DiskType::TwoGbMaxExtentFlat)
+            .create(dir.join("split.vmdk"))
+            .unwrap();
+        assert_eq!(vmdk.size(), capacity);
+        let f001 = std::fs::metadata(dir.join("split-f001.vmdk")).unwrap().len();
+        let f002 = std::fs::metadata(dir.join("split-f002.vmdk")).unwrap().len();
+        assert_eq!(f001, SPLIT_EXTENT_SECTORS * SECTOR_SIZE);
+        assert_eq!(f001 + f002, capacity);
+    }
+
+    #[test]
+    fn test_create_degenerate() {
+        let dir = scratch_dir("create-degenerate");
+        let types = [DiskType::MonolithicSparse, DiskType::TwoGbMaxExtentSparse, DiskType::MonolithicFlat];
+        // Empty, one sector, less than a grain, exactly one grain
+        for &capacity in &[0u64, 512, 1000, 65536] {
+            for create_type in types.iter() {
+                let path = dir.join(format!("{}-{}.vmdk", create_type.as_str(), capacity));
+                let size = capacity.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+                let mut vmdk = VmdkBuilder::new(capacity).create_type(create_type.clone()).create(&path).unwrap();
+                assert_eq!(vmdk.size(), size);
+                let mut buf = [0u8; 1024];
+                assert_eq!(vmdk.write_at(size.saturating_sub(512), &[0xb1; 1024]).unwrap(), std::cmp::min(size, 512) as usize);
+                assert_eq!(vmdk.sparsify().unwrap(), 0);
+                assert!(vmdk.check().unwrap().problems.is_empty());
+                vmdk.close().unwrap();
+
+                let mut vmdk = Vmdk::new(&path).unwrap();
+                assert!(vmdk.warnings().is_empty(), "{:?}", vmdk.warnings());
+                assert_eq!(vmdk.map().unwrap().len(), if size == 0 { 0 } else { 1 });
+                assert_eq!(vmdk.read_at(size, &mut buf).unwrap(), 0);
+                if size > 0 {
+                    assert_eq!(vmdk.read_at(size - 512, &mut buf).unwrap(), 512);
+                    assert_eq!(buf[..512], [0xb1; 512]);
+                }
+
+                let stream = vmdk.export_stream_optimized(Vec::new(), "disk.vmdk", &crate::clone::CloneOptions::new()).unwrap();
+                let mut reader = crate::stream::StreamReader::new(&stream[..]).unwrap();
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data).unwrap();
+                assert_eq!(data.len() as u64, size);
+                assert!(data.iter().rev().take(512).all(|&b| b == 0xb1));
+            }
+        }
+    }
+}
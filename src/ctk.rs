@@ -0,0 +1,137 @@
+//! Association of disks with their changed block tracking files.
+//!
+//! Disks with changed block tracking (CBT) enabled name a `-ctk.vmdk` file
+//! in the `changeTrackPath` key of their descriptor. The hypervisor keeps
+//! that file up to date, and creates a fresh one when the key names a file
+//! that does not exist, which backups must treat as a reset: the next
+//! backup of the disk is a full one.
+
+use std::path::{Path, PathBuf};
+use failure::Error;
+use log::info;
+
+use crate::descriptor;
+use crate::{Vmdk, VmdkError};
+
+/// Comment heading the `changeTrackPath` key, as ESXi writes it
+const CTK_SECTION: &str = "# Change Tracking File";
+
+/// Name of the change tracking file of the disk whose descriptor is at
+/// `path`, `disk-ctk.vmdk` for `disk.vmdk`
+pub fn ctk_name(path: &Path) -> String {
+    let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    format!("{}-ctk.vmdk", stem)
+}
+
+impl Vmdk {
+    /// Change tracking file named by the descriptor, resolved like extent
+    /// file names. It need not exist.
+    pub fn change_track_path(&self) -> Option<&Path> {
+        self.ctk_path.as_deref()
+    }
+
+    /// Whether the descriptor names a change tracking file
+    pub fn change_tracking_enabled(&self) -> bool {
+        self.descriptor.change_track_path.is_some()
+    }
+
+    /// Name `ctk_name` of this disk as its change tracking file, returning
+    /// its path. Disks already tracking changes keep their file. The disk
+    /// must be open for writing.
+    pub fn enable_change_tracking(&mut self) -> Result<PathBuf, Error> {
+        if let Some(path) = &self.ctk_path {
+            return Ok(path.clone());
+        }
+        self.check_descriptor_writable()?;
+        let name = ctk_name(&self.path);
+        let section = format!("{}\nchangeTrackPath=\"{}\"\n", CTK_SECTION, name);
+        let text = descriptor::insert_section(self.descriptor_text(), &section);
+        self.write_descriptor(text)?;
+
+        let path = self.path.with_file_name(&name);
+        info!("Change tracking of {} enabled in {}", self.path.display(), path.display());
+        self.descriptor.change_track_path = Some(name);
+        self.ctk_path = Some(path.clone());
+        Ok(path)
+    }
+
+    /// Remove `changeTrackPath` from the descriptor, deleting the change
+    /// tracking file too if `delete`. The disk must be open for writing.
+    pub fn disable_change_tracking(&mut self, delete: bool) -> Result<(), Error> {
+        if !self.change_tracking_enabled() {
+            return Ok(());
+        }
+        self.check_descriptor_writable()?;
+        let text = descriptor::remove_value(self.descriptor_text(), "changeTrackPath");
+        let text: String = text.split_inclusive('\n').filter(|line| line.trim() != CTK_SECTION).collect();
+        self.write_descriptor(text)?;
+
+        self.descriptor.change_track_path = None;
+        if let Some(path) = self.ctk_path.take() {
+            if delete && path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            info!("Change tracking of {} disabled", self.path.display());
+        }
+        Ok(())
+    }
+
+    /// Text descriptors are only kept open, and so writable, by handles
+    /// opened for writing; embedded ones are checked by their extent
+    fn check_descriptor_writable(&self) -> Result<(), Error> {
+        if self.extent_header.is_none() && self.desc_file.is_none() {
+            return Err(VmdkError::NotWritable("descriptor of a disk opened read-only".to_owned()).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+    use crate::VmdkOpenOptions;
+
+    #[test]
+    fn test_change_tracking() {
+        let dir = scratch_dir("ctk");
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, "# Disk DescriptorFile\nversion=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            createType=\"monolithicFlat\"\n\n# Extent description\nRW 1024 FLAT \"disk-flat.vmdk\" 0\n\n\
+            # The Disk Data Base\n#DDB\n\nddb.adapterType = \"lsilogic\"\n").unwrap();
+        std::fs::write(dir.join("disk-flat.vmdk"), vec![0u8; 1024 * 512]).unwrap();
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert!(!vmdk.change_tracking_enabled());
+        assert!(vmdk.enable_change_tracking().is_err());
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        let ctk = vmdk.enable_change_tracking().unwrap();
+        assert_eq!(ctk, dir.join("disk-ctk.vmdk"));
+        assert!(vmdk.raw_descriptor().contains(
+            "\"disk-flat.vmdk\" 0\n\n# Change Tracking File\nchangeTrackPath=\"disk-ctk.vmdk\"\n\n# The Disk Data Base\n"
+        ));
+        drop(vmdk);
+
+        std::fs::write(&ctk, b"tracking").unwrap();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        assert_eq!(vmdk.change_track_path(), Some(ctk.as_path()));
+        assert!(vmdk.component_files().contains(&ctk));
+        assert_eq!(vmdk.enable_change_tracking().unwrap(), ctk);
+        vmdk.disable_change_tracking(true).unwrap();
+        assert!(!ctk.exists());
+        assert!(!vmdk.raw_descriptor().contains("Change Tracking"));
+        drop(vmdk);
+        assert_eq!(Vmdk::new(&path).unwrap().change_track_path(), None);
+
+        // Embedded descriptors are rewritten in place
+        let path = dir.join("sparse.vmdk");
+        std::fs::write(&path, SparseImage::new(1024, 128).monolithic("sparse.vmdk").build()).unwrap();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        vmdk.enable_change_tracking().unwrap();
+        drop(vmdk);
+        let vmdk = Vmdk::new(&path).unwrap();
+        assert_eq!(vmdk.change_track_path(), Some(dir.join("sparse-ctk.vmdk").as_path()));
+        assert!(vmdk.raw_descriptor().contains("\n\n# Change Tracking File\nchangeTrackPath=\"sparse-ctk.vmdk\"\n\n# The Disk"));
+    }
+}
@@ -0,0 +1,218 @@
+//! Annotated hexdumps of metadata, for triaging images that fail to open.
+//!
+//! Dumps work from the raw bytes and check nothing beyond what they need to
+//! find a region, so they also render headers `Vmdk::new` rejects.
+
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::io::{Read, Seek, SeekFrom};
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Error;
+
+use crate::extent::GD_AT_END;
+use crate::{VmdkError, EXTENT_MAGIC, MAX_TEXT_DESCRIPTOR, SECTOR_SIZE};
+
+/// Metadata region of an extent file to dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// The sparse header, field by field
+    Header,
+    /// The embedded descriptor, or all of a text descriptor file
+    Descriptor,
+    /// The grain directory, entry by entry
+    GrainDirectory,
+    RedundantGrainDirectory,
+}
+
+/// Fields of the sparse header as (name, offset, size)
+pub(crate) const HEADER_FIELDS: [(&str, usize, usize); 17] = [
+    ("magicNumber", 0, 4),
+    ("version", 4, 4),
+    ("flags", 8, 4),
+    ("capacity", 12, 8),
+    ("grainSize", 20, 8),
+    ("descriptorOffset", 28, 8),
+    ("descriptorSize", 36, 8),
+    ("numGTEsPerGT", 44, 4),
+    ("rgdOffset", 48, 8),
+    ("gdOffset", 56, 8),
+    ("overHead", 64, 8),
+    ("uncleanShutdown", 72, 1),
+    ("singleEndLineChar", 73, 1),
+    ("nonEndLineChar", 74, 1),
+    ("doubleEndLineChar1", 75, 1),
+    ("doubleEndLineChar2", 76, 1),
+    ("compressAlgorithm", 77, 2),
+];
+
+/// Bytes of the header holding fields, the rest of the sector is padding
+pub(crate) const HEADER_LEN: usize = 79;
+
+/// Render `region` of the extent or descriptor file read by `reader`, such
+/// as `File::open(vmdk.path())`, as an annotated hexdump
+pub fn dump<R: Read + Seek>(mut reader: R, region: Region) -> Result<String, Error> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut header = vec![0u8; std::cmp::min(len, SECTOR_SIZE).try_into()?];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+    let sparse = header.len() >= HEADER_LEN && LittleEndian::read_u32(&header) == EXTENT_MAGIC;
+
+    let mut out = String::new();
+    match region {
+        Region::Header if sparse => dump_header(&mut out, &header)?,
+        Region::Descriptor if sparse => {
+            let (offset, size) = (field(&header, "descriptorOffset"), field(&header, "descriptorSize"));
+            if offset == 0 || size == 0 {
+                return Err(VmdkError::InvalidArgument("the extent has no embedded descriptor".to_owned()).into());
+            }
+            let bytes = read_region(&mut reader, len, offset * SECTOR_SIZE, size * SECTOR_SIZE)?;
+            hexdump(&mut out, offset * SECTOR_SIZE, &bytes)?;
+        }
+        Region::Descriptor if len <= MAX_TEXT_DESCRIPTOR => {
+            let bytes = read_region(&mut reader, len, 0, len)?;
+            hexdump(&mut out, 0, &bytes)?;
+        }
+        Region::GrainDirectory | Region::RedundantGrainDirectory if sparse => {
+            let redundant = region == Region::RedundantGrainDirectory;
+            let name = if redundant { "rgdOffset" } else { "gdOffset" };
+            if field(&header, "gdOffset") == GD_AT_END && len >= 3 * SECTOR_SIZE {
+                // Stream-optimized: the footer knows where the directory is
+                header = read_region(&mut reader, len, len - 2 * SECTOR_SIZE, SECTOR_SIZE)?;
+                writeln!(out, "footer at 0x{:08x}", len - 2 * SECTOR_SIZE)?;
+            }
+            let offset = match field(&header, name) {
+                GD_AT_END => 0,
+                offset => offset,
+            };
+            let (grain_size, gtes_per_gt) = (field(&header, "grainSize"), field(&header, "numGTEsPerGT"));
+            if offset == 0 || grain_size == 0 || gtes_per_gt == 0 {
+                return Err(VmdkError::InvalidArgument("the extent has no such grain directory".to_owned()).into());
+            }
+            let num_gts = field(&header, "capacity").div_ceil(grain_size).div_ceil(gtes_per_gt);
+            let bytes = read_region(&mut reader, len, offset * SECTOR_SIZE, num_gts * 4)?;
+            dump_table(&mut out, if redundant { "RGD" } else { "GD" }, offset * SECTOR_SIZE, &bytes)?;
+        }
+        _ => return Err(VmdkError::InvalidArgument(format!("no {:?} region in a file that is not a sparse extent", region)).into()),
+    }
+    Ok(out)
+}
+
+/// The value of the header field `name`
+fn field(header: &[u8], name: &str) -> u64 {
+    let &(_, offset, size) = HEADER_FIELDS.iter().find(|f| f.0 == name).expect("known header field");
+    LittleEndian::read_uint(&header[offset..offset + size], size)
+}
+
+/// `size` bytes at `offset`, cut short at the end of the file
+fn read_region<R: Read + Seek>(reader: &mut R, len: u64, offset: u64, size: u64) -> Result<Vec<u8>, Error> {
+    let end = std::cmp::min(offset.saturating_add(size), len);
+    let mut bytes = vec![0u8; end.saturating_sub(offset).try_into()?];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn dump_header(out: &mut String, header: &[u8]) -> Result<(), Error> {
+    for &(name, offset, size) in HEADER_FIELDS.iter() {
+        let bytes = &header[offset..offset + size];
+        let value = LittleEndian::read_uint(bytes, size);
+        let note = match name {
+            "magicNumber" => format!(" {:?}", String::from_utf8_lossy(bytes)),
+            "flags" => format!(" (0x{:x})", value),
+            "singleEndLineChar" | "nonEndLineChar" | "doubleEndLineChar1" | "doubleEndLineChar2" => {
+                format!(" {:?}", char::from(bytes[0]))
+            }
+            "descriptorOffset" | "rgdOffset" | "gdOffset" | "overHead" => {
+                format!(" (byte 0x{:x})", value.wrapping_mul(SECTOR_SIZE))
+            }
+            _ => String::new(),
+        };
+        writeln!(out, "0x{:08x}  {:<23}  {} = {}{}", offset, hex(bytes), name, value, note)?;
+    }
+    let padding = &header[HEADER_LEN..];
+    let used = padding.iter().filter(|&&b| b != 0).count();
+    writeln!(out, "0x{:08x}  {:<23}  pad ({} bytes, {} non-zero)", HEADER_LEN, "..", padding.len(), used)?;
+    Ok(())
+}
+
+/// Lines of 16 bytes with their ASCII, runs of zero lines shown as `*`
+fn hexdump(out: &mut String, base: u64, bytes: &[u8]) -> Result<(), Error> {
+    let mut skipping = false;
+    for (i, line) in bytes.chunks(16).enumerate() {
+        if line.iter().all(|&b| b == 0) && i > 0 {
+            if !skipping {
+                out.push_str("*\n");
+            }
+            skipping = true;
+            continue;
+        }
+        skipping = false;
+        let ascii: String = line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { char::from(b) } else { '.' }).collect();
+        writeln!(out, "0x{:08x}  {:<47}  |{}|", base + i as u64 * 16, hex(line), ascii)?;
+    }
+    writeln!(out, "0x{:08x}", base + bytes.len() as u64)?;
+    Ok(())
+}
+
+/// One line per entry, runs of empty entries shown as `*`
+fn dump_table(out: &mut String, name: &str, base: u64, bytes: &[u8]) -> Result<(), Error> {
+    let mut skipping = false;
+    for (i, entry) in bytes.chunks_exact(4).enumerate() {
+        let value = LittleEndian::read_u32(entry);
+        if value == 0 && i > 0 {
+            if !skipping {
+                out.push_str("*\n");
+            }
+            skipping = true;
+            continue;
+        }
+        skipping = false;
+        let note = match value {
+            0 => " (no grain table)".to_owned(),
+            gt => format!(" (grain table at byte 0x{:x})", u64::from(gt) * SECTOR_SIZE),
+        };
+        writeln!(out, "0x{:08x}  {}  {}[{}] = {}{}", base + i as u64 * 4, hex(entry), name, i, value, note)?;
+    }
+    writeln!(out, "0x{:08x}  {} entries", base + bytes.len() as u64, bytes.len() / 4)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::testutil::{SparseImage, StreamImage};
+
+    #[test]
+    fn test_dump() {
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        // Damage the header the way a rejected image might be
+        image[20..28].copy_from_slice(&3u64.to_le_bytes());
+
+        let header = dump(Cursor::new(&image), Region::Header).unwrap();
+        let magic = format!("0x00000000  {:<23}  magicNumber = 1447904331 \"KDMV\"\n", "4b 44 4d 56");
+        assert!(header.contains(&magic), "{}", header);
+        assert!(header.contains("0x00000014  03 00 00 00 00 00 00 00  grainSize = 3\n"), "{}", header);
+        assert!(header.contains("singleEndLineChar = 10 '\\n'"));
+
+        let descriptor = dump(Cursor::new(&image), Region::Descriptor).unwrap();
+        assert!(descriptor.starts_with("0x00000200  23 20 44 69 73 6b 20 44 65 73 63 72 69 70 74 6f  |# Disk Descripto|\n"));
+        assert!(descriptor.contains("\n*\n"));
+
+        let gd = dump(Cursor::new(&image), Region::GrainDirectory).unwrap();
+        assert!(gd.contains("  GD[0] = "), "{}", gd);
+        assert!(dump(Cursor::new(&image), Region::RedundantGrainDirectory).unwrap().contains("  RGD[0] = "));
+
+        let stream = StreamImage::new(1024, 128).grain(0, 0x77).build();
+        let gd = dump(Cursor::new(&stream), Region::GrainDirectory).unwrap();
+        assert!(gd.starts_with("footer at "), "{}", gd);
+
+        let text = b"# Disk DescriptorFile\nversion=1\n".to_vec();
+        assert!(dump(Cursor::new(&text), Region::Descriptor).unwrap().contains("|rFile.version=1.|"));
+        assert!(dump(Cursor::new(&text), Region::Header).is_err());
+    }
+}
@@ -0,0 +1,1656 @@
+//! Parsing of the text descriptor that describes a virtual disk's layout.
+//!
+//! The descriptor is either embedded in a sparse extent or stored in its own
+//! file next to the extents it references.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use failure::Error;
+use log::{info, warn};
+
+use crate::diagnostics::{Diagnostics, Strictness};
+use crate::path::normalize_separators;
+use crate::{VmdkError, SECTOR_SIZE};
+
+/// CID value used by disks that have no parent
+pub const NO_PARENT_CID: u32 = 0xffffffff;
+
+/// Sector sizes a disk may declare in `ddb.logicalSectorSize` and
+/// `ddb.physicalSectorSize`
+pub const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+/// First line of a descriptor
+const DESCRIPTOR_HEADER: &str = "# Disk DescriptorFile";
+
+/// Keys whose values the specification quotes
+const STRING_KEYS: [&str; 3] = ["createType", "parentFileNameHint", "changeTrackPath"];
+
+/// The `createType` of a disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiskType {
+    MonolithicSparse,
+    VmfsSparse,
+    MonolithicFlat,
+    Vmfs,
+    TwoGbMaxExtentSparse,
+    TwoGbMaxExtentFlat,
+    FullDevice,
+    PartitionedDevice,
+    VmfsRaw,
+    VmfsRawDeviceMap,
+    VmfsPassthroughRawDeviceMap,
+    StreamOptimized,
+    /// Any create type this crate does not know about
+    Other(String),
+}
+
+impl DiskType {
+    /// Name of the disk type as written in the descriptor
+    pub fn as_str(&self) -> &str {
+        match self {
+            DiskType::MonolithicSparse => "monolithicSparse",
+            DiskType::VmfsSparse => "vmfsSparse",
+            DiskType::MonolithicFlat => "monolithicFlat",
+            DiskType::Vmfs => "vmfs",
+            DiskType::TwoGbMaxExtentSparse => "twoGbMaxExtentSparse",
+            DiskType::TwoGbMaxExtentFlat => "twoGbMaxExtentFlat",
+            DiskType::FullDevice => "fullDevice",
+            DiskType::PartitionedDevice => "partitionedDevice",
+            DiskType::VmfsRaw => "vmfsRaw",
+            DiskType::VmfsRawDeviceMap => "vmfsRawDeviceMap",
+            DiskType::VmfsPassthroughRawDeviceMap => "vmfsPassthroughRawDeviceMap",
+            DiskType::StreamOptimized => "streamOptimized",
+            DiskType::Other(s) => s,
+        }
+    }
+
+    /// Whether the disk is backed by a physical device
+    pub fn is_device(&self) -> bool {
+        matches!(self, DiskType::FullDevice | DiskType::PartitionedDevice)
+    }
+}
+
+impl fmt::Display for DiskType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DiskType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let t = match s {
+            "monolithicSparse" => DiskType::MonolithicSparse,
+            "vmfsSparse" => DiskType::VmfsSparse,
+            "monolithicFlat" => DiskType::MonolithicFlat,
+            "vmfs" => DiskType::Vmfs,
+            "twoGbMaxExtentSparse" => DiskType::TwoGbMaxExtentSparse,
+            "twoGbMaxExtentFlat" => DiskType::TwoGbMaxExtentFlat,
+            "fullDevice" => DiskType::FullDevice,
+            "partitionedDevice" => DiskType::PartitionedDevice,
+            "vmfsRaw" => DiskType::VmfsRaw,
+            "vmfsRawDeviceMap" => DiskType::VmfsRawDeviceMap,
+            "vmfsPassthroughRawDeviceMap" => DiskType::VmfsPassthroughRawDeviceMap,
+            "streamOptimized" => DiskType::StreamOptimized,
+            other => DiskType::Other(other.to_owned()),
+        };
+        Ok(t)
+    }
+}
+
+/// Serialized as in the descriptor, e.g. `"monolithicSparse"`
+#[cfg(feature = "serde")]
+impl serde::Serialize for DiskType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DiskType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Access mode of an extent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AccessMode {
+    Rw,
+    RdOnly,
+    NoAccess,
+}
+
+impl AccessMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessMode::Rw => "RW",
+            AccessMode::RdOnly => "RDONLY",
+            AccessMode::NoAccess => "NOACCESS",
+        }
+    }
+}
+
+impl FromStr for AccessMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "RW" => Ok(AccessMode::Rw),
+            "RDONLY" => Ok(AccessMode::RdOnly),
+            "NOACCESS" => Ok(AccessMode::NoAccess),
+            _ => Err(VmdkError::ParseError.into()),
+        }
+    }
+}
+
+/// Type of an extent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExtentType {
+    Flat,
+    Sparse,
+    Zero,
+    Vmfs,
+    VmfsSparse,
+    VmfsRdm,
+    VmfsRaw,
+    /// ESXi space-efficient sparse extent
+    SeSparse,
+}
+
+impl ExtentType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExtentType::Flat => "FLAT",
+            ExtentType::Sparse => "SPARSE",
+            ExtentType::Zero => "ZERO",
+            ExtentType::Vmfs => "VMFS",
+            ExtentType::VmfsSparse => "VMFSSPARSE",
+            ExtentType::VmfsRdm => "VMFSRDM",
+            ExtentType::VmfsRaw => "VMFSRAW",
+            ExtentType::SeSparse => "SESPARSE",
+        }
+    }
+}
+
+impl FromStr for ExtentType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "FLAT" => Ok(ExtentType::Flat),
+            "SPARSE" => Ok(ExtentType::Sparse),
+            "ZERO" => Ok(ExtentType::Zero),
+            "VMFS" => Ok(ExtentType::Vmfs),
+            "VMFSSPARSE" => Ok(ExtentType::VmfsSparse),
+            "VMFSRDM" => Ok(ExtentType::VmfsRdm),
+            "VMFSRAW" => Ok(ExtentType::VmfsRaw),
+            "SESPARSE" => Ok(ExtentType::SeSparse),
+            _ => Err(VmdkError::ParseError.into()),
+        }
+    }
+}
+
+/// How the space of a disk is allocated, matching the provisioning
+/// options of vSphere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Provisioning {
+    /// Space is allocated as it is written
+    Thin,
+    /// Space is reserved at creation and zeroed on first write
+    LazyZeroed,
+    /// Space is reserved and zeroed at creation
+    EagerZeroed,
+}
+
+impl Provisioning {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Provisioning::Thin => "thin",
+            Provisioning::LazyZeroed => "zeroedthick",
+            Provisioning::EagerZeroed => "eagerzeroedthick",
+        }
+    }
+}
+
+impl FromStr for Provisioning {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "thin" => Ok(Provisioning::Thin),
+            "zeroedthick" => Ok(Provisioning::LazyZeroed),
+            "eagerzeroedthick" => Ok(Provisioning::EagerZeroed),
+            _ => Err(VmdkError::ParseError.into()),
+        }
+    }
+}
+
+/// A single line of the "Extent description" section
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExtentDescriptor {
+    /// Access mode
+    pub access: AccessMode,
+    /// Size of the extent in sectors
+    pub sectors: u64,
+    /// Type of the extent
+    pub extent_type: ExtentType,
+    /// File backing the extent, `None` for ZERO extents
+    pub filename: Option<String>,
+    /// Sector offset into the backing file (flat extents only)
+    pub offset: u64,
+}
+
+impl ExtentDescriptor {
+    /// Parse an extent line, e.g. `RW 41943040 SPARSE "disk.vmdk"`
+    pub fn new(line: &str) -> Result<Self, Error> {
+        let line = line.trim();
+        let (access, rest) = split_word(line);
+        let access = access.parse()?;
+        let (sectors, rest) = split_word(rest);
+        let sectors = sectors.parse().map_err(|_| VmdkError::ParseError)?;
+        let (extent_type, rest) = split_word(rest);
+        let extent_type = extent_type.parse()?;
+
+        let rest = rest.trim_start();
+        let (filename, rest) = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').ok_or(VmdkError::ParseError)?;
+            (Some(quoted[..end].to_owned()), &quoted[end + 1..])
+        } else {
+            (None, rest)
+        };
+        if filename.is_none() && extent_type != ExtentType::Zero {
+            return Err(VmdkError::ParseError.into());
+        }
+
+        let (offset, _) = split_word(rest);
+        let offset = if offset.is_empty() {
+            0
+        } else {
+            offset.parse().map_err(|_| VmdkError::ParseError)?
+        };
+
+        Ok(ExtentDescriptor {
+            access,
+            sectors,
+            extent_type,
+            filename,
+            offset,
+        })
+    }
+}
+
+impl fmt::Display for ExtentDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.access.as_str(), self.sectors, self.extent_type.as_str())?;
+        if let Some(filename) = &self.filename {
+            write!(f, " \"{}\"", filename)?;
+        }
+        if self.offset != 0 || self.extent_type == ExtentType::Flat {
+            write!(f, " {}", self.offset)?;
+        }
+        Ok(())
+    }
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, ""),
+    }
+}
+
+/// Type of the value of a disk database key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DdbType {
+    /// `1`/`0`, `true`/`false` or `yes`/`no`
+    Bool,
+    /// Unsigned decimal integer
+    Int,
+    /// 32 hex digits, grouped by dashes as VirtualBox writes them or by
+    /// spaces as VMware does
+    Uuid,
+    Str,
+}
+
+impl DdbType {
+    /// Whether `value` is a valid value of this type
+    pub fn accepts(self, value: &str) -> bool {
+        match self {
+            DdbType::Bool => parse_bool(value).is_some(),
+            DdbType::Int => value.parse::<u64>().is_ok(),
+            DdbType::Uuid => parse_uuid(value).is_some(),
+            DdbType::Str => true,
+        }
+    }
+
+    fn expected(self) -> &'static str {
+        match self {
+            DdbType::Bool => "a boolean",
+            DdbType::Int => "an integer",
+            DdbType::Uuid => "a UUID",
+            DdbType::Str => "a string",
+        }
+    }
+}
+
+/// Disk database keys written by VMware and VirtualBox, with the type of
+/// their values. Other keys are kept as they are, see
+/// `DiskDatabase::unknown`.
+pub const KNOWN_KEYS: [(&str, DdbType); 21] = [
+    ("adapterType", DdbType::Str),
+    ("comment", DdbType::Str),
+    ("deletable", DdbType::Bool),
+    ("geometry.biosCylinders", DdbType::Int),
+    ("geometry.biosHeads", DdbType::Int),
+    ("geometry.biosSectors", DdbType::Int),
+    ("geometry.cylinders", DdbType::Int),
+    ("geometry.heads", DdbType::Int),
+    ("geometry.sectors", DdbType::Int),
+    ("logicalSectorSize", DdbType::Int),
+    ("longContentID", DdbType::Str),
+    ("physicalSectorSize", DdbType::Int),
+    ("thinProvisioned", DdbType::Bool),
+    ("toolsInstallType", DdbType::Int),
+    ("toolsVersion", DdbType::Int),
+    ("uuid", DdbType::Uuid),
+    ("uuid.image", DdbType::Uuid),
+    ("uuid.modification", DdbType::Uuid),
+    ("uuid.parent", DdbType::Uuid),
+    ("uuid.parentmodification", DdbType::Uuid),
+    ("virtualHWVersion", DdbType::Int),
+];
+
+/// Type of the disk database key `key`, without its `ddb.` prefix, `None`
+/// for keys not in `KNOWN_KEYS`
+pub fn key_type(key: &str) -> Option<DdbType> {
+    KNOWN_KEYS.iter().find(|(k, _)| *k == key).map(|&(_, t)| t)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_uuid(value: &str) -> Option<[u8; 16]> {
+    let digits: Vec<u8> = value.bytes().filter(|&b| b != b' ' && b != b'-').collect();
+    if digits.len() != 32 {
+        return None;
+    }
+    let mut uuid = [0u8; 16];
+    for (byte, pair) in uuid.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+/// The disk database (`ddb.*` keys), kept in file order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct DiskDatabase {
+    entries: Vec<(String, String)>,
+}
+
+impl DiskDatabase {
+    /// Look up a key without its `ddb.` prefix, e.g. `adapterType`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Give the disk a fresh random `uuid.image`, as a copy that should
+    /// look like a new disk needs, returning it
+    pub fn new_image_uuid(&mut self) -> String {
+        let uuid = new_uuid();
+        self.set("uuid.image", &uuid);
+        uuid
+    }
+
+    /// Give the disk a fresh random `uuid.modification`, returning it
+    pub fn new_modification_uuid(&mut self) -> String {
+        let uuid = new_uuid();
+        self.set("uuid.modification", &uuid);
+        uuid
+    }
+
+    /// Set a key, replacing an existing value in place
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value.to_owned(),
+            None => self.entries.push((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    /// Remove a key, returning its value
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let i = self.entries.iter().position(|(k, _)| k == key)?;
+        Some(self.entries.remove(i).1)
+    }
+
+    /// Same as `get`
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)
+    }
+
+    /// `key` as a boolean, `None` if missing or not a boolean
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        parse_bool(self.get(key)?)
+    }
+
+    /// `key` as an integer, `None` if missing or not an integer
+    pub fn get_int(&self, key: &str) -> Option<u64> {
+        self.get(key)?.parse().ok()
+    }
+
+    /// The bytes of the UUID in `key`, `None` if missing or not a UUID
+    pub fn get_uuid(&self, key: &str) -> Option<[u8; 16]> {
+        parse_uuid(self.get(key)?)
+    }
+
+    /// Entries whose keys are not in `KNOWN_KEYS`, in file order
+    pub fn unknown(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.iter().filter(|&(k, _)| key_type(k).is_none())
+    }
+
+    /// `thinProvisioned`, set to 1 by vSphere on thin provisioned disks.
+    /// `None` if missing or not a boolean.
+    pub fn thin_provisioned(&self) -> Option<bool> {
+        self.get_bool("thinProvisioned")
+    }
+
+    pub fn set_thin_provisioned(&mut self, thin: bool) {
+        self.set("thinProvisioned", if thin { "1" } else { "0" });
+    }
+
+    /// Iterate over all entries in file order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// `geometry.cylinders`, `geometry.heads` and `geometry.sectors`, the
+    /// geometry presented to the guest
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.geometry_keys("cylinders", "heads", "sectors")
+    }
+
+    /// `geometry.biosCylinders`, `geometry.biosHeads` and
+    /// `geometry.biosSectors`, the translated geometry VirtualBox records
+    /// for the BIOS. VMware does not write them.
+    pub fn bios_geometry(&self) -> Option<Geometry> {
+        self.geometry_keys("biosCylinders", "biosHeads", "biosSectors")
+    }
+
+    fn geometry_keys(&self, cylinders: &str, heads: &str, sectors: &str) -> Option<Geometry> {
+        let get = |key: &str| self.get_int(&format!("geometry.{}", key));
+        Some(Geometry { cylinders: get(cylinders)?, heads: get(heads)?, sectors: get(sectors)? })
+    }
+
+    /// Recompute the geometry keys present for a disk of `capacity`
+    /// sectors: `geometry.*` as VMware sets them for `adapterType`, and
+    /// the BIOS geometry as VirtualBox does
+    pub fn update_geometry(&mut self, capacity: u64) {
+        let adapter = self.get("adapterType").unwrap_or("lsilogic").to_owned();
+        let updates = [
+            (Geometry::for_capacity(capacity, &adapter), ["cylinders", "heads", "sectors"]),
+            (Geometry::bios_for_capacity(capacity), ["biosCylinders", "biosHeads", "biosSectors"]),
+        ];
+        for (geometry, keys) in updates.iter() {
+            let values = [geometry.cylinders, geometry.heads, geometry.sectors];
+            for (key, value) in keys.iter().zip(values.iter()) {
+                let key = format!("geometry.{}", key);
+                if self.get(&key).is_some() {
+                    self.set(&key, &value.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Cylinders, heads and sectors per track of a disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Geometry {
+    pub cylinders: u64,
+    pub heads: u64,
+    pub sectors: u64,
+}
+
+impl Geometry {
+    /// Most cylinders BIOS geometries have; larger disks keep this many and
+    /// are addressed by LBA past it
+    pub const MAX_CYLINDERS: u64 = 16383;
+
+    /// Most cylinders VMware gives disks on SCSI adapters
+    pub const MAX_SCSI_CYLINDERS: u64 = 65535;
+
+    /// The geometry VMware gives a disk of `capacity` sectors on an
+    /// adapter of type `adapter`: 16 heads of 63 sectors on IDE, up to
+    /// `MAX_CYLINDERS`; on SCSI adapters 64 heads of 32 sectors below 1 GB,
+    /// 128 heads of 32 sectors below 2 GB, and 255 heads of 63 sectors
+    /// above
+    pub fn for_capacity(capacity: u64, adapter: &str) -> Self {
+        let (heads, sectors, max) = match adapter {
+            "ide" => (16, 63, Self::MAX_CYLINDERS),
+            _ if capacity < 1 << 21 => (64, 32, Self::MAX_SCSI_CYLINDERS),
+            _ if capacity < 1 << 22 => (128, 32, Self::MAX_SCSI_CYLINDERS),
+            _ => (255, 63, Self::MAX_SCSI_CYLINDERS),
+        };
+        Geometry { cylinders: std::cmp::min(capacity / (heads * sectors), max), heads, sectors }
+    }
+
+    /// The translated BIOS geometry of a disk of `capacity` sectors, 255
+    /// heads of 63 sectors and at most 1024 cylinders
+    pub fn bios_for_capacity(capacity: u64) -> Self {
+        Geometry { cylinders: std::cmp::min(capacity / (255 * 63), 1024), heads: 255, sectors: 63 }
+    }
+
+    /// Whether the geometry reaches the end of a disk of `capacity`
+    /// sectors, or has as many cylinders as BIOS geometries can. Rounding
+    /// to whole cylinders may leave less than one uncovered.
+    pub fn covers(&self, capacity: u64) -> bool {
+        let track = self.heads * self.sectors;
+        track != 0 && (self.cylinders >= Self::MAX_CYLINDERS || (self.cylinders + 1).saturating_mul(track) > capacity)
+    }
+}
+
+/// Key-bundle metadata of a disk protected by VM Encryption
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Encryption {
+    /// `encryption.keyID`, identifies the key at the key provider
+    pub key_id: Option<String>,
+    /// `encryption.keySafe`, locator of the wrapped key
+    pub key_safe: Option<String>,
+    /// `encryption.data`, the encrypted data key bundle
+    pub data: Option<String>,
+    /// All encryption related descriptor and ddb keys in file order
+    pub keys: Vec<(String, String)>,
+}
+
+impl Encryption {
+    fn add(&mut self, key: &str, value: &str) {
+        match key {
+            "encryption.keyID" => self.key_id = Some(value.to_owned()),
+            "encryption.keySafe" => self.key_safe = Some(value.to_owned()),
+            "encryption.data" => self.data = Some(value.to_owned()),
+            _ => (),
+        }
+        self.keys.push((key.to_owned(), value.to_owned()));
+    }
+}
+
+fn is_crypto_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    key.starts_with("encryption.") || key.starts_with("ddb.encryption") || key.starts_with("ddb.crypto")
+}
+
+/// A parsed disk descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Descriptor {
+    /// Descriptor format version
+    pub version: u32,
+    /// Content ID of this disk
+    pub cid: u32,
+    /// Content ID of the parent, `NO_PARENT_CID` if there is none
+    pub parent_cid: u32,
+    /// Layout of the disk
+    pub create_type: DiskType,
+    /// Path to the parent descriptor for delta disks
+    pub parent_file_name_hint: Option<String>,
+    /// Changed block tracking file, `changeTrackPath`
+    pub change_track_path: Option<String>,
+    /// Extents in logical order
+    pub extents: Vec<ExtentDescriptor>,
+    /// The disk database
+    pub ddb: DiskDatabase,
+    /// Key-bundle metadata if the disk is encrypted
+    pub encryption: Option<Encryption>,
+}
+
+/// A summary, see `to_text` for the descriptor itself
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sectors: u64 = self.extents.iter().map(|e| e.sectors).sum();
+        writeln!(f, "{} disk of {}, CID {:08x}", self.create_type, crate::human_size(sectors * SECTOR_SIZE), self.cid)?;
+        match &self.parent_file_name_hint {
+            Some(hint) => writeln!(f, "parent: {} (CID {:08x})", hint, self.parent_cid)?,
+            None if self.parent_cid != NO_PARENT_CID => writeln!(f, "parent CID: {:08x}", self.parent_cid)?,
+            None => (),
+        }
+        if self.encryption.is_some() {
+            writeln!(f, "encrypted")?;
+        }
+        write!(f, "extents:")?;
+        for extent in &self.extents {
+            write!(f, "\n    {}", extent)?;
+        }
+        for (key, value) in self.ddb.iter() {
+            write!(f, "\nddb.{} = {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Descriptor {
+    /// Parse a descriptor, failing with `VmdkError::Malformed` listing every
+    /// line that does not parse, with byte offsets within `text`
+    pub fn new(text: &str) -> Result<Self, Error> {
+        let (desc, warnings) = Descriptor::with_strictness(text, Strictness::Lenient)?;
+        for warning in warnings.warnings() {
+            warn!("Descriptor: {}", warning);
+        }
+        Ok(desc)
+    }
+
+    /// Parse a descriptor, also failing on deviations from the
+    /// specification with `Strictness::Strict`, and returning those accepted
+    pub fn with_strictness(text: &str, strictness: Strictness) -> Result<(Self, Diagnostics), Error> {
+        let mut diagnostics = Diagnostics::default();
+        let mut version = 1;
+        let mut cid = None;
+        let mut parent_cid = NO_PARENT_CID;
+        let mut create_type = None;
+        let mut parent_file_name_hint = None;
+        let mut change_track_path = None;
+        let mut extents = Vec::new();
+        let mut ddb = DiskDatabase::default();
+        let mut encryption: Option<Encryption> = None;
+
+        let first = text.lines().map(str::trim).find(|line| !line.is_empty());
+        if first != Some(DESCRIPTOR_HEADER) {
+            diagnostics.warning(Some(0), "header", first.unwrap_or("nothing"), DESCRIPTOR_HEADER);
+        }
+
+        let mut start = 0;
+        for raw in text.split_inclusive('\n') {
+            let line = raw.trim();
+            let offset = Some((start + raw.len() - raw.trim_start().len()) as u64);
+            let content = raw.strip_suffix('\n').unwrap_or(raw);
+            // Comments are free text, VirtualBox ends its ddb heading with a space
+            if !line.is_empty() && !line.starts_with('#') && content.ends_with(char::is_whitespace) {
+                let end = Some((start + content.trim_end().len()) as u64);
+                diagnostics.warning(end, "line", format!("{:?}", &content[content.trim_end().len()..]), "no trailing whitespace");
+            }
+            start += raw.len();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with("RW ") || line.starts_with("RDONLY ") || line.starts_with("NOACCESS ") {
+                match ExtentDescriptor::new(line) {
+                    Ok(extent) => {
+                        info!("Extent: {:?}", extent);
+                        extents.push(extent);
+                    }
+                    Err(_) => diagnostics.error(offset, "extent", line, "ACCESS SECTORS TYPE [\"FILE\" [OFFSET]]"),
+                }
+                continue;
+            }
+
+            let (key, quoted) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => {
+                    diagnostics.warning(offset, "line", line, "KEY = VALUE or an extent");
+                    continue;
+                }
+            };
+            let value = unquote(quoted);
+            if STRING_KEYS.contains(&key) && value == quoted {
+                diagnostics.warning(offset, key, value, "a quoted string");
+            }
+
+            if is_crypto_key(key) {
+                encryption.get_or_insert_with(Encryption::default).add(key, value);
+            }
+
+            if let Some(key) = key.strip_prefix("ddb.") {
+                if let Some(t) = key_type(key).filter(|t| !t.accepts(value)) {
+                    diagnostics.warning(offset, format!("ddb.{}", key), value, t.expected());
+                }
+                ddb.set(key, value);
+                continue;
+            }
+
+            let mut invalid = |expected: &str| diagnostics.error(offset, key, value, expected);
+            match key {
+                "version" => match value.parse() {
+                    Ok(v) => version = v,
+                    Err(_) => invalid("a version number"),
+                },
+                "CID" => match parse_cid(value) {
+                    Ok(c) => cid = Some(c),
+                    Err(_) => invalid("8 hex digits"),
+                },
+                "parentCID" => match parse_cid(value) {
+                    Ok(c) => parent_cid = c,
+                    Err(_) => invalid("8 hex digits"),
+                },
+                "createType" => match value.parse() {
+                    Ok(t) => create_type = Some(t),
+                    Err(_) => invalid("a known disk type"),
+                },
+                "parentFileNameHint" => parent_file_name_hint = Some(value.to_owned()),
+                "changeTrackPath" => change_track_path = Some(value.to_owned()),
+                // Applied when decoding, see `decode`
+                "encoding" => (),
+                _ if encryption.is_some() && key.starts_with("encryption.") => (),
+                _ => info!("Unhandled descriptor key: {}", key),
+            }
+        }
+
+        // Byte offsets within the disk and its flat extents must fit 64 bits
+        let sectors = extents.iter().try_fold(0u64, |total, e| total.checked_add(e.sectors));
+        let fits = |sectors: Option<u64>| sectors.and_then(|s| s.checked_mul(SECTOR_SIZE)).is_some();
+        if !fits(sectors) || !extents.iter().all(|e| fits(e.offset.checked_add(e.sectors))) {
+            diagnostics.error(None, "extent", "extents past 2^64 bytes", "at most 2^55 sectors");
+        }
+        for (key, missing) in [("CID", cid.is_none()), ("createType", create_type.is_none())] {
+            // Lines that did not parse are reported already
+            if missing && !diagnostics.errors().any(|d| d.field == key) {
+                diagnostics.error(None, key, "nothing", format!("a {} line", key));
+            }
+        }
+        if ddb.iter().next().is_none() {
+            diagnostics.warning(None, "ddb", "nothing", "a disk database section");
+        }
+        let warnings = diagnostics.enforce(strictness)?;
+
+        for key in &["logicalSectorSize", "physicalSectorSize"] {
+            if let Some(value) = ddb.get(key) {
+                if !value.parse().is_ok_and(|size| SECTOR_SIZES.contains(&size)) {
+                    warn!("Ignoring unsupported ddb.{} {}", key, value);
+                }
+            }
+        }
+
+        Ok((Descriptor {
+            version,
+            cid: cid.expect("checked above"),
+            parent_cid,
+            create_type: create_type.expect("checked above"),
+            parent_file_name_hint,
+            change_track_path,
+            extents,
+            ddb,
+            encryption,
+        }, warnings))
+    }
+
+    /// Give the disk a fresh random content ID, different from the current
+    /// one, returning it. Children of the disk no longer match it.
+    pub fn regenerate_cid(&mut self) -> u32 {
+        self.cid = new_cid(self.cid);
+        self.cid
+    }
+
+    /// Total capacity of all extents in sectors
+    pub fn capacity(&self) -> u64 {
+        self.extents.iter().map(|e| e.sectors).sum()
+    }
+
+    /// Grow the disk to `capacity` sectors, extending its last extent and
+    /// recomputing the geometry keys for the new capacity. Disks cannot
+    /// shrink.
+    pub fn set_capacity(&mut self, capacity: u64) -> Result<(), Error> {
+        let current = self.capacity();
+        if capacity < current {
+            return Err(VmdkError::InvalidArgument(format!("cannot shrink a disk of {} sectors to {}", current, capacity)).into());
+        }
+        let last = self.extents.last_mut().ok_or(VmdkError::ParseError)?;
+        last.sectors += capacity - current;
+        self.ddb.update_geometry(capacity);
+        Ok(())
+    }
+
+    /// How the space of the disk is allocated: thin if set in
+    /// `ddb.thinProvisioned` or the disk has sparse extents, otherwise
+    /// lazy-zeroed. Whether a flat extent was zeroed at creation is not
+    /// recorded in the descriptor, so eager-zeroed disks read as lazy-zeroed.
+    pub fn provisioning(&self) -> Provisioning {
+        if self.ddb.thin_provisioned() == Some(true) {
+            return Provisioning::Thin;
+        }
+        let sparse = self.extents.iter().any(|e| matches!(e.extent_type, ExtentType::Sparse | ExtentType::VmfsSparse | ExtentType::SeSparse));
+        if sparse {
+            Provisioning::Thin
+        } else {
+            Provisioning::LazyZeroed
+        }
+    }
+
+    /// Size in bytes of the sectors the guest addresses the disk in, from
+    /// `ddb.logicalSectorSize`: 4096 for 4Kn disks, otherwise 512
+    pub fn logical_sector_size(&self) -> u64 {
+        self.sector_size("logicalSectorSize").unwrap_or(512)
+    }
+
+    /// Size in bytes of the sectors of the underlying storage, from
+    /// `ddb.physicalSectorSize`: 4096 for 512e and 4Kn disks. Defaults to
+    /// the logical sector size.
+    pub fn physical_sector_size(&self) -> u64 {
+        self.sector_size("physicalSectorSize").unwrap_or_else(|| self.logical_sector_size())
+    }
+
+    fn sector_size(&self, key: &str) -> Option<u64> {
+        self.ddb.get(key)?.parse().ok().filter(|size| SECTOR_SIZES.contains(size))
+    }
+
+    /// Render the descriptor as text in the layout VMware writes. Lines
+    /// this crate does not model, such as comments, are not kept; use
+    /// `rewrite_text` to update an existing descriptor instead.
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "# Disk DescriptorFile\nversion={}\nCID={:08x}\nparentCID={:08x}\ncreateType=\"{}\"\n",
+            self.version,
+            self.cid,
+            self.parent_cid,
+            self.create_type.as_str()
+        );
+        if let Some(hint) = &self.parent_file_name_hint {
+            text.push_str(&format!("parentFileNameHint=\"{}\"\n", hint));
+        }
+        if let Some(ctk) = &self.change_track_path {
+            text.push_str(&format!("changeTrackPath=\"{}\"\n", ctk));
+        }
+        text.push_str("\n# Extent description\n");
+        for extent in &self.extents {
+            text.push_str(&format!("{}\n", extent));
+        }
+        text.push_str("\n# The Disk Data Base\n#DDB\n\n");
+        for (key, value) in self.ddb.iter() {
+            text.push_str(&format!("ddb.{} = \"{}\"\n", key, value));
+        }
+        text
+    }
+
+    /// Point extent file names and the parent hint that lie under
+    /// `old_base` to the same place under `new_base`, as needed after a VM
+    /// directory was moved. Relative names are left alone. Returns whether
+    /// anything changed.
+    pub fn rewrite_paths<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, old_base: P, new_base: Q) -> bool {
+        let (old_base, new_base) = (old_base.as_ref(), new_base.as_ref());
+        let mut changed = false;
+        let names = self
+            .extents
+            .iter_mut()
+            .filter_map(|e| e.filename.as_mut())
+            .chain(self.parent_file_name_hint.as_mut());
+        for name in names {
+            if let Some(rebased) = rebase(name, old_base, new_base) {
+                info!("Rewriting {} to {}", name, rebased);
+                *name = rebased;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Write the extents and parent hint of this descriptor into `text`,
+    /// the descriptor it was parsed from, keeping comments and all other
+    /// lines as they are
+    pub fn rewrite_text(&self, text: &str) -> Result<String, Error> {
+        let mut out = String::with_capacity(text.len());
+        let mut extents = self.extents.iter();
+        for line in text.split_inclusive('\n') {
+            let trimmed = line.trim();
+            if trimmed.starts_with("RW ") || trimmed.starts_with("RDONLY ") || trimmed.starts_with("NOACCESS ") {
+                let extent = extents.next().ok_or(VmdkError::ParseError)?;
+                let eol = &line[line.trim_end_matches(&['\r', '\n'][..]).len()..];
+                out.push_str(&format!("{}{}", extent, eol));
+            } else {
+                out.push_str(line);
+            }
+        }
+        if extents.next().is_some() {
+            return Err(VmdkError::ParseError.into());
+        }
+
+        match &self.parent_file_name_hint {
+            Some(hint) => set_value(&out, "parentFileNameHint", &format!("\"{}\"", hint)),
+            None => Ok(out),
+        }
+    }
+
+    /// What changed from this descriptor to `other`, field by field, e.g.
+    /// between the descriptor of a disk before and after a hypervisor used
+    /// it. Extents are compared by position, disk database keys by name.
+    pub fn diff(&self, other: &Descriptor) -> Vec<DescriptorChange> {
+        let mut changes = Vec::new();
+        if self.version != other.version {
+            changes.push(DescriptorChange::Version { old: self.version, new: other.version });
+        }
+        if self.cid != other.cid {
+            changes.push(DescriptorChange::Cid { old: self.cid, new: other.cid });
+        }
+        if self.parent_cid != other.parent_cid {
+            changes.push(DescriptorChange::ParentCid { old: self.parent_cid, new: other.parent_cid });
+        }
+        if self.create_type != other.create_type {
+            changes.push(DescriptorChange::CreateType { old: self.create_type.clone(), new: other.create_type.clone() });
+        }
+        if self.parent_file_name_hint != other.parent_file_name_hint {
+            changes.push(DescriptorChange::ParentFileNameHint {
+                old: self.parent_file_name_hint.clone(),
+                new: other.parent_file_name_hint.clone(),
+            });
+        }
+        if self.change_track_path != other.change_track_path {
+            changes.push(DescriptorChange::ChangeTrackPath {
+                old: self.change_track_path.clone(),
+                new: other.change_track_path.clone(),
+            });
+        }
+
+        for index in 0..std::cmp::max(self.extents.len(), other.extents.len()) {
+            match (self.extents.get(index), other.extents.get(index)) {
+                (Some(old), Some(new)) if old != new => {
+                    changes.push(DescriptorChange::ExtentModified { index, old: old.clone(), new: new.clone() })
+                }
+                (Some(old), None) => changes.push(DescriptorChange::ExtentRemoved { index, extent: old.clone() }),
+                (None, Some(new)) => changes.push(DescriptorChange::ExtentAdded { index, extent: new.clone() }),
+                _ => (),
+            }
+        }
+
+        for (key, old) in self.ddb.iter() {
+            match other.ddb.get(key) {
+                Some(new) if new != old => changes.push(DescriptorChange::DdbModified {
+                    key: key.to_owned(),
+                    old: old.to_owned(),
+                    new: new.to_owned(),
+                }),
+                Some(_) => (),
+                None => changes.push(DescriptorChange::DdbRemoved { key: key.to_owned(), value: old.to_owned() }),
+            }
+        }
+        for (key, new) in other.ddb.iter().filter(|(key, _)| self.ddb.get(key).is_none()) {
+            changes.push(DescriptorChange::DdbAdded { key: key.to_owned(), value: new.to_owned() });
+        }
+
+        if self.encryption != other.encryption {
+            changes.push(DescriptorChange::Encryption { old: self.encryption.clone(), new: other.encryption.clone() });
+        }
+        changes
+    }
+}
+
+/// One difference between two descriptors, see `Descriptor::diff`. Extents
+/// are identified by their index, disk database keys by their name without
+/// the `ddb.` prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DescriptorChange {
+    Version { old: u32, new: u32 },
+    Cid { old: u32, new: u32 },
+    ParentCid { old: u32, new: u32 },
+    CreateType { old: DiskType, new: DiskType },
+    ParentFileNameHint { old: Option<String>, new: Option<String> },
+    ChangeTrackPath { old: Option<String>, new: Option<String> },
+    ExtentAdded { index: usize, extent: ExtentDescriptor },
+    ExtentRemoved { index: usize, extent: ExtentDescriptor },
+    ExtentModified { index: usize, old: ExtentDescriptor, new: ExtentDescriptor },
+    DdbAdded { key: String, value: String },
+    DdbRemoved { key: String, value: String },
+    DdbModified { key: String, old: String, new: String },
+    Encryption { old: Option<Encryption>, new: Option<Encryption> },
+}
+
+impl fmt::Display for DescriptorChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn or_none(value: &Option<String>) -> &str {
+            value.as_deref().unwrap_or("(none)")
+        }
+        match self {
+            DescriptorChange::Version { old, new } => write!(f, "version: {} -> {}", old, new),
+            DescriptorChange::Cid { old, new } => write!(f, "CID: {:08x} -> {:08x}", old, new),
+            DescriptorChange::ParentCid { old, new } => write!(f, "parentCID: {:08x} -> {:08x}", old, new),
+            DescriptorChange::CreateType { old, new } => write!(f, "createType: {} -> {}", old, new),
+            DescriptorChange::ParentFileNameHint { old, new } => {
+                write!(f, "parentFileNameHint: {} -> {}", or_none(old), or_none(new))
+            }
+            DescriptorChange::ChangeTrackPath { old, new } => {
+                write!(f, "changeTrackPath: {} -> {}", or_none(old), or_none(new))
+            }
+            DescriptorChange::ExtentAdded { index, extent } => write!(f, "extent {} added: {}", index, extent),
+            DescriptorChange::ExtentRemoved { index, extent } => write!(f, "extent {} removed: {}", index, extent),
+            DescriptorChange::ExtentModified { index, old, new } => write!(f, "extent {}: {} -> {}", index, old, new),
+            DescriptorChange::DdbAdded { key, value } => write!(f, "ddb.{} added: \"{}\"", key, value),
+            DescriptorChange::DdbRemoved { key, value } => write!(f, "ddb.{} removed: \"{}\"", key, value),
+            DescriptorChange::DdbModified { key, old, new } => write!(f, "ddb.{}: \"{}\" -> \"{}\"", key, old, new),
+            DescriptorChange::Encryption { old, new } => match (old, new) {
+                (None, Some(_)) => write!(f, "encryption added"),
+                (Some(_), None) => write!(f, "encryption removed"),
+                _ => write!(f, "encryption keys changed"),
+            },
+        }
+    }
+}
+
+/// Builds descriptors for new disks.
+///
+/// Starts out as a `monolithicSparse` disk without extents, with a random
+/// content ID and no parent.
+#[derive(Debug, Clone)]
+pub struct DescriptorBuilder {
+    desc: Descriptor,
+}
+
+impl Default for DescriptorBuilder {
+    fn default() -> Self {
+        DescriptorBuilder {
+            desc: Descriptor {
+                version: 1,
+                cid: new_cid(NO_PARENT_CID),
+                parent_cid: NO_PARENT_CID,
+                create_type: DiskType::MonolithicSparse,
+                parent_file_name_hint: None,
+                change_track_path: None,
+                extents: Vec::new(),
+                ddb: DiskDatabase::default(),
+                encryption: None,
+            },
+        }
+    }
+}
+
+impl DescriptorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn create_type(&mut self, create_type: DiskType) -> &mut Self {
+        self.desc.create_type = create_type;
+        self
+    }
+
+    pub fn cid(&mut self, cid: u32) -> &mut Self {
+        self.desc.cid = cid;
+        self
+    }
+
+    /// Append an extent of `sectors` stored in `path`, which is ignored for
+    /// ZERO extents
+    pub fn add_extent(&mut self, access: AccessMode, sectors: u64, extent_type: ExtentType, path: &str) -> &mut Self {
+        let filename = if extent_type == ExtentType::Zero { None } else { Some(path.to_owned()) };
+        self.desc.extents.push(ExtentDescriptor { access, sectors, extent_type, filename, offset: 0 });
+        self
+    }
+
+    /// Make the disk a delta of the disk with content ID `cid` at `path`
+    pub fn parent(&mut self, path: &str, cid: u32) -> &mut Self {
+        self.desc.parent_file_name_hint = Some(path.to_owned());
+        self.desc.parent_cid = cid;
+        self
+    }
+
+    /// Set the disk database key `key`, without its `ddb.` prefix
+    pub fn ddb(&mut self, key: &str, value: &str) -> &mut Self {
+        self.desc.ddb.set(key, value);
+        self
+    }
+
+    /// Set the `geometry.*` keys for a disk of `capacity` bytes on an
+    /// adapter of type `adapter`, as `Geometry::for_capacity` computes them
+    pub fn geometry_for_capacity(&mut self, capacity: u64, adapter: &str) -> &mut Self {
+        let geometry = Geometry::for_capacity(capacity / SECTOR_SIZE, adapter);
+        self.ddb("geometry.cylinders", &geometry.cylinders.to_string());
+        self.ddb("geometry.heads", &geometry.heads.to_string());
+        self.ddb("geometry.sectors", &geometry.sectors.to_string())
+    }
+
+    pub fn build(&self) -> Descriptor {
+        self.desc.clone()
+    }
+
+    /// The descriptor as text, see `Descriptor::to_text`
+    pub fn to_text(&self) -> String {
+        self.desc.to_text()
+    }
+}
+
+/// `name` moved from under `old_base` to under `new_base`, `None` if it is
+/// not under `old_base`
+fn rebase(name: &str, old_base: &Path, new_base: &Path) -> Option<String> {
+    let rest = normalize_separators(name).strip_prefix(old_base).ok()?.to_owned();
+    Some(new_base.join(rest).to_string_lossy().into_owned())
+}
+
+impl FromStr for Descriptor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Descriptor::new(s)
+    }
+}
+
+fn unquote(s: &str) -> &str {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    }
+}
+
+fn parse_cid(s: &str) -> Result<u32, Error> {
+    Ok(u32::from_str_radix(s, 16).map_err(|_| VmdkError::ParseError)?)
+}
+
+/// Bits that differ between calls, even within the same nanosecond
+fn entropy() -> u64 {
+    match getrandom::u64() {
+        Ok(bits) => bits,
+        Err(e) => {
+            warn!("No OS random numbers, falling back to the clock: {}", e);
+            clock_entropy()
+        }
+    }
+}
+
+/// Bits from the clock, the process and a counter, for hosts without an
+/// OS random number generator
+fn clock_entropy() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    mix(time ^ u64::from(std::process::id()).rotate_left(32) ^ count.wrapping_mul(0x9e3779b97f4a7c15))
+}
+
+/// splitmix64 finalizer, spreads any change of `z` over all bits
+pub(crate) fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A fresh content ID that differs from `old` and from `NO_PARENT_CID`
+pub fn new_cid(old: u32) -> u32 {
+    loop {
+        let cid = entropy() as u32;
+        if cid != old && cid != NO_PARENT_CID {
+            return cid;
+        }
+    }
+}
+
+/// A fresh random (version 4) UUID in the form used by `ddb.uuid.*` keys
+pub fn new_uuid() -> String {
+    format_uuid(entropy(), entropy())
+}
+
+/// Format 128 bits as a version 4 UUID
+pub(crate) fn format_uuid(hi: u64, lo: u64) -> String {
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        hi >> 32,
+        (hi >> 16) & 0xffff,
+        hi & 0xfff,
+        0x8000 | (lo >> 48) & 0x3fff,
+        lo & 0xffff_ffff_ffff
+    )
+}
+
+/// UUID of "no disk", used for the parent UUIDs of disks without a parent
+pub const NULL_UUID: &str = "00000000-0000-0000-0000-000000000000";
+
+/// Replace the value of the first top-level `key = value` line in
+/// descriptor `text`, leaving every other line untouched
+pub fn set_value(text: &str, key: &str, value: &str) -> Result<String, Error> {
+    let mut out = String::with_capacity(text.len() + value.len());
+    let mut found = false;
+    for line in text.split_inclusive('\n') {
+        let matches = !found
+            && line
+                .find('=')
+                .map(|i| line[..i].trim() == key)
+                .unwrap_or(false);
+        if matches {
+            let eol = &line[line.trim_end_matches(&['\r', '\n'][..]).len()..];
+            out.push_str(&format!("{}={}{}", key, value, eol));
+            found = true;
+        } else {
+            out.push_str(line);
+        }
+    }
+    if !found {
+        return Err(VmdkError::ParseError.into());
+    }
+    Ok(out)
+}
+
+/// Characters of bytes 0x80 to 0x9f in windows-1252, where it differs from
+/// ISO-8859-1. Unassigned bytes map to the C1 control of the same value.
+const WINDOWS_1252: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+/// Single-byte encodings a descriptor may declare with its `encoding` key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SingleByte {
+    Latin1,
+    Windows1252,
+}
+
+/// The single-byte encoding named by the `encoding` key of `text`, if any.
+/// Only ASCII is needed to find the key, so `text` may be decoded lossily.
+fn single_byte_encoding(text: &str) -> Option<SingleByte> {
+    let value = text.lines().find_map(|line| {
+        let i = line.find('=')?;
+        if line[..i].trim() == "encoding" {
+            Some(unquote(line[i + 1..].trim()).to_ascii_lowercase())
+        } else {
+            None
+        }
+    })?;
+    match value.as_str() {
+        "iso-8859-1" | "latin1" => Some(SingleByte::Latin1),
+        "windows-1252" | "cp1252" => Some(SingleByte::Windows1252),
+        _ => None,
+    }
+}
+
+/// Decode the bytes of a descriptor. Descriptors declaring a Western
+/// single-byte `encoding` are decoded as such; others as UTF-8, replacing
+/// invalid sequences, such as Latin-1 comments or garbage padding, with
+/// U+FFFD so the keys around them can still be read; `encode_replacing`
+/// writes them back unchanged.
+pub fn decode(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let encoding = match single_byte_encoding(&text) {
+        Some(encoding) => encoding,
+        None => {
+            if let std::borrow::Cow::Owned(_) = text {
+                warn!("Descriptor is not valid UTF-8, replacing invalid bytes");
+            }
+            return text.into_owned();
+        }
+    };
+    bytes
+        .iter()
+        .map(|&b| match (encoding, b) {
+            (SingleByte::Windows1252, 0x80..=0x9f) => WINDOWS_1252[usize::from(b - 0x80)],
+            _ => char::from(b),
+        })
+        .collect()
+}
+
+/// Encode descriptor `text` for writing, in the single-byte encoding its
+/// `encoding` key declares, otherwise as UTF-8. Characters the encoding
+/// cannot represent are written as `?`.
+pub fn encode(text: &str) -> Vec<u8> {
+    encode_in(text, single_byte_encoding(text))
+}
+
+/// Encode descriptor `text` like `encode`, to replace a descriptor stored
+/// as `old_bytes` and decoded as `old_text`. If those bytes did not decode
+/// losslessly, the lines of `text` also found in `old_text` keep their
+/// original bytes, so bytes `decode` replaced are written back untouched;
+/// a changed line still holding a replaced byte cannot be written.
+pub fn encode_replacing(text: &str, old_text: &str, old_bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let encoding = single_byte_encoding(text);
+    if encode_in(old_text, single_byte_encoding(old_text)) == old_bytes {
+        return Ok(encode_in(text, encoding));
+    }
+    // Decoding keeps every newline, so old lines and their bytes pair up
+    let mut old_lines: HashMap<&str, VecDeque<&[u8]>> = HashMap::new();
+    for (line, raw) in old_text.split_inclusive('\n').zip(old_bytes.split_inclusive(|&b| b == b'\n')) {
+        old_lines.entry(line).or_default().push_back(raw);
+    }
+    let mut bytes = Vec::with_capacity(old_bytes.len());
+    for line in text.split_inclusive('\n') {
+        match old_lines.get_mut(line).and_then(VecDeque::pop_front) {
+            Some(raw) => bytes.extend_from_slice(raw),
+            None if line.contains(char::REPLACEMENT_CHARACTER) => {
+                let line = format!("descriptor line {:?}, its original bytes were not readable", line.trim_end());
+                return Err(VmdkError::NotWritable(line).into());
+            }
+            None => bytes.extend(encode_in(line, encoding)),
+        }
+    }
+    Ok(bytes)
+}
+
+fn encode_in(text: &str, encoding: Option<SingleByte>) -> Vec<u8> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return text.as_bytes().to_vec(),
+    };
+    text.chars()
+        .map(|c| match (encoding, u32::from(c)) {
+            (SingleByte::Windows1252, _) if WINDOWS_1252.contains(&c) => {
+                0x80 + WINDOWS_1252.iter().position(|&w| w == c).unwrap_or(0) as u8
+            }
+            (SingleByte::Windows1252, 0x80..=0x9f) => b'?',
+            (_, code) => u8::try_from(code).unwrap_or(b'?'),
+        })
+        .collect()
+}
+
+/// Remove every top-level `key = value` line for `key` from descriptor
+/// `text`, leaving every other line untouched
+pub fn remove_value(text: &str, key: &str) -> String {
+    text.split_inclusive('\n')
+        .filter(|line| line.find('=').map(|i| line[..i].trim() != key).unwrap_or(true))
+        .collect()
+}
+
+/// Insert `lines`, each ending in a newline, into descriptor `text` as a
+/// section of its own, before the disk database or at the end
+pub(crate) fn insert_section(text: &str, lines: &str) -> String {
+    let is_ddb = |line: &str| {
+        let line = line.trim();
+        line.eq_ignore_ascii_case("# The Disk Data Base") || line == "#DDB" || line.starts_with("ddb.")
+    };
+    let at: usize = text.split_inclusive('\n').take_while(|line| !is_ddb(line)).map(str::len).sum();
+    let mut out = text[..at].to_owned();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(lines);
+    if at < text.len() {
+        out.push('\n');
+        out.push_str(&text[at..]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTOR: &str = r#"# Disk DescriptorFile
+version=1
+CID=def0d352
+parentCID=ffffffff
+createType="monolithicSparse"
+
+# Extent description
+RW 41943040 SPARSE "OMS CS6250 Course VM-disk1.vmdk"
+
+# The disk Data Base
+#DDB
+
+ddb.virtualHWVersion = "4"
+ddb.adapterType="ide"
+ddb.geometry.cylinders="16383"
+"#;
+
+    #[test]
+    fn test_parse_descriptor() {
+        let desc = Descriptor::new(DESCRIPTOR).unwrap();
+        assert_eq!(desc.cid, 0xdef0d352);
+        assert_eq!(desc.parent_cid, NO_PARENT_CID);
+        assert_eq!(desc.create_type, DiskType::MonolithicSparse);
+        assert_eq!(desc.extents.len(), 1);
+        assert_eq!(desc.extents[0].sectors, 41943040);
+        assert_eq!(desc.extents[0].extent_type, ExtentType::Sparse);
+        assert_eq!(desc.extents[0].filename.as_deref(), Some("OMS CS6250 Course VM-disk1.vmdk"));
+        assert_eq!(desc.ddb.get("virtualHWVersion"), Some("4"));
+        assert_eq!(desc.ddb.get("adapterType"), Some("ide"));
+    }
+
+    #[test]
+    fn test_sector_sizes() {
+        let desc = Descriptor::new(DESCRIPTOR).unwrap();
+        assert_eq!((desc.logical_sector_size(), desc.physical_sector_size()), (512, 512));
+
+        let emulated = format!("{}ddb.physicalSectorSize = \"4096\"\n", DESCRIPTOR);
+        let desc = Descriptor::new(&emulated).unwrap();
+        assert_eq!((desc.logical_sector_size(), desc.physical_sector_size()), (512, 4096));
+
+        let native = format!("{}ddb.logicalSectorSize = \"4096\"\n", DESCRIPTOR);
+        let desc = Descriptor::new(&native).unwrap();
+        assert_eq!((desc.logical_sector_size(), desc.physical_sector_size()), (4096, 4096));
+
+        let odd = format!("{}ddb.logicalSectorSize = \"1000\"\n", DESCRIPTOR);
+        assert_eq!(Descriptor::new(&odd).unwrap().logical_sector_size(), 512);
+    }
+
+    #[test]
+    fn test_ddb_types() {
+        let text = format!(
+            "{}ddb.uuid = \"60 00 C2 9e 1a 2b 3c 4d-5e 6f 70 81 92 a3 b4 c5\"\nddb.deletable = \"TRUE\"\n\
+             ddb.toolsVersion = \"soon\"\nddb.sidecars = \"2\"\n",
+            DESCRIPTOR
+        );
+        let (desc, warnings) = Descriptor::with_strictness(&text, Strictness::Lenient).unwrap();
+        let ddb = &desc.ddb;
+        assert_eq!(ddb.get_uuid("uuid").map(|u| (u[0], u[15])), Some((0x60, 0xc5)));
+        assert_eq!(ddb.get_bool("deletable"), Some(true));
+        assert_eq!((ddb.get_int("geometry.cylinders"), ddb.get_int("toolsVersion")), (Some(16383), None));
+        assert_eq!(ddb.get_str("toolsVersion"), Some("soon"));
+        assert_eq!(ddb.unknown().collect::<Vec<_>>(), [("sidecars", "2")]);
+        assert_eq!(key_type("uuid.image"), Some(DdbType::Uuid));
+        let warning = warnings.warnings().next().unwrap();
+        assert_eq!((warning.field.as_str(), warning.expected.as_str()), ("ddb.toolsVersion", "an integer"));
+        assert!(desc.to_text().contains("ddb.sidecars = \"2\""));
+    }
+
+    #[test]
+    fn test_thin_provisioned() {
+        let thin = format!("{}ddb.thinProvisioned = \"1\"\n", DESCRIPTOR);
+        let mut desc = Descriptor::new(&thin).unwrap();
+        assert_eq!(desc.ddb.thin_provisioned(), Some(true));
+        desc.ddb.set_thin_provisioned(false);
+        assert_eq!(desc.ddb.get("thinProvisioned"), Some("0"));
+        assert_eq!(desc.ddb.remove("thinProvisioned").as_deref(), Some("0"));
+        assert_eq!(desc.ddb.thin_provisioned(), None);
+    }
+
+    #[test]
+    fn test_encodings() {
+        let mut bytes = b"# Disk DescriptorFile\n# Cr\xe9\xe9 par \x93moi\x94\n".to_vec();
+        bytes.extend_from_slice(b"version=1\nCID=fffffffe\nparentCID=ffffffff\ncreateType=\"monolithicSparse\"\n");
+        bytes.extend_from_slice(b"RW 8 SPARSE \"disk.vmdk\"\n");
+
+        // Without an encoding key, invalid bytes are replaced
+        let text = decode(&bytes);
+        assert!(text.contains("Cr\u{fffd}\u{fffd} par"));
+        assert_eq!(Descriptor::new(&text).unwrap().cid, 0xfffffffe);
+
+        let mut declared = b"encoding=\"windows-1252\"\n".to_vec();
+        declared.extend_from_slice(&bytes);
+        let text = decode(&declared);
+        assert!(text.contains("Cr\u{e9}\u{e9} par \u{201c}moi\u{201d}"));
+        assert_eq!(Descriptor::new(&text).unwrap().extents.len(), 1);
+        assert_eq!(encode(&text), declared);
+        assert_eq!(encode(&text.replace("moi", "\u{263a}")).len(), declared.len() - 2);
+
+        // Bytes replaced on reading are written back as they were
+        let text = decode(&bytes);
+        let changed = text.replace("CID=fffffffe", "CID=12345678");
+        let rewritten = encode_replacing(&changed, &text, &bytes).unwrap();
+        let cid = bytes.windows(4).position(|w| w == b"CID=").unwrap() + 4;
+        assert_eq!(rewritten, [&bytes[..cid], b"12345678", &bytes[cid + 8..]].concat());
+        assert!(encode_replacing(&text.replace(" par ", " by "), &text, &bytes).is_err());
+        assert_eq!(encode_replacing(&text, &decode(&declared), &declared).unwrap(), encode(&text));
+
+        let utf8 = "encoding=\"UTF-8\"\n# Cr\u{e9}\u{e9}\n";
+        assert_eq!(decode(utf8.as_bytes()), utf8);
+        assert_eq!(encode(utf8), utf8.as_bytes());
+    }
+
+    #[test]
+    fn test_parse_extent_lines() {
+        let e = ExtentDescriptor::new(r#"RW 8388608 FLAT "/dev/sdb" 0"#).unwrap();
+        assert_eq!(e.extent_type, ExtentType::Flat);
+        assert_eq!(e.filename.as_deref(), Some("/dev/sdb"));
+        assert_eq!(e.offset, 0);
+
+        let e = ExtentDescriptor::new(r#"RDONLY 2048 FLAT "disk-pt.vmdk" 63"#).unwrap();
+        assert_eq!(e.access, AccessMode::RdOnly);
+        assert_eq!(e.offset, 63);
+
+        let e = ExtentDescriptor::new("RW 100 ZERO").unwrap();
+        assert_eq!(e.extent_type, ExtentType::Zero);
+        assert_eq!(e.filename, None);
+
+        assert!(ExtentDescriptor::new("RW 100 FLAT").is_err());
+    }
+
+    #[test]
+    fn test_parse_encryption() {
+        assert_eq!(Descriptor::new(DESCRIPTOR).unwrap().encryption, None);
+
+        let text = format!(
+            "{}encryption.keySafe = \"vmware:key/list/(pair/(fqid/<VMWARE-NULL>/KMS/abc,HMAC-SHA-256,xyz))\"\n\
+             encryption.data = \"AAAAAQ==\"\nencryption.keyID = \"42\"\nddb.encryption.cipher = \"XTS-AES-256\"\n",
+            DESCRIPTOR
+        );
+        let enc = Descriptor::new(&text).unwrap().encryption.unwrap();
+        assert_eq!(enc.key_id.as_deref(), Some("42"));
+        assert_eq!(enc.data.as_deref(), Some("AAAAAQ=="));
+        assert!(enc.key_safe.unwrap().starts_with("vmware:key/list/"));
+        assert_eq!(enc.keys.len(), 4);
+        assert_eq!(enc.keys[3], ("ddb.encryption.cipher".to_owned(), "XTS-AES-256".to_owned()));
+    }
+
+    #[test]
+    fn test_rewrite_paths() {
+        let text = "version=1\nCID=fffffffe\nparentCID=12345678\ncreateType=\"twoGbMaxExtentFlat\"\n\
+                    parentFileNameHint=\"/vms/old/base.vmdk\"\n\n# Extent description\n\
+                    RW 2 FLAT \"/vms/old/disk-f001.vmdk\" 0\r\nRW 2 FLAT \"disk-f002.vmdk\" 0\nRW 4 ZERO\n";
+        let mut desc = Descriptor::new(text).unwrap();
+        assert!(!desc.rewrite_paths("/vms/other", "/vms/new"));
+        assert!(desc.rewrite_paths("/vms/old", "/vms/new"));
+        assert_eq!(desc.extents[0].filename.as_deref(), Some("/vms/new/disk-f001.vmdk"));
+        assert_eq!(desc.extents[1].filename.as_deref(), Some("disk-f002.vmdk"));
+
+        let rewritten = desc.rewrite_text(text).unwrap();
+        assert_eq!(
+            rewritten,
+            "version=1\nCID=fffffffe\nparentCID=12345678\ncreateType=\"twoGbMaxExtentFlat\"\n\
+             parentFileNameHint=\"/vms/new/base.vmdk\"\n\n# Extent description\n\
+             RW 2 FLAT \"/vms/new/disk-f001.vmdk\" 0\r\nRW 2 FLAT \"disk-f002.vmdk\" 0\nRW 4 ZERO\n"
+        );
+        assert_eq!(Descriptor::new(&rewritten).unwrap(), desc);
+    }
+
+    #[test]
+    fn test_set_value() {
+        let text = set_value(DESCRIPTOR, "CID", "0badcafe").unwrap();
+        assert_eq!(Descriptor::new(&text).unwrap().cid, 0x0badcafe);
+        assert_eq!(text.replace("CID=0badcafe\n", "CID=def0d352\n"), DESCRIPTOR);
+        assert!(set_value(DESCRIPTOR, "parentFileNameHint", "\"a.vmdk\"").is_err());
+        let text = remove_value(DESCRIPTOR, "ddb.adapterType");
+        assert_eq!(text, DESCRIPTOR.replace("ddb.adapterType=\"ide\"\n", ""));
+
+        let cid = new_cid(0xdef0d352);
+        assert_ne!(cid, 0xdef0d352);
+        assert_ne!(cid, NO_PARENT_CID);
+
+        let uuid = new_uuid();
+        assert_eq!(uuid.len(), NULL_UUID.len());
+        assert_eq!(&uuid[14..15], "4");
+        assert_ne!(uuid, new_uuid());
+
+        let mut desc = Descriptor::new(DESCRIPTOR).unwrap();
+        let cid = desc.regenerate_cid();
+        assert_eq!(desc.cid, cid);
+        assert_ne!(cid, 0xdef0d352);
+        let image = desc.ddb.new_image_uuid();
+        let modification = desc.ddb.new_modification_uuid();
+        assert_ne!(image, modification);
+        assert_eq!(desc.ddb.get_uuid("uuid.image").map(|u| u[6] >> 4), Some(4));
+        assert_eq!(desc.ddb.get("uuid.modification"), Some(modification.as_str()));
+    }
+
+    #[test]
+    fn test_to_text() {
+        let desc = Descriptor::new(DESCRIPTOR).unwrap();
+        let text = desc.to_text();
+        assert!(text.starts_with("# Disk DescriptorFile\nversion=1\nCID=def0d352\n"));
+        assert!(text.contains("\nRW 41943040 SPARSE \"OMS CS6250 Course VM-disk1.vmdk\"\n"));
+        assert!(text.contains("\nddb.adapterType = \"ide\"\n"));
+        assert_eq!(Descriptor::new(&text).unwrap(), desc);
+
+        let mut builder = DescriptorBuilder::new();
+        builder
+            .create_type(DiskType::TwoGbMaxExtentFlat)
+            .cid(0x1234)
+            .add_extent(AccessMode::Rw, 2, ExtentType::Flat, "a-f001.vmdk")
+            .add_extent(AccessMode::Rw, 2, ExtentType::Zero, "")
+            .parent("base.vmdk", 0xabcd)
+            .geometry_for_capacity(20 << 30, "lsilogic");
+        let built = Descriptor::new(&builder.to_text()).unwrap();
+        assert_eq!(built, builder.build());
+        assert_eq!(built.extents[1].filename, None);
+        assert_eq!(built.parent_file_name_hint.as_deref(), Some("base.vmdk"));
+        assert_eq!(built.ddb.get("geometry.cylinders"), Some("2610"));
+        builder.geometry_for_capacity(20 << 30, "ide");
+        assert_eq!(builder.build().ddb.geometry(), Some(Geometry { cylinders: 16383, heads: 16, sectors: 63 }));
+
+        let summary = desc.to_string();
+        assert!(summary.starts_with("monolithicSparse disk of 20 GiB, CID def0d352\nextents:\n"), "{}", summary);
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let mut desc = Descriptor::new(DESCRIPTOR).unwrap();
+        desc.set_capacity(83886080).unwrap();
+        assert_eq!(desc.extents[0].sectors, 83886080);
+        // IDE geometry stays capped, only keys present are written
+        assert_eq!(desc.ddb.get("geometry.cylinders"), Some("16383"));
+        assert_eq!(desc.ddb.get("geometry.heads"), None);
+        assert!(desc.set_capacity(1024).is_err());
+
+        let scsi = |capacity| Geometry::for_capacity(capacity, "lsilogic");
+        assert_eq!(scsi(1 << 20), Geometry { cylinders: 512, heads: 64, sectors: 32 });
+        assert_eq!(scsi(3 << 20), Geometry { cylinders: 768, heads: 128, sectors: 32 });
+        assert_eq!(scsi(40 << 21), Geometry { cylinders: 5221, heads: 255, sectors: 63 });
+        assert_eq!(Geometry::for_capacity(40 << 21, "ide"), Geometry { cylinders: 16383, heads: 16, sectors: 63 });
+        assert_eq!(Geometry::bios_for_capacity(40 << 21).cylinders, 1024);
+        assert!(scsi(40 << 21).covers(40 << 21) && scsi(3 << 20).covers(3 << 20));
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = Descriptor::new(DESCRIPTOR).unwrap();
+        assert!(old.diff(&old).is_empty());
+        let mut new = old.clone();
+        new.cid = 0x1234abcd;
+        new.extents[0].access = AccessMode::RdOnly;
+        new.extents.push(ExtentDescriptor::new("RW 1024 ZERO").unwrap());
+        new.ddb.set("adapterType", "lsilogic");
+        new.ddb.remove("virtualHWVersion");
+        new.ddb.set("uuid.image", "60 00 c2 9b");
+
+        let changes: Vec<String> = old.diff(&new).iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            changes,
+            [
+                "CID: def0d352 -> 1234abcd",
+                "extent 0: RW 41943040 SPARSE \"OMS CS6250 Course VM-disk1.vmdk\" -> RDONLY 41943040 SPARSE \"OMS CS6250 Course VM-disk1.vmdk\"",
+                "extent 1 added: RW 1024 ZERO",
+                "ddb.virtualHWVersion removed: \"4\"",
+                "ddb.adapterType: \"ide\" -> \"lsilogic\"",
+                "ddb.uuid.image added: \"60 00 c2 9b\"",
+            ]
+        );
+        assert_eq!(new.diff(&old)[2], DescriptorChange::ExtentRemoved { index: 1, extent: new.extents[1].clone() });
+    }
+}
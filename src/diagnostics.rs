@@ -0,0 +1,330 @@
+//! What exactly is wrong with metadata that fails to parse.
+
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use failure::Error;
+
+pub use crate::check::Severity;
+use crate::descriptor::{self, Descriptor, Geometry};
+use crate::{ExtentHeader, VmdkError, EXTENT_MAGIC, MAX_TEXT_DESCRIPTOR};
+
+/// How closely metadata must follow the VMDK specification
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Reject any deviation from the specification
+    Strict,
+    /// Accept deviations common in real-world images, such as trailing
+    /// whitespace or a missing disk database, recording them as warnings
+    #[default]
+    Lenient,
+}
+
+/// What reads do with grains their grain table places past the end of the
+/// extent file, as in truncated images. Writes to such grains always fail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PastEof {
+    /// Fail with `VmdkError::CorruptGrain`
+    #[default]
+    Error,
+    /// Read the whole grain as zeros, logging a warning
+    Zero,
+    /// Read what the file holds of the grain and the rest as zeros, logging
+    /// a warning. Compressed grains cannot be inflated in part and read as
+    /// zeros.
+    Partial,
+}
+
+/// One problem found while parsing
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Byte offset of the field in the file, where known
+    pub offset: Option<u64>,
+    /// Field being parsed, e.g. `grainSize` or `CID`
+    pub field: String,
+    pub found: String,
+    pub expected: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}", severity, self.field)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        write!(f, ": found {}, expected {}", self.found, self.expected)
+    }
+}
+
+/// Something odd about a disk found when opening it, not enough to refuse
+/// it, as listed by `Vmdk::warnings`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VmdkWarning {
+    /// A deviation of the header or descriptor from the specification
+    /// accepted by `Strictness::Lenient`, with its byte offset in the file
+    /// holding the descriptor
+    Deviation(Diagnostic),
+    /// A disk database key, without its `ddb.` prefix, not in
+    /// `descriptor::KNOWN_KEYS`. It is kept as it is.
+    UnknownDdbKey(String),
+    /// `ddb.geometry.*` stops short of the capacity, in sectors, so guests
+    /// using it would not see the whole disk, as after a resize that left
+    /// the geometry alone
+    GeometryMismatch { geometry: Geometry, capacity: u64 },
+    /// The parent hint names a file that is not where the parent was
+    /// found, e.g. an absolute path from another host
+    ParentHintRedirected { hint: String, path: PathBuf },
+    /// The parent has another CID than recorded, so it was written since
+    /// this delta was created and the contents shown may be corrupt
+    ParentCidMismatch { parent: PathBuf, expected: u32, found: u32 },
+}
+
+impl fmt::Display for VmdkWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmdkWarning::Deviation(diagnostic) => write!(f, "{}", diagnostic),
+            VmdkWarning::UnknownDdbKey(key) => write!(f, "unknown disk database key ddb.{}", key),
+            VmdkWarning::GeometryMismatch { geometry, capacity } => write!(
+                f,
+                "geometry {}/{}/{} does not cover the {} sectors of the disk",
+                geometry.cylinders, geometry.heads, geometry.sectors, capacity
+            ),
+            VmdkWarning::ParentHintRedirected { hint, path } => write!(f, "parent {} found at {}", hint, path.display()),
+            VmdkWarning::ParentCidMismatch { parent, expected, found } => {
+                write!(f, "parent {} has CID {:08x}, expected {:08x}", parent.display(), found, expected)
+            }
+        }
+    }
+}
+
+/// Problems found while parsing, in the order they were found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn error<F: Into<String>, S: Into<String>, E: Into<String>>(&mut self, offset: Option<u64>, field: F, found: S, expected: E) {
+        self.push(Severity::Error, offset, field.into(), found.into(), expected.into());
+    }
+
+    pub fn warning<F: Into<String>, S: Into<String>, E: Into<String>>(&mut self, offset: Option<u64>, field: F, found: S, expected: E) {
+        self.push(Severity::Warning, offset, field.into(), found.into(), expected.into());
+    }
+
+    fn push(&mut self, severity: Severity, offset: Option<u64>, field: String, found: String, expected: String) {
+        self.0.push(Diagnostic { severity, offset, field, found, expected });
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter().filter(|d| d.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter().filter(|d| d.severity == Severity::Warning)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    /// Shift the offsets by `base`, for metadata parsed from the middle of
+    /// a file
+    pub fn rebase(&mut self, base: u64) {
+        for offset in self.0.iter_mut().filter_map(|d| d.offset.as_mut()) {
+            *offset += base;
+        }
+    }
+
+    /// `VmdkError::Malformed` if any error was found, or with
+    /// `Strictness::Strict` anything at all, warnings then counting as errors
+    pub(crate) fn enforce(mut self, strictness: Strictness) -> Result<Self, Error> {
+        if strictness == Strictness::Strict {
+            for diagnostic in &mut self.0 {
+                diagnostic.severity = Severity::Error;
+            }
+        }
+        if self.has_errors() {
+            return Err(VmdkError::Malformed(self).into());
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let errors: Vec<String> = self.errors().map(|d| d.to_string()).collect();
+        write!(f, "{}", errors.join("; "))?;
+        match self.warnings().count() {
+            0 => Ok(()),
+            n => write!(f, " ({} warnings)", n),
+        }
+    }
+}
+
+/// Shift the offsets of the diagnostics of a `VmdkError::Malformed` by
+/// `base`, leaving other errors alone
+pub(crate) fn rebase(e: Error, base: u64) -> Error {
+    match e.downcast::<VmdkError>() {
+        Ok(VmdkError::Malformed(mut diagnostics)) => {
+            diagnostics.rebase(base);
+            VmdkError::Malformed(diagnostics).into()
+        }
+        Ok(other) => other.into(),
+        Err(e) => e,
+    }
+}
+
+/// Everything wrong with the header and descriptor of the extent or
+/// descriptor file read by `reader`, without stopping at the first error
+pub fn diagnose<R: Read + Seek>(mut reader: R) -> Result<Diagnostics, Error> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = [0u8; 4];
+    let sparse = len >= 512 && reader.read_exact(&mut magic).is_ok() && u32::from_le_bytes(magic) == EXTENT_MAGIC;
+
+    let mut diagnostics = Diagnostics::default();
+    let (base, size) = if sparse {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = ExtentHeader::read(&mut reader)?;
+        diagnostics.0.extend(header.diagnose().0);
+        if header.desc_offset.0 == 0 || header.desc_size.0 == 0 {
+            return Ok(diagnostics);
+        }
+        match header.descriptor_area(len) {
+            Ok(area) => area,
+            Err(found) => {
+                diagnostics.0.extend(found.0);
+                return Ok(diagnostics);
+            }
+        }
+    } else if len <= MAX_TEXT_DESCRIPTOR {
+        (0, len)
+    } else {
+        diagnostics.error(Some(0), "magicNumber", crate::debug::hex(&magic), "a sparse extent or a text descriptor");
+        return Ok(diagnostics);
+    };
+
+    let mut bytes = vec![0u8; size as usize];
+    reader.seek(SeekFrom::Start(base))?;
+    reader.read_exact(&mut bytes)?;
+    let text = descriptor::decode(&bytes);
+    if let Err(e) = Descriptor::new(text.trim_end_matches(char::from(0))) {
+        match rebase(e, base).downcast::<VmdkError>() {
+            Ok(VmdkError::Malformed(found)) => diagnostics.0.extend(found.0),
+            Ok(other) => return Err(other.into()),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::testutil::SparseImage;
+    use crate::{Vmdk, VmdkOpenOptions};
+    use crate::testutil::scratch_dir;
+
+    #[test]
+    fn test_diagnostics() {
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").build();
+        image[20..28].fill(0);
+        image[44..48].copy_from_slice(&100u32.to_le_bytes());
+        let at = image.windows(3).position(|w| w == b"CID").unwrap();
+        image[at + 4..at + 6].copy_from_slice(b"zz");
+
+        let diagnostics = diagnose(Cursor::new(&image)).unwrap();
+        let errors: Vec<_> = diagnostics.errors().collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].field.as_str(), errors[0].offset), ("grainSize", Some(20)));
+        assert_eq!(errors[0].found, "00 00 00 00 00 00 00 00 (0)");
+        assert_eq!((errors[1].field.as_str(), errors[1].offset), ("CID", Some(at as u64)));
+        let warning = diagnostics.warnings().next().unwrap();
+        assert_eq!((warning.field.as_str(), warning.expected.as_str()), ("numGTEsPerGT", "512"));
+
+        let dir = scratch_dir("diagnostics");
+        std::fs::write(dir.join("disk.vmdk"), &image).unwrap();
+        let err = Vmdk::new(dir.join("disk.vmdk")).err().unwrap();
+        match err.downcast::<VmdkError>() {
+            Ok(VmdkError::Malformed(found)) => {
+                assert_eq!(found.0.len(), 2);
+                assert!(found.to_string().starts_with("error: grainSize at byte 20: found 00 00"), "{}", found);
+            }
+            other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_strictness() {
+        let dir = scratch_dir("strictness");
+        let text = "# Disk DescriptorFile\nversion=1\nCID=fffffffe \nparentCID=ffffffff\n\
+            createType=monolithicFlat\nRW 1024 FLAT \"disk-flat.vmdk\" 0\n";
+        std::fs::write(dir.join("disk.vmdk"), text).unwrap();
+        std::fs::write(dir.join("disk-flat.vmdk"), vec![0u8; 1024 * 512]).unwrap();
+
+        let vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        let fields: Vec<_> = vmdk
+            .warnings()
+            .iter()
+            .map(|w| match w {
+                VmdkWarning::Deviation(d) => (d.field.as_str(), d.offset),
+                other => panic!("unexpected {}", other),
+            })
+            .collect();
+        assert_eq!(fields, [("line", Some(44)), ("createType", Some(65)), ("ddb", None)]);
+
+        let err = VmdkOpenOptions::new().strictness(Strictness::Strict).open(dir.join("disk.vmdk")).err().unwrap();
+        match err.downcast::<VmdkError>() {
+            Ok(VmdkError::Malformed(found)) => assert_eq!(found.errors().count(), 3),
+            other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+        }
+
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk");
+        image.gtes_per_gt = 100;
+        std::fs::write(dir.join("sparse.vmdk"), image.build()).unwrap();
+        let vmdk = Vmdk::new(dir.join("sparse.vmdk")).unwrap();
+        assert!(matches!(vmdk.warnings(), [VmdkWarning::Deviation(_)]));
+        assert!(VmdkOpenOptions::new().strictness(Strictness::Strict).open(dir.join("sparse.vmdk")).is_err());
+    }
+
+    #[test]
+    fn test_open_warnings() {
+        let dir = scratch_dir("open-warnings");
+        let mut base = SparseImage::new(1024, 128).monolithic("base.vmdk");
+        if let Some(text) = base.descriptor.as_mut() {
+            // 2 cylinders of 4 heads of 63 sectors cover 504 of 1024 sectors
+            text.push_str("ddb.geometry.cylinders = \"2\"\nddb.geometry.heads = \"4\"\nddb.geometry.sectors = \"63\"\n");
+            text.push_str("ddb.sidecars = \"2\"\n");
+        }
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let vmdk = Vmdk::new(dir.join("base.vmdk")).unwrap();
+        let geometry = Geometry { cylinders: 2, heads: 4, sectors: 63 };
+        assert_eq!(
+            vmdk.warnings(),
+            [VmdkWarning::UnknownDdbKey("sidecars".to_owned()), VmdkWarning::GeometryMismatch { geometry, capacity: 1024 }]
+        );
+        assert!(Geometry { cylinders: 4, ..geometry }.covers(1024) && Geometry { cylinders: 16383, heads: 16, sectors: 63 }.covers(1 << 40));
+
+        // A parent named by a path from another host, written since
+        let child = SparseImage::new(1024, 128).child("C:\\VMs\\base.vmdk", 0x11111111);
+        std::fs::write(dir.join("child.vmdk"), child.build()).unwrap();
+        let vmdk = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let parent = dir.join("base.vmdk");
+        assert_eq!(
+            vmdk.warnings()[1..],
+            [
+                VmdkWarning::ParentHintRedirected { hint: "C:\\VMs\\base.vmdk".to_owned(), path: parent.clone() },
+                VmdkWarning::ParentCidMismatch { parent, expected: 0x11111111, found: 0x12345678 },
+            ]
+        );
+        assert_eq!(vmdk.parent().unwrap().warnings().len(), 2);
+    }
+}
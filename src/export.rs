@@ -0,0 +1,549 @@
+//! Exporting disks as images of other hypervisors.
+//!
+//! `Vmdk::export_qcow2` writes a version 2 qcow2 image, as QEMU reads it,
+//! and `Vmdk::export_vhd` a fixed VHD, as Hyper-V and Virtual PC read it.
+//! Both leave out what reads as zeros: qcow2 images store only the clusters
+//! holding data, fixed VHDs keep holes where the filesystem supports sparse
+//! files. `Qcow2Image` and `VhdImage` read such images back through
+//! `ExtentRead`, so exports can be verified.
+
+use std::convert::TryFrom;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use failure::Error;
+use log::info;
+
+use crate::clone::CloneOptions;
+use crate::descriptor::{new_uuid, parse_uuid};
+use crate::formats::{grains_allocation, read_grains, read_table_bytes, Allocation, ExtentRead, Grain, GrainLookup};
+use crate::storage::Storage;
+use crate::{Vmdk, VmdkError, SECTOR_SIZE};
+
+/// Magic at the start of a qcow2 image, "QFI\xfb"
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+/// Bytes of a version 2 qcow2 header
+const QCOW2_HEADER_SIZE: usize = 72;
+/// Clusters of exported qcow2 images are 64 KiB, as qemu-img makes them
+const QCOW2_CLUSTER_BITS: u32 = 16;
+/// Set in L1 and L2 entries of clusters referenced only once
+const QCOW2_COPIED: u64 = 1 << 63;
+/// Set in L2 entries of compressed clusters
+const QCOW2_COMPRESSED: u64 = 1 << 62;
+/// Set in L2 entries of clusters reading as zeros, from version 3
+const QCOW2_ZERO: u64 = 1;
+/// Bits of L1 and L2 entries holding the byte offset of the table or data
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+/// Cookie at the start of a VHD footer
+const VHD_COOKIE: &[u8] = b"conectix";
+/// Bytes of the footer closing a VHD
+const VHD_FOOTER_SIZE: u64 = 512;
+/// Disk type of a fixed VHD, whose data precedes the footer byte for byte
+const VHD_FIXED: u32 = 2;
+/// Seconds from the Unix epoch to 2000-01-01, where VHD timestamps start
+const VHD_EPOCH: u64 = 946_684_800;
+/// Largest disk a VHD can hold, 2040 GiB
+pub const MAX_VHD_SIZE: u64 = 2040 << 30;
+
+impl Vmdk {
+    /// Write the contents of the disk, with the data of all its parents,
+    /// to a new qcow2 image at `path`. Only clusters holding data other
+    /// than zeros are stored. Only `progress` and `cancel_token` of
+    /// `options` apply.
+    ///
+    /// If the export is cancelled or fails, the image is removed again.
+    pub fn export_qcow2<P: AsRef<Path>>(&mut self, path: P, options: &CloneOptions) -> Result<(), Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        info!("Exporting qcow2 image {}", path.display());
+        let exported = self.write_qcow2(file, options);
+        if exported.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        exported
+    }
+
+    /// Write the contents of the disk, with the data of all its parents,
+    /// to a new fixed VHD at `path`. Ranges without data are left as holes
+    /// where the filesystem supports sparse files. Disks larger than
+    /// `MAX_VHD_SIZE` cannot be exported. Only `progress` and
+    /// `cancel_token` of `options` apply.
+    ///
+    /// If the export is cancelled or fails, the image is removed again.
+    pub fn export_vhd<P: AsRef<Path>>(&mut self, path: P, options: &CloneOptions) -> Result<(), Error> {
+        let path = path.as_ref();
+        let size = self.size();
+        if size > MAX_VHD_SIZE {
+            return Err(VmdkError::InvalidArgument(format!("{} bytes do not fit a VHD", size)).into());
+        }
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        info!("Exporting VHD {}", path.display());
+
+        let exported = file.set_len(size + VHD_FOOTER_SIZE).map_err(Error::from).and_then(|()| {
+            self.copy_allocated(options.monitor(), |offset, data| {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)?;
+                Ok(())
+            })?;
+            file.seek(SeekFrom::Start(size))?;
+            file.write_all(&vhd_footer(size)?)?;
+            Ok(file.sync_all()?)
+        });
+        if exported.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        exported
+    }
+
+    /// Write the header, then the L1 table, the data clusters as they are
+    /// read, their L2 tables and last the refcounts
+    fn write_qcow2(&mut self, file: File, options: &CloneOptions) -> Result<(), Error> {
+        let size = self.size();
+        let cluster = 1u64 << QCOW2_CLUSTER_BITS;
+        let l2_entries = cluster / 8;
+        let l1_size = size.div_ceil(cluster * l2_entries);
+        let l1_offset = cluster;
+        let mut clusters = ClusterWriter {
+            out: BufWriter::new(file),
+            end: l1_offset + (l1_size * 8).div_ceil(cluster) * cluster,
+            stored: BTreeMap::new(),
+            pending: None,
+            buf: vec![0u8; cluster as usize],
+        };
+        clusters.out.seek(SeekFrom::Start(clusters.end))?;
+        self.copy_allocated(options.monitor(), |offset, data| clusters.add(offset, data))?;
+        clusters.flush()?;
+        let ClusterWriter { mut out, mut end, stored, .. } = clusters;
+
+        let mut l1 = vec![0u64; usize::try_from(l1_size)?];
+        let mut stored = stored.into_iter().peekable();
+        while let Some(&(first, _)) = stored.peek() {
+            let index = first / l2_entries;
+            let mut table = vec![0u8; cluster as usize];
+            while let Some((data, at)) = stored.next_if(|&(data, _)| data / l2_entries == index) {
+                let entry = (data % l2_entries) as usize * 8;
+                BigEndian::write_u64(&mut table[entry..entry + 8], at | QCOW2_COPIED);
+            }
+            out.write_all(&table)?;
+            l1[index as usize] = end | QCOW2_COPIED;
+            end += cluster;
+        }
+
+        // Refcount blocks of 16 bit counts, and the table pointing at them,
+        // count themselves too
+        let used = end / cluster;
+        let per_block = cluster / 2;
+        let (mut blocks, mut table_clusters) = (0, 0);
+        loop {
+            let needed = (used + blocks + table_clusters).div_ceil(per_block);
+            let needed_table = (needed * 8).div_ceil(cluster);
+            if (needed, needed_table) == (blocks, table_clusters) {
+                break;
+            }
+            blocks = needed;
+            table_clusters = needed_table;
+        }
+        let total = used + blocks + table_clusters;
+        let mut refcount_table = Vec::new();
+        for block in 0..blocks {
+            let counts = std::cmp::min(per_block, total - block * per_block) as usize;
+            let mut refcounts = vec![0u8; cluster as usize];
+            for count in refcounts[..counts * 2].chunks_mut(2) {
+                BigEndian::write_u16(count, 1);
+            }
+            out.write_all(&refcounts)?;
+            refcount_table.write_u64::<BigEndian>(end)?;
+            end += cluster;
+        }
+        refcount_table.resize((table_clusters * cluster) as usize, 0);
+        out.write_all(&refcount_table)?;
+
+        let mut header = Vec::with_capacity(QCOW2_HEADER_SIZE);
+        header.write_u32::<BigEndian>(QCOW2_MAGIC)?;
+        header.write_u32::<BigEndian>(2)?;
+        // No backing file
+        header.write_u64::<BigEndian>(0)?;
+        header.write_u32::<BigEndian>(0)?;
+        header.write_u32::<BigEndian>(QCOW2_CLUSTER_BITS)?;
+        header.write_u64::<BigEndian>(size)?;
+        // No encryption
+        header.write_u32::<BigEndian>(0)?;
+        header.write_u32::<BigEndian>(u32::try_from(l1_size)?)?;
+        header.write_u64::<BigEndian>(l1_offset)?;
+        header.write_u64::<BigEndian>(end)?;
+        header.write_u32::<BigEndian>(u32::try_from(table_clusters)?)?;
+        // No snapshots
+        header.write_u32::<BigEndian>(0)?;
+        header.write_u64::<BigEndian>(0)?;
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(&header)?;
+        out.seek(SeekFrom::Start(l1_offset))?;
+        for entry in &l1 {
+            out.write_u64::<BigEndian>(*entry)?;
+        }
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Data clusters of a qcow2 image being written, appended as blocks of
+/// the disk come in order
+struct ClusterWriter {
+    out: BufWriter<File>,
+    /// Byte offset of the end of the image
+    end: u64,
+    /// Byte offsets in the image of the clusters stored, by cluster of the
+    /// disk
+    stored: BTreeMap<u64, u64>,
+    /// Cluster of the disk `buf` is being filled for
+    pending: Option<u64>,
+    buf: Vec<u8>,
+}
+
+impl ClusterWriter {
+    fn add(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let cluster = self.buf.len() as u64;
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let within = (pos % cluster) as usize;
+            let n = std::cmp::min(self.buf.len() - within, data.len() - done);
+            if self.pending.is_some_and(|pending| pending != pos / cluster) {
+                self.flush()?;
+            }
+            self.pending = Some(pos / cluster);
+            self.buf[within..within + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(pending) = self.pending.take() {
+            self.out.write_all(&self.buf)?;
+            self.stored.insert(pending, self.end);
+            self.end += self.buf.len() as u64;
+            self.buf.iter_mut().for_each(|b| *b = 0);
+        }
+        Ok(())
+    }
+}
+
+/// Footer of a fixed VHD of `size` bytes
+fn vhd_footer(size: u64) -> Result<Vec<u8>, Error> {
+    let (cylinders, heads, sectors) = vhd_geometry(size / SECTOR_SIZE);
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs().saturating_sub(VHD_EPOCH));
+    let mut footer = Vec::with_capacity(VHD_FOOTER_SIZE as usize);
+    footer.extend_from_slice(VHD_COOKIE);
+    // Features: reserved bit always set
+    footer.write_u32::<BigEndian>(2)?;
+    footer.write_u32::<BigEndian>(0x0001_0000)?;
+    // Data offset: none for fixed disks
+    footer.write_u64::<BigEndian>(u64::MAX)?;
+    footer.write_u32::<BigEndian>(timestamp as u32)?;
+    footer.extend_from_slice(b"vmdk");
+    footer.write_u32::<BigEndian>(0x0001_0000)?;
+    footer.extend_from_slice(b"Wi2k");
+    // Original and current size
+    footer.write_u64::<BigEndian>(size)?;
+    footer.write_u64::<BigEndian>(size)?;
+    footer.write_u16::<BigEndian>(cylinders)?;
+    footer.push(heads);
+    footer.push(sectors);
+    footer.write_u32::<BigEndian>(VHD_FIXED)?;
+    // Checksum, filled in below
+    footer.write_u32::<BigEndian>(0)?;
+    footer.extend_from_slice(&parse_uuid(&new_uuid()).expect("fresh UUID"));
+    footer.resize(VHD_FOOTER_SIZE as usize, 0);
+    let checksum = vhd_checksum(&footer);
+    BigEndian::write_u32(&mut footer[64..68], checksum);
+    Ok(footer)
+}
+
+/// One's complement of the sum of the bytes of `footer`, but its checksum
+fn vhd_checksum(footer: &[u8]) -> u32 {
+    let sum = footer.iter().enumerate().filter(|&(i, _)| !(64..68).contains(&i)).fold(0u32, |sum, (_, &b)| sum.wrapping_add(u32::from(b)));
+    !sum
+}
+
+/// Cylinders, heads and sectors per track of a VHD of `sectors` sectors,
+/// as the VHD specification computes them
+fn vhd_geometry(sectors: u64) -> (u16, u8, u8) {
+    let total = std::cmp::min(sectors, 65535 * 16 * 255);
+    let (track, heads) = if total >= 65535 * 16 * 63 {
+        (255, 16)
+    } else {
+        let mut track = 17;
+        let mut heads = std::cmp::max((total / track).div_ceil(1024), 4);
+        if total / track >= heads * 1024 || heads > 16 {
+            track = 31;
+            heads = 16;
+        }
+        if total / track >= heads * 1024 {
+            track = 63;
+            heads = 16;
+        }
+        (track, heads)
+    };
+    ((total / track / heads) as u16, heads as u8, track as u8)
+}
+
+/// A qcow2 image, version 2 or 3, read through `ExtentRead`. Images with a
+/// backing file, encryption, compressed clusters or incompatible features
+/// are not supported.
+pub struct Qcow2Image {
+    file: Box<dyn Storage>,
+    size: u64,
+    cluster_bits: u32,
+    /// Byte offsets of the L2 tables, 0 where none is allocated
+    l1: Vec<u64>,
+    /// The L2 table read last, by index
+    table: Option<(u64, Vec<u64>)>,
+}
+
+impl Qcow2Image {
+    /// Open the qcow2 image at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        info!("Opening qcow2 image {}", path.as_ref().display());
+        Qcow2Image::new(Box::new(File::open(path)?))
+    }
+
+    /// Read the qcow2 image stored in `file`
+    pub fn new(file: Box<dyn Storage>) -> Result<Self, Error> {
+        let mut header = [0u8; QCOW2_HEADER_SIZE + 8];
+        let len = std::cmp::min(file.size()?, header.len() as u64) as usize;
+        if len < QCOW2_HEADER_SIZE {
+            return Err(VmdkError::ParseError.into());
+        }
+        file.read_exact_at(&mut header[..len], 0)?;
+        let version = BigEndian::read_u32(&header[4..]);
+        if BigEndian::read_u32(&header) != QCOW2_MAGIC || !(2..=3).contains(&version) {
+            return Err(VmdkError::ParseError.into());
+        }
+        let unsupported = |what: &str| Err(VmdkError::UnsupportedFormat(format!("qcow2 image {}", what)).into());
+        if BigEndian::read_u64(&header[8..]) != 0 {
+            return unsupported("with a backing file");
+        }
+        if BigEndian::read_u32(&header[32..]) != 0 {
+            return unsupported("encrypted");
+        }
+        // Anything but the dirty bit needs support
+        if version == 3 && BigEndian::read_u64(&header[72..]) & !1 != 0 {
+            return unsupported("with incompatible features");
+        }
+        let cluster_bits = BigEndian::read_u32(&header[20..]);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(VmdkError::ParseError.into());
+        }
+        let size = BigEndian::read_u64(&header[24..]);
+        let l1_size = u64::from(BigEndian::read_u32(&header[36..]));
+        let covered = l1_size.checked_shl(2 * cluster_bits - 3).filter(|_| l1_size >> (67 - 2 * cluster_bits) == 0);
+        if covered.is_none_or(|covered| covered < size) {
+            return Err(VmdkError::ParseError.into());
+        }
+        let bytes = read_table_bytes(&*file, BigEndian::read_u64(&header[40..]), l1_size, 8)?;
+        let mut l1 = vec![0u64; bytes.len() / 8];
+        BigEndian::read_u64_into(&bytes, &mut l1);
+        l1.iter_mut().for_each(|entry| *entry &= QCOW2_OFFSET_MASK);
+        Ok(Qcow2Image { file, size, cluster_bits, l1, table: None })
+    }
+}
+
+impl GrainLookup for Qcow2Image {
+    fn file(&self) -> &dyn Storage {
+        &*self.file
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn lookup(&mut self, grain: u64) -> Result<Grain, Error> {
+        let l2_bits = self.cluster_bits - 3;
+        let index = grain >> l2_bits;
+        let l2 = match self.l1.get(index as usize) {
+            Some(&0) | None => return Ok(Grain::Unallocated),
+            Some(&l2) => l2,
+        };
+        if self.table.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            let bytes = read_table_bytes(&*self.file, l2, 1 << l2_bits, 8)?;
+            let mut table = vec![0u64; bytes.len() / 8];
+            BigEndian::read_u64_into(&bytes, &mut table);
+            self.table = Some((index, table));
+        }
+        let entry = self.table.as_ref().expect("table read").1[(grain & ((1 << l2_bits) - 1)) as usize];
+        if entry & QCOW2_COMPRESSED != 0 {
+            return Err(VmdkError::UnsupportedFormat("qcow2 image with compressed clusters".to_owned()).into());
+        }
+        match entry & QCOW2_OFFSET_MASK {
+            _ if entry & QCOW2_ZERO != 0 => Ok(Grain::Zero),
+            0 => Ok(Grain::Unallocated),
+            offset if offset + self.grain_bytes() > self.file.size()? => {
+                let problem = "past the end of the file".to_owned();
+                Err(VmdkError::CorruptGrain { grain, sector: offset / SECTOR_SIZE, problem }.into())
+            }
+            offset => Ok(Grain::Data(offset / SECTOR_SIZE)),
+        }
+    }
+}
+
+impl ExtentRead for Qcow2Image {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn grain_size(&self) -> u64 {
+        self.grain_bytes()
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        read_grains(self, offset, buf)
+    }
+
+    fn allocation(&mut self, offset: u64, len: u64) -> Result<Allocation, Error> {
+        grains_allocation(self, offset, len)
+    }
+}
+
+/// A fixed VHD read through `ExtentRead`. Dynamic and differencing VHDs
+/// are not supported.
+pub struct VhdImage {
+    file: Box<dyn Storage>,
+    size: u64,
+}
+
+impl VhdImage {
+    /// Open the VHD at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        info!("Opening VHD {}", path.as_ref().display());
+        VhdImage::new(Box::new(File::open(path)?))
+    }
+
+    /// Read the VHD stored in `file`, from the footer in its last sector
+    pub fn new(file: Box<dyn Storage>) -> Result<Self, Error> {
+        let len = file.size()?;
+        if len < VHD_FOOTER_SIZE {
+            return Err(VmdkError::ParseError.into());
+        }
+        let mut footer = [0u8; VHD_FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, len - VHD_FOOTER_SIZE)?;
+        if &footer[..8] != VHD_COOKIE || BigEndian::read_u32(&footer[64..]) != vhd_checksum(&footer) {
+            return Err(VmdkError::ParseError.into());
+        }
+        if BigEndian::read_u32(&footer[60..]) != VHD_FIXED {
+            return Err(VmdkError::UnsupportedFormat("dynamic or differencing VHD".to_owned()).into());
+        }
+        let size = BigEndian::read_u64(&footer[48..]);
+        if size > len - VHD_FOOTER_SIZE {
+            return Err(VmdkError::ParseError.into());
+        }
+        Ok(VhdImage { file, size })
+    }
+}
+
+impl ExtentRead for VhdImage {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn grain_size(&self) -> u64 {
+        SECTOR_SIZE
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        Ok(self.file.read_exact_at(buf, offset)?)
+    }
+
+    fn allocation(&mut self, _offset: u64, _len: u64) -> Result<Allocation, Error> {
+        Ok(Allocation::Data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{contents, scratch_dir, write_chain, SparseImage};
+
+    fn read_all(image: &mut dyn ExtentRead) -> Vec<u8> {
+        let mut buf = vec![0u8; image.size() as usize];
+        image.read_at(0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_export_qcow2() {
+        let dir = scratch_dir("export-qcow2");
+        write_chain(&dir, 4096);
+        let mut child = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        child.export_qcow2(dir.join("disk.qcow2"), &CloneOptions::new()).unwrap();
+
+        let mut image = Qcow2Image::open(dir.join("disk.qcow2")).unwrap();
+        assert_eq!(image.size(), child.size());
+        assert_eq!(read_all(&mut image), contents(&mut child));
+        let grain = 128 * 512;
+        assert_eq!(image.allocation(0, grain).unwrap(), Allocation::Unallocated);
+        assert_eq!(image.allocation(grain, grain).unwrap(), Allocation::Data);
+        // Header, L1 table, two data clusters, an L2 table, a refcount
+        // block and the refcount table
+        assert_eq!(std::fs::metadata(dir.join("disk.qcow2")).unwrap().len(), 7 << 16);
+        assert!(child.export_qcow2(dir.join("disk.qcow2"), &CloneOptions::new()).is_err());
+    }
+
+    #[test]
+    fn test_export_qcow2_small_grains() {
+        let dir = scratch_dir("export-qcow2-small");
+        // Grains of 4 KiB, two of them sharing a cluster, past the first
+        // L2 table
+        let image = SparseImage::new((1 << 20) + 1024, 8).monolithic("disk.vmdk");
+        let image = image.grain(1, 0xa1).grain(3, 0xa3).grain(1 << 17, 0xb1);
+        std::fs::write(dir.join("disk.vmdk"), image.build()).unwrap();
+        let mut vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        vmdk.export_qcow2(dir.join("disk.qcow2"), &CloneOptions::new()).unwrap();
+
+        let mut image = Qcow2Image::open(dir.join("disk.qcow2")).unwrap();
+        assert_eq!(image.size(), vmdk.size());
+        assert_eq!(image.l1.iter().filter(|&&l2| l2 != 0).count(), 2);
+        for &offset in &[0, 1 << 29, vmdk.size() - (1 << 16)] {
+            let (mut expected, mut actual) = (vec![0u8; 1 << 16], vec![0xffu8; 1 << 16]);
+            vmdk.read_at(offset, &mut expected).unwrap();
+            image.read_at(offset, &mut actual).unwrap();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_export_vhd() {
+        let dir = scratch_dir("export-vhd");
+        write_chain(&dir, 4096);
+        let mut child = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        child.export_vhd(dir.join("disk.vhd"), &CloneOptions::new()).unwrap();
+
+        let file = std::fs::read(dir.join("disk.vhd")).unwrap();
+        assert_eq!(file.len() as u64, child.size() + VHD_FOOTER_SIZE);
+        let footer = &file[file.len() - 512..];
+        assert_eq!(&footer[..8], VHD_COOKIE);
+        // 4096 sectors: 17 sectors per track on 4 heads
+        assert_eq!(&footer[56..60], &[0, 60, 4, 17]);
+        let mut image = VhdImage::open(dir.join("disk.vhd")).unwrap();
+        assert_eq!(read_all(&mut image), contents(&mut child));
+
+        let mut damaged = file.clone();
+        damaged[file.len() - 100] ^= 1;
+        std::fs::write(dir.join("damaged.vhd"), &damaged).unwrap();
+        assert!(VhdImage::open(dir.join("damaged.vhd")).is_err());
+    }
+
+    #[test]
+    fn test_vhd_geometry() {
+        assert_eq!(vhd_geometry(4096), (60, 4, 17));
+        // 127 GiB and more are capped at 65535 cylinders of 16 heads
+        assert_eq!(vhd_geometry(1 << 28), (65535, 16, 255));
+        assert_eq!(vhd_geometry(1 << 30), (65535, 16, 255));
+        assert_eq!(vhd_geometry(20 << 21), (41610, 16, 63));
+    }
+}
@@ -0,0 +1,1504 @@
+//! Access to the data of individual extents.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Error;
+use log::{info, warn};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+use crate::cache::{GrainCache, GrainKey, LruGrainCache};
+use crate::compress::{read_compressed_grain, COMPRESSION_DEFLATE};
+use crate::descriptor::{AccessMode, ExtentDescriptor, ExtentType};
+use crate::diagnostics::{self, PastEof, Strictness};
+use crate::formats::{CowdExtent, ExtentRead, SeSparseExtent};
+use crate::lba::LbaMapper;
+use crate::lock::lock_file;
+use crate::pool::{FilePool, Handle};
+use crate::positioned::read_u32_at;
+use crate::progress::CancelToken;
+use crate::storage::{Storage, StorageProvider};
+use crate::stream::DEFAULT_GRAIN_SIZE;
+use crate::{ExtentHeader, Vmdk, VmdkError, VmdkOpenOptions, FLAG_COMPRESSED, SECTOR_SIZE};
+
+/// `gd_offset` of stream-optimized extents whose grain directory follows the
+/// grains; the real offset is in the footer.
+pub const GD_AT_END: u64 = 0xffffffffffffffff;
+
+/// Most zeros written to a flat extent at once by `write_zeroes`
+const ZERO_CHUNK: u64 = 1 << 20;
+
+/// What actually stores the data of an extent
+pub(crate) enum Backing {
+    /// Hosted sparse extent with grain directory and grain tables
+    Sparse { file: Handle, header: ExtentHeader },
+    /// Flat file or physical device, read at a fixed sector offset
+    Flat { file: Handle },
+    /// No backing storage, reads as zeros
+    Zero,
+    /// Extent of another format, such as COWD or SESparse, read through
+    /// `ExtentRead` and never written
+    Other(Box<dyn ExtentRead>),
+}
+
+/// The backing of an extent, opened on first access when the disk was
+/// opened with `VmdkOpenOptions::max_open_files`
+pub(crate) struct LazyBacking {
+    cell: OnceLock<Backing>,
+    extent_type: ExtentType,
+    path: Option<PathBuf>,
+    write: bool,
+    force: bool,
+    allow_devices: bool,
+    redundant_gd: bool,
+    strictness: Strictness,
+    /// Pool the file is opened in, read-only extents only
+    pool: Option<FilePool>,
+    /// Where the file is opened from, if not the local filesystem
+    provider: Option<Arc<dyn StorageProvider>>,
+}
+
+impl LazyBacking {
+    fn new(extent_type: ExtentType, path: Option<PathBuf>, write: bool, options: &VmdkOpenOptions) -> Self {
+        LazyBacking {
+            cell: OnceLock::new(),
+            extent_type,
+            path,
+            write,
+            force: options.force,
+            allow_devices: options.allow_devices,
+            redundant_gd: options.redundant_gd,
+            strictness: options.strictness,
+            // Writable files stay open, so they stay locked
+            pool: if write { None } else { options.pool.clone() },
+            provider: options.storage.clone(),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Result<&Backing, Error> {
+        if let Some(backing) = self.cell.get() {
+            return Ok(backing);
+        }
+        let backing = self.open()?;
+        Ok(self.cell.get_or_init(|| backing))
+    }
+
+    pub(crate) fn get_mut(&mut self) -> Result<&mut Backing, Error> {
+        self.get()?;
+        Ok(self.cell.get_mut().expect("backing opened"))
+    }
+
+    fn open(&self) -> Result<Backing, Error> {
+        let path = match (self.extent_type, &self.path) {
+            (ExtentType::Zero, _) => return Ok(Backing::Zero),
+            (_, Some(path)) => path,
+            (_, None) => return Err(VmdkError::ParseError.into()),
+        };
+        if self.extent_type == ExtentType::Sparse {
+            info!("Opening sparse extent {}", path.display());
+            let mut file = self.open_file(path)?;
+            let (header, warnings) = ExtentHeader::with_strictness(&mut *file, self.strictness)?;
+            for warning in warnings.warnings() {
+                warn!("{}: {}", path.display(), warning);
+            }
+            let header = select_gd(resolve_footer(&mut *file, header)?, self.redundant_gd)?;
+            let file = Handle::new(file, path, self.pool.as_ref(), self.provider.as_ref());
+            return Ok(Backing::Sparse { file, header });
+        }
+        if let ExtentType::VmfsSparse | ExtentType::SeSparse = self.extent_type {
+            // Opened read-only, as extents of these formats are never written
+            let file = match &self.provider {
+                Some(provider) => provider.open(path, false)?,
+                None => Box::new(open_file(path, false, self.force)?),
+            };
+            return Ok(Backing::Other(match self.extent_type {
+                ExtentType::VmfsSparse => {
+                    info!("Opening COWD extent {}", path.display());
+                    Box::new(CowdExtent::new(file)?)
+                }
+                _ => {
+                    info!("Opening SESparse extent {}", path.display());
+                    Box::new(SeSparseExtent::new(file)?)
+                }
+            }));
+        }
+        if self.provider.is_none() && is_device(path) && !self.allow_devices {
+            return Err(VmdkError::DeviceNotAllowed(path.display().to_string()).into());
+        }
+        info!("Opening flat extent {}", path.display());
+        let file = self.open_file(path)?;
+        Ok(Backing::Flat { file: Handle::new(file, path, self.pool.as_ref(), self.provider.as_ref()) })
+    }
+
+    fn open_file(&self, path: &Path) -> Result<Box<dyn Storage>, Error> {
+        match &self.provider {
+            Some(provider) => provider.open(path, self.write),
+            None => Ok(Box::new(open_file(path, self.write, self.force)?)),
+        }
+    }
+}
+
+pub(crate) struct Extent {
+    pub(crate) descriptor: ExtentDescriptor,
+    /// File backing the extent, `None` for ZERO extents
+    pub(crate) path: Option<PathBuf>,
+    /// First logical sector covered by this extent
+    pub(crate) start: u64,
+    pub(crate) backing: LazyBacking,
+    /// Whether the backing file was opened for writing
+    pub(crate) writable: bool,
+    /// Whether this handle set the header's `dirty_shutdown` flag
+    dirty: bool,
+    /// Whether `sparsify` punches holes where dropped grains were
+    punch_holes: bool,
+    /// How reads treat grains past the end of the file
+    past_eof: PastEof,
+    /// Cache of grain data, read-only extents only
+    cache: Option<Arc<dyn GrainCache>>,
+    /// Recently decompressed grains, used without `cache`
+    inflated: Option<LruGrainCache>,
+    map: Mapping,
+}
+
+/// What backs a range of an extent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// At least part of the range is stored in the extent
+    Data,
+    /// The whole range reads as zeros
+    Zero,
+    /// Nothing is stored, at least part of the range falls through to the
+    /// parent
+    Unallocated,
+}
+
+/// A memory map of an extent file, made on first use. Only read-only
+/// extents are mapped, with `VmdkOpenOptions::mmap`.
+pub(crate) struct Mapping {
+    #[cfg(feature = "mmap")]
+    inner: Option<(PathBuf, OnceLock<Mmap>)>,
+}
+
+impl Mapping {
+    #[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+    fn new(path: Option<PathBuf>, enabled: bool) -> Self {
+        Mapping {
+            #[cfg(feature = "mmap")]
+            inner: path.filter(|_| enabled).map(|path| (path, OnceLock::new())),
+        }
+    }
+
+    /// The contents of the file, `None` unless mapping is enabled
+    #[cfg(feature = "mmap")]
+    fn get(&self) -> Result<Option<&[u8]>, Error> {
+        let (path, map) = match &self.inner {
+            Some(inner) => inner,
+            None => return Ok(None),
+        };
+        if let Some(map) = map.get() {
+            return Ok(Some(map));
+        }
+        info!("Mapping {}", path.display());
+        let file = File::open(path)?;
+        // Safety: the extent is read-only here, and the caller of the unsafe
+        // `VmdkOpenOptions::mmap` promised nobody modifies it while mapped
+        let mapped = unsafe { Mmap::map(&file)? };
+        Ok(Some(map.get_or_init(|| mapped)))
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn get(&self) -> Result<Option<&[u8]>, Error> {
+        Ok(None)
+    }
+}
+
+/// A grain of an extent as found in its memory map
+#[cfg_attr(not(feature = "mmap"), allow(dead_code))]
+pub(crate) enum GrainRef<'a> {
+    /// The data of the grain, stored uncompressed
+    Data(&'a [u8]),
+    /// Not stored, falls through to the parent
+    Unallocated,
+    /// Reads as zeros, is compressed or truncated, or the extent is not
+    /// mapped
+    Unavailable,
+}
+
+/// Where a byte of an extent is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Placement {
+    /// At the given byte offset of the extent file
+    File(u64),
+    /// Inside a compressed grain or an extent of another format, so at
+    /// no fixed offset
+    Compressed,
+    /// Nowhere, it reads as zero
+    Zero,
+    /// Nowhere, it falls through to the parent
+    Unallocated,
+}
+
+/// Byte offset of `dirty_shutdown` in the extent header
+const DIRTY_SHUTDOWN_OFFSET: u64 = 72;
+
+/// Where the data of a grain lives
+enum GrainState {
+    /// Not present in this extent, falls through to the parent
+    Unallocated,
+    /// Reads as zeros regardless of the parent
+    Zero,
+    /// Stored at the given sector
+    Allocated(u64),
+    /// Said to be stored at the given sector, but the file ends before the
+    /// grain does, as in truncated images
+    PastEof(u64),
+}
+
+impl Extent {
+    /// Open the extent described by `descriptor`, backed by the file at
+    /// `path`.
+    pub(crate) fn open(
+        descriptor: ExtentDescriptor,
+        start: u64,
+        path: Option<PathBuf>,
+        options: &VmdkOpenOptions,
+    ) -> Result<Self, Error> {
+        let write = options.write && descriptor.access == AccessMode::Rw;
+        match (descriptor.extent_type, &path) {
+            (ExtentType::Zero, _) => (),
+            (ExtentType::Flat | ExtentType::Vmfs | ExtentType::Sparse, Some(_)) => (),
+            (ExtentType::VmfsSparse | ExtentType::SeSparse, Some(_)) => (),
+            (ExtentType::Flat | ExtentType::Vmfs | ExtentType::Sparse | ExtentType::VmfsSparse | ExtentType::SeSparse, None) => {
+                return Err(VmdkError::ParseError.into())
+            }
+            (t, _) => return Err(VmdkError::UnsupportedExtent(t.as_str().to_owned()).into()),
+        }
+        let backing = LazyBacking::new(descriptor.extent_type, path.clone(), write, options);
+        let map = Mapping::new(path.clone(), options.mmap && !write && options.storage.is_none());
+        if backing.pool.is_none() {
+            backing.get()?;
+        }
+
+        Ok(Extent {
+            descriptor,
+            path,
+            start,
+            backing,
+            writable: write,
+            dirty: false,
+            punch_holes: options.punch_holes,
+            past_eof: options.past_eof,
+            cache: if write { None } else { options.cache.clone() },
+            inflated: if write { None } else { options.compressed_cache() },
+            map,
+        })
+    }
+
+    /// Wrap an already opened sparse file, used for monolithic sparse disks
+    /// whose descriptor is embedded in the extent itself.
+    pub(crate) fn from_sparse(
+        descriptor: ExtentDescriptor,
+        start: u64,
+        path: PathBuf,
+        mut file: Box<dyn Storage>,
+        header: ExtentHeader,
+        options: &VmdkOpenOptions,
+    ) -> Result<Self, Error> {
+        let header = select_gd(resolve_footer(&mut *file, header)?, options.redundant_gd)?;
+        let writable = options.write && descriptor.access == AccessMode::Rw;
+        let backing = LazyBacking::new(descriptor.extent_type, Some(path.clone()), writable, options);
+        let file = Handle::new(file, &path, backing.pool.as_ref(), options.storage.as_ref());
+        let _ = backing.cell.set(Backing::Sparse { file, header });
+        let map = Mapping::new(Some(path.clone()), options.mmap && !writable && options.storage.is_none());
+        Ok(Extent {
+            descriptor,
+            path: Some(path),
+            start,
+            backing,
+            writable,
+            dirty: false,
+            punch_holes: options.punch_holes,
+            past_eof: options.past_eof,
+            cache: if writable { None } else { options.cache.clone() },
+            inflated: if writable { None } else { options.compressed_cache() },
+            map,
+        })
+    }
+
+    /// Size of the extent in bytes
+    pub(crate) fn size(&self) -> u64 {
+        self.descriptor.sectors * SECTOR_SIZE
+    }
+
+    /// Granularity of allocation in bytes. Extents without grains report
+    /// the default grain size.
+    pub(crate) fn grain_bytes(&self) -> Result<u64, Error> {
+        match self.backing.get()? {
+            Backing::Sparse { header, .. } => Ok(header.grain_size.0 * SECTOR_SIZE),
+            Backing::Other(reader) => Ok(other_grain_bytes(&**reader)),
+            _ => Ok(DEFAULT_GRAIN_SIZE * SECTOR_SIZE),
+        }
+    }
+
+    /// Fill `buf` with data starting at byte `offset` within the extent. The
+    /// caller guarantees that the range lies inside the extent. Grains not
+    /// allocated in a sparse extent are read from `parent`, or as zeros.
+    pub(crate) fn read_at(&mut self, offset: u64, buf: &mut [u8], parent: Option<&mut Vmdk>) -> Result<(), Error> {
+        let base = self.start * SECTOR_SIZE;
+        match self.backing.get_mut()? {
+            Backing::Zero => {
+                zero(buf);
+                Ok(())
+            }
+            Backing::Flat { file } => {
+                file.get()?.read_exact_at(buf, self.descriptor.offset * SECTOR_SIZE + offset)?;
+                Ok(())
+            }
+            Backing::Sparse { file, header } => {
+                let compressed = header.flags & FLAG_COMPRESSED != 0;
+                let cache: Option<&dyn GrainCache> = match (&self.cache, &self.inflated) {
+                    (Some(cache), _) => Some(&**cache),
+                    (None, Some(inflated)) if compressed => Some(inflated),
+                    _ => None,
+                };
+                let sources = GrainSources { cache: cache.zip(self.path.as_deref()), map: self.map.get()?, past_eof: self.past_eof };
+                read_sparse(&mut *file.get()?, header, base, offset, buf, parent, sources)
+            }
+            Backing::Other(reader) => read_other(&mut **reader, base, offset, buf, parent),
+        }
+    }
+
+    /// What backs `len` bytes at byte `offset` within the extent
+    pub(crate) fn allocation(&mut self, offset: u64, len: u64) -> Result<Allocation, Error> {
+        let (mut file, header) = match self.backing.get_mut()? {
+            Backing::Zero => return Ok(Allocation::Zero),
+            Backing::Flat { .. } => return Ok(Allocation::Data),
+            Backing::Other(reader) => return reader.allocation(offset, len),
+            Backing::Sparse { file, header } => (file.get()?, header),
+        };
+        let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+
+        let mut result = Allocation::Zero;
+        for (grain, _, _, _) in grain_chunks(grain_bytes, offset, len.try_into()?) {
+            match grain_state(&mut *file, header, grain)? {
+                GrainState::Allocated(_) | GrainState::PastEof(_) => return Ok(Allocation::Data),
+                GrainState::Unallocated => result = Allocation::Unallocated,
+                GrainState::Zero => (),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Offset within the extent of the data the byte at `file_offset` of the
+    /// extent file holds. Bytes of a compressed grain map to the start of
+    /// the grain.
+    pub(crate) fn logical_offset(&mut self, file_offset: u64) -> Result<Option<u64>, Error> {
+        let size = self.size();
+        match self.backing.get_mut()? {
+            Backing::Zero | Backing::Other(_) => Ok(None),
+            Backing::Flat { .. } => {
+                let start = self.descriptor.offset * SECTOR_SIZE;
+                let inside = file_offset >= start && file_offset < start + size;
+                Ok(if inside { Some(file_offset - start) } else { None })
+            }
+            Backing::Sparse { file, header } => {
+                let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+                let compressed = header.flags & FLAG_COMPRESSED != 0;
+                let mut file = file.get()?;
+                let gtes = grain_table(&mut *file, header)?;
+                for (grain, &gte) in gtes.iter().enumerate().filter(|&(_, &gte)| gte > 1) {
+                    let start = u64::from(gte) * SECTOR_SIZE;
+                    let end = if compressed {
+                        // Marker of the LBA and size before the data
+                        file.seek(SeekFrom::Start(start + 8))?;
+                        start + 12 + u64::from(file.read_u32::<LittleEndian>()?)
+                    } else {
+                        start + grain_bytes
+                    };
+                    if file_offset >= start && file_offset < end {
+                        let within = if compressed { 0 } else { file_offset - start };
+                        return Ok(Some(grain as u64 * grain_bytes + within));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Where the byte at `offset` within the extent is stored
+    pub(crate) fn placement(&mut self, offset: u64) -> Result<Placement, Error> {
+        match self.backing.get_mut()? {
+            Backing::Zero => Ok(Placement::Zero),
+            Backing::Flat { .. } => Ok(Placement::File(self.descriptor.offset * SECTOR_SIZE + offset)),
+            Backing::Other(reader) => match reader.allocation(offset, 1)? {
+                Allocation::Data => Ok(Placement::Compressed),
+                Allocation::Zero => Ok(Placement::Zero),
+                Allocation::Unallocated => Ok(Placement::Unallocated),
+            },
+            Backing::Sparse { file, header } => {
+                let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+                let grain = offset / grain_bytes;
+                match grain_state(&mut *file.get()?, header, grain)? {
+                    GrainState::PastEof(sector) => Err(past_eof(grain, sector)),
+                    GrainState::Unallocated => Ok(Placement::Unallocated),
+                    GrainState::Zero => Ok(Placement::Zero),
+                    GrainState::Allocated(_) if header.flags & FLAG_COMPRESSED != 0 => Ok(Placement::Compressed),
+                    GrainState::Allocated(sector) => Ok(Placement::File(sector * SECTOR_SIZE + offset % grain_bytes)),
+                }
+            }
+        }
+    }
+
+    /// The grain holding the byte at `offset` within the extent, borrowed
+    /// from the memory map of the extent file
+    #[cfg_attr(not(feature = "mmap"), allow(dead_code))]
+    pub(crate) fn grain_ref(&self, offset: u64) -> Result<GrainRef<'_>, Error> {
+        let (map, header) = match (self.map.get()?, self.backing.get()?) {
+            (Some(map), Backing::Sparse { header, .. }) if header.flags & FLAG_COMPRESSED == 0 => (map, header),
+            _ => return Ok(GrainRef::Unavailable),
+        };
+        let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+        match mapped_grain_state(map, header, offset / grain_bytes)? {
+            GrainState::Allocated(sector) => Ok(GrainRef::Data(mapped(map, sector * SECTOR_SIZE, grain_bytes)?)),
+            GrainState::Unallocated => Ok(GrainRef::Unallocated),
+            GrainState::Zero | GrainState::PastEof(_) => Ok(GrainRef::Unavailable),
+        }
+    }
+
+    /// Write `buf` at byte `offset` within the extent. Partially written
+    /// grains that are not yet allocated are first filled from `parent`.
+    pub(crate) fn write_at(&mut self, offset: u64, buf: &[u8], parent: Option<&mut Vmdk>) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let base = self.start * SECTOR_SIZE;
+        match self.backing.get_mut()? {
+            Backing::Zero | Backing::Other(_) => unreachable!(),
+            Backing::Flat { file } => {
+                file.get()?.write_all_at(buf, self.descriptor.offset * SECTOR_SIZE + offset)?;
+                Ok(())
+            }
+            Backing::Sparse { file, header } => {
+                let mut file = file.get()?;
+                if !self.dirty && header.dirty_shutdown == 0 {
+                    set_dirty_shutdown(&mut *file, header, 1)?;
+                    self.dirty = true;
+                }
+                write_sparse(&mut *file, header, base, offset, buf, parent)
+            }
+        }
+    }
+
+    /// Make `len` bytes at byte `offset` within the extent read as zeros.
+    /// Grains wholly covered are dropped from the grain tables, or with a parent become
+    /// zero grains, instead of being written; only partly covered ones are
+    /// written. Flat extents are written with zeros or, with `punch_holes`,
+    /// have holes punched.
+    pub(crate) fn write_zeroes(&mut self, offset: u64, len: u64, mut parent: Option<&mut Vmdk>) -> Result<(), Error> {
+        self.check_writable()?;
+        self.mark_dirty()?;
+
+        let base = self.start * SECTOR_SIZE;
+        let size = self.size();
+        let punch_holes = self.punch_holes;
+        match self.backing.get_mut()? {
+            Backing::Zero | Backing::Other(_) => unreachable!(),
+            Backing::Flat { file } => {
+                let file = file.get()?;
+                let pos = self.descriptor.offset * SECTOR_SIZE + offset;
+                if !(punch_holes && file.punch_hole(pos, len)?) {
+                    let zeros = vec![0u8; std::cmp::min(len, ZERO_CHUNK).try_into()?];
+                    let mut done = 0;
+                    while done < len {
+                        let n = std::cmp::min(len - done, ZERO_CHUNK);
+                        file.write_all_at(&zeros[..n as usize], pos + done)?;
+                        done += n;
+                    }
+                }
+                Ok(())
+            }
+            Backing::Sparse { file, header } => {
+                let mut file = file.get()?;
+                let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+                let mut zeros = Vec::new();
+                for (grain, within, _, n) in grain_chunks(grain_bytes, offset, len.try_into()?) {
+                    let state = grain_state(&mut *file, header, grain)?;
+                    // The last grain may extend past the end of the extent
+                    let grain_len = std::cmp::min(grain_bytes, size - grain * grain_bytes);
+                    let whole = within == 0 && n as u64 >= grain_len;
+                    match (state, whole) {
+                        (GrainState::PastEof(sector), _) => return Err(past_eof(grain, sector)),
+                        (GrainState::Zero, _) => (),
+                        (GrainState::Unallocated, _) if parent.is_none() => (),
+                        (GrainState::Allocated(sector), true) => {
+                            set_gte(&mut *file, header, grain, if parent.is_some() { 1 } else { 0 })?;
+                            if punch_holes {
+                                file.punch_hole(sector * SECTOR_SIZE, grain_bytes)?;
+                            }
+                        }
+                        (GrainState::Unallocated, true) => set_gte(&mut *file, header, grain, 1)?,
+                        (_, false) => {
+                            zeros.resize(n, 0);
+                            write_sparse(&mut *file, header, base, grain * grain_bytes + within, &zeros, parent.as_deref_mut())?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Grow a flat extent to `sectors`, extending its file as needed, and
+    /// return the previous length of the file if it was extended, for
+    /// `shrink`. Devices must already be large enough.
+    pub(crate) fn grow(&mut self, sectors: u64) -> Result<Option<u64>, Error> {
+        self.check_writable()?;
+        let needed = (self.descriptor.offset + sectors) * SECTOR_SIZE;
+        let mut extended = None;
+        match self.backing.get_mut()? {
+            Backing::Flat { file } => {
+                let file = file.get()?;
+                let device = self.path.as_deref().is_some_and(is_device);
+                let len = file.size()?;
+                if !device && len < needed {
+                    file.set_len(needed)?;
+                    file.sync_all()?;
+                    extended = Some(len);
+                }
+            }
+            _ => {
+                let kind = self.descriptor.extent_type.as_str();
+                return Err(VmdkError::InvalidArgument(format!("cannot grow {} extents", kind)).into());
+            }
+        }
+        self.descriptor.sectors = sectors;
+        Ok(extended)
+    }
+
+    /// Undo `grow`, back to `sectors` and the file length it returned
+    pub(crate) fn shrink(&mut self, sectors: u64, len: Option<u64>) -> Result<(), Error> {
+        if let (Some(len), Backing::Flat { file }) = (len, self.backing.get_mut()?) {
+            let file = file.get()?;
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        self.descriptor.sectors = sectors;
+        Ok(())
+    }
+
+    /// Set `dirty_shutdown` before the first modification of a sparse
+    /// extent through this handle
+    pub(crate) fn mark_dirty(&mut self) -> Result<(), Error> {
+        if let Backing::Sparse { file, header } = self.backing.get_mut()? {
+            if !self.dirty && header.dirty_shutdown == 0 {
+                set_dirty_shutdown(&mut *file.get()?, header, 1)?;
+                self.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the file is opened from, if not the local filesystem
+    pub(crate) fn provider(&self) -> Option<&Arc<dyn StorageProvider>> {
+        self.backing.provider.as_ref()
+    }
+
+    /// Open the file of the extent anew and read-only, from where the disk
+    /// was opened
+    pub(crate) fn open_file(&self) -> Result<Box<dyn Storage>, Error> {
+        let path = self.path.as_deref().ok_or(VmdkError::ParseError)?;
+        match self.provider() {
+            Some(provider) => provider.open(path, false),
+            None => Ok(Box::new(File::open(path)?)),
+        }
+    }
+
+    /// Whether this handle marked the extent as in use
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Fail unless `write_at` can modify this extent
+    pub(crate) fn check_writable(&self) -> Result<(), Error> {
+        if !self.writable {
+            return Err(VmdkError::NotWritable(format!("{} extent", self.descriptor.access.as_str())).into());
+        }
+        match self.backing.get()? {
+            Backing::Zero => Err(VmdkError::NotWritable("ZERO extent".to_owned()).into()),
+            Backing::Other(_) => Err(VmdkError::NotWritable(format!("{} extent", self.descriptor.extent_type.as_str())).into()),
+            Backing::Sparse { header, .. } if header.flags & FLAG_COMPRESSED != 0 => {
+                Err(VmdkError::NotWritable("compressed extent".to_owned()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Overwrite the descriptor embedded in this sparse extent with `text`
+    pub(crate) fn write_embedded_descriptor(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        match self.backing.get_mut()? {
+            Backing::Sparse { file, header } if self.writable => write_embedded_descriptor(&mut *file.get()?, header, bytes),
+            _ => Err(VmdkError::NotWritable("embedded descriptor".to_owned()).into()),
+        }
+    }
+
+    /// Drop allocated grains that hold only zeros from the grain tables,
+    /// returning how many were dropped. With a parent they become zero
+    /// grains instead, so the parent's data stays hidden. Stops between
+    /// grains once `cancel` is cancelled.
+    pub(crate) fn sparsify(&mut self, has_parent: bool, cancel: &CancelToken) -> Result<u64, Error> {
+        if let Backing::Sparse { .. } = self.backing.get()? {
+            self.check_writable()?;
+        }
+        let (mut file, header) = match self.backing.get_mut()? {
+            Backing::Sparse { file, header } => (file.get()?, header),
+            _ => return Ok(0),
+        };
+        let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+        let num_grains = header.capacity.0.div_ceil(header.grain_size.0);
+        let mut data = vec![0u8; grain_bytes.try_into()?];
+        let mut dropped = 0;
+        let mut punched = 0;
+
+        for grain in 0..num_grains {
+            if cancel.is_cancelled() {
+                return Err(VmdkError::Cancelled.into());
+            }
+            let sector = match grain_state(&mut *file, header, grain)? {
+                GrainState::Allocated(sector) => sector,
+                _ => continue,
+            };
+            file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+            file.read_exact(&mut data)?;
+            if !is_zero(&data) {
+                continue;
+            }
+
+            if !self.dirty && header.dirty_shutdown == 0 {
+                set_dirty_shutdown(&mut *file, header, 1)?;
+                self.dirty = true;
+            }
+            set_gte(&mut *file, header, grain, if has_parent { 1 } else { 0 })?;
+            dropped += 1;
+            if self.punch_holes && file.punch_hole(sector * SECTOR_SIZE, grain_bytes)? {
+                punched += 1;
+            }
+        }
+
+        if self.punch_holes && punched < dropped {
+            warn!("Reclaimed the space of {} of {} dropped grains", punched, dropped);
+        }
+        Ok(dropped)
+    }
+
+    /// Flush all data and metadata to stable storage
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        match self.backing.get_mut()? {
+            Backing::Zero | Backing::Other(_) => (),
+            Backing::Flat { file } | Backing::Sparse { file, .. } => {
+                if self.writable {
+                    file.get()?.sync_all()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush and, if this handle marked the extent dirty, clear
+    /// `dirty_shutdown` again
+    pub(crate) fn close(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        if let Backing::Sparse { file, header } = self.backing.get_mut()? {
+            if self.dirty {
+                set_dirty_shutdown(&mut *file.get()?, header, 0)?;
+                self.dirty = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Extent {
+    fn drop(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        warn!("Extent written but not closed, flushing on drop");
+        if let Err(e) = self.close() {
+            warn!("Failed to close extent, left marked dirty: {}", e);
+        }
+    }
+}
+
+/// A view of one extent of a disk, from `Vmdk::extents`
+pub struct ExtentHandle<'a> {
+    extent: &'a Extent,
+}
+
+impl<'a> ExtentHandle<'a> {
+    pub(crate) fn new(extent: &'a Extent) -> Self {
+        ExtentHandle { extent }
+    }
+
+    /// The line of the descriptor describing the extent
+    pub fn descriptor(&self) -> &'a ExtentDescriptor {
+        &self.extent.descriptor
+    }
+
+    pub fn extent_type(&self) -> ExtentType {
+        self.extent.descriptor.extent_type
+    }
+
+    /// The backing file, `None` for ZERO extents
+    pub fn path(&self) -> Option<&'a Path> {
+        self.extent.path.as_deref()
+    }
+
+    /// Sectors of the disk the extent covers
+    pub fn sectors(&self) -> Range<u64> {
+        self.extent.start..self.extent.start + self.extent.descriptor.sectors
+    }
+
+    /// Header of a sparse extent, with `gd_offset` the grain directory
+    /// lookups go through
+    pub fn header(&self) -> Result<Option<&'a ExtentHeader>, Error> {
+        match self.extent.backing.get()? {
+            Backing::Sparse { header, .. } => Ok(Some(header)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Bytes of the extent holding data: all of them for flat extents, the
+    /// allocated grains for sparse ones
+    pub fn allocated_bytes(&self) -> Result<u64, Error> {
+        match self.extent.backing.get()? {
+            Backing::Sparse { header, .. } => {
+                let mut file = self.extent.open_file()?;
+                let grains = grain_table(&mut *file, header)?.into_iter().filter(|&gte| gte > 1).count() as u64;
+                Ok(std::cmp::min(grains * header.grain_size.0 * SECTOR_SIZE, self.extent.size()))
+            }
+            Backing::Flat { .. } => Ok(self.extent.size()),
+            _ => Ok(0),
+        }
+    }
+
+    /// The entries of the grain directory of a sparse extent, the sectors
+    /// of its grain tables or 0 where none is allocated
+    pub fn grain_directory(&self) -> Result<Vec<u32>, Error> {
+        let (header, mut file) = self.sparse_file()?;
+        read_table(&mut *file, header.gd_offset.0, LbaMapper::from(header).num_gts())
+    }
+
+    /// The entries of grain table `index` of a sparse extent, the sectors
+    /// of its grains, 0 for unallocated and 1 for zero grains. `None` if the
+    /// grain directory has no table at `index`.
+    pub fn grain_table(&self, index: u64) -> Result<Option<Vec<u32>>, Error> {
+        let (header, mut file) = self.sparse_file()?;
+        let mapper = LbaMapper::from(header);
+        if index >= mapper.num_gts() {
+            return Err(VmdkError::InvalidArgument(format!("no grain table {}, the extent has {}", index, mapper.num_gts())).into());
+        }
+        let gt = read_u32_at(&*file, header.gd_offset.0 * SECTOR_SIZE + index * 4)?;
+        if gt == 0 {
+            return Ok(None);
+        }
+        Ok(Some(read_table(&mut *file, u64::from(gt), mapper.gtes_per_gt())?))
+    }
+
+    /// The header of a sparse extent and its file, opened anew
+    fn sparse_file(&self) -> Result<(&'a ExtentHeader, Box<dyn Storage>), Error> {
+        match self.extent.backing.get()? {
+            Backing::Sparse { header, .. } => Ok((header, self.extent.open_file()?)),
+            _ => Err(VmdkError::InvalidArgument("not a sparse extent".to_owned()).into()),
+        }
+    }
+
+    /// A reader over the data of the extent with its own file handle, so
+    /// extents can be read in parallel. Grains the extent does not hold
+    /// read as zeros, whatever the parent holds.
+    pub fn reader(&self) -> Result<ExtentReader, Error> {
+        let mut options = VmdkOpenOptions::new();
+        options.allow_devices(true);
+        options.storage = self.extent.provider().cloned();
+        let descriptor = self.extent.descriptor.clone();
+        let extent = Extent::open(descriptor, 0, self.extent.path.clone(), &options)?;
+        Ok(ExtentReader { extent, pos: 0 })
+    }
+}
+
+/// Reads the data of a single extent, from `ExtentHandle::reader`
+pub struct ExtentReader {
+    extent: Extent,
+    pos: u64,
+}
+
+impl ExtentReader {
+    /// Open the sparse extent file at `path` on its own, without the
+    /// descriptor of its disk, such as a `-s001.vmdk` file of a split
+    /// disk. Grains it does not hold read as zeros.
+    pub fn open_sparse<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let header = ExtentHeader::new(&mut file)?;
+        let descriptor = ExtentDescriptor {
+            access: AccessMode::RdOnly,
+            sectors: header.capacity.0,
+            extent_type: ExtentType::Sparse,
+            filename: Some(path.to_string_lossy().into_owned()),
+            offset: 0,
+        };
+        let extent = Extent::from_sparse(descriptor, 0, path.to_owned(), Box::new(file), header, &VmdkOpenOptions::new())?;
+        Ok(ExtentReader { extent, pos: 0 })
+    }
+
+    /// Size of the extent in bytes
+    pub fn size(&self) -> u64 {
+        self.extent.size()
+    }
+
+    /// Header of a sparse extent, with the real grain directory offset of
+    /// a stream-optimized one
+    pub fn header(&self) -> Result<Option<&ExtentHeader>, Error> {
+        ExtentHandle::new(&self.extent).header()
+    }
+}
+
+impl Read for ExtentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.extent.size();
+        if self.pos >= size {
+            return Ok(0);
+        }
+        let n = std::cmp::min(buf.len() as u64, size - self.pos) as usize;
+        self.extent
+            .read_at(self.pos, &mut buf[..n], None)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl ExtentRead for ExtentReader {
+    fn size(&self) -> u64 {
+        self.extent.size()
+    }
+
+    fn grain_size(&self) -> u64 {
+        self.extent.grain_bytes().unwrap_or(DEFAULT_GRAIN_SIZE * SECTOR_SIZE)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.extent.read_at(offset, buf, None)
+    }
+
+    fn allocation(&mut self, offset: u64, len: u64) -> Result<Allocation, Error> {
+        self.extent.allocation(offset, len)
+    }
+}
+
+impl Seek for ExtentReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.extent.size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Overwrite the descriptor embedded in the sparse extent `file` with `text`
+pub(crate) fn write_embedded_descriptor(file: &mut dyn Storage, header: &ExtentHeader, bytes: &[u8]) -> Result<(), Error> {
+    let mut buf = bytes.to_vec();
+    let size = header.desc_size.0 * SECTOR_SIZE;
+    if header.desc_offset.0 == 0 || buf.len() as u64 > size {
+        return Err(VmdkError::NotWritable("descriptor does not fit its embedded area".to_owned()).into());
+    }
+    buf.resize(size.try_into()?, 0);
+    file.seek(SeekFrom::Start(header.desc_offset.0 * SECTOR_SIZE))?;
+    file.write_all(&buf)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Persist the `dirty_shutdown` flag before (when set) or after (when
+/// cleared) every other update
+pub(crate) fn set_dirty_shutdown(file: &mut dyn Storage, header: &mut ExtentHeader, value: u8) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(DIRTY_SHUTDOWN_OFFSET))?;
+    file.write_u8(value)?;
+    file.sync_data()?;
+    header.dirty_shutdown = value;
+    Ok(())
+}
+
+fn open_file(path: &Path, write: bool, force: bool) -> Result<File, Error> {
+    let file = OpenOptions::new().read(true).write(write).open(path)?;
+    if write {
+        lock_file(&file, path, force)?;
+    }
+    Ok(file)
+}
+
+/// Split `[offset, offset + len)` into pieces that do not cross a grain
+/// boundary, as (grain, offset within grain, offset within buffer, length).
+pub(crate) fn grain_chunks(grain_bytes: u64, offset: u64, len: usize) -> impl Iterator<Item = (u64, u64, usize, usize)> {
+    let mut done = 0;
+    std::iter::from_fn(move || {
+        if done >= len {
+            return None;
+        }
+        let pos = offset + done as u64;
+        let within = pos % grain_bytes;
+        let n = std::cmp::min((grain_bytes - within).try_into().unwrap_or(usize::MAX), len - done);
+        let chunk = (pos / grain_bytes, within, done, n);
+        done += n;
+        Some(chunk)
+    })
+}
+
+/// Where `read_sparse` may find grains other than in the extent file, and
+/// what it makes of grains missing from it
+#[derive(Clone, Copy, Default)]
+struct GrainSources<'a> {
+    /// Cache of grains, along with the extent file name
+    cache: Option<(&'a dyn GrainCache, &'a Path)>,
+    /// Memory map of the extent file
+    map: Option<&'a [u8]>,
+    /// How to read grains past the end of the file
+    past_eof: PastEof,
+}
+
+fn read_sparse(
+    file: &mut dyn Storage,
+    header: &ExtentHeader,
+    base: u64,
+    offset: u64,
+    buf: &mut [u8],
+    mut parent: Option<&mut Vmdk>,
+    sources: GrainSources,
+) -> Result<(), Error> {
+    let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+    let compressed = header.flags & FLAG_COMPRESSED != 0;
+    // Compressed grains are inflated, and so cached, all the same
+    let data_map = sources.map.filter(|_| !compressed);
+
+    for (grain, within, start, len) in grain_chunks(grain_bytes, offset, buf.len()) {
+        let chunk = &mut buf[start..start + len];
+        let state = match sources.map {
+            Some(map) => mapped_grain_state(map, header, grain)?,
+            None => grain_state(file, header, grain)?,
+        };
+
+        match (state, data_map, sources.cache) {
+            (GrainState::Allocated(sector), Some(map), _) => {
+                chunk.copy_from_slice(mapped(map, sector * SECTOR_SIZE + within, len as u64)?);
+            }
+            (GrainState::Allocated(sector), None, Some((cache, path))) => {
+                let key = GrainKey { path: path.to_owned(), sector };
+                let data = match cache.get(&key) {
+                    Some(data) => data,
+                    None => {
+                        let data: Arc<[u8]> = read_grain(file, header, sector)?.into();
+                        cache.insert(key, data.clone());
+                        data
+                    }
+                };
+                let within = within as usize;
+                chunk.copy_from_slice(&data[within..within + len]);
+            }
+            (GrainState::Allocated(sector), None, None) if compressed => {
+                let (_, data) = read_compressed_grain(file, sector, grain_bytes)?;
+                let within = within as usize;
+                chunk.copy_from_slice(&data[within..within + len]);
+            }
+            (GrainState::Allocated(sector), None, None) => file.read_exact_at(chunk, sector * SECTOR_SIZE + within)?,
+            (GrainState::Unallocated, _, _) => match parent.as_mut() {
+                Some(parent) => read_parent(parent, base + grain * grain_bytes + within, chunk)?,
+                None => zero(chunk),
+            },
+            (GrainState::Zero, _, _) => zero(chunk),
+            (GrainState::PastEof(sector), _, _) => match sources.past_eof {
+                PastEof::Error => return Err(past_eof(grain, sector)),
+                PastEof::Zero => {
+                    warn!("Grain {} at sector {} lies past the end of the file, reading zeros", grain, sector);
+                    zero(chunk);
+                }
+                PastEof::Partial => {
+                    warn!("Grain {} at sector {} lies past the end of the file, reading what is left", grain, sector);
+                    let pos = sector * SECTOR_SIZE + within;
+                    let held = if compressed { 0 } else { file.size()?.saturating_sub(pos) };
+                    let n = std::cmp::min(held, len as u64) as usize;
+                    file.read_exact_at(&mut chunk[..n], pos)?;
+                    zero(&mut chunk[n..]);
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// The data of the grain at `sector`, decompressed
+fn read_grain(file: &mut dyn Storage, header: &ExtentHeader, sector: u64) -> Result<Vec<u8>, Error> {
+    let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+    if header.flags & FLAG_COMPRESSED != 0 {
+        return Ok(read_compressed_grain(file, sector, grain_bytes)?.1);
+    }
+    let mut data = vec![0u8; grain_bytes.try_into()?];
+    file.read_exact_at(&mut data, sector * SECTOR_SIZE)?;
+    Ok(data)
+}
+
+/// Granularity of allocation of an extent of another format, at least a
+/// sector even if it reports none
+pub(crate) fn other_grain_bytes(reader: &dyn ExtentRead) -> u64 {
+    std::cmp::max(reader.grain_size(), SECTOR_SIZE)
+}
+
+/// Read from an extent of another format, reading the grains it does not
+/// hold from `parent`
+fn read_other(reader: &mut dyn ExtentRead, base: u64, offset: u64, buf: &mut [u8], mut parent: Option<&mut Vmdk>) -> Result<(), Error> {
+    let grain_bytes = other_grain_bytes(reader);
+    for (grain, within, start, len) in grain_chunks(grain_bytes, offset, buf.len()) {
+        let pos = grain * grain_bytes + within;
+        let chunk = &mut buf[start..start + len];
+        match parent.as_mut() {
+            Some(parent) if reader.allocation(pos, len as u64)? == Allocation::Unallocated => read_parent(parent, base + pos, chunk)?,
+            _ => reader.read_at(pos, chunk)?,
+        }
+    }
+    Ok(())
+}
+
+/// Read from the parent, which may be smaller than the child
+fn read_parent(parent: &mut Vmdk, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+    let n = parent.read_at(offset, buf)?;
+    zero(&mut buf[n..]);
+    Ok(())
+}
+
+fn write_sparse(
+    file: &mut dyn Storage,
+    header: &ExtentHeader,
+    base: u64,
+    offset: u64,
+    buf: &[u8],
+    mut parent: Option<&mut Vmdk>,
+) -> Result<(), Error> {
+    let grain_bytes = header.grain_size.0 * SECTOR_SIZE;
+
+    for (grain, within, start, len) in grain_chunks(grain_bytes, offset, buf.len()) {
+        let chunk = &buf[start..start + len];
+
+        let state = grain_state(file, header, grain)?;
+        if let GrainState::PastEof(sector) = state {
+            return Err(past_eof(grain, sector));
+        }
+        if let GrainState::Allocated(sector) = state {
+            file.write_all_at(chunk, sector * SECTOR_SIZE + within)?;
+            continue;
+        }
+
+        // Copy the parts of the grain before and after this write up from
+        // the parent so they keep their contents. Zero grains stay zeros.
+        let mut data = vec![0u8; grain_bytes.try_into()?];
+        let within = within as usize;
+        let end = within + len;
+        if let (GrainState::Unallocated, Some(parent)) = (&state, parent.as_mut()) {
+            let grain_start = base + grain * grain_bytes;
+            if within > 0 {
+                read_parent(parent, grain_start, &mut data[..within])?;
+            }
+            if end < data.len() {
+                read_parent(parent, grain_start + end as u64, &mut data[end..])?;
+            }
+        }
+        data[within..end].copy_from_slice(chunk);
+
+        // The grain must be on disk before any table points at it
+        let sector = append(file, &data)?;
+        file.sync_data()?;
+        set_gte(file, header, grain, sector)?;
+    }
+
+    Ok(())
+}
+
+/// Append `data` at the first sector boundary past the end of the file
+pub(crate) fn append(file: &mut dyn Storage, data: &[u8]) -> Result<u64, Error> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let sector = len.div_ceil(SECTOR_SIZE);
+    file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    file.write_all(data)?;
+    Ok(sector)
+}
+
+/// Point `grain` at `sector` in the grain table and then its redundant copy,
+/// allocating grain tables as needed. New grain tables are zeroed on disk
+/// before the directory references them.
+pub(crate) fn set_gte(file: &mut dyn Storage, header: &ExtentHeader, grain: u64, sector: u64) -> Result<(), Error> {
+    let mapper = LbaMapper::from(header);
+    let sector: u32 = sector
+        .try_into()
+        .map_err(|_| VmdkError::NotWritable("extent too large for 32-bit grain offsets".to_owned()))?;
+
+    let redundant = if header.has_redundant_gd() { header.rgd_offset.0 } else { 0 };
+    for gd_offset in &[header.gd_offset.0, redundant] {
+        if *gd_offset == 0 {
+            continue;
+        }
+        let gd_entry = mapper.gd_entry(*gd_offset, grain);
+        file.seek(SeekFrom::Start(gd_entry))?;
+        let mut gt = u64::from(file.read_u32::<LittleEndian>()?);
+        if gt == 0 {
+            let gt_bytes = mapper.gt_sectors() * SECTOR_SIZE;
+            gt = append(file, &vec![0u8; gt_bytes.try_into()?])?;
+            file.sync_data()?;
+            file.seek(SeekFrom::Start(gd_entry))?;
+            file.write_u32::<LittleEndian>(gt.try_into()?)?;
+        }
+
+        file.seek(SeekFrom::Start(mapper.gt_entry(gt, grain)))?;
+        file.write_u32::<LittleEndian>(sector)?;
+    }
+
+    Ok(())
+}
+
+/// Stream-optimized extents may only know where their grain directory is
+/// once fully written, in which case the footer two sectors before the end
+/// of the file holds the real header.
+fn resolve_footer(file: &mut dyn Storage, header: ExtentHeader) -> Result<ExtentHeader, Error> {
+    if header.flags & FLAG_COMPRESSED != 0 && header.compress_method != COMPRESSION_DEFLATE {
+        return Err(VmdkError::UnsupportedCompression(header.compress_method).into());
+    }
+    if header.gd_offset.0 != GD_AT_END {
+        return Ok(header);
+    }
+
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 3 * SECTOR_SIZE {
+        return Err(VmdkError::ParseError.into());
+    }
+    file.seek(SeekFrom::Start(len - 2 * SECTOR_SIZE))?;
+    let footer = ExtentHeader::new(&mut *file).map_err(|e| diagnostics::rebase(e, len - 2 * SECTOR_SIZE))?;
+    info!("Using footer, GD offset: 0x{:x}", footer.gd_offset.0);
+    if footer.gd_offset.0 == GD_AT_END {
+        return Err(VmdkError::ParseError.into());
+    }
+
+    Ok(footer)
+}
+
+/// The header grains are looked up through: its grain directory in
+/// `gd_offset` is the redundant one if `redundant` asks for it, or if the
+/// primary one is missing and the header flags keep the redundant one up to
+/// date
+fn select_gd(mut header: ExtentHeader, redundant: bool) -> Result<ExtentHeader, Error> {
+    if redundant || (header.gd_offset.0 == 0 && header.has_redundant_gd()) {
+        if !header.has_redundant_gd() {
+            return Err(VmdkError::InvalidArgument("extent has no redundant grain directory".to_owned()).into());
+        }
+        info!("Using the redundant grain directory at sector {}", header.rgd_offset.0);
+        std::mem::swap(&mut header.gd_offset, &mut header.rgd_offset);
+    }
+    Ok(header)
+}
+
+/// Read `count` little-endian entries starting at `sector`
+pub(crate) fn read_table(file: &mut dyn Storage, sector: u64, count: u64) -> Result<Vec<u32>, Error> {
+    let mut buf = vec![0u8; (count * 4).try_into()?];
+    file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    file.read_exact(&mut buf)?;
+    let mut table = vec![0u32; count.try_into()?];
+    LittleEndian::read_u32_into(&buf, &mut table);
+    Ok(table)
+}
+
+/// The grain table entries of every grain of the extent, 0 where no
+/// grain table is allocated
+pub(crate) fn grain_table(file: &mut dyn Storage, header: &ExtentHeader) -> Result<Vec<u32>, Error> {
+    let mapper = LbaMapper::from(header);
+    let gtes_per_gt = mapper.gtes_per_gt();
+    let num_grains = mapper.num_grains();
+    let gd = read_table(file, header.gd_offset.0, mapper.num_gts())?;
+    let mut gtes = Vec::with_capacity(num_grains.try_into()?);
+    for gt in gd {
+        match gt {
+            0 => gtes.resize(gtes.len() + gtes_per_gt as usize, 0),
+            gt => gtes.extend(read_table(file, u64::from(gt), gtes_per_gt)?),
+        }
+    }
+    gtes.truncate(num_grains.try_into()?);
+    Ok(gtes)
+}
+
+/// `len` bytes at `pos` of a mapped file
+fn mapped(map: &[u8], pos: u64, len: u64) -> Result<&[u8], Error> {
+    let end = pos.checked_add(len).filter(|&end| end <= map.len() as u64).ok_or(VmdkError::ParseError)?;
+    Ok(&map[pos.try_into()?..end.try_into()?])
+}
+
+/// Look up where `grain` is stored in a mapped extent file
+fn mapped_grain_state(map: &[u8], header: &ExtentHeader, grain: u64) -> Result<GrainState, Error> {
+    let mapper = LbaMapper::from(header);
+    let gt = LittleEndian::read_u32(mapped(map, mapper.gd_entry(header.gd_offset.0, grain), 4)?);
+    if gt == 0 {
+        return Ok(GrainState::Unallocated);
+    }
+
+    let gt_entry = mapper.gt_entry(u64::from(gt), grain);
+    match LittleEndian::read_u32(mapped(map, gt_entry, 4)?) {
+        0 => Ok(GrainState::Unallocated),
+        1 => Ok(GrainState::Zero),
+        sector => allocated_grain(header, grain, u64::from(gt), u64::from(sector), map.len() as u64 / SECTOR_SIZE),
+    }
+}
+
+/// Look up where `grain` is stored
+fn grain_state(file: &mut dyn Storage, header: &ExtentHeader, grain: u64) -> Result<GrainState, Error> {
+    let mapper = LbaMapper::from(header);
+    let gt = read_u32_at(file, mapper.gd_entry(header.gd_offset.0, grain))?;
+    if gt == 0 {
+        return Ok(GrainState::Unallocated);
+    }
+
+    let gte = read_u32_at(file, mapper.gt_entry(u64::from(gt), grain))?;
+    match gte {
+        0 => Ok(GrainState::Unallocated),
+        1 => Ok(GrainState::Zero),
+        sector => allocated_grain(header, grain, u64::from(gt), u64::from(sector), file.size()? / SECTOR_SIZE),
+    }
+}
+
+/// State of `grain`, stored at `sector` according to the grain table at
+/// sector `gt`, in a file of `file_sectors` sectors. Fails with
+/// `VmdkError::CorruptGrain` if the grain overlaps the header, descriptor,
+/// grain directories or that table. Overlaps with other grain tables are
+/// left to `Vmdk::check`, which reads them all.
+fn allocated_grain(header: &ExtentHeader, grain: u64, gt: u64, sector: u64, file_sectors: u64) -> Result<GrainState, Error> {
+    let mapper = LbaMapper::from(header);
+    // Compressed grains are smaller than a grain, and their size is only
+    // known once read
+    let end = if header.flags & FLAG_COMPRESSED != 0 { sector + 1 } else { sector + header.grain_size.0 };
+    let gd_sectors = mapper.gd_sectors();
+    let rgd = if header.has_redundant_gd() { header.rgd_offset.0 } else { 0 };
+    // Checked on every lookup, so kept off the heap
+    let mut regions = [
+        (0, 1, "header"),
+        (header.desc_offset.0, header.desc_offset.0 + header.desc_size.0, "descriptor"),
+        (header.gd_offset.0, header.gd_offset.0 + gd_sectors, "grain directory"),
+        (rgd, if rgd != 0 { rgd + gd_sectors } else { 0 }, "redundant grain directory"),
+        (gt, gt + mapper.gt_sectors(), "grain table"),
+    ];
+    regions.sort_unstable();
+    match regions.iter().find(|&&(start, stop, _)| start < stop && start < end && sector < stop) {
+        Some((_, _, name)) => Err(VmdkError::CorruptGrain { grain, sector, problem: format!("overlapping the {}", name) }.into()),
+        None if end > file_sectors => Ok(GrainState::PastEof(sector)),
+        None => Ok(GrainState::Allocated(sector)),
+    }
+}
+
+fn past_eof(grain: u64, sector: u64) -> Error {
+    VmdkError::CorruptGrain { grain, sector, problem: "past the end of the file".to_owned() }.into()
+}
+
+/// Whether `buf` holds only zeros. Compares 16 bytes at a time, which the
+/// compiler turns into SIMD compares.
+pub(crate) fn is_zero(buf: &[u8]) -> bool {
+    let mut chunks = buf.chunks_exact(16);
+    let wide = chunks
+        .by_ref()
+        .fold(0u128, |acc, c| acc | u128::from_ne_bytes(c.try_into().unwrap()));
+    wide == 0 && chunks.remainder().iter().all(|&b| b == 0)
+}
+
+fn zero(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+}
+
+/// Whether `path` refers to a block or character device
+#[cfg(unix)]
+pub(crate) fn is_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::metadata(path) {
+        Ok(meta) => meta.file_type().is_block_device() || meta.file_type().is_char_device(),
+        Err(_) => false,
+    }
+}
+
+/// Whether `path` refers to a physical drive or partition
+#[cfg(not(unix))]
+pub(crate) fn is_device(path: &Path) -> bool {
+    path.to_string_lossy().starts_with(r"\\.\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+
+    proptest! {
+        #[test]
+        fn test_grain_chunks(grain_bytes in 1u64..(1 << 32), offset in 0u64..(62 << 40), len in 0usize..(1 << 24)) {
+            let mut expected = 0;
+            for (grain, within, start, n) in grain_chunks(grain_bytes, offset, len) {
+                prop_assert_eq!(start, expected);
+                prop_assert!(n > 0 && within + n as u64 <= grain_bytes);
+                prop_assert_eq!(grain * grain_bytes + within, offset + start as u64);
+                expected += n;
+            }
+            prop_assert_eq!(expected, len);
+        }
+    }
+
+    #[test]
+    fn test_raw_tables() {
+        let dir = scratch_dir("raw-tables");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024 * 8, 8).monolithic("disk.vmdk").grain(1000, 0xb1).grain(3, 0xb2);
+        image.gtes_per_gt = 4;
+        std::fs::write(&path, image.build()).unwrap();
+
+        let vmdk = Vmdk::new(&path).unwrap();
+        let extent = vmdk.extents().next().unwrap();
+        let gd = extent.grain_directory().unwrap();
+        assert_eq!(gd.len(), 256);
+        assert!(gd.iter().all(|&gt| gt != 0));
+        let table = extent.grain_table(250).unwrap().unwrap();
+        assert_eq!(table.len(), 4);
+        assert!(table[0] > 1 && table[1..] == [0, 0, 0]);
+        // Grains are laid out in the order they were added
+        assert_eq!(extent.grain_table(0).unwrap().unwrap()[3], table[0] + 8);
+        assert!(extent.grain_table(256).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_grain() {
+        let dir = scratch_dir("corrupt-grain");
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        let gt = LittleEndian::read_u32(&image[header.gd_offset.bytes() as usize..]) as u64;
+        let problem = |gte: u32| {
+            let mut broken = image.clone();
+            let entry = (gt * SECTOR_SIZE + 4) as usize;
+            broken[entry..entry + 4].copy_from_slice(&gte.to_le_bytes());
+            std::fs::write(&path, &broken).unwrap();
+            let err = Vmdk::new(&path).unwrap().read_at(128 * 512, &mut [0u8; 512]).err().unwrap();
+            match err.downcast::<VmdkError>() {
+                Ok(VmdkError::CorruptGrain { grain: 1, sector, problem }) if sector == u64::from(gte) => problem,
+                other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+            }
+        };
+
+        // A grain starting within the table pointing to it
+        assert_eq!(problem(gt as u32 + 1), "overlapping the grain table");
+        // The first region overlapped is reported
+        assert_eq!(problem(header.rgd_offset.0 as u32), "overlapping the redundant grain directory");
+        assert_eq!(problem((image.len() / 512) as u32 - 100), "past the end of the file");
+        // Neighbouring grains still read
+        let mut buf = [0xffu8; 512];
+        Vmdk::new(&path).unwrap().read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [0; 512]);
+    }
+
+    #[test]
+    fn test_past_eof() {
+        let dir = scratch_dir("past-eof");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).grain(2, 0xb2).build();
+        // Keep 100 sectors of the last grain
+        image.truncate(image.len() - 28 * 512);
+        std::fs::write(&path, &image).unwrap();
+        let read = |policy| {
+            let mut buf = vec![0xffu8; 2 * 128 * 512];
+            VmdkOpenOptions::new().past_eof(policy).open(&path)?.read_at(128 * 512, &mut buf)?;
+            Ok::<_, Error>(buf)
+        };
+
+        match read(PastEof::Error).err().unwrap().downcast::<VmdkError>() {
+            Ok(VmdkError::CorruptGrain { grain: 2, problem, .. }) => assert_eq!(problem, "past the end of the file"),
+            other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+        }
+        let zeroed = read(PastEof::Zero).unwrap();
+        assert!(zeroed[..128 * 512].iter().all(|&b| b == 0xb1) && is_zero(&zeroed[128 * 512..]));
+        let partial = read(PastEof::Partial).unwrap();
+        assert!(partial[128 * 512..228 * 512].iter().all(|&b| b == 0xb2) && is_zero(&partial[228 * 512..]));
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).past_eof(PastEof::Zero).open(&path).unwrap();
+        assert!(vmdk.write_at(2 * 128 * 512, &[1]).is_err());
+        vmdk.write_at(3 * 128 * 512, &[1]).unwrap();
+    }
+
+    #[test]
+    fn test_partial_grain_writes() {
+        let dir = scratch_dir("partial-grain-writes");
+        let g = 128 * 512;
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(0, 0xb0).grain(1, 0xb1).grain(2, 0xb2).grain(3, 0xb3);
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let child = SparseImage::new(1024, 128).child("base.vmdk", 0x12345678).grain(3, 0xc3).build();
+        let mut before = vec![0u8; 8 * g];
+        for (grain, byte) in [(0, 0xb0), (1, 0xb1), (2, 0xb2), (3, 0xc3)] {
+            before[grain * g..(grain + 1) * g].iter_mut().for_each(|b| *b = byte);
+        }
+
+        let cases = [
+            (0, 1),
+            (g - 1, 1),
+            (g, 1),
+            (2 * g - 1, 1),
+            (g - 1, 2),
+            (g + 512, 512),
+            (g + 100, 1000),
+            (g, g),
+            (g - 1, g + 2),
+            (3 * g - 1, 2),
+            (4 * g - 1, 2),
+            (5 * g - 3, 7),
+            (8 * g - 1, 1),
+        ];
+        for &(offset, len) in &cases {
+            let path = dir.join("child.vmdk");
+            std::fs::write(&path, &child).unwrap();
+            let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+            assert_eq!(vmdk.write_at(offset as u64, &vec![0x5a; len]).unwrap(), len);
+            drop(vmdk);
+
+            let mut expected = before.clone();
+            expected[offset..offset + len].iter_mut().for_each(|b| *b = 0x5a);
+            let mut vmdk = Vmdk::new(&path).unwrap();
+            let mut buf = vec![0xffu8; 8 * g];
+            vmdk.read_at(0, &mut buf).unwrap();
+            assert!(buf == expected, "write of {} bytes at {}", len, offset);
+
+            // Every grain written holds all of its data without the parent
+            vmdk.parent = None;
+            vmdk.read_at(0, &mut buf).unwrap();
+            let touched = offset / g * g..(offset + len).div_ceil(g) * g;
+            assert!(buf[touched.clone()] == expected[touched], "write of {} bytes at {}", len, offset);
+        }
+
+        // Partial writes to zero grains keep the rest of the grain zero
+        let mut zeroed = SparseImage::new(1024, 128).child("base.vmdk", 0x12345678).build();
+        let header = ExtentHeader::new(&zeroed[..]).unwrap();
+        let gt = LittleEndian::read_u32(&zeroed[header.gd_offset.bytes() as usize..]) as u64;
+        let entry = (gt * SECTOR_SIZE + 4) as usize;
+        zeroed[entry..entry + 4].copy_from_slice(&1u32.to_le_bytes());
+        let path = dir.join("child.vmdk");
+        std::fs::write(&path, &zeroed).unwrap();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        vmdk.write_at(g as u64 + 10, &[0x5a; 10]).unwrap();
+        let mut buf = vec![0xffu8; g];
+        vmdk.read_at(g as u64, &mut buf).unwrap();
+        assert!(is_zero(&buf[..10]) && buf[10..20] == [0x5a; 10] && is_zero(&buf[20..]));
+    }
+}
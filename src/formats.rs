@@ -0,0 +1,508 @@
+//! Reading extents of any format through one interface.
+//!
+//! `ExtentRead` is implemented by `ExtentReader` for the extents of hosted
+//! disks (sparse, stream-optimized, flat and zero) and here for the sparse
+//! formats of ESX: `CowdExtent` for the `COWD` extents of ESX 2, listed as
+//! `VMFSSPARSE` in descriptors, and `SeSparseExtent` for the space-efficient
+//! sparse extents of ESXi 6.5 and later, listed as `SESPARSE`. Disks read
+//! extents of the ESX formats through it, and code reading extents through
+//! it can be handed formats of its own.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::path::Path;
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Error;
+use log::info;
+
+use crate::extent::grain_chunks;
+use crate::storage::Storage;
+use crate::{VmdkError, SECTOR_SIZE};
+
+pub use crate::extent::Allocation;
+
+/// Read access to the data of one extent, whatever its format
+pub trait ExtentRead: Send + Sync {
+    /// Size of the extent in bytes
+    fn size(&self) -> u64;
+
+    /// Granularity of allocation in bytes; `allocation` is the same for all
+    /// bytes of an aligned range of this size
+    fn grain_size(&self) -> u64;
+
+    /// Fill `buf` with the data at byte `offset` within the extent. The
+    /// caller guarantees that the range lies inside the extent. Data the
+    /// extent does not hold reads as zeros.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// What backs `len` bytes at byte `offset` within the extent
+    fn allocation(&mut self, offset: u64, len: u64) -> Result<Allocation, Error>;
+}
+
+/// Where the data of a grain of a COWD or SESparse extent lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Grain {
+    Unallocated,
+    Zero,
+    /// Stored at the given sector
+    Data(u64),
+}
+
+/// Extents whose grains are found through a table
+pub(crate) trait GrainLookup {
+    fn file(&self) -> &dyn Storage;
+    fn grain_bytes(&self) -> u64;
+    fn lookup(&mut self, grain: u64) -> Result<Grain, Error>;
+}
+
+pub(crate) fn read_grains<T: GrainLookup>(extent: &mut T, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+    for (grain, within, start, len) in grain_chunks(extent.grain_bytes(), offset, buf.len()) {
+        let chunk = &mut buf[start..start + len];
+        match extent.lookup(grain)? {
+            Grain::Data(sector) => extent.file().read_exact_at(chunk, sector * SECTOR_SIZE + within)?,
+            Grain::Unallocated | Grain::Zero => chunk.iter_mut().for_each(|b| *b = 0),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn grains_allocation<T: GrainLookup>(extent: &mut T, offset: u64, len: u64) -> Result<Allocation, Error> {
+    let mut result = Allocation::Zero;
+    for (grain, _, _, _) in grain_chunks(extent.grain_bytes(), offset, usize::try_from(len)?) {
+        match extent.lookup(grain)? {
+            Grain::Data(_) => return Ok(Allocation::Data),
+            Grain::Unallocated => result = Allocation::Unallocated,
+            Grain::Zero => (),
+        }
+    }
+    Ok(result)
+}
+
+/// Read `entries` entries of `width` bytes at byte `offset`, refusing
+/// tables that do not lie within the file
+pub(crate) fn read_table_bytes(file: &dyn Storage, offset: u64, entries: u64, width: u64) -> Result<Vec<u8>, Error> {
+    let len = entries.checked_mul(width).filter(|&len| offset.checked_add(len).is_some());
+    let len = match len {
+        Some(len) if offset + len <= file.size()? => len,
+        _ => return Err(VmdkError::ParseError.into()),
+    };
+    let mut bytes = vec![0u8; usize::try_from(len)?];
+    file.read_exact_at(&mut bytes, offset)?;
+    Ok(bytes)
+}
+
+/// Read the table of `entries` little-endian `u64`s at byte `offset`
+fn read_u64_table(file: &dyn Storage, offset: u64, entries: u64) -> Result<Vec<u64>, Error> {
+    let bytes = read_table_bytes(file, offset, entries, 8)?;
+    let mut table = vec![0u64; bytes.len() / 8];
+    LittleEndian::read_u64_into(&bytes, &mut table);
+    Ok(table)
+}
+
+/// Read the table of `entries` little-endian `u32`s at byte `offset`
+fn read_u32_table(file: &dyn Storage, offset: u64, entries: u64) -> Result<Vec<u32>, Error> {
+    let bytes = read_table_bytes(file, offset, entries, 4)?;
+    let mut table = vec![0u32; bytes.len() / 4];
+    LittleEndian::read_u32_into(&bytes, &mut table);
+    Ok(table)
+}
+
+/// Magic at the start of a COWD extent
+const COWD_MAGIC: &[u8] = b"COWD";
+/// Entries of each grain table of a COWD extent
+const COWD_GTES_PER_GT: u64 = 4096;
+
+/// An ESX 2 `COWD` sparse extent, such as the delta of a `vmfsSparse` disk
+pub struct CowdExtent {
+    file: Box<dyn Storage>,
+    /// Capacity in sectors
+    capacity: u64,
+    /// Sectors per grain
+    granularity: u64,
+    /// Sectors of the grain tables, 0 where none is allocated
+    directory: Vec<u32>,
+    /// The grain table read last, by index
+    table: Option<(u64, Vec<u32>)>,
+}
+
+impl CowdExtent {
+    /// Open the COWD extent file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        info!("Opening COWD extent {}", path.as_ref().display());
+        CowdExtent::new(Box::new(File::open(path)?))
+    }
+
+    /// Read the COWD extent stored in `file`
+    pub fn new(file: Box<dyn Storage>) -> Result<Self, Error> {
+        let mut header = [0u8; 28];
+        file.read_exact_at(&mut header, 0)?;
+        if &header[..4] != COWD_MAGIC {
+            return Err(VmdkError::ParseError.into());
+        }
+        let capacity = u64::from(LittleEndian::read_u32(&header[12..]));
+        let granularity = u64::from(LittleEndian::read_u32(&header[16..]));
+        let gd_offset = u64::from(LittleEndian::read_u32(&header[20..]));
+        let gd_entries = u64::from(LittleEndian::read_u32(&header[24..]));
+        if granularity == 0 {
+            return Err(VmdkError::ParseError.into());
+        }
+        let num_gts = capacity.div_ceil(granularity * COWD_GTES_PER_GT);
+        if gd_entries < num_gts {
+            return Err(VmdkError::ParseError.into());
+        }
+        let directory = read_u32_table(&*file, gd_offset * SECTOR_SIZE, num_gts)?;
+        Ok(CowdExtent { file, capacity, granularity, directory, table: None })
+    }
+}
+
+impl GrainLookup for CowdExtent {
+    fn file(&self) -> &dyn Storage {
+        &*self.file
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.granularity * SECTOR_SIZE
+    }
+
+    fn lookup(&mut self, grain: u64) -> Result<Grain, Error> {
+        let index = grain / COWD_GTES_PER_GT;
+        let gt = match self.directory.get(index as usize) {
+            Some(&0) | None => return Ok(Grain::Unallocated),
+            Some(&gt) => u64::from(gt),
+        };
+        if self.table.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            self.table = Some((index, read_u32_table(&*self.file, gt * SECTOR_SIZE, COWD_GTES_PER_GT)?));
+        }
+        let table = &self.table.as_ref().expect("table read").1;
+        match table[(grain % COWD_GTES_PER_GT) as usize] {
+            0 => Ok(Grain::Unallocated),
+            sector => Ok(Grain::Data(u64::from(sector))),
+        }
+    }
+}
+
+impl ExtentRead for CowdExtent {
+    fn size(&self) -> u64 {
+        self.capacity * SECTOR_SIZE
+    }
+
+    fn grain_size(&self) -> u64 {
+        self.grain_bytes()
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        read_grains(self, offset, buf)
+    }
+
+    fn allocation(&mut self, offset: u64, len: u64) -> Result<Allocation, Error> {
+        grains_allocation(self, offset, len)
+    }
+}
+
+/// Constant at the start of the header of a SESparse extent
+const SESPARSE_MAGIC: u64 = 0x0000_0000_cafe_babe;
+/// The only version of SESparse extents there is
+const SESPARSE_VERSION: u64 = 0x0000_0002_0000_0001;
+/// Constant at the start of the volatile header of a SESparse extent
+const SESPARSE_VOLATILE_MAGIC: u64 = 0x0000_0000_cafe_cafe;
+/// Marker in the high bits of allocated grain directory entries
+const SESPARSE_GD_ALLOCATED: u64 = 0x1000_0000;
+
+/// An ESXi space-efficient sparse (`SESparse`) extent, such as the delta of
+/// a snapshot of a disk larger than 2 TB
+pub struct SeSparseExtent {
+    file: Box<dyn Storage>,
+    /// Capacity in sectors
+    capacity: u64,
+    /// Sectors per grain
+    grain_sectors: u64,
+    /// Entries of each grain table
+    gtes_per_gt: u64,
+    /// Byte offset of the first grain table
+    tables_offset: u64,
+    /// Sector of the first grain
+    grains_offset: u64,
+    /// Length of the file in sectors, which grains must lie within
+    file_sectors: u64,
+    directory: Vec<u64>,
+    /// The grain table read last, by index
+    table: Option<(u64, Vec<u64>)>,
+}
+
+impl SeSparseExtent {
+    /// Open the SESparse extent file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        info!("Opening SESparse extent {}", path.as_ref().display());
+        SeSparseExtent::new(Box::new(File::open(path)?))
+    }
+
+    /// Read the SESparse extent stored in `file`. Extents whose journal
+    /// must be replayed first, as after a crash of the host, are refused.
+    pub fn new(file: Box<dyn Storage>) -> Result<Self, Error> {
+        let mut bytes = [0u8; 208];
+        file.read_exact_at(&mut bytes, 0)?;
+        let mut header = [0u64; 26];
+        LittleEndian::read_u64_into(&bytes, &mut header);
+        if header[0] != SESPARSE_MAGIC {
+            return Err(VmdkError::ParseError.into());
+        }
+        if header[1] != SESPARSE_VERSION {
+            return Err(VmdkError::UnsupportedFormat(format!("SESparse version {:#x}", header[1])).into());
+        }
+        let (capacity, grain_sectors, gt_sectors, flags) = (header[2], header[3], header[4], header[5]);
+        if flags != 0 {
+            return Err(VmdkError::UnsupportedFormat(format!("SESparse flags {:#x}", flags)).into());
+        }
+        // Every field is checked against the file, so crafted ones cannot
+        // overflow offsets or size tables past it
+        let file_size = file.size()?;
+        let bytes = |sectors: u64| sectors.checked_mul(SECTOR_SIZE).filter(|&bytes| bytes < file_size).ok_or(VmdkError::ParseError);
+        if grain_sectors == 0 || gt_sectors == 0 || capacity.checked_mul(SECTOR_SIZE).is_none() {
+            return Err(VmdkError::ParseError.into());
+        }
+        bytes(grain_sectors)?;
+
+        let mut volatile = [0u8; 32];
+        file.read_exact_at(&mut volatile, bytes(header[10])?)?;
+        if LittleEndian::read_u64(&volatile) != SESPARSE_VOLATILE_MAGIC {
+            return Err(VmdkError::ParseError.into());
+        }
+        if LittleEndian::read_u64(&volatile[24..]) != 0 {
+            return Err(VmdkError::UnsupportedFormat("SESparse extent whose journal needs replaying, open it on ESXi first".to_owned()).into());
+        }
+
+        let gtes_per_gt = bytes(gt_sectors)? / 8;
+        let num_gts = capacity.div_ceil(grain_sectors.checked_mul(gtes_per_gt).ok_or(VmdkError::ParseError)?);
+        let (gd_offset, gd_sectors) = (header[16], header[17]);
+        if gd_sectors.saturating_mul(SECTOR_SIZE / 8) < num_gts {
+            return Err(VmdkError::ParseError.into());
+        }
+        let directory = read_u64_table(&*file, bytes(gd_offset)?, num_gts)?;
+        Ok(SeSparseExtent {
+            file,
+            capacity,
+            grain_sectors,
+            gtes_per_gt,
+            tables_offset: bytes(header[18])?,
+            grains_offset: header[24],
+            file_sectors: file_size / SECTOR_SIZE,
+            directory,
+            table: None,
+        })
+    }
+}
+
+impl GrainLookup for SeSparseExtent {
+    fn file(&self) -> &dyn Storage {
+        &*self.file
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.grain_sectors * SECTOR_SIZE
+    }
+
+    fn lookup(&mut self, grain: u64) -> Result<Grain, Error> {
+        let index = grain / self.gtes_per_gt;
+        let gt = match self.directory.get(index as usize) {
+            Some(&0) | None => return Ok(Grain::Unallocated),
+            Some(&gde) if gde >> 32 == SESPARSE_GD_ALLOCATED => gde & 0xffff_ffff,
+            Some(_) => return Err(VmdkError::ParseError.into()),
+        };
+        if self.table.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            let offset = gt.checked_mul(self.gtes_per_gt * 8).and_then(|o| o.checked_add(self.tables_offset));
+            let offset = offset.ok_or(VmdkError::ParseError)?;
+            self.table = Some((index, read_u64_table(&*self.file, offset, self.gtes_per_gt)?));
+        }
+        let gte = self.table.as_ref().expect("table read").1[(grain % self.gtes_per_gt) as usize];
+        match gte >> 60 {
+            0 if gte == 0 => Ok(Grain::Unallocated),
+            // Unmapped by the guest, or written with zeros
+            1 | 2 => Ok(Grain::Zero),
+            3 => {
+                let cluster = ((gte & 0x0fff_0000_0000_0000) >> 48) | ((gte & 0x0000_ffff_ffff_ffff) << 12);
+                let sector = cluster.checked_mul(self.grain_sectors).and_then(|c| c.checked_add(self.grains_offset));
+                match sector {
+                    Some(sector) if sector < self.file_sectors => Ok(Grain::Data(sector)),
+                    _ => {
+                        let sector = self.grains_offset.saturating_add(cluster.saturating_mul(self.grain_sectors));
+                        let problem = "past the end of the file".to_owned();
+                        Err(VmdkError::CorruptGrain { grain, sector, problem }.into())
+                    }
+                }
+            }
+            _ => Err(VmdkError::CorruptGrain { grain, sector: 0, problem: format!("unknown grain table entry {:#x}", gte) }.into()),
+        }
+    }
+}
+
+impl ExtentRead for SeSparseExtent {
+    fn size(&self) -> u64 {
+        self.capacity * SECTOR_SIZE
+    }
+
+    fn grain_size(&self) -> u64 {
+        self.grain_bytes()
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        read_grains(self, offset, buf)
+    }
+
+    fn allocation(&mut self, offset: u64, len: u64) -> Result<Allocation, Error> {
+        grains_allocation(self, offset, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryFiles;
+    use crate::storage::StorageProvider;
+    use crate::testutil::{scratch_dir, SparseImage};
+    use crate::{open_any, AnyImage, Vmdk, VmdkOpenOptions};
+
+    /// A COWD extent of 8192 sectors with grains of 16 sectors, grain 1
+    /// holding `0xc1` and all others unallocated
+    fn cowd_image() -> Vec<u8> {
+        let mut image = vec![0u8; 50 * 512];
+        image[..4].copy_from_slice(COWD_MAGIC);
+        for (i, value) in [1u32, 3, 8192, 16, 1, 1].iter().enumerate() {
+            LittleEndian::write_u32(&mut image[4 + i * 4..], *value);
+        }
+        // Grain directory at sector 1, grain table at 2..34, grain at 34
+        LittleEndian::write_u32(&mut image[512..], 2);
+        LittleEndian::write_u32(&mut image[1024 + 4..], 34);
+        image[34 * 512..50 * 512].fill(0xc1);
+        image
+    }
+
+    /// A SESparse extent of 8192 sectors with grains of 8 sectors: grain 0
+    /// holds `0xd0`, grain 1 `0xd1` stored in cluster 4097, grain 2 is a
+    /// zero grain, and the others are unallocated
+    fn sesparse_image() -> Vec<u8> {
+        let mut header = [0u64; 26];
+        header[..5].copy_from_slice(&[SESPARSE_MAGIC, SESPARSE_VERSION, 8192, 8, 64]);
+        // Volatile header at 1, grain directory at 2, tables at 3..67,
+        // grains from 67
+        header[10] = 1;
+        header[16..20].copy_from_slice(&[2, 1, 3, 64]);
+        header[24] = 67;
+        let mut image = vec![0u8; (67 + 4098 * 8) * 512];
+        LittleEndian::write_u64_into(&header, &mut image[..208]);
+        LittleEndian::write_u64(&mut image[512..], SESPARSE_VOLATILE_MAGIC);
+        LittleEndian::write_u64(&mut image[1024..], SESPARSE_GD_ALLOCATED << 32);
+        let allocated = |cluster: u64| (3 << 60) | ((cluster & 0xfff) << 48) | (cluster >> 12);
+        let gtes = [allocated(0), allocated(4097), 2 << 60];
+        LittleEndian::write_u64_into(&gtes, &mut image[3 * 512..3 * 512 + 24]);
+        image[67 * 512..75 * 512].fill(0xd0);
+        image[(67 + 4097 * 8) * 512..].fill(0xd1);
+        image
+    }
+
+    #[test]
+    fn test_cowd_extent() {
+        let dir = scratch_dir("formats-cowd");
+        std::fs::write(dir.join("delta.cowd"), cowd_image()).unwrap();
+        let mut extent = CowdExtent::open(dir.join("delta.cowd")).unwrap();
+        assert_eq!(extent.size(), 8192 * 512);
+        assert_eq!(extent.grain_size(), 16 * 512);
+        let mut buf = [0u8; 8];
+        extent.read_at(16 * 512 - 4, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0xc1, 0xc1, 0xc1, 0xc1]);
+        assert_eq!(extent.allocation(0, 16 * 512).unwrap(), Allocation::Unallocated);
+        assert_eq!(extent.allocation(0, 17 * 512).unwrap(), Allocation::Data);
+
+        // As the delta of a disk, unallocated grains fall through
+        let base = SparseImage::new(8192, 128).monolithic("base.vmdk").grain(0, 0xb0).build();
+        std::fs::write(dir.join("base.vmdk"), base).unwrap();
+        std::fs::write(dir.join("delta.vmdk"), "# Disk DescriptorFile\nversion=1\nCID=fffffffe\nparentCID=12345678\n\
+            createType=\"vmfsSparse\"\nparentFileNameHint=\"base.vmdk\"\nRW 8192 VMFSSPARSE \"delta.cowd\"\n").unwrap();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(dir.join("delta.vmdk")).unwrap();
+        let mut buf = vec![0u8; 32 * 512];
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert!(buf[..16 * 512].iter().all(|&b| b == 0xb0));
+        assert!(buf[16 * 512..].iter().all(|&b| b == 0xc1));
+        let err = vmdk.write_at(0, &[1]).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(VmdkError::NotWritable(_))));
+    }
+
+    #[test]
+    fn test_sesparse_extent() {
+        let dir = scratch_dir("formats-sesparse");
+        let path = dir.join("delta-sesparse.vmdk");
+        std::fs::write(&path, sesparse_image()).unwrap();
+        let mut extent = SeSparseExtent::open(&path).unwrap();
+        let mut buf = vec![0u8; 4 * 4096];
+        extent.read_at(0, &mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|&b| b == 0xd0));
+        assert!(buf[4096..8192].iter().all(|&b| b == 0xd1));
+        assert!(buf[8192..].iter().all(|&b| b == 0));
+        assert_eq!(extent.allocation(8192, 4096).unwrap(), Allocation::Zero);
+        assert_eq!(extent.allocation(8192, 8192).unwrap(), Allocation::Unallocated);
+        assert_eq!(extent.allocation(4096, 8192).unwrap(), Allocation::Data);
+
+        // Through the disk and `open_any`, uniformly
+        std::fs::write(dir.join("delta.vmdk"), "# Disk DescriptorFile\nversion=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            createType=\"seSparse\"\nRW 8192 SESPARSE \"delta-sesparse.vmdk\"\n").unwrap();
+        let vmdk = Vmdk::new(dir.join("delta.vmdk")).unwrap();
+        let mut extents: Vec<Box<dyn ExtentRead>> = vec![Box::new(vmdk.extents().next().unwrap().reader().unwrap())];
+        match open_any(&path).unwrap() {
+            AnyImage::Extent(extent) => extents.push(extent),
+            _ => panic!("not opened as an extent"),
+        }
+        for extent in &mut extents {
+            let mut buf = [0u8; 2];
+            extent.read_at(4095, &mut buf).unwrap();
+            assert_eq!(buf, [0xd0, 0xd1]);
+            assert_eq!(extent.allocation(3 * 4096, 4096).unwrap(), Allocation::Unallocated);
+        }
+
+        // Grains past the end of the file
+        let mut image = sesparse_image();
+        LittleEndian::write_u64(&mut image[3 * 512 + 8..], (3 << 60) | 0xffff_ffff_ffff);
+        std::fs::write(&path, image).unwrap();
+        let err = SeSparseExtent::open(&path).unwrap().read_at(4096, &mut buf).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(VmdkError::CorruptGrain { grain: 1, .. })));
+
+        let mut journal = sesparse_image();
+        journal[512 + 24] = 1;
+        std::fs::write(&path, journal).unwrap();
+        let err = SeSparseExtent::open(&path).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(VmdkError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn test_sesparse_hostile_fields() {
+        let files = MemoryFiles::new();
+        let path = Path::new("/nonexistent/hostile-sesparse.vmdk");
+        let hostile = [0, 1, 7, 1 << 20, 1 << 40, 1 << 55, 1 << 58, u64::MAX / 8, u64::MAX / 512 + 1, u64::MAX];
+        // Header fields, the grain directory entry and the first entries of
+        // the grain table
+        let fields = [2 * 8, 3 * 8, 4 * 8, 10 * 8, 16 * 8, 17 * 8, 18 * 8, 24 * 8, 1024, 3 * 512, 3 * 512 + 8];
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        for round in 0..500 {
+            let mut image = sesparse_image();
+            for _ in 0..1 + round % 3 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let field = fields[(state % fields.len() as u64) as usize];
+                let value = hostile[((state >> 8) % hostile.len() as u64) as usize];
+                let value = if state & (1 << 40) != 0 { value | (3 << 60) } else { value };
+                LittleEndian::write_u64(&mut image[field..], value);
+            }
+            files.insert(path, image);
+            let mut extent = match SeSparseExtent::new(files.open(path, false).unwrap()) {
+                Ok(extent) => extent,
+                Err(_) => continue,
+            };
+            let size = extent.size();
+            let mut buf = [0u8; 3 * 4096];
+            for offset in [0, 4096, size.saturating_sub(4096)].iter().copied().filter(|&offset| offset < size) {
+                let len = std::cmp::min(buf.len() as u64, size - offset) as usize;
+                let _ = extent.read_at(offset, &mut buf[..len]);
+                let _ = extent.allocation(offset, len as u64);
+            }
+        }
+    }
+}
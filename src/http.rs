@@ -0,0 +1,251 @@
+//! Serving the contents of a disk over HTTP.
+//!
+//! `DiskServer` serves the disk, with the data of all its parents, as a raw
+//! image at `/disk.raw`, answering `GET` and `HEAD` requests for the whole
+//! image or a single byte range. Tools reading raw images over HTTP can
+//! then use disks in place, e.g.
+//! `guestfish --format=raw -a http://host:port/disk.raw`. Nothing is ever
+//! written.
+
+use std::convert::TryFrom;
+use std::io::{self, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use failure::Error;
+use log::{info, warn};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+use crate::{Vmdk, VmdkError};
+
+/// Path the disk is served at
+pub const DISK_PATH: &str = "/disk.raw";
+
+/// Most bytes read from the disk at once while answering a request
+const READ_SIZE: usize = 1 << 20;
+
+/// How often idle workers check whether the server was shut down
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Serves one disk, read-only, at `DISK_PATH`
+pub struct DiskServer {
+    server: Server,
+    vmdk: Mutex<Vmdk>,
+    size: u64,
+    stopped: AtomicBool,
+}
+
+impl DiskServer {
+    /// Listen on `addr` for requests for `vmdk`. Requests are only answered
+    /// once `serve` is called.
+    pub fn bind<A: ToSocketAddrs>(addr: A, vmdk: Vmdk) -> Result<Self, Error> {
+        let server = Server::http(addr).map_err(|e| VmdkError::InvalidArgument(format!("cannot listen: {}", e)))?;
+        let size = vmdk.size();
+        Ok(DiskServer { server, vmdk: Mutex::new(vmdk), size, stopped: AtomicBool::new(false) })
+    }
+
+    /// Address the server listens on, e.g. to find the port chosen when
+    /// bound to port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answer requests on `threads` threads until `shutdown` is called.
+    /// Reads of the disk are serialized, so more threads mostly help slow
+    /// clients.
+    pub fn serve(&self, threads: usize) {
+        if let Some(addr) = self.local_addr() {
+            info!("Serving disk of {} bytes at http://{}{}", self.size, addr, DISK_PATH);
+        }
+        std::thread::scope(|scope| {
+            for _ in 0..std::cmp::max(threads, 1) {
+                scope.spawn(|| {
+                    while !self.stopped.load(Ordering::SeqCst) {
+                        match self.server.recv_timeout(POLL_INTERVAL) {
+                            Ok(Some(request)) => self.handle(request),
+                            Ok(None) => {}
+                            Err(e) => warn!("Accepting HTTP connection failed: {}", e),
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Make `serve` return once the requests being answered are
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Answer one request, for servers driving their own `tiny_http` loop
+    pub fn handle(&self, request: Request) {
+        let response = if request.url() != DISK_PATH {
+            Response::new(StatusCode(404), Vec::new(), Body::empty(), Some(0), None)
+        } else if request.method() != &Method::Get && request.method() != &Method::Head {
+            Response::new(StatusCode(405), vec![header("Allow", "GET, HEAD")], Body::empty(), Some(0), None)
+        } else {
+            self.respond(&request)
+        };
+        let status = response.status_code().0;
+        let (method, url) = (request.method().to_string(), request.url().to_owned());
+        if let Err(e) = request.respond(response) {
+            warn!("Answering {} {} failed: {}", method, url, e);
+        }
+        info!("{} {} {}", method, url, status);
+    }
+
+    fn respond(&self, request: &Request) -> Response<Body<'_>> {
+        let range = request.headers().iter().find(|h| h.field.equiv("Range")).map(|h| h.value.as_str());
+        let mut headers = vec![header("Accept-Ranges", "bytes"), header("Content-Type", "application/octet-stream")];
+        let (status, start, end) = match range.map(|range| parse_range(range, self.size)) {
+            Some(Some(Ok((start, end)))) => {
+                headers.push(header("Content-Range", &format!("bytes {}-{}/{}", start, end - 1, self.size)));
+                (206, start, end)
+            }
+            Some(Some(Err(()))) => {
+                headers.push(header("Content-Range", &format!("bytes */{}", self.size)));
+                return Response::new(StatusCode(416), headers, Body::empty(), Some(0), None);
+            }
+            // Requests for several ranges get the whole disk
+            Some(None) | None => (200, 0, self.size),
+        };
+        let body = Body { vmdk: Some(&self.vmdk), pos: start, end };
+        // A known length rather than chunks, as clients of raw images expect
+        Response::new(StatusCode(status), headers, body, usize::try_from(end - start).ok(), None)
+            .with_chunked_threshold(usize::MAX)
+    }
+}
+
+/// The bytes `start..end` of the disk, read as they are sent
+struct Body<'a> {
+    vmdk: Option<&'a Mutex<Vmdk>>,
+    pos: u64,
+    end: u64,
+}
+
+impl Body<'_> {
+    fn empty() -> Self {
+        Body { vmdk: None, pos: 0, end: 0 }
+    }
+}
+
+impl Read for Body<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let vmdk = match self.vmdk {
+            Some(vmdk) => vmdk,
+            None => return Ok(0),
+        };
+        let n = std::cmp::min(std::cmp::min(buf.len(), READ_SIZE) as u64, self.end - self.pos) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        let mut vmdk = vmdk.lock().unwrap_or_else(PoisonError::into_inner);
+        vmdk.read_at(self.pos, &mut buf[..n]).map_err(io::Error::other)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+/// The bytes `start..end` selected by the `Range` header `range` of a disk
+/// of `size` bytes, `Err` if no byte is, and `None` for anything but a
+/// single byte range, which is then ignored
+fn parse_range(range: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = if first.is_empty() {
+        // The last `last` bytes
+        let suffix: u64 = last.parse().ok()?;
+        (size.saturating_sub(suffix), size)
+    } else {
+        let start: u64 = first.parse().ok()?;
+        if last.is_empty() {
+            (start, size)
+        } else {
+            let last: u64 = last.parse().ok()?;
+            if last < start {
+                return None;
+            }
+            (start, std::cmp::min(last.saturating_add(1), size))
+        }
+    };
+    if start >= end {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use crate::testutil::{scratch_dir, SparseImage};
+
+    /// Send `request` to `addr`, returning the status line, headers and body
+    fn fetch(addr: SocketAddr, request: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(format!("{}\r\nHost: localhost\r\nConnection: close\r\n\r\n", request).as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        (String::from_utf8(response[..split].to_vec()).unwrap(), response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Ok((0, 10))));
+        assert_eq!(parse_range("bytes=90-", 100), Some(Ok((90, 100))));
+        assert_eq!(parse_range("bytes=-10", 100), Some(Ok((90, 100))));
+        assert_eq!(parse_range("bytes=50-1000", 100), Some(Ok((50, 100))));
+        assert_eq!(parse_range("bytes=100-", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=100-150", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("bytes=9-2", 100), None);
+        assert_eq!(parse_range("lines=1-2", 100), None);
+    }
+
+    #[test]
+    fn test_disk_server() {
+        let dir = scratch_dir("http");
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build()).unwrap();
+
+        let server = Arc::new(DiskServer::bind("127.0.0.1:0", Vmdk::new(&path).unwrap()).unwrap());
+        let addr = server.local_addr().unwrap();
+        let serving = server.clone();
+        let worker = std::thread::spawn(move || serving.serve(2));
+
+        let (head, body) = fetch(addr, "GET /disk.raw HTTP/1.1");
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(body.len(), 1024 * 512);
+        assert!(body[65536..2 * 65536].iter().all(|&b| b == 0xb1));
+        assert!(body[..65536].iter().all(|&b| b == 0));
+
+        let (head, body) = fetch(addr, "GET /disk.raw HTTP/1.1\r\nRange: bytes=65530-65539");
+        assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+        assert!(head.contains("Content-Range: bytes 65530-65539/524288"), "{}", head);
+        assert_eq!(body, [0, 0, 0, 0, 0, 0, 0xb1, 0xb1, 0xb1, 0xb1]);
+
+        let (head, body) = fetch(addr, "HEAD /disk.raw HTTP/1.1");
+        assert!(head.contains("Content-Length: 524288") && head.contains("Accept-Ranges: bytes"), "{}", head);
+        assert!(body.is_empty());
+
+        let (head, _) = fetch(addr, "GET /disk.raw HTTP/1.1\r\nRange: bytes=524288-");
+        assert!(head.starts_with("HTTP/1.1 416") && head.contains("Content-Range: bytes */524288"), "{}", head);
+        assert!(fetch(addr, "GET /other HTTP/1.1").0.starts_with("HTTP/1.1 404"));
+        assert!(fetch(addr, "PUT /disk.raw HTTP/1.1\r\nContent-Length: 0").0.starts_with("HTTP/1.1 405"));
+        server.shutdown();
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! Address translation through the grain directory and grain tables of
+//! sparse extents, without any IO.
+//!
+//! A byte of a sparse extent lies in grain `offset / grain bytes`. The grain
+//! directory holds one entry per grain table, each table one entry per
+//! grain, so grain `g` is entry `g % gtes_per_gt` of the table in grain
+//! directory entry `g / gtes_per_gt`. Large extents have directories
+//! spanning many sectors; entries are addressed in bytes, so they need not
+//! start a sector.
+
+use crate::{ExtentHeader, SECTOR_SIZE};
+
+/// Size of grain directory and grain table entries in bytes
+const ENTRY_SIZE: u64 = 4;
+
+/// Where a byte of a sparse extent is found through its grain tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrainAddress {
+    pub grain: u64,
+    /// Entry of the grain directory pointing at the grain table
+    pub gd_index: u64,
+    /// Entry of that grain table pointing at the grain
+    pub gt_index: u64,
+    /// Byte within the grain
+    pub within: u64,
+}
+
+/// Translates extent offsets into grain directory and grain table entries
+/// for the geometry of one sparse extent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbaMapper {
+    /// Capacity in sectors
+    capacity: u64,
+    /// Grain size in sectors
+    grain_size: u64,
+    gtes_per_gt: u64,
+}
+
+impl LbaMapper {
+    /// Mapper for an extent of `capacity` sectors in grains of `grain_size`
+    /// sectors. `grain_size` and `gtes_per_gt` must not be zero, which
+    /// parsed headers guarantee.
+    pub fn new(capacity: u64, grain_size: u64, gtes_per_gt: u32) -> Self {
+        assert!(grain_size != 0 && gtes_per_gt != 0, "empty grains or grain tables");
+        LbaMapper { capacity, grain_size, gtes_per_gt: u64::from(gtes_per_gt) }
+    }
+
+    pub fn grain_bytes(&self) -> u64 {
+        self.grain_size * SECTOR_SIZE
+    }
+
+    pub fn gtes_per_gt(&self) -> u64 {
+        self.gtes_per_gt
+    }
+
+    /// Grains needed to cover the capacity, the last one possibly partial
+    pub fn num_grains(&self) -> u64 {
+        self.capacity.div_ceil(self.grain_size)
+    }
+
+    /// Grain tables, and so grain directory entries, needed for every grain
+    pub fn num_gts(&self) -> u64 {
+        self.num_grains().div_ceil(self.gtes_per_gt)
+    }
+
+    /// Sectors taken by the grain directory
+    pub fn gd_sectors(&self) -> u64 {
+        (self.num_gts() * ENTRY_SIZE).div_ceil(SECTOR_SIZE)
+    }
+
+    /// Sectors taken by one grain table
+    pub fn gt_sectors(&self) -> u64 {
+        (self.gtes_per_gt * ENTRY_SIZE).div_ceil(SECTOR_SIZE)
+    }
+
+    /// Metadata overhead in sectors of a hosted sparse extent laid out as
+    /// VMware does: the header, a descriptor of `desc_sectors`, then each
+    /// grain directory followed by all of its grain tables, the redundant
+    /// one first if there is one, rounded up to whole grains
+    pub fn overhead(&self, desc_sectors: u64, redundant: bool) -> u64 {
+        let directories = if redundant { 2 } else { 1 };
+        let metadata = 1 + desc_sectors + directories * (self.gd_sectors() + self.num_gts() * self.gt_sectors());
+        metadata.div_ceil(self.grain_size) * self.grain_size
+    }
+
+    /// Address of the byte at `offset` in the extent, `None` past its
+    /// capacity
+    pub fn address(&self, offset: u64) -> Option<GrainAddress> {
+        if offset >= self.capacity.checked_mul(SECTOR_SIZE)? {
+            return None;
+        }
+        let mut address = self.grain_address(offset / self.grain_bytes());
+        address.within = offset % self.grain_bytes();
+        Some(address)
+    }
+
+    /// Address of the first byte of `grain`
+    pub fn grain_address(&self, grain: u64) -> GrainAddress {
+        GrainAddress { grain, gd_index: grain / self.gtes_per_gt, gt_index: grain % self.gtes_per_gt, within: 0 }
+    }
+
+    /// Byte position of the grain directory entry of `grain`, in a
+    /// directory starting at sector `gd_offset`
+    pub fn gd_entry(&self, gd_offset: u64, grain: u64) -> u64 {
+        gd_offset * SECTOR_SIZE + self.grain_address(grain).gd_index * ENTRY_SIZE
+    }
+
+    /// Byte position of the grain table entry of `grain`, in the grain
+    /// table starting at sector `gt`
+    pub fn gt_entry(&self, gt: u64, grain: u64) -> u64 {
+        gt * SECTOR_SIZE + self.grain_address(grain).gt_index * ENTRY_SIZE
+    }
+}
+
+impl From<&ExtentHeader> for LbaMapper {
+    fn from(header: &ExtentHeader) -> Self {
+        LbaMapper::new(header.capacity.0, header.grain_size.0, header.gtes_per_gt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+    use crate::Vmdk;
+
+    #[test]
+    fn test_lba_mapper() {
+        let mapper = LbaMapper::new(1000, 128, 512);
+        assert_eq!((mapper.num_grains(), mapper.num_gts(), mapper.gd_sectors(), mapper.gt_sectors()), (8, 1, 1, 4));
+        assert_eq!(mapper.address(1000 * 512), None);
+        let last = mapper.address(1000 * 512 - 1).unwrap();
+        assert_eq!((last.grain, last.gd_index, last.gt_index, last.within), (7, 0, 7, 104 * 512 - 1));
+
+        // Empty and single grain extents need no or a single grain table
+        let mapper = LbaMapper::new(0, 128, 512);
+        assert_eq!((mapper.num_grains(), mapper.num_gts(), mapper.gd_sectors(), mapper.overhead(1, true)), (0, 0, 0, 128));
+        assert_eq!(mapper.address(0), None);
+        let mapper = LbaMapper::new(1, 128, 512);
+        assert_eq!((mapper.num_grains(), mapper.num_gts(), mapper.gd_sectors()), (1, 1, 1));
+        assert_eq!(mapper.address(511).map(|a| (a.grain, a.within)), Some((0, 511)));
+        assert_eq!(mapper.address(512), None);
+
+        // 256 TiB in 64 KiB grains: a grain directory of 2^16 sectors
+        let mapper = LbaMapper::new(1 << 39, 128, 512);
+        assert_eq!(mapper.num_grains(), 1 << 32);
+        assert_eq!(mapper.num_gts(), 1 << 23);
+        assert_eq!(mapper.gd_sectors(), 1 << 16);
+        let address = mapper.address((1 << 47) + 3 * 65536 + 17).unwrap();
+        assert_eq!(address.grain, (1 << 31) + 3);
+        assert_eq!((address.gd_index, address.gt_index, address.within), (1 << 22, 3, 17));
+        assert_eq!(mapper.gd_entry(10, address.grain), 10 * 512 + (1 << 24));
+        assert_eq!(mapper.gt_entry(100, address.grain), 100 * 512 + 12);
+        let end = mapper.grain_address(mapper.num_grains() - 1);
+        assert_eq!(mapper.gd_entry(0, end.grain) + 4, mapper.gd_sectors() * 512);
+
+        // 8 GiB: 256 grain tables of 4 sectors behind a two sector directory
+        let mapper = LbaMapper::new(16 << 20, 128, 512);
+        assert_eq!(mapper.overhead(20, true), 17 * 128);
+        assert_eq!(mapper.overhead(0, false), 9 * 128);
+    }
+
+    proptest! {
+        #[test]
+        fn test_address_roundtrip(capacity in 1u64..(1 << 45), grain_size in 1u64..4096, gtes_per_gt in 1u32..2048, offset: u64) {
+            let mapper = LbaMapper::new(capacity, grain_size, gtes_per_gt);
+            let offset = offset % (capacity * 512);
+            let address = mapper.address(offset).unwrap();
+            prop_assert_eq!(address.grain * mapper.grain_bytes() + address.within, offset);
+            prop_assert_eq!(address.gd_index * u64::from(gtes_per_gt) + address.gt_index, address.grain);
+            prop_assert!(address.gd_index < mapper.num_gts());
+            prop_assert!(mapper.gd_entry(0, address.grain) + 4 <= mapper.gd_sectors() * 512);
+            prop_assert!(mapper.gt_entry(0, address.grain) + 4 <= mapper.gt_sectors() * 512);
+        }
+    }
+
+    #[test]
+    fn test_multi_sector_gd() {
+        // 256 grain tables of 4 entries: a grain directory of two sectors
+        let dir = scratch_dir("multi-sector-gd");
+        let path = dir.join("disk.vmdk");
+        let mut image = SparseImage::new(1024 * 8, 8).monolithic("disk.vmdk").grain(1000, 0xb1).grain(3, 0xb2);
+        image.gtes_per_gt = 4;
+        std::fs::write(&path, image.build()).unwrap();
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        let mapper = LbaMapper::from(vmdk.extent_header.as_ref().unwrap());
+        assert_eq!(mapper.gd_sectors(), 2);
+        let mut buf = [0u8; 512];
+        vmdk.read_at(1000 * 8 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+        vmdk.read_at(3 * 8 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb2; 512]);
+        vmdk.read_at(999 * 8 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0; 512]);
+    }
+}
@@ -1,29 +1,157 @@
+// `failure`'s derive expands to impls that newer compilers lint against
+#![allow(non_local_definitions)]
+
 /// "VMDK"
 const EXTENT_MAGIC: u32 = 0x564d444b;
-const EXTENT_VERSION: u32 = 1;
+/// Highest extent version, used by stream-optimized extents
+const EXTENT_VERSION_MAX: u32 = 3;
 const SECTOR_SIZE: u64 = 512;
 
-use std::convert::TryInto;
-use std::path::Path;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use byteorder::{LittleEndian, ReadBytesExt};
+/// Header flag: the newline characters in the header are valid
+pub const FLAG_VALID_NEWLINE_DETECTION: u32 = 1 << 0;
+/// Header flag: the redundant grain table will be used
+pub const FLAG_USE_REDUNDANT_GT: u32 = 1 << 1;
+/// Header flag: zeroed-grain GTEs are used
+pub const FLAG_ZEROED_GRAIN_GTE: u32 = 1 << 2;
+/// Header flag: grains are compressed
+pub const FLAG_COMPRESSED: u32 = 1 << 16;
+/// Header flag: metadata markers are present
+pub const FLAG_MARKERS: u32 = 1 << 17;
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use failure::{Error, Fail};
-use log::info;
+use log::{info, warn};
+
+pub mod descriptor;
+pub mod analysis;
+pub mod audit;
+pub mod blocks;
+pub mod cache;
+pub mod chain;
+pub mod check;
+pub mod clone;
+pub mod compress;
+pub mod conformance;
+pub mod create;
+pub mod ctk;
+pub mod debug;
+pub mod diagnostics;
+pub mod export;
+pub mod formats;
+#[cfg(feature = "http")]
+pub mod http;
+mod extent;
+pub mod lba;
+pub mod lock;
+pub mod memory;
+pub mod overlay;
+pub mod path;
+mod pool;
+mod positioned;
+pub mod probe;
+pub mod progress;
+pub mod readonly;
+mod reflink;
+pub mod retry;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod scan;
+pub mod session;
+pub mod sink;
+pub mod snapshot;
+pub mod storage;
+pub mod stream;
+pub mod throttle;
+pub mod timeline;
+#[cfg(test)]
+mod testutil;
 
-mod descriptor;
+pub use clone::copy_range;
+pub use extent::{ExtentHandle, ExtentReader};
+pub use probe::{open_any, probe_dir, AnyImage, ProbeOptions};
+pub use snapshot::linked_clone;
 
+use audit::{AuditOperation, AuditSink};
+use cache::{GrainCache, LruGrainCache, DEFAULT_COMPRESSED_CACHE};
+use descriptor::{AccessMode, Descriptor, DiskDatabase, DiskType, Encryption, ExtentDescriptor, NO_PARENT_CID};
+use diagnostics::{Diagnostics, PastEof, Strictness, VmdkWarning};
+use extent::{Allocation, Extent, Placement};
+use lock::VmwareLock;
+use path::{DefaultResolver, PathResolver};
+use pool::FilePool;
+use progress::CancelToken;
+use retry::RetryPolicy;
+use storage::{Storage, StorageProvider};
+use throttle::Throttle;
 
 #[derive(Debug, Fail)]
 pub enum VmdkError {
     #[fail(display = "Parsing error")]
     ParseError,
+    #[fail(display = "Extent {} is a physical device, enable device access to open it", _0)]
+    DeviceNotAllowed(String),
+    #[fail(display = "Unsupported extent type {}", _0)]
+    UnsupportedExtent(String),
+    #[fail(display = "Disk is encrypted, data cannot be read without its key")]
+    Encrypted,
+    #[fail(display = "Newline detection characters are damaged (found {:?}), the file \
+                      was likely transferred in FTP ASCII mode instead of binary mode", _0)]
+    TransferCorruption([u8; 4]),
+    #[fail(display = "Unsupported compression method {}", _0)]
+    UnsupportedCompression(u16),
+    #[fail(display = "Invalid argument: {}", _0)]
+    InvalidArgument(String),
+    #[fail(display = "Cannot write to {}", _0)]
+    NotWritable(String),
+    #[fail(display = "Disk is in use, locked by {}", _0)]
+    Locked(String),
+    #[fail(display = "Disk is the parent of {}, writing to it would corrupt the delta", _0)]
+    HasChildren(String),
+    #[fail(display = "Operation cancelled")]
+    Cancelled,
+    /// Comes with the log of `VmdkReadOnly::close`, hashes at close filled
+    /// in
+    #[fail(display = "Metadata of {} changed while the disk was open", path)]
+    EvidenceModified { path: String, log: readonly::EvidenceLog },
+    #[fail(display = "Disk {} was modified during the read session", _0)]
+    ConcurrentModification(String),
+    #[fail(display = "Copy does not match its source, {}", _0)]
+    CopyMismatch(String),
+    #[fail(display = "Grain {} points to sector {}, {}", grain, sector, problem)]
+    CorruptGrain { grain: u64, sector: u64, problem: String },
+    #[fail(display = "Sparse extent has no embedded descriptor, {}", _0)]
+    MissingDescriptor(String),
+    #[fail(display = "Unsupported image format, {}", _0)]
+    UnsupportedFormat(String),
+    #[fail(display = "Malformed metadata, {}", _0)]
+    Malformed(Diagnostics),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct SectorType(u64);
 
-#[derive(Debug)]
+impl SectorType {
+    /// The value as a number of 512-byte sectors
+    pub fn sectors(self) -> u64 {
+        self.0
+    }
+
+    /// The value in bytes
+    pub fn bytes(self) -> u64 {
+        self.0 * SECTOR_SIZE
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ExtentHeader {
     /// The header signature "KDMV"
     pub magic_number: u32,
@@ -54,25 +182,41 @@ pub struct ExtentHeader {
     pub single_eol_char: u8,
     /// Non EOL character
     pub non_eol_char: u8,
-    /// Second double EOL character
+    /// First double EOL character
     pub dbl_eol_char: u8,
+    /// Second double EOL character
+    pub dbl_eol_char2: u8,
     /// Compression method
     pub compress_method: u16,
 }
 
 impl ExtentHeader {
-    pub fn new<R: Read>(mut reader: R) -> Result<Self, Error> {
+    /// Read and validate a header, failing with `VmdkError::Malformed` on
+    /// the problems `diagnose` finds
+    pub fn new<R: Read>(reader: R) -> Result<Self, Error> {
+        let (ext, warnings) = ExtentHeader::with_strictness(reader, Strictness::Lenient)?;
+        for warning in warnings.warnings() {
+            warn!("Extent header: {}", warning);
+        }
+        Ok(ext)
+    }
+
+    /// Read and validate a header, also failing on the warnings of
+    /// `diagnose` with `Strictness::Strict`, and returning those accepted
+    pub fn with_strictness<R: Read>(reader: R, strictness: Strictness) -> Result<(Self, Diagnostics), Error> {
+        let ext = ExtentHeader::read(reader)?;
+        let warnings = ext.diagnose().enforce(strictness)?;
+        ext.check_newlines()?;
+        Ok((ext, warnings))
+    }
+
+    /// Read the fields of a header, whatever their values
+    pub(crate) fn read<R: Read>(mut reader: R) -> Result<Self, Error> {
         let magic = reader.read_u32::<LittleEndian>()?;
         info!("Magic: 0x{:x}", magic);
-        if magic != EXTENT_MAGIC {
-            return Err(VmdkError::ParseError.into());
-        }
 
         let version = reader.read_u32::<LittleEndian>()?;
         info!("Version: 0x{:x}", version);
-        if version != EXTENT_VERSION {
-            return Err(VmdkError::ParseError.into());
-        }
 
         let flags = reader.read_u32::<LittleEndian>()?;
         info!("Flags: 0x{:x}", flags);
@@ -118,76 +262,2521 @@ impl ExtentHeader {
         info!("Non EOL Char: 0x{:x}", non_eol_char);
 
         let dbl_eol_char = reader.read_u8()?;
-        info!("Double EOL Char: 0x{:x}", dbl_eol_char);
+        info!("Double EOL Char 1: 0x{:x}", dbl_eol_char);
+
+        let dbl_eol_char2 = reader.read_u8()?;
+        info!("Double EOL Char 2: 0x{:x}", dbl_eol_char2);
 
         let compress_method = reader.read_u16::<LittleEndian>()?;
         info!("Compression Algo: 0x{:x}", compress_method);
 
-        let ext = ExtentHeader {
+        Ok(ExtentHeader {
             magic_number: magic,
-            version: version,
-            flags: flags,
-            capacity: capacity,
-            grain_size: grain_size,
-            desc_offset: desc_offset,
-            desc_size: desc_size,
+            version,
+            flags,
+            capacity,
+            grain_size,
+            desc_offset,
+            desc_size,
             gtes_per_gt: gte_per_gt,
-            rgd_offset: rgd_offset,
-            gd_offset: gd_offset,
+            rgd_offset,
+            gd_offset,
             overhead: meta_overhead,
-            dirty_shutdown: dirty_shutdown,
+            dirty_shutdown,
             single_eol_char: eol_char,
-            non_eol_char: non_eol_char,
-            dbl_eol_char: dbl_eol_char,
-            compress_method: compress_method,
+            non_eol_char,
+            dbl_eol_char,
+            dbl_eol_char2,
+            compress_method,
+        })
+    }
+
+    /// Parse the header from the 512 byte sector at the start of `buf`,
+    /// without copying it, returning the header and the bytes consumed
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize), Error> {
+        let len = SECTOR_SIZE as usize;
+        if buf.len() < len {
+            return Err(VmdkError::ParseError.into());
+        }
+        Ok((ExtentHeader::new(&buf[..len])?, len))
+    }
+
+    /// Parse the footer of a stream-optimized extent from the footer marker
+    /// and footer sectors at the start of `buf`, returning the footer and
+    /// the bytes consumed
+    pub fn parse_footer(buf: &[u8]) -> Result<(Self, usize), Error> {
+        let len = SECTOR_SIZE as usize;
+        if buf.len() < 2 * len {
+            return Err(VmdkError::ParseError.into());
+        }
+        // Metadata markers have no data and their type after lba and size
+        let size = LittleEndian::read_u32(&buf[8..12]);
+        if size != 0 || LittleEndian::read_u32(&buf[12..16]) != stream::MARKER_FOOTER {
+            return Err(VmdkError::ParseError.into());
+        }
+        let (footer, consumed) = ExtentHeader::parse(&buf[len..])?;
+        Ok((footer, len + consumed))
+    }
+
+    /// Write the header as a full 512 byte sector
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(SECTOR_SIZE as usize);
+        buf.write_u32::<LittleEndian>(self.magic_number)?;
+        buf.write_u32::<LittleEndian>(self.version)?;
+        buf.write_u32::<LittleEndian>(self.flags)?;
+        buf.write_u64::<LittleEndian>(self.capacity.0)?;
+        buf.write_u64::<LittleEndian>(self.grain_size.0)?;
+        buf.write_u64::<LittleEndian>(self.desc_offset.0)?;
+        buf.write_u64::<LittleEndian>(self.desc_size.0)?;
+        buf.write_u32::<LittleEndian>(self.gtes_per_gt)?;
+        buf.write_u64::<LittleEndian>(self.rgd_offset.0)?;
+        buf.write_u64::<LittleEndian>(self.gd_offset.0)?;
+        buf.write_u64::<LittleEndian>(self.overhead.0)?;
+        buf.write_u8(self.dirty_shutdown)?;
+        buf.write_u8(self.single_eol_char)?;
+        buf.write_u8(self.non_eol_char)?;
+        buf.write_u8(self.dbl_eol_char)?;
+        buf.write_u8(self.dbl_eol_char2)?;
+        buf.write_u16::<LittleEndian>(self.compress_method)?;
+        buf.resize(SECTOR_SIZE as usize, 0);
+        writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// When the header declares valid newline detection characters, make
+    /// sure they survived. A text-mode transfer rewrites "\r\n" and "\n",
+    /// which also damages grain data and metadata throughout the file.
+    pub fn check_newlines(&self) -> Result<(), Error> {
+        if self.flags & FLAG_VALID_NEWLINE_DETECTION == 0 {
+            return Ok(());
+        }
+
+        let found = [self.single_eol_char, self.non_eol_char, self.dbl_eol_char, self.dbl_eol_char2];
+        if found != [b'\n', b' ', b'\r', b'\n'] {
+            return Err(VmdkError::TransferCorruption(found).into());
+        }
+
+        Ok(())
+    }
+
+    /// Whether the extent keeps a redundant grain directory up to date, as
+    /// declared by `FLAG_USE_REDUNDANT_GT`
+    pub fn has_redundant_gd(&self) -> bool {
+        self.flags & FLAG_USE_REDUNDANT_GT != 0 && self.rgd_offset.0 != 0 && self.rgd_offset.0 != extent::GD_AT_END
+    }
+
+    /// Problems of the header, with offsets relative to its start. Errors
+    /// are sizes that would divide by zero and sector offsets that do not
+    /// fit a byte offset, so later arithmetic cannot overflow.
+    pub fn diagnose(&self) -> Diagnostics {
+        let mut diagnostics = Diagnostics::default();
+        let mut bytes = Vec::with_capacity(SECTOR_SIZE as usize);
+        self.write(&mut bytes).expect("header fits a sector");
+        let found = |name: &str| {
+            let &(_, offset, size) = debug::HEADER_FIELDS.iter().find(|f| f.0 == name).expect("known header field");
+            let value = LittleEndian::read_uint(&bytes[offset..offset + size], size);
+            (Some(offset as u64), format!("{} ({})", debug::hex(&bytes[offset..offset + size]), value))
         };
 
-        Ok(ext)
+        if self.magic_number != EXTENT_MAGIC {
+            let (offset, found) = found("magicNumber");
+            diagnostics.error(offset, "magicNumber", found, "4b 44 4d 56 (\"KDMV\")");
+        }
+        if self.version == 0 || self.version > EXTENT_VERSION_MAX {
+            let (offset, found) = found("version");
+            diagnostics.error(offset, "version", found, format!("1 to {}", EXTENT_VERSION_MAX));
+        }
+        let unknown = self.flags & !FLAG_NAMES.iter().fold(0, |all, (flag, _)| all | flag);
+        if unknown != 0 {
+            let (offset, found) = found("flags");
+            diagnostics.warning(offset, "flags", found, format!("no flags in 0x{:x}", unknown));
+        }
+        let grain_size = "a power of two of at least 8 sectors";
+        if self.grain_size.0 == 0 {
+            let (offset, found) = found("grainSize");
+            diagnostics.error(offset, "grainSize", found, grain_size);
+        } else if self.grain_size.0 < 8 || !self.grain_size.0.is_power_of_two() {
+            let (offset, found) = found("grainSize");
+            diagnostics.warning(offset, "grainSize", found, grain_size);
+        }
+        if self.gtes_per_gt == 0 {
+            let (offset, found) = found("numGTEsPerGT");
+            diagnostics.error(offset, "numGTEsPerGT", found, "512");
+        } else if self.gtes_per_gt != 512 {
+            let (offset, found) = found("numGTEsPerGT");
+            diagnostics.warning(offset, "numGTEsPerGT", found, "512");
+        }
+        let sectors = [
+            ("capacity", self.capacity.0),
+            ("grainSize", self.grain_size.0),
+            ("descriptorOffset", self.desc_offset.0),
+            ("descriptorSize", self.desc_size.0),
+            ("rgdOffset", self.rgd_offset.0),
+            ("gdOffset", if self.gd_offset.0 == extent::GD_AT_END { 0 } else { self.gd_offset.0 }),
+            ("overHead", self.overhead.0),
+        ];
+        for (name, _) in sectors.iter().filter(|(_, s)| s.checked_mul(SECTOR_SIZE).is_none()) {
+            let (offset, found) = found(name);
+            diagnostics.error(offset, *name, found, format!("at most {} sectors", u64::MAX / SECTOR_SIZE));
+        }
+        diagnostics
+    }
+
+    /// Byte offset and size of the embedded descriptor, if it lies within
+    /// a file of `len` bytes and is small enough to be one
+    pub(crate) fn descriptor_area(&self, len: u64) -> Result<(u64, u64), Diagnostics> {
+        let (offset, size) = (self.desc_offset.bytes(), self.desc_size.bytes());
+        if size > MAX_TEXT_DESCRIPTOR || offset.checked_add(size).is_none_or(|end| end > len) {
+            let mut diagnostics = Diagnostics::default();
+            diagnostics.error(
+                Some(36),
+                "descriptorSize",
+                format!("{} sectors at sector {}", self.desc_size.0, self.desc_offset.0),
+                format!("at most {} bytes within the file of {} bytes", MAX_TEXT_DESCRIPTOR, len),
+            );
+            return Err(diagnostics);
+        }
+        Ok((offset, size))
+    }
+}
+
+impl TryFrom<&[u8]> for ExtentHeader {
+    type Error = Error;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Error> {
+        Ok(ExtentHeader::parse(buf)?.0)
+    }
+}
+
+/// Names of the header flags, for `Display`
+const FLAG_NAMES: [(u32, &str); 5] = [
+    (FLAG_VALID_NEWLINE_DETECTION, "valid-newline-detection"),
+    (FLAG_USE_REDUNDANT_GT, "redundant-gt"),
+    (FLAG_ZEROED_GRAIN_GTE, "zeroed-grain-gte"),
+    (FLAG_COMPRESSED, "compressed"),
+    (FLAG_MARKERS, "markers"),
+];
+
+impl fmt::Display for ExtentHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut flags: Vec<String> =
+            FLAG_NAMES.iter().filter(|(flag, _)| self.flags & flag != 0).map(|(_, name)| name.to_string()).collect();
+        let unknown = self.flags & !FLAG_NAMES.iter().fold(0, |all, (flag, _)| all | flag);
+        if unknown != 0 {
+            flags.push(format!("{:#x}", unknown));
+        }
+        let compression = match self.compress_method {
+            compress::COMPRESSION_NONE => "none".to_owned(),
+            compress::COMPRESSION_DEFLATE => "deflate".to_owned(),
+            other => format!("unknown ({})", other),
+        };
+        if flags.is_empty() {
+            flags.push("none".to_owned());
+        }
+        writeln!(f, "version {}, flags: {}", self.version, flags.join(", "))?;
+        writeln!(
+            f,
+            "capacity: {}, grain size: {}, {} GTEs per GT",
+            human_size(self.capacity.bytes()),
+            human_size(self.grain_size.bytes()),
+            self.gtes_per_gt
+        )?;
+        writeln!(
+            f,
+            "descriptor: sector {} ({} sectors), GD: sector {}, redundant GD: sector {}, overhead: {} sectors",
+            self.desc_offset.0, self.desc_size.0, self.gd_offset.0, self.rgd_offset.0, self.overhead.0
+        )?;
+        let shutdown = if self.dirty_shutdown != 0 { "not closed cleanly" } else { "closed cleanly" };
+        write!(f, "compression: {}, {}", compression, shutdown)
+    }
+}
+
+/// Format `bytes` with a binary unit, e.g. "20 GiB"
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 || size.fract() == 0.0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
     }
 }
 
 pub struct Vmdk {
     pub extent_header: Option<ExtentHeader>,
-    pub descriptor: Option<String>,
-    file: File,
+    pub descriptor: Descriptor,
+    /// Descriptor text as read, or as last written by this handle
+    raw_descriptor: String,
+    /// The same, encoded as stored
+    raw_descriptor_bytes: Vec<u8>,
+    /// File holding the descriptor
+    path: PathBuf,
+    /// Changed block tracking file, if the descriptor names one
+    ctk_path: Option<PathBuf>,
+    /// Separate descriptor file, kept open so it stays locked and can be
+    /// rewritten
+    desc_file: Option<Box<dyn Storage>>,
+    /// CID when the disk was opened
+    original_cid: u32,
+    /// Whether writes no longer need to change the CID
+    cid_updated: bool,
+    extents: Vec<Extent>,
+    /// The disk this one is a delta of
+    parent: Option<Box<Vmdk>>,
+    /// Limit on reads and writes through this disk
+    throttle: Option<Throttle>,
+    /// Receives the changes made to the disk
+    audit: Option<Arc<dyn AuditSink>>,
+    /// Where the files of the disk are opened from, if not the local
+    /// filesystem
+    storage: Option<Arc<dyn StorageProvider>>,
+    /// How to retry failed reads
+    retry: Option<RetryPolicy>,
+    /// Oddities found when opening
+    warnings: Vec<VmdkWarning>,
+    /// Locks held while writable, released after the extents are closed
+    _locks: Vec<VmwareLock>,
 }
 
-impl Vmdk {
-    // TODO: make the input generic over R: Read
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let mut file = File::open(path)?;
+/// A range of the virtual disk in the map returned by `Vmdk::map`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MapEntry {
+    /// Byte offset of the range
+    pub offset: u64,
+    /// Length in bytes
+    pub length: u64,
+    /// Whether the range holds data rather than reading as zeros
+    pub data: bool,
+    /// How many parents down the chain the range is stored, 0 for this disk
+    pub depth: usize,
+}
 
-        // Extent Header
-        file.seek(SeekFrom::Start(0))?;
-        let extent_header = ExtentHeader::new(&mut file)?;
-        eprintln!("Ext: {:?}", extent_header);
-
-        // Embedded Descriptor
-        file.seek(SeekFrom::Start(512))?;
-        //let mut buf = [0u8; 0x2a0];
-        let desc_size_in_bytes = extent_header.desc_size.0 * SECTOR_SIZE;
-        let mut buf: Vec<u8> = vec![0u8; desc_size_in_bytes.try_into()?];
-        file.read_exact(&mut buf)?;
-        let descriptor = std::str::from_utf8(&buf)?.to_owned();
-        let descriptor = descriptor.trim_matches(char::from(0)).to_owned();
-        eprintln!("Descriptor string: {}", descriptor);
-        eprintln!("Descriptor string len: {}", descriptor.len());
+/// Summary of the metadata of a disk, from `Vmdk::info`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VmdkInfo {
+    /// File holding the descriptor
+    pub path: PathBuf,
+    /// Size of the virtual disk in bytes
+    pub size: u64,
+    pub create_type: DiskType,
+    pub cid: u32,
+    /// `NO_PARENT_CID` if there is no parent
+    pub parent_cid: u32,
+    /// File holding the descriptor of the parent, if there is one
+    pub parent: Option<PathBuf>,
+    /// Header of the extent holding the descriptor, for monolithic sparse
+    /// disks
+    pub extent_header: Option<ExtentHeader>,
+    pub extents: Vec<ExtentDescriptor>,
+    pub ddb: DiskDatabase,
+}
+
+impl fmt::Display for VmdkInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {} {}", self.path.display(), human_size(self.size), self.create_type)?;
+        write!(f, "CID: {:08x}", self.cid)?;
+        match &self.parent {
+            Some(parent) => writeln!(f, ", parent: {} (CID {:08x})", parent.display(), self.parent_cid)?,
+            None => writeln!(f)?,
+        }
+        if let Some(header) = &self.extent_header {
+            writeln!(f, "header:")?;
+            for line in header.to_string().lines() {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+        write!(f, "extents:")?;
+        for extent in &self.extents {
+            write!(f, "\n    {} ({})", extent, human_size(extent.sectors * SECTOR_SIZE))?;
+        }
+        Ok(())
+    }
+}
+
+/// Options controlling how a disk is opened
+#[derive(Debug, Clone, Default)]
+pub struct VmdkOpenOptions {
+    allow_devices: bool,
+    write: bool,
+    vmware_lock: bool,
+    force: bool,
+    ignore_children: bool,
+    resolver: Option<Arc<dyn PathResolver>>,
+    throttle: Option<Throttle>,
+    retry: Option<RetryPolicy>,
+    audit: Option<Arc<dyn AuditSink>>,
+    write_blocker: bool,
+    max_open_files: Option<usize>,
+    cache: Option<Arc<dyn GrainCache>>,
+    compressed_cache: Option<usize>,
+    mmap: bool,
+    punch_holes: bool,
+    past_eof: PastEof,
+    redundant_gd: bool,
+    strictness: Strictness,
+    storage: Option<Arc<dyn StorageProvider>>,
+    /// Pool shared by the disks of the chain being opened
+    pool: Option<FilePool>,
+}
+
+impl VmdkOpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow opening extents that refer to physical devices, as used by
+    /// `fullDevice` and `partitionedDevice` disks. Off by default.
+    pub fn allow_devices(&mut self, allow: bool) -> &mut Self {
+        self.allow_devices = allow;
+        self
+    }
+
+    /// Open the disk for writing. Parent disks are always opened read-only;
+    /// writes to grains they hold copy the grain into this disk first.
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// When writing, take VMware-style `.lck` locks on the descriptor and
+    /// every writable extent, and refuse to open files locked by someone
+    /// else, such as a running VM. Off by default.
+    pub fn vmware_lock(&mut self, lock: bool) -> &mut Self {
+        self.vmware_lock = lock;
+        self
+    }
+
+    /// Open for writing even if the disk is locked by someone else, through
+    /// VMware lock files or OS advisory locks
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    /// Open for writing even if delta disks next to this one name it as
+    /// their parent. Off by default, as any write to a parent silently
+    /// corrupts every child.
+    pub fn ignore_children(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_children = ignore;
+        self
+    }
+
+    /// Resolve extent and parent file names with `resolver` instead of
+    /// `DefaultResolver`, e.g. to remap paths of a copied VM directory
+    pub fn path_resolver<R: PathResolver + 'static>(&mut self, resolver: R) -> &mut Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Pace `read_at` and `write_at` of the disk with `throttle`. Each call
+    /// counts as one request; reads falling through to parents are not
+    /// counted again.
+    pub fn throttle(&mut self, throttle: &Throttle) -> &mut Self {
+        self.throttle = Some(throttle.clone());
+        self
+    }
+
+    /// Retry `read_at` calls that fail with transient I/O errors, such as
+    /// `EIO` from a flaky network filesystem, according to `policy`
+    pub fn retry(&mut self, policy: &RetryPolicy) -> &mut Self {
+        self.retry = Some(policy.clone());
+        self
+    }
+
+    /// Report every change made to the disk, and to parents written by
+    /// operations such as `Vmdk::commit`, to `sink`, see `audit`
+    pub fn audit<S: AuditSink + 'static>(&mut self, sink: S) -> &mut Self {
+        self.audit = Some(Arc::new(sink));
+        self
+    }
+
+    /// With `open_read_only`, hash the metadata of every file of the disk
+    /// when opening and verify it is unchanged when closing, see
+    /// `readonly::EvidenceLog`
+    pub fn write_blocker(&mut self, verify: bool) -> &mut Self {
+        self.write_blocker = verify;
+        self
+    }
+
+    /// Open extent files on first access rather than when opening the
+    /// disk, and keep at most `n` of the read-only ones open at once across
+    /// the whole chain, closing the least recently used. Writable extents
+    /// and files in use by a read are never closed, so the limit may be
+    /// exceeded by those.
+    pub fn max_open_files(&mut self, n: usize) -> &mut Self {
+        self.max_open_files = Some(n);
+        self
+    }
+
+    /// Keep grains read from extents opened read-only, parents included,
+    /// in `cache`. Sharing a cache, such as a clone of one
+    /// `cache::LruGrainCache`, between disks bounds their memory use as a
+    /// whole.
+    pub fn grain_cache<C: GrainCache + 'static>(&mut self, cache: C) -> &mut Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Keep up to `bytes` of recently decompressed grains for each
+    /// compressed extent, such as those of stream-optimized disks, so
+    /// nearby reads do not inflate the same grain again. Unused with
+    /// `grain_cache`, which caches compressed grains as well. Defaults to
+    /// 1 MiB; 0 disables it.
+    pub fn compressed_cache_size(&mut self, bytes: usize) -> &mut Self {
+        self.compressed_cache = Some(bytes);
+        self
+    }
+
+    /// Read extents opened read-only, parents included, through memory
+    /// maps rather than system calls, and allow `Vmdk::read_grain_ref`.
+    ///
+    /// # Safety
+    ///
+    /// The extent files must not be modified or truncated, by this process
+    /// or any other, while the disk is open. Otherwise slices returned by
+    /// `read_grain_ref` change under their borrowers, which is undefined
+    /// behavior, and reads past a shrunk file kill the process.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Deallocate the space of grains dropped by `Vmdk::sparsify` in the
+    /// extent files, so the host reclaims it, where the filesystem can punch
+    /// holes. Off by default, as the files then become sparse.
+    pub fn punch_holes(&mut self, punch: bool) -> &mut Self {
+        self.punch_holes = punch;
+        self
+    }
+
+    /// How reads treat grains the grain tables place past the end of their
+    /// extent file, as in truncated images. `PastEof::Error` by default;
+    /// the other policies let forensic tools salvage what is left.
+    pub fn past_eof(&mut self, policy: PastEof) -> &mut Self {
+        self.past_eof = policy;
+        self
+    }
+
+    /// Look grains up through the redundant grain directory of sparse
+    /// extents instead of the primary one, to verify its integrity. Opening
+    /// fails for extents without one, or when also writing.
+    pub fn redundant_gd(&mut self, redundant: bool) -> &mut Self {
+        self.redundant_gd = redundant;
+        self
+    }
+
+    /// How closely the metadata of the disk, its extents and its parents
+    /// must follow the specification. `Strictness::Lenient` by default;
+    /// the deviations accepted are listed by `Vmdk::warnings`.
+    pub fn strictness(&mut self, strictness: Strictness) -> &mut Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// Open the descriptor and extent files of the disk and its parents
+    /// through `provider` rather than from the local filesystem, see
+    /// `storage`
+    pub fn storage<P: StorageProvider + 'static>(&mut self, provider: P) -> &mut Self {
+        self.storage = Some(Arc::new(provider));
+        self
+    }
+
+    /// Cache for the decompressed grains of one extent
+    fn compressed_cache(&self) -> Option<LruGrainCache> {
+        match self.compressed_cache.unwrap_or(DEFAULT_COMPRESSED_CACHE) {
+            0 => None,
+            bytes => Some(LruGrainCache::new(bytes)),
+        }
+    }
+
+    fn resolve(&self, descriptor_path: &Path, name: &str) -> PathBuf {
+        match &self.resolver {
+            Some(resolver) => resolver.resolve(descriptor_path, name),
+            None => DefaultResolver.resolve(descriptor_path, name),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Vmdk, Error> {
+        let path = path.as_ref();
+        if let (Some(n), None) = (self.max_open_files, &self.pool) {
+            let mut options = self.clone();
+            options.pool = Some(FilePool::new(n));
+            return options.open(path);
+        }
+        if self.write && self.redundant_gd {
+            return Err(VmdkError::InvalidArgument("reading through the redundant grain directory is read-only".to_owned()).into());
+        }
+        let mut locks = Vec::new();
+        if self.write && self.vmware_lock {
+            locks.push(VmwareLock::acquire(path, self.force)?);
+        }
+        let mut file: Box<dyn Storage> = match &self.storage {
+            Some(provider) => provider.open(path, self.write)?,
+            None => Box::new(OpenOptions::new().read(true).write(self.write).open(path)?),
+        };
+        if let (true, Some(local)) = (self.write, file.as_file()) {
+            lock::lock_file(local, path, self.force)?;
+        }
+
+        let (header, bytes, mut warnings) = match read_descriptor(&mut *file, self.strictness) {
+            Err(e) if matches!(e.downcast_ref(), Some(VmdkError::MissingDescriptor(_))) => match find_descriptor_of(path) {
+                Some(desc) => {
+                    let hint = format!("open its disk {} instead", desc.display());
+                    return Err(VmdkError::MissingDescriptor(hint).into());
+                }
+                None => return Err(e),
+            },
+            result => result?,
+        };
+        let text = descriptor::decode(&bytes);
+        let base = header.as_ref().map(|h| h.desc_offset.bytes()).unwrap_or(0);
+        let (desc, mut desc_warnings) = Descriptor::with_strictness(text.trim_end_matches(char::from(0)), self.strictness)
+            .map_err(|e| diagnostics::rebase(e, base))?;
+        desc_warnings.rebase(base);
+        warnings.0.extend(desc_warnings.0);
+        let mut warnings: Vec<VmdkWarning> = warnings.warnings().cloned().map(VmdkWarning::Deviation).collect();
+        warnings.extend(desc.ddb.unknown().map(|(key, _)| VmdkWarning::UnknownDdbKey(key.to_owned())));
+        if let Some(geometry) = desc.ddb.geometry().filter(|g| !g.covers(desc.capacity())) {
+            warnings.push(VmdkWarning::GeometryMismatch { geometry, capacity: desc.capacity() });
+        }
+        for warning in &warnings {
+            warn!("{}: {}", path.display(), warning);
+        }
+        // Only local directories can be searched for children
+        if self.write && !self.ignore_children && self.storage.is_none() {
+            if let Some(child) = find_children(path, desc.cid)?.first() {
+                return Err(VmdkError::HasChildren(child.display().to_string()).into());
+            }
+        }
+
+        let ctk_path = desc.change_track_path.as_ref().map(|ctk| self.resolve(path, ctk));
+
+        let extent_header = match header {
+            Some(header) => header,
+            None => {
+                // Text descriptor referencing separate extent files
+                let extents = self.open_extents(&desc, path, None, &mut locks)?;
+                let parent = self.open_parent(&desc, path, &mut warnings)?;
+
+                return Ok(Vmdk {
+                    extent_header: None,
+                    path: path.to_owned(),
+                    ctk_path,
+                    original_cid: desc.cid,
+                    cid_updated: false,
+                    descriptor: desc,
+                    raw_descriptor: text,
+                    raw_descriptor_bytes: bytes,
+                    desc_file: if self.write { Some(file) } else { None },
+                    extents,
+                    parent,
+                    throttle: self.throttle.clone(),
+                    audit: self.audit.clone(),
+                    storage: self.storage.clone(),
+                    retry: self.retry.clone(),
+                    warnings,
+                    _locks: locks,
+                });
+            }
+        };
+        let embedded = (extent_header.clone(), file);
+        let extents = self.open_extents(&desc, path, Some(embedded), &mut locks)?;
+        let parent = self.open_parent(&desc, path, &mut warnings)?;
 
         Ok(Vmdk {
             extent_header: Some(extent_header),
-            descriptor: Some(descriptor),
-            file: file,
+            path: path.to_owned(),
+            ctk_path,
+            original_cid: desc.cid,
+            cid_updated: false,
+            descriptor: desc,
+            raw_descriptor: text,
+            raw_descriptor_bytes: bytes,
+            desc_file: None,
+            extents,
+            parent,
+            throttle: self.throttle.clone(),
+            audit: self.audit.clone(),
+            storage: self.storage.clone(),
+            retry: self.retry.clone(),
+            warnings,
+            _locks: locks,
         })
     }
+
+    /// Open the parent of a delta disk read-only, following the whole chain
+    fn open_parent(&self, desc: &Descriptor, path: &Path, warnings: &mut Vec<VmdkWarning>) -> Result<Option<Box<Vmdk>>, Error> {
+        if desc.parent_cid == NO_PARENT_CID {
+            return Ok(None);
+        }
+        let hint = match &desc.parent_file_name_hint {
+            Some(hint) => hint,
+            None => return Err(VmdkError::ParseError.into()),
+        };
+
+        let mut options = self.clone();
+        options.write = false;
+        options.throttle = None;
+        options.retry = None;
+        let parent_path = self.resolve(path, hint);
+        let parent = options.open(&parent_path)?;
+        let first = warnings.len();
+        let named = path.parent().unwrap_or_else(|| Path::new("")).join(path::normalize_separators(hint));
+        if parent_path != named {
+            warnings.push(VmdkWarning::ParentHintRedirected { hint: hint.clone(), path: parent_path.clone() });
+        }
+        if parent.descriptor.cid != desc.parent_cid {
+            let (expected, found) = (desc.parent_cid, parent.descriptor.cid);
+            warnings.push(VmdkWarning::ParentCidMismatch { parent: parent_path, expected, found });
+        }
+        for warning in &warnings[first..] {
+            warn!("{}: {}", path.display(), warning);
+        }
+
+        Ok(Some(Box::new(parent)))
+    }
+
+    /// Open every extent of `desc`. For monolithic sparse disks the first
+    /// extent is the file holding the descriptor, passed in as `embedded` so
+    /// a renamed file still opens.
+    fn open_extents(
+        &self,
+        desc: &Descriptor,
+        disk_path: &Path,
+        mut embedded: Option<(ExtentHeader, Box<dyn Storage>)>,
+        locks: &mut Vec<VmwareLock>,
+    ) -> Result<Vec<Extent>, Error> {
+        let mut extents = Vec::new();
+        let mut start = 0;
+
+        for ext in &desc.extents {
+            let ext: ExtentDescriptor = ext.clone();
+            let sectors = ext.sectors;
+            let extent = match embedded.take() {
+                Some((header, file)) => Extent::from_sparse(ext, start, disk_path.to_owned(), file, header, self)?,
+                None => {
+                    let path = ext.filename.as_ref().map(|f| self.resolve(disk_path, f));
+                    if let Some(path) = &path {
+                        let writable = self.write && ext.access == AccessMode::Rw;
+                        if writable && self.vmware_lock && path != disk_path {
+                            locks.push(VmwareLock::acquire(path, self.force)?);
+                        }
+                    }
+                    Extent::open(ext, start, path, self)?
+                }
+            };
+            extents.push(extent);
+            start += sectors;
+        }
+
+        Ok(extents)
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Read the descriptor of the disk in `file`, either a text descriptor or
+/// the one embedded in a sparse extent along with the extent's header. The
+/// bytes are returned as stored, see `descriptor::decode`, along with the
+/// header warnings `strictness` accepted.
+fn read_descriptor(file: &mut dyn Storage, strictness: Strictness) -> Result<(Option<ExtentHeader>, Vec<u8>, Diagnostics), Error> {
+    file.seek(SeekFrom::Start(0))?;
+    let magic = file.read_u32::<LittleEndian>()?;
+    if magic != EXTENT_MAGIC {
+        file.seek(SeekFrom::Start(0))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        return Ok((None, bytes, Diagnostics::default()));
+    }
 
-    #[test]
-    fn test_vmdk() {
-        let vmdk = Vmdk::new("/home/josh/VirtualBox VMs/OMS CS6250 Course \
+    // Extent Header
+    file.seek(SeekFrom::Start(0))?;
+    let (extent_header, warnings) = ExtentHeader::with_strictness(&mut *file, strictness)?;
+
+    // Embedded Descriptor
+    if extent_header.desc_offset.0 == 0 || extent_header.desc_size.0 == 0 {
+        let hint = "open the descriptor file of its disk instead, or the extent alone with ExtentReader::open_sparse";
+        return Err(VmdkError::MissingDescriptor(hint.to_owned()).into());
+    }
+    let file_len = file.size()?;
+    let (desc_offset, desc_size_in_bytes) = extent_header.descriptor_area(file_len).map_err(VmdkError::Malformed)?;
+    file.seek(SeekFrom::Start(desc_offset))?;
+    let mut buf: Vec<u8> = vec![0u8; desc_size_in_bytes.try_into()?];
+    file.read_exact(&mut buf)?;
+
+    Ok((Some(extent_header), buf, warnings))
+}
+
+/// Let `rewrite` change the extent names and parent hint of the descriptor
+/// of the disk at `path`, without opening the disk, writing them back into
+/// the descriptor text if it returns `true`. Returns the descriptor and
+/// whether it was written.
+pub(crate) fn rewrite_descriptor_file<F>(path: &Path, rewrite: F) -> Result<(Descriptor, bool), Error>
+where
+    F: FnOnce(&mut Descriptor) -> bool,
+{
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    lock::lock_file(&file, path, false)?;
+    let (header, bytes, _) = read_descriptor(&mut file, Strictness::Lenient)?;
+    let text = descriptor::decode(&bytes);
+    let mut desc = Descriptor::new(text.trim_end_matches(char::from(0)))?;
+    if !rewrite(&mut desc) {
+        return Ok((desc, false));
+    }
+
+    let new_text = desc.rewrite_text(&text)?;
+    let new_bytes = descriptor::encode_replacing(&new_text, &text, &bytes)?;
+    match &header {
+        Some(header) => extent::write_embedded_descriptor(&mut file, header, &new_bytes)?,
+        None => {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&new_bytes)?;
+            file.sync_data()?;
+        }
+    }
+    Ok((desc, true))
+}
+
+/// Largest file considered a text descriptor when looking for children;
+/// anything bigger without a sparse header is a flat extent
+const MAX_TEXT_DESCRIPTOR: u64 = 1 << 20;
+
+/// Delta disks next to `path` whose descriptor names it, with content ID
+/// `cid`, as their parent
+pub fn find_children(path: &Path, cid: u32) -> Result<Vec<PathBuf>, Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut children = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let sibling = entry?.path();
+        if sibling.file_name() == Some(name)
+            || sibling.extension().map(|ext| ext != "vmdk").unwrap_or(true)
+        {
+            continue;
+        }
+        let desc = match read_sibling_descriptor(&sibling) {
+            Some(desc) => desc,
+            None => continue,
+        };
+        let hint = desc.parent_file_name_hint.as_deref().map(path::normalize_separators);
+        if desc.parent_cid == cid && hint.map(|h| h.file_name() == Some(name)).unwrap_or(true) {
+            children.push(sibling);
+        }
+    }
+
+    children.sort();
+    Ok(children)
+}
+
+/// The descriptor next to the extent file at `path` that names it
+fn find_descriptor_of(path: &Path) -> Option<PathBuf> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name()?;
+    let mut found: Vec<PathBuf> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|sibling| sibling.file_name() != Some(name) && sibling.extension().is_some_and(|ext| ext == "vmdk"))
+        .filter(|sibling| {
+            let desc = match read_sibling_descriptor(sibling) {
+                Some(desc) => desc,
+                None => return false,
+            };
+            let names = desc.extents.iter().filter_map(|e| e.filename.as_deref());
+            names.map(path::normalize_separators).any(|f| f.file_name() == Some(name))
+        })
+        .collect();
+    found.sort();
+    found.into_iter().next()
+}
+
+/// Descriptor of a file that may or may not be a disk, `None` if unreadable
+fn read_sibling_descriptor(path: &Path) -> Option<Descriptor> {
+    let mut file = File::open(path).ok()?;
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic).ok()?;
+    if u32::from_le_bytes(magic) != EXTENT_MAGIC && file.metadata().ok()?.len() > MAX_TEXT_DESCRIPTOR {
+        return None;
+    }
+    let (_, bytes, _) = read_descriptor(&mut file, Strictness::Lenient).ok()?;
+    Descriptor::new(&descriptor::decode(&bytes)).ok()
+}
+
+impl Vmdk {
+    // TODO: make the input generic over R: Read
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        VmdkOpenOptions::new().open(path)
+    }
+
+    /// Size of the virtual disk in bytes
+    pub fn size(&self) -> u64 {
+        self.descriptor.capacity() * SECTOR_SIZE
+    }
+
+    /// Size in bytes of the sectors `read_sectors` and `write_sectors`
+    /// address, see `Descriptor::logical_sector_size`
+    pub fn logical_sector_size(&self) -> u64 {
+        self.descriptor.logical_sector_size()
+    }
+
+    /// See `Descriptor::physical_sector_size`
+    pub fn physical_sector_size(&self) -> u64 {
+        self.descriptor.physical_sector_size()
+    }
+
+    /// Oddities found when opening this disk that did not stop it from
+    /// opening, such as deviations from the specification accepted by
+    /// `Strictness::Lenient` or a parent with an unexpected CID. Each was
+    /// also logged. Those of parents are listed by the parents.
+    pub fn warnings(&self) -> &[VmdkWarning] {
+        &self.warnings
+    }
+
+    /// Key-bundle metadata if the disk uses VM Encryption
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.descriptor.encryption.as_ref()
+    }
+
+    /// Read from the virtual disk at byte `offset`, returning the number of
+    /// bytes read. Reads are short only at the end of the disk.
+    ///
+    /// Encrypted disks fail with `VmdkError::Encrypted` rather than returning
+    /// ciphertext.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.retry.take() {
+            Some(policy) => {
+                let result = policy.run(|| self.read_once(offset, buf));
+                self.retry = Some(policy);
+                result
+            }
+            None => self.read_once(offset, buf),
+        }
+    }
+
+    fn read_once(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.descriptor.encryption.is_some() {
+            return Err(VmdkError::Encrypted.into());
+        }
+        let size = self.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = std::cmp::min(buf.len() as u64, size - offset) as usize;
+        let mut done = 0;
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire(len as u64);
+        }
+
+        for extent in self.extents.iter_mut() {
+            let ext_start = extent.start * SECTOR_SIZE;
+            let ext_end = ext_start + extent.size();
+            let pos = offset + done as u64;
+            if done == len {
+                break;
+            }
+            if pos >= ext_end {
+                continue;
+            }
+
+            let n = std::cmp::min(ext_end - pos, (len - done) as u64) as usize;
+            extent.read_at(pos - ext_start, &mut buf[done..done + n], self.parent.as_deref_mut())?;
+            done += n;
+        }
+
+        Ok(done)
+    }
+
+    /// Granularity of allocation in bytes of the extent holding byte
+    /// `offset`, the default grain size past the end of the disk
+    pub(crate) fn grain_bytes_at(&self, offset: u64) -> Result<u64, Error> {
+        let extent = self.extents.iter().find(|e| offset < e.start * SECTOR_SIZE + e.size());
+        match extent {
+            Some(extent) => extent.grain_bytes(),
+            None => Ok(stream::DEFAULT_GRAIN_SIZE * SECTOR_SIZE),
+        }
+    }
+
+    /// Whether any of `len` bytes at `offset` is stored in this disk or one
+    /// of its parents, rather than reading as zeros
+    pub(crate) fn is_allocated(&mut self, offset: u64, len: u64) -> Result<bool, Error> {
+        let end = std::cmp::min(offset.saturating_add(len), self.size());
+        for extent in self.extents.iter_mut() {
+            let ext_start = extent.start * SECTOR_SIZE;
+            let ext_end = ext_start + extent.size();
+            let (start, stop) = (std::cmp::max(offset, ext_start), std::cmp::min(end, ext_end));
+            if start >= stop {
+                continue;
+            }
+
+            match extent.allocation(start - ext_start, stop - start)? {
+                Allocation::Data => return Ok(true),
+                Allocation::Unallocated => {
+                    if let Some(parent) = self.parent.as_mut() {
+                        if parent.is_allocated(start, stop - start)? {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Allocation::Zero => (),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Map which ranges of the disk hold data and which disk of the chain
+    /// stores them, like `qemu-img map`. Adjacent ranges with the same
+    /// state are merged.
+    pub fn map(&mut self) -> Result<Vec<MapEntry>, Error> {
+        let mut map: Vec<MapEntry> = Vec::new();
+        for i in 0..self.extents.len() {
+            let ext_start = self.extents[i].start * SECTOR_SIZE;
+            let ext_end = ext_start + self.extents[i].size();
+            let step = self.extents[i].grain_bytes()?;
+            let mut offset = ext_start;
+
+            while offset < ext_end {
+                let length = std::cmp::min(step - offset % step, ext_end - offset);
+                let (data, depth) = self.layer_of(offset, length)?;
+                match map.last_mut() {
+                    Some(last) if last.data == data && last.depth == depth => last.length += length,
+                    _ => map.push(MapEntry { offset, length, data, depth }),
+                }
+                offset += length;
+            }
+        }
+        Ok(map)
+    }
+
+    /// The byte offset in the disk whose data the byte at `offset` of the
+    /// extent file `file` holds, if any, looking through the parents too.
+    /// This is the reverse of `locate`, except that bytes of a compressed
+    /// grain map to the start of the grain. The data may be hidden by a
+    /// child in the chain.
+    pub fn physical_to_logical<P: AsRef<Path>>(&mut self, file: P, offset: u64) -> Result<Option<u64>, Error> {
+        let file = file.as_ref();
+        for extent in self.extents.iter_mut().filter(|e| e.path.as_deref() == Some(file)) {
+            if let Some(within) = extent.logical_offset(offset)? {
+                return Ok(Some(extent.start * SECTOR_SIZE + within));
+            }
+        }
+        match self.parent.as_mut() {
+            Some(parent) => parent.physical_to_logical(file, offset),
+            None => Ok(None),
+        }
+    }
+
+    /// The file and byte offset in it storing the byte at `offset` of the
+    /// disk, following the chain of parents. `None` if the byte reads as
+    /// zero without being stored, or lies in a compressed grain.
+    pub fn locate(&mut self, offset: u64) -> Result<Option<(PathBuf, u64)>, Error> {
+        let extent = self.extents.iter_mut().find(|e| {
+            let start = e.start * SECTOR_SIZE;
+            offset >= start && offset < start + e.size()
+        });
+        let extent = match extent {
+            Some(extent) => extent,
+            None => return Ok(None),
+        };
+        match extent.placement(offset - extent.start * SECTOR_SIZE)? {
+            Placement::File(pos) => Ok(extent.path.clone().map(|path| (path, pos))),
+            Placement::Compressed | Placement::Zero => Ok(None),
+            Placement::Unallocated => match self.parent.as_mut() {
+                Some(parent) => parent.locate(offset),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// The grain holding sector `lba`, borrowed from the memory map of the
+    /// extent file without copying it, for disks opened with
+    /// `VmdkOpenOptions::mmap`. Grains not stored in this disk are looked
+    /// up in its parents. `None` if the grain is not stored uncompressed in
+    /// a mapped file, such as zero or compressed grains, which `read_at`
+    /// reads instead.
+    #[cfg(feature = "mmap")]
+    pub fn read_grain_ref(&self, lba: u64) -> Result<Option<&[u8]>, Error> {
+        use extent::GrainRef;
+
+        let offset = lba.checked_mul(SECTOR_SIZE).filter(|&offset| offset < self.size());
+        let offset = offset.ok_or_else(|| VmdkError::InvalidArgument(format!("sector {} past the end", lba)))?;
+        let extent = self.extents.iter().find(|e| {
+            let start = e.start * SECTOR_SIZE;
+            offset >= start && offset < start + e.size()
+        });
+        let extent = match extent {
+            Some(extent) => extent,
+            None => return Ok(None),
+        };
+        match extent.grain_ref(offset - extent.start * SECTOR_SIZE)? {
+            GrainRef::Data(data) => Ok(Some(data)),
+            GrainRef::Unavailable => Ok(None),
+            GrainRef::Unallocated => match &self.parent {
+                Some(parent) if offset < parent.size() => parent.read_grain_ref(lba),
+                _ => Ok(None),
+            },
+        }
+    }
+
+    /// Whether `len` bytes at `offset`, lying within one extent and grain,
+    /// hold data, and how deep in the chain that was decided
+    fn layer_of(&mut self, offset: u64, len: u64) -> Result<(bool, usize), Error> {
+        let extent = self.extents.iter_mut().find(|e| {
+            let start = e.start * SECTOR_SIZE;
+            offset >= start && offset < start + e.size()
+        });
+        let extent = match extent {
+            Some(extent) => extent,
+            None => return Ok((false, 0)),
+        };
+        let start = extent.start * SECTOR_SIZE;
+        match extent.allocation(offset - start, len)? {
+            Allocation::Data => Ok((true, 0)),
+            Allocation::Zero => Ok((false, 0)),
+            Allocation::Unallocated => match self.parent.as_mut() {
+                Some(parent) => parent.layer_of(offset, len).map(|(data, depth)| (data, depth + 1)),
+                None => Ok((false, 0)),
+            },
+        }
+    }
+
+    /// Grow the disk to `size` bytes, a multiple of its logical sector
+    /// size. The last extent grows to cover the new sectors, which read as
+    /// zeros, and its line in the descriptor and the geometry keys are
+    /// rewritten to match, so VMware still boots the disk. If rewriting the
+    /// descriptor fails, the extent is shrunk back.
+    ///
+    /// Only disks whose last extent is flat can be resized: the grain
+    /// directories of sparse extents are sized for their capacity, so
+    /// sparse and stream-optimized disks fail with
+    /// `VmdkError::InvalidArgument`. Disks with a parent cannot be resized,
+    /// and no disk can shrink.
+    pub fn resize(&mut self, size: u64) -> Result<(), Error> {
+        if self.parent.is_some() {
+            return Err(VmdkError::InvalidArgument("disks with a parent cannot be resized".to_owned()).into());
+        }
+        if !size.is_multiple_of(self.logical_sector_size()) {
+            return Err(VmdkError::InvalidArgument(format!("size {} is not a multiple of the sector size", size)).into());
+        }
+        if size == self.size() {
+            return Ok(());
+        }
+        let result = self.grow(size / SECTOR_SIZE);
+        self.audit(AuditOperation::Resize { size }, &result);
+        result?;
+        info!("Resized {} to {} bytes", self.path.display(), size);
+        Ok(())
+    }
+
+    fn grow(&mut self, capacity: u64) -> Result<(), Error> {
+        let mut desc = self.descriptor.clone();
+        desc.set_capacity(capacity)?;
+        let mut text = desc.rewrite_text(self.descriptor_text())?;
+        for (key, value) in desc.ddb.iter().filter(|(key, _)| key.starts_with("geometry.")) {
+            text = descriptor::set_value(&text, &format!("ddb.{}", key), &format!("\"{}\"", value))?;
+        }
+
+        let last = desc.extents.last().ok_or(VmdkError::ParseError)?;
+        let extent = self.extents.last_mut().ok_or(VmdkError::ParseError)?;
+        let sectors = extent.descriptor.sectors;
+        let extended = extent.grow(last.sectors)?;
+        if let Err(e) = self.replace_descriptor(text) {
+            let extent = self.extents.last_mut().ok_or(VmdkError::ParseError)?;
+            if let Err(undo) = extent.shrink(sectors, extended) {
+                warn!("Could not shrink {} back: {}", self.path.display(), undo);
+            }
+            return Err(e);
+        }
+        self.descriptor = desc;
+        Ok(())
+    }
+
+    /// Deallocate grains of this disk that hold only zeros, returning how
+    /// many were found. The contents of the disk do not change; the space
+    /// they took in the extent files is only reclaimed when opened with
+    /// `VmdkOpenOptions::punch_holes`.
+    pub fn sparsify(&mut self) -> Result<u64, Error> {
+        self.sparsify_with(&CancelToken::new())
+    }
+
+    /// Like `sparsify`, but stops with `VmdkError::Cancelled` once `cancel`
+    /// is cancelled. Grains are dropped one at a time, so the disk stays
+    /// consistent with only part of its zero grains deallocated.
+    pub fn sparsify_with(&mut self, cancel: &CancelToken) -> Result<u64, Error> {
+        let has_parent = self.parent.is_some();
+        let mut dropped = 0;
+        let mut result = Ok(());
+        for extent in self.extents.iter_mut() {
+            match extent.sparsify(has_parent, cancel) {
+                Ok(n) => dropped += n,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if dropped > 0 || result.is_err() {
+            self.audit(AuditOperation::Sparsify { grains: dropped }, &result);
+        }
+        result?;
+        info!("Deallocated {} zero grains", dropped);
+        Ok(dropped)
+    }
+
+    /// Pace further reads and writes with `throttle`, or stop pacing them
+    pub fn set_throttle(&mut self, throttle: Option<Throttle>) {
+        self.throttle = throttle;
+    }
+
+    /// Close the disk, ignoring errors, and delete its own files, leaving
+    /// any parent alone. Used to clean up disks whose creation failed.
+    pub(crate) fn remove(mut self) {
+        self.parent = None;
+        let files = self.component_files();
+        let provider = self.storage.clone();
+        let _ = self.close();
+        for file in &files {
+            let _ = match &provider {
+                Some(provider) => provider.remove(file),
+                None => std::fs::remove_file(file).map_err(Error::from),
+            };
+        }
+    }
+
+    /// Open `path`, a file of this disk, anew and read-only from where the
+    /// disk was opened
+    pub(crate) fn open_component(&self, path: &Path) -> Result<Box<dyn Storage>, Error> {
+        match &self.storage {
+            Some(provider) => provider.open(path, false),
+            None => Ok(Box::new(File::open(path)?)),
+        }
+    }
+
+    /// Flush written data and metadata of every extent to stable storage.
+    /// The extents stay marked dirty until `close`.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for extent in self.extents.iter_mut() {
+            extent.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush everything and mark the extents as cleanly closed. Writable
+    /// handles should always be closed explicitly so errors are not lost;
+    /// dropping one closes it on a best-effort basis and only logs failures.
+    pub fn close(mut self) -> Result<(), Error> {
+        for extent in self.extents.iter_mut() {
+            extent.close()?;
+        }
+        Ok(())
+    }
+
+    /// Write to the virtual disk at byte `offset`, returning the number of
+    /// bytes written. Writes are short only at the end of the disk.
+    ///
+    /// The disk must have been opened with `VmdkOpenOptions::write`, and
+    /// should be finished with `close`.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+        let result = self.write_extents(offset, buf);
+        let length = match &result {
+            Ok(0) => return result,
+            Ok(n) => *n as u64,
+            Err(_) => buf.len() as u64,
+        };
+        self.audit(AuditOperation::Write { offset, length }, &result);
+        result
+    }
+
+    fn write_extents(&mut self, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+        if self.descriptor.encryption.is_some() {
+            return Err(VmdkError::Encrypted.into());
+        }
+        let size = self.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = std::cmp::min(buf.len() as u64, size - offset) as usize;
+        let mut done = 0;
+        // Nothing is written unless every extent in range, ZERO ones
+        // included, can be
+        let end = offset + len as u64;
+        for extent in self.extents.iter().filter(|e| e.start * SECTOR_SIZE < end && e.start * SECTOR_SIZE + e.size() > offset) {
+            extent.check_writable()?;
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire(len as u64);
+        }
+
+        for i in 0..self.extents.len() {
+            let ext_start = self.extents[i].start * SECTOR_SIZE;
+            let ext_end = ext_start + self.extents[i].size();
+            let pos = offset + done as u64;
+            if done == len {
+                break;
+            }
+            if pos >= ext_end {
+                continue;
+            }
+
+            if !self.cid_updated {
+                self.set_cid(descriptor::new_cid(self.original_cid))?;
+                self.cid_updated = true;
+            }
+            let n = std::cmp::min(ext_end - pos, (len - done) as u64) as usize;
+            self.extents[i].write_at(pos - ext_start, &buf[done..done + n], self.parent.as_deref_mut())?;
+            done += n;
+        }
+
+        Ok(done)
+    }
+
+    /// Make `len` bytes at byte `offset` read as zeros, returning how many
+    /// bytes were zeroed, fewer past the end of the disk. Grains wholly
+    /// covered are deallocated, or marked zero over a parent, so only
+    /// metadata is written for them.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64) -> Result<u64, Error> {
+        let result = self.zero_extents(offset, len);
+        let length = match &result {
+            Ok(0) => return result,
+            Ok(n) => *n,
+            Err(_) => len,
+        };
+        self.audit(AuditOperation::WriteZeroes { offset, length }, &result);
+        result
+    }
+
+    fn zero_extents(&mut self, offset: u64, len: u64) -> Result<u64, Error> {
+        if self.descriptor.encryption.is_some() {
+            return Err(VmdkError::Encrypted.into());
+        }
+        let size = self.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let end = offset + std::cmp::min(len, size - offset);
+        let in_range = |e: &&mut extent::Extent| e.start * SECTOR_SIZE < end && e.start * SECTOR_SIZE + e.size() > offset;
+        for extent in self.extents.iter_mut().filter(in_range) {
+            extent.check_writable()?;
+        }
+        if !self.cid_updated && end > offset {
+            self.set_cid(descriptor::new_cid(self.original_cid))?;
+            self.cid_updated = true;
+        }
+
+        for extent in self.extents.iter_mut().filter(in_range) {
+            let ext_start = extent.start * SECTOR_SIZE;
+            let from = std::cmp::max(offset, ext_start) - ext_start;
+            let to = std::cmp::min(end, ext_start + extent.size()) - ext_start;
+            extent.write_zeroes(from, to - from, self.parent.as_deref_mut())?;
+        }
+        Ok(end - offset)
+    }
+
+    /// Read whole logical sectors starting at sector `lba`, returning the
+    /// number of sectors read. `buf` must hold a whole number of sectors of
+    /// `logical_sector_size` bytes.
+    pub fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let sector_size = self.logical_sector_size();
+        let offset = self.sector_offset(lba, buf.len())?;
+        Ok(self.read_at(offset, buf)? / sector_size as usize)
+    }
+
+    /// Write whole logical sectors starting at sector `lba`, returning the
+    /// number of sectors written, see `read_sectors`
+    pub fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<usize, Error> {
+        let sector_size = self.logical_sector_size();
+        let offset = self.sector_offset(lba, buf.len())?;
+        Ok(self.write_at(offset, buf)? / sector_size as usize)
+    }
+
+    /// Byte offset of logical sector `lba`, checking `len` is a whole
+    /// number of sectors
+    fn sector_offset(&self, lba: u64, len: usize) -> Result<u64, Error> {
+        let sector_size = self.logical_sector_size();
+        if !(len as u64).is_multiple_of(sector_size) {
+            return Err(VmdkError::InvalidArgument(format!("{} bytes is not a whole number of {} byte sectors", len, sector_size)).into());
+        }
+        let offset = lba.checked_mul(sector_size);
+        Ok(offset.ok_or_else(|| VmdkError::InvalidArgument(format!("sector {} past the end", lba)))?)
+    }
+
+    /// Every file making up the disk: its descriptor, extents and change
+    /// tracking file, followed by those of each parent. This is what a
+    /// backup needs to copy for a complete image.
+    pub fn component_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.path.clone()];
+        let extents = self.extents.iter().filter_map(|e| e.path.as_ref());
+        for file in extents.chain(self.ctk_path.as_ref()) {
+            if !files.contains(file) {
+                files.push(file.clone());
+            }
+        }
+        if let Some(parent) = &self.parent {
+            files.extend(parent.component_files());
+        }
+        files
+    }
+
+    /// Rewrite the descriptors of the disk at `path` and of its parents
+    /// after their directory was moved from `old_base` to `new_base`, so
+    /// absolute extent and parent paths point to the new location. The disk
+    /// is not opened, as its extents usually cannot be found before this.
+    /// Returns the files that were rewritten.
+    pub fn relocate<P, Q, R>(path: P, old_base: Q, new_base: R) -> Result<Vec<PathBuf>, Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        R: AsRef<Path>,
+    {
+        let mut rewritten = Vec::new();
+        let mut next = Some(path.as_ref().to_owned());
+
+        while let Some(path) = next.take() {
+            let (desc, changed) = rewrite_descriptor_file(&path, |desc| desc.rewrite_paths(old_base.as_ref(), new_base.as_ref()))?;
+            if changed {
+                rewritten.push(path.clone());
+            }
+
+            if let Some(hint) = &desc.parent_file_name_hint {
+                let parent = DefaultResolver.resolve(&path, hint);
+                if parent.exists() {
+                    next = Some(parent);
+                } else {
+                    warn!("Parent {} of {} not found", parent.display(), path.display());
+                }
+            }
+        }
+
+        Ok(rewritten)
+    }
+
+    /// File holding the descriptor
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The disk this one is a delta of, if any
+    pub fn parent(&self) -> Option<&Vmdk> {
+        self.parent.as_deref()
+    }
+
+    /// Current content ID. It changes on the first write after opening, so
+    /// delta disks still pointing at the old value can tell their parent
+    /// was modified.
+    pub fn cid(&self) -> u32 {
+        self.descriptor.cid
+    }
+
+    /// The extents of this disk in logical order, without those of its
+    /// parents
+    pub fn extents(&self) -> impl Iterator<Item = ExtentHandle<'_>> {
+        self.extents.iter().map(ExtentHandle::new)
+    }
+
+    /// Summary of the metadata of this disk
+    pub fn info(&self) -> VmdkInfo {
+        VmdkInfo {
+            path: self.path.clone(),
+            size: self.size(),
+            create_type: self.descriptor.create_type.clone(),
+            cid: self.descriptor.cid,
+            parent_cid: self.descriptor.parent_cid,
+            parent: self.parent.as_ref().map(|p| p.path.clone()),
+            extent_header: self.extent_header.clone(),
+            extents: self.descriptor.extents.clone(),
+            ddb: self.descriptor.ddb.clone(),
+        }
+    }
+
+    /// Content ID the disk had when it was opened
+    pub fn original_cid(&self) -> u32 {
+        self.original_cid
+    }
+
+    /// Persist `cid` in the descriptor, before any data it covers changes
+    fn set_cid(&mut self, cid: u32) -> Result<(), Error> {
+        let text = descriptor::set_value(self.descriptor_text(), "CID", &format!("{:08x}", cid))?;
+        self.write_descriptor(text)?;
+        info!("CID changed from {:08x} to {:08x}", self.descriptor.cid, cid);
+        self.descriptor.cid = cid;
+        Ok(())
+    }
+
+    /// The descriptor text exactly as read when the disk was opened,
+    /// including the NUL padding of an embedded descriptor, or as last
+    /// rewritten by this handle
+    pub fn raw_descriptor(&self) -> &str {
+        &self.raw_descriptor
+    }
+
+    /// The bytes of `raw_descriptor` in the encoding they are stored in,
+    /// which may not be UTF-8, see `descriptor::decode`
+    pub fn raw_descriptor_bytes(&self) -> &[u8] {
+        &self.raw_descriptor_bytes
+    }
+
+    /// Report `operation` to the audit sink, if any
+    pub(crate) fn audit<T>(&self, operation: AuditOperation, result: &Result<T, Error>) {
+        audit::record(self.audit.as_deref(), &self.path, operation, result);
+    }
+
+    /// The descriptor text without padding
+    fn descriptor_text(&self) -> &str {
+        self.raw_descriptor.trim_matches(char::from(0))
+    }
+
+    /// Replace the on-disk descriptor with `text`, in the encoding it
+    /// declares
+    fn write_descriptor(&mut self, text: String) -> Result<(), Error> {
+        let result = self.replace_descriptor(text);
+        self.audit(AuditOperation::Descriptor, &result);
+        result
+    }
+
+    fn replace_descriptor(&mut self, text: String) -> Result<(), Error> {
+        let bytes = descriptor::encode_replacing(&text, &self.raw_descriptor, &self.raw_descriptor_bytes)?;
+        match &mut self.desc_file {
+            Some(file) => {
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&bytes)?;
+                file.sync_data()?;
+            }
+            None => self.extents[0].write_embedded_descriptor(&bytes)?,
+        }
+        self.raw_descriptor = text;
+        self.raw_descriptor_bytes = bytes;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, write_chain, SparseImage, StreamImage};
+
+    #[test]
+    fn test_read_monolithic_sparse() {
+        let dir = scratch_dir("monolithic-sparse");
+        let path = dir.join("renamed.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(2, 0xab).grain(5, 0xcd);
+        std::fs::write(&path, image.build()).unwrap();
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert_eq!(vmdk.size(), 1024 * 512);
+        assert_eq!(vmdk.descriptor.extents[0].filename.as_deref(), Some("disk.vmdk"));
+        // The embedded descriptor keeps the padding of its sector
+        assert_eq!(vmdk.raw_descriptor().len(), 512);
+        assert!(vmdk.raw_descriptor().starts_with(image.descriptor.as_deref().unwrap()));
+
+        let mut buf = vec![0xffu8; 4 * 128 * 512];
+        assert_eq!(vmdk.read_at(128 * 512, &mut buf).unwrap(), buf.len());
+        assert!(buf[..128 * 512].iter().all(|&b| b == 0));
+        assert!(buf[128 * 512..256 * 512].iter().all(|&b| b == 0xab));
+        assert!(buf[256 * 512..].iter().all(|&b| b == 0));
+
+        let mut buf = [0u8; 1024];
+        assert_eq!(vmdk.read_at(1024 * 512 - 512, &mut buf).unwrap(), 512);
+        assert_eq!(vmdk.read_at(5 * 128 * 512 + 10, &mut buf[..4]).unwrap(), 4);
+        assert_eq!(&buf[..4], &[0xcd; 4]);
+    }
+
+    #[test]
+    fn test_read_stream_optimized() {
+        let dir = scratch_dir("stream-optimized");
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, StreamImage::new(1000, 128).grain(0, 0x77).grain(6, 0x66).build()).unwrap();
+
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert_eq!(vmdk.size(), 1000 * 512);
+        let mut buf = vec![0u8; 1000 * 512];
+        assert_eq!(vmdk.read_at(0, &mut buf).unwrap(), buf.len());
+        let grain = 128 * 512;
+        assert!(buf[..grain].iter().all(|&b| b == 0x77));
+        assert!(buf[grain..6 * grain].iter().all(|&b| b == 0));
+        assert!(buf[6 * grain..7 * grain].iter().all(|&b| b == 0x66));
+        assert!(buf[7 * grain..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_read_through_parent() {
+        let dir = scratch_dir("read-parent");
+        write_chain(&dir, 1024);
+
+        let mut vmdk = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let mut buf = vec![0u8; 4 * 128 * 512];
+        vmdk.read_at(0, &mut buf).unwrap();
+        let grain = 128 * 512;
+        assert!(buf[..grain].iter().all(|&b| b == 0));
+        assert!(buf[grain..2 * grain].iter().all(|&b| b == 0xb1));
+        assert!(buf[2 * grain..3 * grain].iter().all(|&b| b == 0xc2));
+        assert!(buf[3 * grain..].iter().all(|&b| b == 0));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap() {
+        let dir = scratch_dir("mmap");
+        write_chain(&dir, 1024);
+        // Safety: nothing else touches the scratch directory
+        let mut vmdk = unsafe { VmdkOpenOptions::new().mmap(true) }.open(dir.join("child.vmdk")).unwrap();
+        let grain = 128 * 512;
+        assert_eq!(vmdk.read_grain_ref(128).unwrap().unwrap(), &vec![0xb1; grain][..]);
+        assert_eq!(vmdk.read_grain_ref(2 * 128 + 5).unwrap().unwrap(), &vec![0xc2; grain][..]);
+        assert!(vmdk.read_grain_ref(0).unwrap().is_none());
+        assert!(vmdk.read_grain_ref(1024).is_err());
+
+        let mut buf = vec![0u8; 2 * grain];
+        vmdk.read_at(grain as u64, &mut buf).unwrap();
+        assert!(buf[..grain].iter().all(|&b| b == 0xb1));
+        assert!(buf[grain..].iter().all(|&b| b == 0xc2));
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let dir = scratch_dir("copy-on-write");
+        write_chain(&dir, 1024);
+        let base_before = std::fs::read(dir.join("base.vmdk")).unwrap();
+        let grain = 128 * 512;
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(dir.join("child.vmdk")).unwrap();
+        // Partial write to a grain only the parent holds, spanning into an
+        // unallocated one, and a write to a grain the child already holds
+        assert_eq!(vmdk.write_at(2 * grain as u64 - 100, &[0x11; 200]).unwrap(), 200);
+        assert_eq!(vmdk.write_at(2 * grain as u64 + 5, &[0x22; 10]).unwrap(), 10);
+        drop(vmdk);
+
+        assert_eq!(std::fs::read(dir.join("base.vmdk")).unwrap(), base_before);
+
+        let mut vmdk = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let mut buf = vec![0u8; 4 * grain];
+        vmdk.read_at(0, &mut buf).unwrap();
+        let mut expected = vec![0u8; 4 * grain];
+        expected[grain..2 * grain].iter_mut().for_each(|b| *b = 0xb1);
+        expected[2 * grain..3 * grain].iter_mut().for_each(|b| *b = 0xc2);
+        expected[2 * grain - 100..2 * grain + 100].iter_mut().for_each(|b| *b = 0x11);
+        expected[2 * grain + 5..2 * grain + 15].iter_mut().for_each(|b| *b = 0x22);
+        assert_eq!(buf, expected);
+
+        // The copied grain no longer depends on the parent
+        vmdk.parent = None;
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert!(buf[grain..2 * grain - 100].iter().all(|&b| b == 0xb1));
+    }
+
+    #[test]
+    fn test_dirty_shutdown_flag() {
+        let dir = scratch_dir("dirty-shutdown");
+        write_chain(&dir, 1024);
+        let path = dir.join("child.vmdk");
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap()[72], 0);
+        vmdk.write_at(0, &[1u8; 512]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap()[72], 1);
+        vmdk.flush().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap()[72], 1);
+        vmdk.close().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap()[72], 0);
+
+        // Dropping a written handle still closes it
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        vmdk.write_at(4096, &[2u8; 512]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap()[72], 1);
+        drop(vmdk);
+        assert_eq!(std::fs::read(&path).unwrap()[72], 0);
+    }
+
+    #[test]
+    fn test_cid_changes_on_first_write() {
+        let dir = scratch_dir("cid-update");
+        write_chain(&dir, 1024);
+        let path = dir.join("child.vmdk");
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        assert_eq!(vmdk.cid(), 0x9abcdef0);
+        vmdk.write_at(0, &[1u8; 512]).unwrap();
+        let cid = vmdk.cid();
+        assert_ne!(cid, 0x9abcdef0);
+        assert_eq!(vmdk.original_cid(), 0x9abcdef0);
+        vmdk.write_at(512, &[1u8; 512]).unwrap();
+        assert_eq!(vmdk.cid(), cid);
+        vmdk.close().unwrap();
+
+        let vmdk = Vmdk::new(&path).unwrap();
+        assert_eq!(vmdk.cid(), cid);
+        assert_eq!(vmdk.parent.as_ref().unwrap().cid(), 0x12345678);
+
+        // Separate descriptor file, with a GBK comment kept byte for byte
+        std::fs::write(dir.join("flat-f001.vmdk"), vec![0u8; 1024]).unwrap();
+        std::fs::write(dir.join("flat.vmdk"), &b"# Disk DescriptorFile\n# \xb4\xc5\xc5\xcc\nversion=1\nCID=fffffffe\n\
+            parentCID=ffffffff\ncreateType=\"monolithicFlat\"\n\
+            RW 2 FLAT \"flat-f001.vmdk\" 0\n"[..]).unwrap();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(dir.join("flat.vmdk")).unwrap();
+        vmdk.write_at(0, &[1u8; 512]).unwrap();
+        let cid = vmdk.cid();
+        vmdk.close().unwrap();
+        let bytes = std::fs::read(dir.join("flat.vmdk")).unwrap();
+        assert!(bytes.starts_with(b"# Disk DescriptorFile\n# \xb4\xc5\xc5\xcc\n"));
+        let text = String::from_utf8_lossy(&bytes);
+        assert!(text.contains(&format!("\nCID={:08x}\n", cid)));
+        assert!(text.ends_with("RW 2 FLAT \"flat-f001.vmdk\" 0\n"));
+    }
+
+    #[test]
+    fn test_parent_with_children_refuses_writes() {
+        let dir = scratch_dir("parent-children");
+        write_chain(&dir, 1024);
+        let base = dir.join("base.vmdk");
+        assert_eq!(find_children(&base, 0x12345678).unwrap(), vec![dir.join("child.vmdk")]);
+        assert!(find_children(&dir.join("child.vmdk"), 0x9abcdef0).unwrap().is_empty());
+
+        let mut options = VmdkOpenOptions::new();
+        options.write(true);
+        match options.open(&base).err().unwrap().downcast::<VmdkError>() {
+            Ok(VmdkError::HasChildren(child)) => assert!(child.ends_with("child.vmdk")),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(Vmdk::new(&base).is_ok());
+        assert!(options.ignore_children(true).open(&base).is_ok());
+    }
+
+    #[test]
+    fn test_extents() {
+        use crate::descriptor::ExtentType;
+        let dir = scratch_dir("extents");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0xb1);
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let child = SparseImage::new(1024, 128).child("base.vmdk", 0x12345678).grain(2, 0xc2);
+        std::fs::write(dir.join("child.vmdk"), child.build()).unwrap();
+        let grain = 128 * 512;
+
+        let vmdk = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let extents: Vec<_> = vmdk.extents().collect();
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].extent_type(), ExtentType::Sparse);
+        assert_eq!(extents[0].path(), Some(dir.join("child.vmdk").as_path()));
+        assert_eq!(extents[0].sectors(), 0..1024);
+        assert_eq!(extents[0].header().unwrap().unwrap().grain_size.sectors(), 128);
+        assert_eq!(extents[0].allocated_bytes().unwrap(), grain);
+
+        // Only the data of the extent itself, not of the parent
+        let mut reader = extents[0].reader().unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 1024 * 512);
+        assert!(data[grain as usize..2 * grain as usize].iter().all(|&b| b == 0));
+        assert!(data[2 * grain as usize..3 * grain as usize].iter().all(|&b| b == 0xc2));
+        reader.seek(SeekFrom::Start(2 * grain - 1)).unwrap();
+        let mut buf = [0xffu8; 2];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0, 0xc2]);
+    }
+
+    #[test]
+    fn test_info() {
+        let dir = scratch_dir("info");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk");
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let child = SparseImage::new(1024, 128).child("base.vmdk", 0x12345678);
+        std::fs::write(dir.join("child.vmdk"), child.build()).unwrap();
+
+        let info = Vmdk::new(dir.join("child.vmdk")).unwrap().info();
+        assert_eq!(info.size, 1024 * 512);
+        assert_eq!(info.parent_cid, 0x12345678);
+        assert_eq!(info.parent, Some(dir.join("base.vmdk")));
+        assert_eq!(info.extents.len(), 1);
+        assert!(info.extent_header.is_some());
+        let text = info.to_string();
+        assert!(text.contains("512 KiB monolithicSparse"), "{}", text);
+        assert!(text.contains("flags: valid-newline-detection, redundant-gt\n"), "{}", text);
+        assert!(text.contains("(CID 12345678)"), "{}", text);
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(&info).unwrap();
+            assert_eq!(json["create_type"], "monolithicSparse");
+            assert_eq!(json["extent_header"]["grain_size"], 128);
+            let back: VmdkInfo = serde_json::from_value(json).unwrap();
+            assert_eq!(back.extents, info.extents);
+            assert_eq!(back.ddb, info.ddb);
+        }
+    }
+
+    #[test]
+    fn test_map() {
+        let dir = scratch_dir("map");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0xb1);
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let child = SparseImage::new(1024, 128).child("base.vmdk", 0x12345678).grain(2, 0xc2);
+        std::fs::write(dir.join("child.vmdk"), child.build()).unwrap();
+        let grain = 128 * 512;
+
+        let mut vmdk = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let entry = |offset, length, data, depth| MapEntry { offset, length, data, depth };
+        assert_eq!(
+            vmdk.map().unwrap(),
+            vec![
+                entry(0, grain, false, 1),
+                entry(grain, grain, true, 1),
+                entry(2 * grain, grain, true, 0),
+                entry(3 * grain, 5 * grain, false, 1),
+            ]
+        );
+        assert_eq!(vmdk.parent().unwrap().path(), dir.join("base.vmdk"));
+        // Grains are stored right after the metadata, which takes one grain
+        assert_eq!(vmdk.locate(2 * grain + 5).unwrap(), Some((dir.join("child.vmdk"), grain + 5)));
+        assert_eq!(vmdk.locate(grain).unwrap(), Some((dir.join("base.vmdk"), grain)));
+        assert_eq!(vmdk.locate(0).unwrap(), None);
+        assert_eq!(vmdk.physical_to_logical(dir.join("child.vmdk"), grain + 5).unwrap(), Some(2 * grain + 5));
+        assert_eq!(vmdk.physical_to_logical(dir.join("base.vmdk"), grain).unwrap(), Some(grain));
+        assert_eq!(vmdk.physical_to_logical(dir.join("base.vmdk"), 0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sparsify() {
+        let dir = scratch_dir("sparsify");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(0, 0).grain(1, 0xb1).grain(3, 0);
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let child = SparseImage::new(1024, 128).child("base.vmdk", 0x12345678).grain(1, 0).grain(2, 0xc2);
+        std::fs::write(dir.join("child.vmdk"), child.build()).unwrap();
+        let grain = 128 * 512;
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(dir.join("child.vmdk")).unwrap();
+        let before = {
+            let mut buf = vec![0u8; 4 * grain];
+            vmdk.read_at(0, &mut buf).unwrap();
+            buf
+        };
+        assert!(vmdk.is_allocated(0, grain as u64).unwrap());
+        assert!(Vmdk::new(dir.join("child.vmdk")).unwrap().sparsify().is_err());
+        // The zero grain of the child hides data of the parent
+        assert_eq!(vmdk.sparsify().unwrap(), 1);
+        vmdk.close().unwrap();
+
+        let mut vmdk = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        let mut buf = vec![0xffu8; 4 * grain];
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, before);
+        assert!(!vmdk.is_allocated(grain as u64, grain as u64).unwrap());
+
+        let mut base = VmdkOpenOptions::new().write(true).ignore_children(true).open(dir.join("base.vmdk")).unwrap();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert!(base.sparsify_with(&cancel).is_err());
+        assert!(base.is_allocated(0, grain as u64).unwrap());
+        assert_eq!(base.sparsify().unwrap(), 2);
+        assert!(!base.is_allocated(0, grain as u64).unwrap());
+        assert!(base.is_allocated(grain as u64, grain as u64).unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sparsify_punch_holes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = scratch_dir("sparsify-punch");
+        let path = dir.join("disk.vmdk");
+        let image = (0..16).fold(SparseImage::new(4096, 128).monolithic("disk.vmdk"), |image, i| image.grain(i, 0));
+        std::fs::write(&path, image.grain(16, 0xb1).build()).unwrap();
+        let blocks = std::fs::metadata(&path).unwrap().blocks();
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).punch_holes(true).open(&path).unwrap();
+        assert_eq!(vmdk.sparsify().unwrap(), 16);
+        vmdk.close().unwrap();
+
+        // Unless the filesystem of the scratch directory cannot punch holes,
+        // the 16 grains of 128 sectors are gone, give or take block rounding
+        let after = std::fs::metadata(&path).unwrap().blocks();
+        assert!(after <= blocks);
+        if after < blocks {
+            assert!(blocks - after >= 15 * 128, "{} -> {}", blocks, after);
+        }
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        let mut buf = vec![0xffu8; 17 * 128 * 512];
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert!(buf[..16 * 128 * 512].iter().all(|&b| b == 0));
+        assert!(buf[16 * 128 * 512..].iter().all(|&b| b == 0xb1));
+    }
+
+    #[test]
+    fn test_vmware_lock_refuses_writes() {
+        let dir = scratch_dir("vmware-lock-open");
+        write_chain(&dir, 1024);
+        let path = dir.join("child.vmdk");
+        let mut options = VmdkOpenOptions::new();
+        options.write(true).vmware_lock(true);
+
+        let vmdk = options.open(&path).unwrap();
+        assert!(lock::is_locked(&path));
+        assert!(options.open(&path).is_err());
+        // Readers are not affected
+        assert!(Vmdk::new(&path).is_ok());
+        vmdk.close().unwrap();
+        assert!(!lock::is_locked(&path));
+
+        std::fs::create_dir(lock::lock_dir(&path)).unwrap();
+        std::fs::write(lock::lock_dir(&path).join("M1234.lck"), "").unwrap();
+        assert!(options.open(&path).is_err());
+        assert!(options.force(true).open(&path).is_ok());
+    }
+
+    #[test]
+    fn test_advisory_lock_refuses_second_writer() {
+        let dir = scratch_dir("advisory-lock-open");
+        write_chain(&dir, 1024);
+        let path = dir.join("child.vmdk");
+        let mut options = VmdkOpenOptions::new();
+        options.write(true);
+
+        let vmdk = options.open(&path).unwrap();
+        assert!(options.open(&path).is_err());
+        assert!(options.force(true).open(&path).is_ok());
+        drop(vmdk);
+        assert!(options.force(false).open(&path).is_ok());
+    }
+
+    #[test]
+    fn test_read_only_refuses_writes() {
+        let dir = scratch_dir("read-only-writes");
+        write_chain(&dir, 1024);
+        let mut vmdk = Vmdk::new(dir.join("child.vmdk")).unwrap();
+        assert!(vmdk.write_at(0, &[1u8; 512]).is_err());
+    }
+
+    #[test]
+    fn test_read_flat_extents() {
+        let dir = scratch_dir("flat-extents");
+        std::fs::write(dir.join("disk-f001.vmdk"), vec![1u8; 1024]).unwrap();
+        std::fs::write(dir.join("disk-f002.vmdk"), vec![2u8; 2048]).unwrap();
+        std::fs::write(dir.join("disk.vmdk"), "version=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            createType=\"twoGbMaxExtentFlat\"\n\
+            RW 2 FLAT \"disk-f001.vmdk\" 0\n\
+            RW 2 FLAT \"disk-f002.vmdk\" 2\n").unwrap();
+
+        let mut vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        assert!(vmdk.extent_header.is_none());
+        let mut buf = [0u8; 2048];
+        assert_eq!(vmdk.read_at(0, &mut buf).unwrap(), 2048);
+        assert!(buf[..1024].iter().all(|&b| b == 1));
+        assert!(buf[1024..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn test_lazy_open() {
+        let dir = scratch_dir("lazy-open");
+        std::fs::write(dir.join("disk-f001.vmdk"), vec![1u8; 1024]).unwrap();
+        std::fs::write(dir.join("disk-f002.vmdk"), vec![2u8; 1024]).unwrap();
+        std::fs::write(dir.join("disk.vmdk"), "version=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            createType=\"twoGbMaxExtentFlat\"\n\
+            RW 2 FLAT \"disk-f001.vmdk\" 0\n\
+            RW 2 FLAT \"disk-f002.vmdk\" 0\n\
+            RW 2 FLAT \"missing.vmdk\" 0\n").unwrap();
+        assert!(Vmdk::new(dir.join("disk.vmdk")).is_err());
+
+        let mut options = VmdkOpenOptions::new();
+        options.max_open_files(1);
+        let mut vmdk = options.open(dir.join("disk.vmdk")).unwrap();
+        let mut buf = [0u8; 2048];
+        for _ in 0..2 {
+            assert_eq!(vmdk.read_at(0, &mut buf).unwrap(), 2048);
+            assert!(buf[..1024].iter().all(|&b| b == 1));
+            assert!(buf[1024..].iter().all(|&b| b == 2));
+        }
+        assert!(vmdk.read_at(2048, &mut buf).is_err());
+
+        write_chain(&dir, 1024);
+        let mut vmdk = options.open(dir.join("child.vmdk")).unwrap();
+        let mut buf = vec![0u8; 4 * 128 * 512];
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert!(buf[128 * 512..2 * 128 * 512].iter().all(|&b| b == 0xb1));
+    }
+
+    #[test]
+    fn test_large_disk_offsets() {
+        use proptest::prelude::*;
+        use proptest::test_runner::{Config, TestRunner};
+
+        // 3 TiB, with 4 MiB grains to keep the grain tables small
+        let dir = scratch_dir("large-disk");
+        let path = dir.join("disk.vmdk");
+        let capacity = 3u64 << 31;
+        std::fs::write(&path, SparseImage::new(capacity, 8192).monolithic("disk.vmdk").build()).unwrap();
+        let mut options = VmdkOpenOptions::new();
+        options.write(true);
+        let vmdk = std::cell::RefCell::new(options.open(&path).unwrap());
+        assert_eq!(vmdk.borrow().size(), 3 << 40);
+
+        let offsets = prop_oneof![(4u64 << 30)..(5u64 << 30), (2u64 << 40)..(3u64 << 40) - 4096];
+        let mut runner = TestRunner::new(Config { cases: 8, ..Config::default() });
+        runner
+            .run(&(offsets, any::<u8>()), |(offset, byte)| {
+                let data = [byte; 4096];
+                let mut vmdk = vmdk.borrow_mut();
+                prop_assert_eq!(vmdk.write_at(offset, &data).unwrap(), 4096);
+                let mut buf = [0u8; 4096];
+                prop_assert_eq!(vmdk.read_at(offset, &mut buf).unwrap(), 4096);
+                prop_assert_eq!(&buf[..], &data[..]);
+                Ok(())
+            })
+            .unwrap();
+
+        let mut vmdk = vmdk.into_inner();
+        let mut buf = [0u8; 512];
+        assert_eq!(vmdk.read_at((3 << 40) - 256, &mut buf).unwrap(), 256);
+        assert_eq!(vmdk.read_at(u64::MAX - 1, &mut buf).unwrap(), 0);
+        vmdk.close().unwrap();
+    }
+
+    #[test]
+    fn test_header_geometry() {
+        let image = SparseImage::new(1024, 128).build();
+        assert!(ExtentHeader::new(&image[..]).is_ok());
+        let mut zero_grains = image.clone();
+        zero_grains[20..28].copy_from_slice(&0u64.to_le_bytes());
+        assert!(ExtentHeader::new(&zero_grains[..]).is_err());
+        let mut huge = image.clone();
+        huge[12..20].copy_from_slice(&(u64::MAX / 256).to_le_bytes());
+        assert!(ExtentHeader::new(&huge[..]).is_err());
+
+        let descriptor = format!("version=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            createType=\"monolithicFlat\"\nRW {} FLAT \"disk-flat.vmdk\" 0\n", u64::MAX / 256);
+        assert!(Descriptor::new(&descriptor).is_err());
+    }
+
+    #[test]
+    fn test_windows_descriptor() {
+        let dir = scratch_dir("windows-descriptor");
+        std::fs::create_dir(dir.join("Disks")).unwrap();
+        std::fs::write(dir.join("Disks").join("disk-flat.vmdk"), vec![3u8; 1024]).unwrap();
+        std::fs::write(dir.join("disk-f002.vmdk"), vec![4u8; 1024]).unwrap();
+        // An absolute path from a Windows host is found next to the
+        // descriptor elsewhere, and taken as it is on Windows
+        #[cfg(not(windows))]
+        let second = r"D:\Virtual Machines\disk-f002.vmdk".to_owned();
+        #[cfg(windows)]
+        let second = dir.join("disk-f002.vmdk").display().to_string();
+        std::fs::write(dir.join("disk.vmdk"), format!("# Disk DescriptorFile\r\nversion=1\r\nCID=fffffffe\r\n\
+            parentCID=ffffffff\r\ncreateType=\"twoGbMaxExtentFlat\"\r\n\r\n\
+            RW 2 FLAT \"Disks\\disk-flat.vmdk\" 0\r\n\
+            RW 2 FLAT \"{}\" 0\r\n\r\n\
+            ddb.adapterType = \"lsilogic\"\r\n", second)).unwrap();
+
+        let mut options = VmdkOpenOptions::new();
+        options.write(true);
+        let mut vmdk = options.open(dir.join("disk.vmdk")).unwrap();
+        assert_eq!(vmdk.descriptor.ddb.get("adapterType"), Some("lsilogic"));
+        let mut buf = [0u8; 2048];
+        assert_eq!(vmdk.read_at(0, &mut buf).unwrap(), 2048);
+        assert!(buf[..1024].iter().all(|&b| b == 3) && buf[1024..].iter().all(|&b| b == 4));
+        vmdk.write_at(1024, &[5u8; 512]).unwrap();
+        vmdk.close().unwrap();
+
+        let text = std::fs::read_to_string(dir.join("disk.vmdk")).unwrap();
+        assert!(!text.contains("CID=fffffffe"));
+        assert_eq!(text.matches("\r\n").count(), text.matches('\n').count());
+        assert_eq!(std::fs::read(dir.join("disk-f002.vmdk")).unwrap()[..512], [5u8; 512]);
+    }
+
+    #[test]
+    fn test_non_utf8_descriptor() {
+        let dir = scratch_dir("non-utf8-descriptor");
+        // Garbage after the embedded descriptor, instead of NUL padding
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        let end = image[512..1024].iter().position(|&b| b == 0).unwrap() + 512;
+        image[end..end + 4].copy_from_slice(&[0xff, 0xfe, 0x80, 0x0a]);
+        std::fs::write(dir.join("disk.vmdk"), &image).unwrap();
+        let mut vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        assert_eq!(vmdk.raw_descriptor_bytes(), &image[512..1024]);
+        let mut buf = [0u8; 512];
+        vmdk.read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+
+        // Latin-1 comments are kept as they were through a rewrite
+        std::fs::write(dir.join("flat-flat.vmdk"), vec![0u8; 1024]).unwrap();
+        let text = b"# Disk DescriptorFile\nencoding=\"windows-1252\"\n# Disque cr\xe9\xe9\n\
+            version=1\nCID=fffffffe\nparentCID=ffffffff\ncreateType=\"monolithicFlat\"\n\
+            RW 2 FLAT \"flat-flat.vmdk\" 0\n";
+        std::fs::write(dir.join("flat.vmdk"), &text[..]).unwrap();
+        let mut options = VmdkOpenOptions::new();
+        options.write(true);
+        let mut vmdk = options.open(dir.join("flat.vmdk")).unwrap();
+        assert!(vmdk.raw_descriptor().contains("Disque cr\u{e9}\u{e9}"));
+        vmdk.write_at(0, &[1; 512]).unwrap();
+        vmdk.close().unwrap();
+        let written = std::fs::read(dir.join("flat.vmdk")).unwrap();
+        assert_eq!(written.len(), text.len());
+        assert!(written.windows(5).any(|w| w == b"cr\xe9\xe9\n"));
+        assert!(!written.windows(8).any(|w| w == b"fffffffe"));
+    }
+
+    #[test]
+    fn test_moved_descriptor() {
+        let dir = scratch_dir("descriptor-offset");
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+
+        // Move the descriptor into the unused sectors before the first grain
+        let mut moved = image.clone();
+        moved[100 * 512..101 * 512].copy_from_slice(&image[512..1024]);
+        moved[512..1024].fill(0);
+        moved[28..36].copy_from_slice(&100u64.to_le_bytes());
+        std::fs::write(&path, &moved).unwrap();
+        let vmdk = Vmdk::new(&path).unwrap();
+        assert_eq!(vmdk.descriptor.cid, 0x12345678);
+        assert_eq!(vmdk.raw_descriptor_bytes(), &image[512..1024]);
+
+        let mut none = image.clone();
+        none[28..36].fill(0);
+        std::fs::write(&path, &none).unwrap();
+        let err = Vmdk::new(&path).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(VmdkError::MissingDescriptor(_))));
+
+        let mut past_end = image.clone();
+        past_end[36..44].copy_from_slice(&1000u64.to_le_bytes());
+        std::fs::write(&path, &past_end).unwrap();
+        assert!(Vmdk::new(&path).is_err());
+    }
+
+    #[test]
+    fn test_resize() {
+        let dir = scratch_dir("resize");
+        let path = dir.join("disk.vmdk");
+        let mut builder = crate::create::VmdkBuilder::new(4 << 20);
+        builder.create_type(DiskType::MonolithicFlat);
+        let mut vmdk = builder.create(&path).unwrap();
+        vmdk.write_at(0, &[0xb1; 512]).unwrap();
+        assert!(vmdk.resize(1 << 20).is_err());
+        assert!(vmdk.resize((3 << 29) + 1).is_err());
+        vmdk.resize(3 << 29).unwrap();
+        vmdk.write_at((3 << 29) - 512, &[0xb2; 512]).unwrap();
+        vmdk.close().unwrap();
+
+        assert_eq!(std::fs::metadata(dir.join("disk-flat.vmdk")).unwrap().len(), 3 << 29);
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        assert_eq!(vmdk.size(), 3 << 29);
+        assert!(vmdk.raw_descriptor().contains("\nRW 3145728 FLAT \"disk-flat.vmdk\" 0\n"));
+        let ddb = &vmdk.descriptor.ddb;
+        assert_eq!(ddb.geometry(), Some(descriptor::Geometry { cylinders: 768, heads: 128, sectors: 32 }));
+        assert!(vmdk.warnings().is_empty());
+        let mut buf = [0u8; 1024];
+        vmdk.read_at((3 << 29) - 1024, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[1023]), (0, 0xb2));
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[1023]), (0xb1, 0));
+
+        // The flat file is shrunk back when the descriptor cannot be written
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        vmdk.desc_file = Some(Box::new(File::open(&path).unwrap()));
+        assert!(vmdk.resize(1 << 31).is_err());
+        assert_eq!(vmdk.size(), 3 << 29);
+        assert_eq!(vmdk.extents[0].size(), 3 << 29);
+        assert_eq!(std::fs::metadata(dir.join("disk-flat.vmdk")).unwrap().len(), 3 << 29);
+        drop(vmdk);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("\nRW 3145728 FLAT \"disk-flat.vmdk\" 0\n"));
+
+        // Sparse extents cannot grow
+        let sparse = dir.join("sparse.vmdk");
+        std::fs::write(&sparse, SparseImage::new(1024, 128).monolithic("sparse.vmdk").build()).unwrap();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&sparse).unwrap();
+        assert!(vmdk.resize(1 << 20).is_err());
+        assert_eq!(vmdk.size(), 1024 * 512);
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        let dir = scratch_dir("write-zeroes");
+        let grain = 128 * 512;
+        let gtes = |vmdk: &Vmdk| vmdk.extents().next().unwrap().grain_table(0).unwrap().unwrap()[..4].to_vec();
+        let read = |vmdk: &mut Vmdk| {
+            let mut buf = vec![0xffu8; 4 * grain];
+            vmdk.read_at(0, &mut buf).unwrap();
+            buf
+        };
+
+        // Without a parent whole grains are dropped and partial ones are
+        // written in place
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).grain(2, 0xb2).grain(3, 0xb3);
+        std::fs::write(&path, image.build()).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        let before = gtes(&vmdk);
+        assert_eq!(vmdk.write_zeroes(grain as u64 + 10, 2 * grain as u64).unwrap(), 2 * grain as u64);
+        assert_eq!(gtes(&vmdk), [0, before[1], 0, before[3]]);
+        let mut expected = vec![0u8; 4 * grain];
+        expected[grain..grain + 10].iter_mut().for_each(|b| *b = 0xb1);
+        expected[3 * grain + 10..].iter_mut().for_each(|b| *b = 0xb3);
+        assert_eq!(read(&mut vmdk), expected);
+        assert_eq!(vmdk.write_zeroes(1024 * 512 - 100, 1000).unwrap(), 100);
+        assert_eq!(vmdk.write_zeroes(1024 * 512, 1000).unwrap(), 0);
+        vmdk.close().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        // Over a parent they become zero grains, hiding the parent's data
+        write_chain(&dir, 1024);
+        let path = dir.join("child.vmdk");
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        vmdk.write_zeroes(grain as u64, 2 * grain as u64).unwrap();
+        assert_eq!(gtes(&vmdk), [0, 1, 1, 0]);
+        assert!(read(&mut vmdk).iter().all(|&b| b == 0));
+        vmdk.close().unwrap();
+        // Partly covered grains the parent holds are copied up
+        write_chain(&dir, 1024);
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        vmdk.write_zeroes(grain as u64 + 100, 100).unwrap();
+        assert!(gtes(&vmdk)[1] > 1);
+        let mut expected = vec![0u8; 4 * grain];
+        expected[grain..2 * grain].iter_mut().for_each(|b| *b = 0xb1);
+        expected[grain + 100..grain + 200].iter_mut().for_each(|b| *b = 0);
+        expected[2 * grain..3 * grain].iter_mut().for_each(|b| *b = 0xc2);
+        assert_eq!(read(&mut vmdk), expected);
+
+        // Flat extents are written with zeros
+        let path = dir.join("flat.vmdk");
+        let mut builder = crate::create::VmdkBuilder::new(1 << 20);
+        builder.create_type(DiskType::MonolithicFlat);
+        let mut vmdk = builder.create(&path).unwrap();
+        vmdk.write_at(0, &vec![0xb1; 1 << 20]).unwrap();
+        assert_eq!(vmdk.write_zeroes(1000, 3 << 18).unwrap(), 3 << 18);
+        let mut buf = vec![0xffu8; 1 << 20];
+        vmdk.read_at(0, &mut buf).unwrap();
+        assert!(buf[..1000].iter().all(|&b| b == 0xb1) && buf[1000..1000 + (3 << 18)].iter().all(|&b| b == 0));
+        assert!(buf[1000 + (3 << 18)..].iter().all(|&b| b == 0xb1));
+
+        let mut vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        assert!(vmdk.write_zeroes(0, 512).is_err());
+    }
+
+    #[test]
+    fn test_extent_only() {
+        let dir = scratch_dir("extent-only");
+        let mut builder = crate::create::VmdkBuilder::new(1 << 20);
+        builder.create_type(DiskType::TwoGbMaxExtentSparse);
+        let mut vmdk = builder.create(dir.join("disk.vmdk")).unwrap();
+        vmdk.write_at(128 * 512, &[0xb1; 512]).unwrap();
+        vmdk.close().unwrap();
+
+        let data = dir.join("disk-s001.vmdk");
+        let err = Vmdk::new(&data).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(VmdkError::MissingDescriptor(_))));
+        assert!(err.to_string().contains("disk.vmdk instead"));
+
+        let mut extent = ExtentReader::open_sparse(&data).unwrap();
+        assert_eq!(extent.size(), 1 << 20);
+        assert_eq!(extent.header().unwrap().unwrap().grain_size.0, 128);
+        let mut buf = [0u8; 512];
+        extent.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0; 512]);
+        extent.seek(SeekFrom::Start(128 * 512)).unwrap();
+        extent.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+    }
+
+    #[test]
+    fn test_redundant_gd() {
+        let dir = scratch_dir("redundant-gd");
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        assert!(header.has_redundant_gd());
+
+        // Point the primary grain table at metadata instead of the grain
+        let gt = LittleEndian::read_u32(&image[header.gd_offset.bytes() as usize..]) as usize;
+        let mut broken = image.clone();
+        broken[gt * 512 + 4..gt * 512 + 8].copy_from_slice(&2u32.to_le_bytes());
+        std::fs::write(&path, &broken).unwrap();
+        let mut buf = [0u8; 512];
+        let err = Vmdk::new(&path).unwrap().read_at(128 * 512, &mut buf).err().unwrap();
+        match err.downcast::<VmdkError>() {
+            Ok(VmdkError::CorruptGrain { grain: 1, sector: 2, .. }) => (),
+            other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+        }
+        let mut options = VmdkOpenOptions::new();
+        options.redundant_gd(true);
+        options.open(&path).unwrap().read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+        assert!(options.clone().write(true).open(&path).is_err());
+
+        // Without a primary directory the redundant one is used if flagged
+        let mut missing = image.clone();
+        missing[56..64].fill(0);
+        std::fs::write(&path, &missing).unwrap();
+        Vmdk::new(&path).unwrap().read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+        missing[8] &= !(FLAG_USE_REDUNDANT_GT as u8);
+        std::fs::write(&path, &missing).unwrap();
+        assert!(options.open(&path).is_err());
+    }
+
+    #[test]
+    fn test_custom_path_resolver() {
+        #[derive(Debug)]
+        struct Moved(PathBuf);
+        impl PathResolver for Moved {
+            fn resolve(&self, _: &Path, name: &str) -> PathBuf {
+                let name = path::normalize_separators(name);
+                self.0.join(name.file_name().unwrap())
+            }
+        }
+
+        let dir = scratch_dir("path-resolver");
+        std::fs::create_dir(dir.join("moved")).unwrap();
+        std::fs::write(dir.join("moved").join("disk-flat.vmdk"), vec![7u8; 1024]).unwrap();
+        std::fs::write(dir.join("disk.vmdk"), "version=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            createType=\"monolithicFlat\"\n\
+            RW 2 FLAT \"C:\\VMs\\Old\\disk-flat.vmdk\" 0\n").unwrap();
+
+        assert!(Vmdk::new(dir.join("disk.vmdk")).is_err());
+        let mut vmdk = VmdkOpenOptions::new().path_resolver(Moved(dir.join("moved"))).open(dir.join("disk.vmdk")).unwrap();
+        let mut buf = [0u8; 1024];
+        assert_eq!(vmdk.read_at(0, &mut buf).unwrap(), 1024);
+        assert!(buf.iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn test_relocate() {
+        let old = scratch_dir("relocate-old");
+        let new = scratch_dir("relocate-new");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0xb1);
+        std::fs::write(new.join("base.vmdk"), base.build()).unwrap();
+        std::fs::write(new.join("disk-flat.vmdk"), vec![0u8; 1024 * 512]).unwrap();
+        std::fs::write(new.join("disk.vmdk"), format!("version=1\nCID=fffffffe\nparentCID=12345678\n\
+            createType=\"monolithicFlat\"\nparentFileNameHint=\"{}\"\n\
+            RW 1024 FLAT \"{}\" 0\n",
+            old.join("base.vmdk").display(), old.join("disk-flat.vmdk").display())).unwrap();
+        assert!(Vmdk::new(new.join("disk.vmdk")).is_err());
+
+        let rewritten = Vmdk::relocate(new.join("disk.vmdk"), &old, &new).unwrap();
+        assert_eq!(rewritten, vec![new.join("disk.vmdk")]);
+        let vmdk = Vmdk::new(new.join("disk.vmdk")).unwrap();
+        assert_eq!(vmdk.descriptor.extents[0].filename, Some(new.join("disk-flat.vmdk").display().to_string()));
+        assert!(Vmdk::relocate(new.join("disk.vmdk"), &old, &new).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_component_files() {
+        let dir = scratch_dir("component-files");
+        write_chain(&dir, 1024);
+        std::fs::write(dir.join("disk-f001.vmdk"), vec![0u8; 1024]).unwrap();
+        std::fs::write(dir.join("disk.vmdk"), "version=1\nCID=fffffffe\nparentCID=9abcdef0\n\
+            createType=\"twoGbMaxExtentFlat\"\nparentFileNameHint=\"child.vmdk\"\n\
+            changeTrackPath=\"disk-ctk.vmdk\"\n\
+            RW 2 FLAT \"disk-f001.vmdk\" 0\nRW 1022 ZERO\n").unwrap();
+
+        let vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        let files: Vec<_> = ["disk.vmdk", "disk-f001.vmdk", "disk-ctk.vmdk", "child.vmdk", "base.vmdk"]
+            .iter()
+            .map(|f| dir.join(f))
+            .collect();
+        assert_eq!(vmdk.component_files(), files);
+    }
+
+    #[test]
+    fn test_zero_extents() {
+        let dir = scratch_dir("zero-extents");
+        std::fs::write(dir.join("disk.vmdk"), "# Disk DescriptorFile\nversion=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            createType=\"monolithicFlat\"\nRW 4 FLAT \"disk-f001.vmdk\" 0\nRW 8 ZERO\nRDONLY 4 FLAT \"disk-f002.vmdk\" 0\n").unwrap();
+        std::fs::write(dir.join("disk-f001.vmdk"), vec![0xa1; 4 * 512]).unwrap();
+        std::fs::write(dir.join("disk-f002.vmdk"), vec![0xa2; 4 * 512]).unwrap();
+
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(dir.join("disk.vmdk")).unwrap();
+        assert_eq!(vmdk.size(), 16 * 512);
+        assert_eq!(vmdk.descriptor.extents[1].filename, None);
+        let mut disk = vec![0xffu8; 16 * 512];
+        assert_eq!(vmdk.read_at(0, &mut disk).unwrap(), disk.len());
+        assert!(disk[..4 * 512].iter().all(|&b| b == 0xa1));
+        assert!(disk[4 * 512..12 * 512].iter().all(|&b| b == 0));
+        assert!(disk[12 * 512..].iter().all(|&b| b == 0xa2));
+
+        let map = vmdk.map().unwrap();
+        let ranges: Vec<_> = map.iter().map(|e| (e.offset / 512, e.length / 512, e.data)).collect();
+        assert_eq!(ranges, [(0, 4, true), (4, 8, false), (12, 4, true)]);
+        assert_eq!(vmdk.locate(5 * 512).unwrap(), None);
+        assert_eq!(vmdk.locate(13 * 512).unwrap(), Some((dir.join("disk-f002.vmdk"), 512)));
+
+        // Writes touching the ZERO extent fail before anything is written
+        let err = vmdk.write_at(3 * 512, &[0xb0; 2 * 512]).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(VmdkError::NotWritable(_))), "{}", err);
+        vmdk.write_at(512, &[0xb1; 512]).unwrap();
+        vmdk.read_at(0, &mut disk).unwrap();
+        assert_eq!((disk[0], disk[512], disk[3 * 512], disk[4 * 512]), (0xa1, 0xb1, 0xa1, 0));
+    }
+
+    #[test]
+    fn test_newline_corruption() {
+        let mut image = SparseImage::new(1024, 128).monolithic("disk.vmdk").build();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        assert_eq!(header.dbl_eol_char2, b'\n');
+        assert_eq!(header.compress_method, 0);
+
+        // "\r\n" collapsed to "\n" shifts the following bytes down
+        image.remove(75);
+        match ExtentHeader::new(&image[..]).unwrap_err().downcast::<VmdkError>() {
+            Ok(VmdkError::TransferCorruption(found)) => assert_eq!(found, [b'\n', b' ', b'\n', 0]),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Without the flag the characters are not checked
+        image[8] &= !(FLAG_VALID_NEWLINE_DETECTION as u8);
+        assert!(ExtentHeader::new(&image[..]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").build();
+        let (header, consumed) = ExtentHeader::parse(&image).unwrap();
+        assert_eq!((header.capacity.sectors(), consumed), (1024, 512));
+        assert_eq!(ExtentHeader::try_from(&image[..]).unwrap().gd_offset.sectors(), header.gd_offset.sectors());
+        assert!(ExtentHeader::parse(&image[..511]).is_err());
+
+        let stream = StreamImage::new(1024, 128).grain(0, 0xa0).build();
+        let footer_marker = stream.len() - 3 * 512;
+        let (footer, consumed) = ExtentHeader::parse_footer(&stream[footer_marker..]).unwrap();
+        assert_eq!(consumed, 1024);
+        assert_ne!(footer.gd_offset.sectors(), extent::GD_AT_END);
+        assert!(ExtentHeader::parse_footer(&stream[..1024]).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_disk_refuses_reads() {
+        let dir = scratch_dir("encrypted");
+        std::fs::write(dir.join("disk-flat.vmdk"), vec![0x5au8; 1024]).unwrap();
+        std::fs::write(dir.join("disk.vmdk"), "version=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            encryption.keySafe = \"vmware:key/list/(pair/null)\"\nencryption.data = \"AAAA\"\n\
+            createType=\"monolithicFlat\"\n\
+            RW 2 FLAT \"disk-flat.vmdk\" 0\n").unwrap();
+
+        let mut vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        assert_eq!(vmdk.encryption().unwrap().data.as_deref(), Some("AAAA"));
+        let err = vmdk.read_at(0, &mut [0u8; 512]).unwrap_err();
+        match err.downcast::<VmdkError>() {
+            Ok(VmdkError::Encrypted) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_full_device_requires_opt_in() {
+        let dir = scratch_dir("full-device");
+        let path = dir.join("device.vmdk");
+        std::fs::write(&path, "version=1\nCID=fffffffe\nparentCID=ffffffff\n\
+            createType=\"fullDevice\"\n\
+            RW 8 FLAT \"/dev/zero\" 0\n").unwrap();
+
+        assert!(Vmdk::new(&path).is_err());
+
+        let mut vmdk = VmdkOpenOptions::new().allow_devices(true).open(&path).unwrap();
+        let mut buf = [0xffu8; 4096];
+        assert_eq!(vmdk.read_at(0, &mut buf).unwrap(), 4096);
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_virtualbox() {
+        let dir = scratch_dir("virtualbox");
+        // VirtualBox overhead values need not match where grains start
+        let mut image = SparseImage::new(1024, 128).virtualbox("disk.vmdk").grain(2, 0xb2);
+        image.overhead = Some(4096);
+        std::fs::write(dir.join("disk.vmdk"), image.build()).unwrap();
+
+        let mut vmdk = VmdkOpenOptions::new().strictness(Strictness::Strict).open(dir.join("disk.vmdk")).unwrap();
+        assert!(vmdk.warnings().is_empty());
+        let ddb = &vmdk.descriptor.ddb;
+        assert_eq!(ddb.bios_geometry(), Some(descriptor::Geometry { cylinders: 1024, heads: 255, sectors: 63 }));
+        assert_eq!(ddb.geometry().map(|g| g.cylinders), Some(16383));
+        let mut buf = [0u8; 512];
+        vmdk.read_at(2 * 128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb2; 512]);
+        let report = vmdk.check().unwrap();
+        assert_eq!((report.count(check::Severity::Error), report.count(check::Severity::Warning)), (0, 1));
+
+        let text = format!(
+            "# Disk DescriptorFile\nversion=1\nCID=1b2c3d4e\nparentCID=ffffffff\n\
+             createType=\"twoGbMaxExtentSparse\"\n\n# Extent description\n\
+             RW 1024 SPARSE \"split-s001.vmdk\"\nRW 1024 SPARSE \"split-s002.vmdk\"\n\n{}",
+            testutil::VIRTUALBOX_DDB
+        );
+        std::fs::write(dir.join("split.vmdk"), text).unwrap();
+        std::fs::write(dir.join("split-s001.vmdk"), SparseImage::new(1024, 128).grain(7, 0xa1).build()).unwrap();
+        std::fs::write(dir.join("split-s002.vmdk"), SparseImage::new(1024, 128).grain(0, 0xa2).build()).unwrap();
+
+        let mut vmdk = VmdkOpenOptions::new().strictness(Strictness::Strict).open(dir.join("split.vmdk")).unwrap();
+        assert_eq!(vmdk.size(), 2048 * 512);
+        let mut buf = vec![0u8; 2 * 512];
+        vmdk.read_at(1023 * 512, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[512]), (0xa1, 0xa2));
+        assert!(vmdk.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_workstation_split() {
+        let dir = scratch_dir("workstation-split");
+        let text = "# Disk DescriptorFile\nversion=1\nencoding=\"UTF-8\"\nCID=5e1f7a20\nparentCID=ffffffff\n\
+            isNativeSnapshot=\"no\"\ncreateType=\"twoGbMaxExtentSparse\"\n\n# Extent description\n\
+            RW 1000 SPARSE \"disk-s001.vmdk\"\nRW 1000 SPARSE \"disk-s002.vmdk\"\nRW 300 SPARSE \"disk-s003.vmdk\"\n\n\
+            # The Disk Data Base \n#DDB\n\nddb.adapterType = \"lsilogic\"\nddb.geometry.cylinders = \"1\"\n\
+            ddb.geometry.heads = \"255\"\nddb.geometry.sectors = \"63\"\nddb.lastModified = \"1697440000\"\n\
+            ddb.longContentID = \"0f5c1e2b9d2e4a7c5e1f7a20\"\nddb.toolsInstallType = \"4\"\n\
+            ddb.toolsVersion = \"12352\"\nddb.uuid = \"60 00 C2 9e 1a 2b 3c 4d-5e 6f 70 81 92 a3 b4 c5\"\n\
+            ddb.virtualHWVersion = \"21\"\n";
+        std::fs::write(dir.join("disk.vmdk"), text).unwrap();
+        // Extents that end within a grain, the last one shorter
+        let extents = [
+            SparseImage::new(1000, 128).grain(7, 0xc1),
+            SparseImage::new(1000, 128).grain(0, 0xc2).grain(3, 0xc3),
+            SparseImage::new(300, 128).grain(2, 0xc4),
+        ];
+        for (i, extent) in extents.iter().enumerate() {
+            std::fs::write(dir.join(format!("disk-s00{}.vmdk", i + 1)), extent.build()).unwrap();
+        }
+
+        let mut vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        assert_eq!(vmdk.size(), 2300 * 512);
+        // Keys unknown to the crate are kept
+        assert_eq!(vmdk.warnings(), [VmdkWarning::UnknownDdbKey("lastModified".to_owned())]);
+        assert_eq!(vmdk.descriptor.ddb.get("toolsVersion"), Some("12352"));
+        assert_eq!(vmdk.descriptor.ddb.get("lastModified"), Some("1697440000"));
+        let mut disk = vec![0u8; 2300 * 512];
+        assert_eq!(vmdk.read_at(0, &mut disk).unwrap(), disk.len());
+        let sector = |s: usize| disk[s * 512];
+        assert_eq!((sector(895), sector(896), sector(999)), (0, 0xc1, 0xc1));
+        assert_eq!((sector(1000), sector(1127), sector(1128)), (0xc2, 0xc2, 0));
+        assert_eq!((sector(1000 + 3 * 128), sector(2000 + 255), sector(2000 + 256), sector(2299)), (0xc3, 0, 0xc4, 0xc4));
+
+        // A write spanning all three extents leaves the ddb alone
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(dir.join("disk.vmdk")).unwrap();
+        vmdk.write_at(990 * 512, &vec![0xd5; 1020 * 512]).unwrap();
+        vmdk.flush().unwrap();
+        drop(vmdk);
+        let mut vmdk = Vmdk::new(dir.join("disk.vmdk")).unwrap();
+        assert_ne!(vmdk.descriptor.cid, 0x5e1f7a20);
+        assert!(vmdk.raw_descriptor().contains("ddb.toolsVersion = \"12352\"\nddb.uuid = "));
+        let mut buf = vec![0u8; 1030 * 512];
+        vmdk.read_at(985 * 512, &mut buf).unwrap();
+        let sector = |s: usize| buf[(s - 985) * 512];
+        assert_eq!((sector(989), sector(990), sector(1999), sector(2009), sector(2010)), (0xc1, 0xd5, 0xd5, 0xd5, 0));
+    }
+
+    #[test]
+    fn test_vmdk() {
+        let _vmdk = Vmdk::new("/home/josh/VirtualBox VMs/OMS CS6250 Course \
                              VM/OMS CS6250 Course VM-disk1.vmdk").unwrap();
     }
 
@@ -221,6 +2810,7 @@ ddb.uuid.modification="e2b662bc-16ff-478e-8b7f-3323b027087e"
 ddb.uuid.parentmodification="00000000-0000-0000-0000-000000000000"
 ddb.comment=""
 "#;
-        assert_eq!(vmdk.descriptor.unwrap(), descriptor_text);
+        assert_eq!(vmdk.raw_descriptor().trim_matches(char::from(0)), descriptor_text);
+        assert_eq!(vmdk.descriptor, Descriptor::new(descriptor_text).unwrap());
     }
 }
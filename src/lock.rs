@@ -0,0 +1,149 @@
+//! Locking of disks opened for writing.
+//!
+//! VMware hosted products lock a file `disk.vmdk` by creating a directory
+//! `disk.vmdk.lck` holding one `M*.lck` file per lock holder. A running VM
+//! keeps such a lock for as long as it uses the disk.
+//!
+//! Independently of that, every file opened for writing carries an OS
+//! advisory lock (`flock` or `LockFileEx`) for as long as it is open, so two
+//! processes using this crate never write to the same image at once.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use failure::Error;
+use log::{info, warn};
+
+use crate::VmdkError;
+
+/// Path of the VMware lock directory of `path`
+pub fn lock_dir(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".lck");
+    PathBuf::from(name)
+}
+
+/// Lock files currently held on `path`, e.g. by a running VM
+pub fn lock_holders(path: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(lock_dir(path)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().map(|ext| ext == "lck").unwrap_or(false))
+        .collect()
+}
+
+/// Whether `path` is locked by VMware or another user of this crate
+pub fn is_locked<P: AsRef<Path>>(path: P) -> bool {
+    !lock_holders(path.as_ref()).is_empty()
+}
+
+/// Take an exclusive advisory lock on `file`, opened from `path`. The lock
+/// lasts until the file is closed.
+pub(crate) fn lock_file(file: &File, path: &Path, force: bool) -> Result<(), Error> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) if force => {
+            warn!("Ignoring advisory lock held on {}", path.display());
+            Ok(())
+        }
+        Err(TryLockError::WouldBlock) => Err(VmdkError::Locked(path.display().to_string()).into()),
+        Err(TryLockError::Error(ref e)) if e.kind() == std::io::ErrorKind::Unsupported => {
+            warn!("Advisory locks not supported for {}", path.display());
+            Ok(())
+        }
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// A VMware-style lock held on one file, released on drop
+#[derive(Debug)]
+pub(crate) struct VmwareLock {
+    dir: PathBuf,
+    file: PathBuf,
+}
+
+impl VmwareLock {
+    /// Lock `path`, refusing if someone else holds a lock unless `force`
+    pub(crate) fn acquire(path: &Path, force: bool) -> Result<Self, Error> {
+        let holders = lock_holders(path);
+        if !holders.is_empty() {
+            if !force {
+                return Err(VmdkError::Locked(holders[0].display().to_string()).into());
+            }
+            warn!("Ignoring existing lock {} on {}", holders[0].display(), path.display());
+        }
+
+        let dir = lock_dir(path);
+        match fs::create_dir(&dir) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let file = dir.join(format!("M{:05}.lck", std::process::id()));
+        let mut lock = OpenOptions::new().write(true).create_new(true).open(&file)?;
+        writeln!(lock, "vmdk pid {}", std::process::id())?;
+        info!("Locked {}", path.display());
+
+        Ok(VmwareLock { dir, file })
+    }
+}
+
+impl Drop for VmwareLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.file) {
+            warn!("Failed to remove lock {}: {}", self.file.display(), e);
+        }
+        // Only succeeds once no other holder is left
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::scratch_dir;
+
+    #[test]
+    fn test_vmware_lock() {
+        let dir = scratch_dir("vmware-lock");
+        let path = dir.join("disk.vmdk");
+        assert!(!is_locked(&path));
+
+        let lock = VmwareLock::acquire(&path, false).unwrap();
+        assert!(is_locked(&path));
+        assert!(VmwareLock::acquire(&path, false).is_err());
+        drop(lock);
+        assert!(!is_locked(&path));
+        assert!(!lock_dir(&path).exists());
+
+        // A lock left by a running VM
+        fs::create_dir(lock_dir(&path)).unwrap();
+        fs::write(lock_dir(&path).join("M31337.lck"), "").unwrap();
+        assert!(VmwareLock::acquire(&path, false).is_err());
+        let forced = VmwareLock::acquire(&path, true).unwrap();
+        drop(forced);
+        assert!(is_locked(&path));
+    }
+
+    #[test]
+    fn test_advisory_lock() {
+        let path = scratch_dir("advisory-lock").join("disk.vmdk");
+        fs::write(&path, "").unwrap();
+
+        let first = OpenOptions::new().write(true).open(&path).unwrap();
+        lock_file(&first, &path, false).unwrap();
+        let second = OpenOptions::new().write(true).open(&path).unwrap();
+        assert!(lock_file(&second, &path, false).is_err());
+        assert!(lock_file(&second, &path, true).is_ok());
+
+        drop(first);
+        lock_file(&second, &path, false).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! Disks held entirely in memory.
+//!
+//! `MemoryFiles` keeps files as byte vectors by path. Passed to
+//! `VmdkBuilder::storage` and `VmdkOpenOptions::storage`, disks are created,
+//! written and opened again without touching the filesystem, as tests and
+//! fuzzers want; their files can be read out or inserted as bytes.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use failure::Error;
+
+use crate::storage::{Storage, StorageProvider};
+
+type Contents = Arc<RwLock<Vec<u8>>>;
+
+/// Files in memory, shared by all clones
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFiles {
+    files: Arc<Mutex<BTreeMap<PathBuf, Contents>>>,
+}
+
+impl MemoryFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `contents` as the file at `path`, replacing any file there
+    pub fn insert<P: Into<PathBuf>>(&self, path: P, contents: Vec<u8>) {
+        self.lock().insert(path.into(), Arc::new(RwLock::new(contents)));
+    }
+
+    /// A copy of the contents of the file at `path`
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        let file = self.lock().get(path.as_ref()).cloned()?;
+        let contents = file.read().unwrap_or_else(|e| e.into_inner()).clone();
+        Some(contents)
+    }
+
+    /// The paths of all files, in order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.lock().keys().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<PathBuf, Contents>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StorageProvider for MemoryFiles {
+    fn open(&self, path: &Path, write: bool) -> Result<Box<dyn Storage>, Error> {
+        let contents = self.lock().get(path).cloned();
+        let contents = contents.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display())))?;
+        Ok(Box::new(MemoryFile { contents, write, pos: 0 }))
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn Storage>, Error> {
+        let mut files = self.lock();
+        if files.contains_key(path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists", path.display())).into());
+        }
+        let contents = Contents::default();
+        files.insert(path.to_owned(), contents.clone());
+        Ok(Box::new(MemoryFile { contents, write: true, pos: 0 }))
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        match self.lock().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display())).into()),
+        }
+    }
+}
+
+/// A file opened from `MemoryFiles`
+#[derive(Debug)]
+struct MemoryFile {
+    contents: Contents,
+    write: bool,
+    pos: u64,
+}
+
+impl MemoryFile {
+    fn check_writable(&self) -> io::Result<()> {
+        if !self.write {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file opened read-only"));
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MemoryFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let contents = self.contents.read().unwrap_or_else(|e| e.into_inner());
+        let start: usize = offset.try_into().map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let data = start.checked_add(buf.len()).and_then(|end| contents.get(start..end));
+        buf.copy_from_slice(data.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?);
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check_writable()?;
+        let mut contents = self.contents.write().unwrap_or_else(|e| e.into_inner());
+        let start: usize = offset.try_into().map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        let end = start + buf.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.contents.read().unwrap_or_else(|e| e.into_inner()).len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        let len = len.try_into().map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        self.contents.write().unwrap_or_else(|e| e.into_inner()).resize(len, 0);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<bool> {
+        self.check_writable()?;
+        let mut contents = self.contents.write().unwrap_or_else(|e| e.into_inner());
+        let size = contents.len() as u64;
+        let (start, end) = (std::cmp::min(offset, size) as usize, std::cmp::min(offset.saturating_add(len), size) as usize);
+        contents[start..end].iter_mut().for_each(|b| *b = 0);
+        Ok(true)
+    }
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len() as u64, self.size()?.saturating_sub(self.pos)) as usize;
+        self.read_exact_at(&mut buf[..n], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all_at(buf, self.pos)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::VmdkBuilder;
+    use crate::descriptor::DiskType;
+    use crate::scan::{Pattern, ScanOptions};
+    use crate::session::ReadSession;
+    use crate::testutil::SparseImage;
+    use crate::{ExtentHeader, Vmdk, VmdkOpenOptions, SECTOR_SIZE};
+
+    #[test]
+    fn test_memory_files() {
+        let files = MemoryFiles::new();
+        let dir = Path::new("/nonexistent/memory");
+        let open = |path: &Path, write: bool| {
+            let mut options = VmdkOpenOptions::new();
+            options.write(write).storage(files.clone());
+            options.open(path)
+        };
+
+        for (name, create_type) in [("sparse.vmdk", DiskType::MonolithicSparse), ("split.vmdk", DiskType::TwoGbMaxExtentFlat)] {
+            let path = dir.join(name);
+            let mut builder = VmdkBuilder::new(1 << 20);
+            builder.create_type(create_type).storage(files.clone());
+            let mut vmdk = builder.create(&path).unwrap();
+            vmdk.write_at(1000, &[0xb1; 100]).unwrap();
+            vmdk.close().unwrap();
+            assert!(builder.create(&path).is_err());
+
+            let mut vmdk = open(&path, true).unwrap();
+            assert!(vmdk.warnings().is_empty());
+            vmdk.write_at(2000, &[0xb2; 100]).unwrap();
+            vmdk.close().unwrap();
+            let mut buf = [0u8; 1200];
+            open(&path, false).unwrap().read_at(1000, &mut buf).unwrap();
+            assert_eq!((buf[0], buf[99], buf[100], buf[1000], buf[1100]), (0xb1, 0xb1, 0, 0xb2, 0));
+        }
+        assert_eq!(files.paths(), [dir.join("sparse.vmdk"), dir.join("split-f001.vmdk"), dir.join("split.vmdk")]);
+        assert!(!dir.exists());
+        assert!(Vmdk::new(dir.join("sparse.vmdk")).is_err());
+
+        // Images made elsewhere, a child reading through its parent
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0xb1);
+        files.insert(dir.join("base.vmdk"), base.build());
+        files.insert(dir.join("child.vmdk"), SparseImage::new(1024, 128).child("base.vmdk", 0x12345678).build());
+        let mut child = open(&dir.join("child.vmdk"), true).unwrap();
+        child.write_at(128 * 512, &[0xc1; 10]).unwrap();
+        child.close().unwrap();
+        let mut buf = [0u8; 20];
+        open(&dir.join("child.vmdk"), false).unwrap().read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[10]), (0xc1, 0xb1));
+        assert_eq!(files.get(dir.join("base.vmdk")).unwrap(), base.build());
+        assert!(open(&dir.join("base.vmdk"), false).unwrap().write_at(0, &[1]).is_err());
+
+        // Tools reading the files on their own read them from memory too
+        let mut base = open(&dir.join("base.vmdk"), false).unwrap();
+        let extent = base.extents().next().unwrap();
+        let gt = extent.grain_directory().unwrap()[0];
+        assert_ne!(gt, 0);
+        assert_ne!(extent.grain_table(0).unwrap().unwrap()[1], 0);
+        assert_eq!(extent.allocated_bytes().unwrap(), 128 * 512);
+        let mut reader = extent.reader().unwrap();
+        let mut buf = vec![0u8; 2 * 128 * 512];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!((buf[0], buf[128 * 512]), (0, 0xb1));
+        let pattern = [Pattern::Bytes(vec![0xb1; 4])];
+        assert_eq!(base.scan(&pattern, &ScanOptions::new()).unwrap().len(), 128 * 512 - 3);
+
+        // A grain no table points to anymore
+        let mut orphaned = SparseImage::new(1024, 128).monolithic("orphan.vmdk").grain(2, 0xb2).grain(1, 0xb1).build();
+        let header = ExtentHeader::new(&orphaned[..]).unwrap();
+        for gd in [header.gd_offset.0, header.rgd_offset.0] {
+            let gd = (gd * SECTOR_SIZE) as usize;
+            let gt = u32::from_le_bytes(orphaned[gd..gd + 4].try_into().unwrap()) as usize * SECTOR_SIZE as usize;
+            orphaned[gt + 4..gt + 8].fill(0);
+        }
+        files.insert(dir.join("orphan.vmdk"), orphaned);
+        let mut options = ScanOptions::new();
+        options.include_orphans(true);
+        let hits = open(&dir.join("orphan.vmdk"), false).unwrap().scan(&pattern, &options).unwrap();
+        assert_eq!(hits.len(), 128 * 512 - 3);
+        assert!(hits.iter().all(|hit| hit.logical.is_none()));
+
+        let mut session = ReadSession::new(base).unwrap();
+        session.read_at(128 * 512, &mut buf[..10]).unwrap();
+        session.verify().unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+//! Throwaway writable layers over disks that must not change.
+//!
+//! An overlay is a delta disk in a spill file on top of a disk opened
+//! read-only, like qemu's `snapshot=on`: writes land in the delta, reads
+//! merge it with the disk below, and the delta is deleted again when the
+//! overlay is dropped, leaving the original as it was.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use failure::Error;
+use log::{info, warn};
+
+use crate::clone::CloneOptions;
+use crate::{Vmdk, VmdkOpenOptions};
+
+/// Numbers spill files made by this process
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A disk opened read-only with a temporary writable layer on top
+pub struct Overlay {
+    /// The delta, with the original disk as its parent
+    delta: Option<Vmdk>,
+    /// Directory made for the spill file, removed along with it
+    spill_dir: Option<PathBuf>,
+}
+
+impl Overlay {
+    /// Open the disk at `path` with an overlay spilling to a new directory
+    /// in the system's temporary directory
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let dir = std::env::temp_dir().join(format!("vmdk-overlay-{}-{}", std::process::id(), next_spill()));
+        std::fs::create_dir(&dir)?;
+        match Overlay::open_in(path, &dir) {
+            Ok(mut overlay) => {
+                overlay.spill_dir = Some(dir);
+                Ok(overlay)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_dir(&dir);
+                Err(e)
+            }
+        }
+    }
+
+    /// Open the disk at `path` with an overlay spilling to a new file in
+    /// the existing directory `dir`. A spill file next to the disk keeps
+    /// it from being opened for writing while the overlay exists.
+    pub fn open_in<P: AsRef<Path>, Q: AsRef<Path>>(path: P, dir: Q) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let base = VmdkOpenOptions::new().open(path)?;
+        let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let spill = dir.as_ref().join(format!("{}-overlay-{}-{}.vmdk", stem, std::process::id(), next_spill()));
+        let delta = base.snapshot(&spill)?;
+        info!("Opened {} with overlay {}", path.display(), spill.display());
+        Ok(Overlay { delta: Some(delta), spill_dir: None })
+    }
+
+    /// The delta holding what was written, with the original disk as its
+    /// parent
+    pub fn vmdk(&self) -> &Vmdk {
+        self.delta.as_ref().expect("overlay open")
+    }
+
+    fn delta(&mut self) -> &mut Vmdk {
+        self.delta.as_mut().expect("overlay open")
+    }
+
+    pub fn size(&self) -> u64 {
+        self.vmdk().size()
+    }
+
+    /// Read at byte `offset`, from the overlay where it was written and
+    /// from the original disk elsewhere
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        self.delta().read_at(offset, buf)
+    }
+
+    /// Write at byte `offset` of the overlay, see `Vmdk::write_at`
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+        self.delta().write_at(offset, buf)
+    }
+
+    /// Zero a range of the overlay, see `Vmdk::write_zeroes`
+    pub fn write_zeroes(&mut self, offset: u64, len: u64) -> Result<u64, Error> {
+        self.delta().write_zeroes(offset, len)
+    }
+
+    /// Save the merged contents as a new disk at `path`, see
+    /// `Vmdk::clone_to`
+    pub fn clone_to<P: AsRef<Path>>(&mut self, path: P, options: &CloneOptions) -> Result<(), Error> {
+        self.delta().clone_to(path, options)
+    }
+}
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        if let Some(delta) = self.delta.take() {
+            delta.remove();
+        }
+        if let Some(dir) = &self.spill_dir {
+            if let Err(e) = std::fs::remove_dir(dir) {
+                warn!("Failed to remove overlay directory {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+fn next_spill() -> usize {
+    SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+
+    #[test]
+    fn test_overlay() {
+        let dir = scratch_dir("overlay");
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build()).unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        let mut overlay = Overlay::open(&path).unwrap();
+        let spill = overlay.vmdk().path.clone();
+        assert!(spill.exists() && !spill.starts_with(&dir));
+        overlay.write_at(128 * 512 + 10, &[0xaa; 10]).unwrap();
+        overlay.write_zeroes(0, 10).unwrap();
+        let mut buf = [0u8; 30];
+        overlay.read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[10], buf[20]), (0xb1, 0xaa, 0xb1));
+        // The original can still be read alongside
+        Vmdk::new(&path).unwrap().read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 30]);
+        overlay.clone_to(dir.join("saved.vmdk"), &CloneOptions::new()).unwrap();
+        drop(overlay);
+
+        assert!(!spill.exists() && !spill.parent().unwrap().exists());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        Vmdk::new(dir.join("saved.vmdk")).unwrap().read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[10], buf[20]), (0xb1, 0xaa, 0xb1));
+
+        // Next to the disk, the spill file keeps writers away
+        let overlay = Overlay::open_in(&path, &dir).unwrap();
+        assert!(VmdkOpenOptions::new().write(true).open(&path).is_err());
+        drop(overlay);
+        assert!(VmdkOpenOptions::new().write(true).open(&path).is_ok());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    }
+}
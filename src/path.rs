@@ -0,0 +1,80 @@
+//! Resolution of the file names stored in descriptors.
+//!
+//! Extent names and `parentFileNameHint` may be relative to the descriptor,
+//! absolute, or written with Windows separators by a Windows host.
+
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use log::info;
+
+/// Maps a file name from a descriptor to the path to open
+pub trait PathResolver: Debug + Send + Sync {
+    /// Resolve `name`, found in the descriptor at `descriptor_path`
+    fn resolve(&self, descriptor_path: &Path, name: &str) -> PathBuf;
+}
+
+/// Resolves relative names against the directory of the descriptor and
+/// converts `\` separators on hosts that do not use them. Absolute Windows
+/// paths, with a drive letter or a UNC prefix, cannot be opened on other
+/// hosts, so the file is looked up next to the descriptor instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultResolver;
+
+impl PathResolver for DefaultResolver {
+    fn resolve(&self, descriptor_path: &Path, name: &str) -> PathBuf {
+        let dir = descriptor_path.parent().unwrap_or_else(|| Path::new(""));
+        if !cfg!(windows) && is_windows_absolute(name) {
+            let file = name.rsplit(['\\', '/']).next().unwrap_or(name);
+            info!("Looking for {} next to the descriptor as {}", name, file);
+            return dir.join(file);
+        }
+        dir.join(normalize_separators(name))
+    }
+}
+
+/// Whether `name` is an absolute Windows path, such as `C:\VMs\disk.vmdk`
+/// or `\\server\share\disk.vmdk`
+pub fn is_windows_absolute(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    let drive = bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && matches!(bytes[2], b'\\' | b'/');
+    drive || name.starts_with("\\\\")
+}
+
+/// Rewrite `\` as the platform separator
+pub fn normalize_separators(name: &str) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(name)
+    } else {
+        PathBuf::from(name.replace('\\', "/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_resolver() {
+        let desc = Path::new("/vms/disk.vmdk");
+        assert_eq!(DefaultResolver.resolve(desc, "disk-f001.vmdk"), Path::new("/vms/disk-f001.vmdk"));
+        assert_eq!(DefaultResolver.resolve(desc, "/other/disk-flat.vmdk"), Path::new("/other/disk-flat.vmdk"));
+        assert_eq!(DefaultResolver.resolve(Path::new("disk.vmdk"), "base.vmdk"), Path::new("base.vmdk"));
+        #[cfg(unix)]
+        assert_eq!(DefaultResolver.resolve(desc, r"..\base\base.vmdk"), Path::new("/vms/../base/base.vmdk"));
+    }
+
+    #[test]
+    fn test_windows_paths() {
+        assert!(is_windows_absolute(r"C:\VMs\disk.vmdk") && is_windows_absolute("d:/VMs/disk.vmdk"));
+        assert!(is_windows_absolute(r"\\server\share\disk.vmdk"));
+        assert!(!is_windows_absolute(r"VMs\disk.vmdk") && !is_windows_absolute("/vms/disk.vmdk") && !is_windows_absolute("C:"));
+
+        let desc = Path::new("vms").join("disk.vmdk");
+        let resolved = DefaultResolver.resolve(&desc, r"Old\disk-flat.vmdk");
+        assert_eq!(resolved, Path::new("vms").join("Old").join("disk-flat.vmdk"));
+        #[cfg(not(windows))]
+        assert_eq!(DefaultResolver.resolve(&desc, r"C:\VMs\Old\disk-flat.vmdk"), Path::new("vms/disk-flat.vmdk"));
+        #[cfg(windows)]
+        assert_eq!(DefaultResolver.resolve(&desc, r"C:\VMs\Old\disk-flat.vmdk"), Path::new(r"C:\VMs\Old\disk-flat.vmdk"));
+    }
+}
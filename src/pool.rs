@@ -0,0 +1,167 @@
+//! Bounding the number of extent files open at once.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use failure::Error;
+use log::info;
+
+use crate::storage::{Storage, StorageProvider};
+
+/// An open file, or `None` once the pool closed it
+type Slot = Mutex<Option<Box<dyn Storage>>>;
+
+/// Read-only extent files shared by the disks of a chain, of which at most
+/// `limit` stay open. The least recently used ones are closed first and
+/// reopened on their next access.
+#[derive(Debug, Clone)]
+pub(crate) struct FilePool(Arc<Mutex<PoolState>>);
+
+#[derive(Debug)]
+struct PoolState {
+    limit: usize,
+    /// Open files, least recently used first
+    open: VecDeque<Weak<Slot>>,
+}
+
+impl FilePool {
+    pub(crate) fn new(limit: usize) -> Self {
+        FilePool(Arc::new(Mutex::new(PoolState { limit, open: VecDeque::new() })))
+    }
+
+    /// Mark `slot` as the most recently used and close the least recently
+    /// used files over the limit. Files in use by someone else, such as a
+    /// child disk reading through to its parent, are left open.
+    fn used(&self, slot: &Arc<Slot>) {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        state.open.retain(|w| w.strong_count() > 0 && w.as_ptr() != Arc::as_ptr(slot));
+        state.open.push_back(Arc::downgrade(slot));
+
+        let mut i = 0;
+        while state.open.len() > state.limit && i + 1 < state.open.len() {
+            let closed = match state.open[i].upgrade() {
+                Some(other) => match other.try_lock() {
+                    Ok(mut file) => {
+                        *file = None;
+                        true
+                    }
+                    Err(_) => false,
+                },
+                None => true,
+            };
+            if closed {
+                state.open.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// The file of an extent, which the pool it belongs to may close between
+/// accesses
+#[derive(Debug)]
+pub(crate) struct Handle {
+    slot: Arc<Slot>,
+    path: PathBuf,
+    pool: Option<FilePool>,
+    /// Where the file is reopened from, if not the local filesystem
+    provider: Option<Arc<dyn StorageProvider>>,
+}
+
+impl Handle {
+    /// Wrap `file`, opened from `path` through `provider`. Files in a pool
+    /// must be read-only, as they are reopened that way.
+    pub(crate) fn new(
+        file: Box<dyn Storage>,
+        path: &Path,
+        pool: Option<&FilePool>,
+        provider: Option<&Arc<dyn StorageProvider>>,
+    ) -> Self {
+        let handle = Handle {
+            slot: Arc::new(Mutex::new(Some(file))),
+            path: path.to_owned(),
+            pool: pool.cloned(),
+            provider: provider.cloned(),
+        };
+        if let Some(pool) = &handle.pool {
+            pool.used(&handle.slot);
+        }
+        handle
+    }
+
+    /// The file, reopened if the pool closed it
+    pub(crate) fn get(&self) -> Result<FileGuard<'_>, Error> {
+        let mut file = self.slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(pool) = &self.pool {
+            if file.is_none() {
+                info!("Reopening {}", self.path.display());
+                *file = Some(match &self.provider {
+                    Some(provider) => provider.open(&self.path, false)?,
+                    None => Box::new(File::open(&self.path)?),
+                });
+            }
+            pool.used(&self.slot);
+        }
+        Ok(FileGuard(file))
+    }
+
+    /// Whether the file is currently open
+    #[cfg(test)]
+    pub(crate) fn is_open(&self) -> bool {
+        self.slot.lock().unwrap_or_else(PoisonError::into_inner).is_some()
+    }
+}
+
+/// Exclusive access to the file of a `Handle`
+pub(crate) struct FileGuard<'a>(MutexGuard<'a, Option<Box<dyn Storage>>>);
+
+impl Deref for FileGuard<'_> {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &(dyn Storage + 'static) {
+        &**self.0.as_ref().expect("file reopened")
+    }
+}
+
+impl DerefMut for FileGuard<'_> {
+    fn deref_mut(&mut self) -> &mut (dyn Storage + 'static) {
+        &mut **self.0.as_mut().expect("file reopened")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::scratch_dir;
+
+    #[test]
+    fn test_pool() {
+        let dir = scratch_dir("pool");
+        let pool = FilePool::new(2);
+        let handles: Vec<_> = (0..3)
+            .map(|i| {
+                let path = dir.join(format!("{}.bin", i));
+                std::fs::write(&path, [i as u8]).unwrap();
+                Handle::new(Box::new(File::open(&path).unwrap()), &path, Some(&pool), None)
+            })
+            .collect();
+        assert!(!handles[0].is_open());
+        assert!(handles[1].is_open() && handles[2].is_open());
+
+        let mut byte = [0u8];
+        handles[0].get().unwrap().read_exact(&mut byte).unwrap();
+        assert_eq!(byte, [0]);
+        assert!(!handles[1].is_open());
+
+        // The least recently used file is skipped while in use
+        let busy = handles[0].get().unwrap();
+        handles[1].get().unwrap();
+        handles[2].get().unwrap();
+        drop(busy);
+        assert!(handles[0].is_open() && handles[2].is_open());
+        assert!(!handles[1].is_open());
+    }
+}
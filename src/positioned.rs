@@ -0,0 +1,107 @@
+//! Reads and writes at an offset, without a separate seek.
+//!
+//! Unix hosts use `pread`/`pwrite`, Windows hosts `seek_read`/`seek_write`,
+//! which leave the file position after the data rather than untouched, so
+//! callers relying on the position must still seek first.
+
+use std::fs::File;
+use std::io;
+
+use crate::storage::Storage;
+
+/// Fill `buf` from `file` at byte `offset`
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut done = 0;
+        while done < buf.len() {
+            match file.seek_read(&mut buf[done..], offset + done as u64) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => done += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+}
+
+/// Write all of `buf` to `file` at byte `offset`
+pub(crate) fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut done = 0;
+        while done < buf.len() {
+            match file.seek_write(&buf[done..], offset + done as u64) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => done += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        use std::io::{Seek, SeekFrom, Write};
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buf)
+    }
+}
+
+/// The little-endian `u32` in `file` at byte `offset`
+pub(crate) fn read_u32_at(file: &dyn Storage, offset: u64) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    file.read_exact_at(&mut bytes, offset)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom, Write};
+    use crate::testutil::scratch_dir;
+
+    #[test]
+    fn test_positioned_io() {
+        let dir = scratch_dir("positioned");
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.join("file.bin"))
+            .unwrap();
+        file.write_all(&[0u8; 16]).unwrap();
+        write_all_at(&file, &0xdeadbeefu32.to_le_bytes(), 8).unwrap();
+        assert_eq!(read_u32_at(&file, 8).unwrap(), 0xdeadbeef);
+
+        let mut buf = [1u8; 4];
+        read_exact_at(&file, &mut buf, 4).unwrap();
+        assert_eq!(buf, [0; 4]);
+        assert_eq!(read_exact_at(&file, &mut buf, 14).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // Seeking first still works, whatever the positioned calls did
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[7]).unwrap();
+        assert_eq!(std::fs::read(dir.join("file.bin")).unwrap()[..2], [7, 0]);
+    }
+}
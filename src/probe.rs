@@ -0,0 +1,529 @@
+//! Telling image formats apart by their contents.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use failure::Error;
+use log::{info, warn};
+
+use crate::descriptor::{self, Descriptor};
+use crate::diagnostics::Strictness;
+use crate::formats::{CowdExtent, ExtentRead, SeSparseExtent};
+use crate::path::{DefaultResolver, PathResolver};
+use crate::positioned::read_exact_at;
+use crate::{ExtentReader, Vmdk, VmdkError, EXTENT_MAGIC, MAX_TEXT_DESCRIPTOR};
+
+/// Magic of an ESX 2 `COWD` sparse extent
+const COWD_MAGIC: &[u8] = b"COWD";
+/// Constant at the start of a `SESparse` extent header
+const SESPARSE_MAGIC: u64 = 0x0000_0000_cafe_babe;
+const QCOW_MAGIC: &[u8] = b"QFI\xfb";
+const VHD_COOKIE: &[u8] = b"conectix";
+const VHDX_SIGNATURE: &[u8] = b"vhdxfile";
+/// Signature of a VirtualBox disk image, at byte 64
+const VDI_SIGNATURE: u32 = 0xbeda_107f;
+
+/// Format of an image file, as far as its contents tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Sparse extent with a `KDMV` header
+    Sparse,
+    /// Text descriptor
+    Descriptor,
+    /// ESX 2 sparse extent
+    Cowd,
+    /// ESXi space-efficient sparse extent
+    SeSparse,
+    Qcow,
+    Vhd,
+    Vhdx,
+    Vdi,
+    /// Anything else, taken as the disk contents byte for byte
+    Raw,
+}
+
+impl Format {
+    /// Whether `open_any` can open images of this format
+    pub fn is_supported(self) -> bool {
+        matches!(self, Format::Sparse | Format::Descriptor | Format::Cowd | Format::SeSparse | Format::Raw)
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Sparse => "VMDK sparse extent",
+            Format::Descriptor => "VMDK descriptor",
+            Format::Cowd => "COWD sparse extent",
+            Format::SeSparse => "SESparse extent",
+            Format::Qcow => "qcow image",
+            Format::Vhd => "VHD image",
+            Format::Vhdx => "VHDX image",
+            Format::Vdi => "VDI image",
+            Format::Raw => "raw image",
+        })
+    }
+}
+
+/// Format of the file at `path`
+pub fn detect<P: AsRef<Path>>(path: P) -> Result<Format, Error> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut start = [0u8; 512];
+    let head = &mut start[..std::cmp::min(len, 512) as usize];
+    read_exact_at(&file, head, 0)?;
+    let head = &*head;
+
+    let format = if head.len() >= 4 && u32::from_le_bytes([head[0], head[1], head[2], head[3]]) == EXTENT_MAGIC {
+        Format::Sparse
+    } else if head.starts_with(COWD_MAGIC) {
+        Format::Cowd
+    } else if head.len() >= 8 && head[..8] == SESPARSE_MAGIC.to_le_bytes() {
+        Format::SeSparse
+    } else if head.starts_with(QCOW_MAGIC) {
+        Format::Qcow
+    } else if head.starts_with(VHDX_SIGNATURE) {
+        Format::Vhdx
+    } else if head.starts_with(VHD_COOKIE) || has_vhd_footer(&file, len)? {
+        Format::Vhd
+    } else if head.len() >= 68 && head[64..68] == VDI_SIGNATURE.to_le_bytes() {
+        Format::Vdi
+    } else if len <= MAX_TEXT_DESCRIPTOR && is_descriptor(&file, len)? {
+        Format::Descriptor
+    } else {
+        Format::Raw
+    };
+    Ok(format)
+}
+
+/// Whether the last sector of a file holds the footer of a fixed VHD
+fn has_vhd_footer(file: &File, len: u64) -> Result<bool, Error> {
+    if len < 1024 || !len.is_multiple_of(512) {
+        return Ok(false);
+    }
+    let mut cookie = [0u8; 8];
+    read_exact_at(file, &mut cookie, len - 512)?;
+    Ok(cookie == VHD_COOKIE)
+}
+
+/// Whether a small file reads as a text descriptor rather than data
+fn is_descriptor(file: &File, len: u64) -> Result<bool, Error> {
+    let mut bytes = vec![0u8; len as usize];
+    read_exact_at(file, &mut bytes, 0)?;
+    let text = String::from_utf8_lossy(&bytes);
+    Ok(text.lines().any(|line| line.trim_start().starts_with("createType")))
+}
+
+/// A handle on an image of any format `open_any` supports
+pub enum AnyImage {
+    /// A disk, from its descriptor or a monolithic sparse extent
+    Vmdk(Box<Vmdk>),
+    /// A single extent opened without its descriptor: a sparse extent of a
+    /// split disk, or a COWD or SESparse extent
+    Extent(Box<dyn ExtentRead>),
+    Raw(File),
+}
+
+impl AnyImage {
+    /// Size of the disk in bytes
+    pub fn size(&self) -> Result<u64, Error> {
+        match self {
+            AnyImage::Vmdk(vmdk) => Ok(vmdk.size()),
+            AnyImage::Extent(extent) => Ok(extent.size()),
+            AnyImage::Raw(file) => Ok(file.metadata()?.len()),
+        }
+    }
+
+    /// Read from the disk at `offset`, see `Vmdk::read_at`
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let size = self.size()?;
+        if offset >= size {
+            return Ok(0);
+        }
+        let n = std::cmp::min(buf.len() as u64, size - offset) as usize;
+        match self {
+            AnyImage::Vmdk(vmdk) => return vmdk.read_at(offset, &mut buf[..n]),
+            AnyImage::Extent(extent) => extent.read_at(offset, &mut buf[..n])?,
+            AnyImage::Raw(file) => read_exact_at(file, &mut buf[..n], offset)?,
+        }
+        Ok(n)
+    }
+}
+
+/// Open the image at `path` read-only, whatever its format. Images of
+/// formats this crate cannot read fail with `VmdkError::UnsupportedFormat`
+/// naming the format they look like.
+pub fn open_any<P: AsRef<Path>>(path: P) -> Result<AnyImage, Error> {
+    let path = path.as_ref();
+    let format = detect(path)?;
+    info!("{} looks like a {}", path.display(), format);
+    match format {
+        Format::Descriptor => Ok(AnyImage::Vmdk(Box::new(Vmdk::new(path)?))),
+        Format::Sparse => match Vmdk::new(path) {
+            Err(e) if matches!(e.downcast_ref(), Some(VmdkError::MissingDescriptor(_))) => {
+                Ok(AnyImage::Extent(Box::new(ExtentReader::open_sparse(path)?)))
+            }
+            result => Ok(AnyImage::Vmdk(Box::new(result?))),
+        },
+        Format::Raw => Ok(AnyImage::Raw(File::open(path)?)),
+        Format::Cowd => Ok(AnyImage::Extent(Box::new(CowdExtent::open(path)?))),
+        Format::SeSparse => Ok(AnyImage::Extent(Box::new(SeSparseExtent::open(path)?))),
+        _ => Err(VmdkError::UnsupportedFormat(format!("this looks like a {}, convert it to VMDK or raw first", format)).into()),
+    }
+}
+
+/// Options for `probe_dir`
+#[derive(Debug, Clone)]
+pub struct ProbeOptions {
+    threads: usize,
+    recursive: bool,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        ProbeOptions {
+            threads: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+            recursive: false,
+        }
+    }
+}
+
+impl ProbeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Files probed at once. Defaults to the available parallelism.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Also probe subdirectories, without following symlinks to them.
+    /// Defaults to false.
+    pub fn recursive(&mut self, recursive: bool) -> &mut Self {
+        self.recursive = recursive;
+        self
+    }
+}
+
+/// A disk found by `probe_dir`: a text descriptor or a monolithic sparse
+/// extent with its embedded one
+#[derive(Debug, Clone)]
+pub struct ProbedDisk {
+    pub path: PathBuf,
+    pub format: Format,
+    pub descriptor: Descriptor,
+    /// Extent files the descriptor names that exist
+    pub extents: Vec<PathBuf>,
+    /// Extent files the descriptor names that do not exist
+    pub missing_extents: Vec<PathBuf>,
+    /// Parent descriptor named by `parentFileNameHint`, if it was found
+    pub parent: Option<PathBuf>,
+    /// Bytes taken by the descriptor and extent files
+    pub allocated: u64,
+}
+
+impl ProbedDisk {
+    /// Size of the virtual disk in bytes
+    pub fn size(&self) -> u64 {
+        self.descriptor.capacity() * crate::SECTOR_SIZE
+    }
+
+    /// Whether the disk is a delta disk
+    pub fn has_parent(&self) -> bool {
+        self.descriptor.parent_cid != descriptor::NO_PARENT_CID
+    }
+}
+
+/// What `probe_dir` found in a directory
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    /// Disks, sorted by path
+    pub disks: Vec<ProbedDisk>,
+    /// Snapshot chains as indices into `disks`, base disk first, one per
+    /// leaf disk. Chains sharing a base are snapshot trees.
+    pub chains: Vec<Vec<usize>>,
+    /// `.vmdk` files no disk names as an extent
+    pub orphans: Vec<(PathBuf, Format)>,
+    /// Delta disks whose parent was not found, as indices into `disks`
+    pub broken: Vec<usize>,
+    /// Files that could not be probed, and why
+    pub unreadable: Vec<(PathBuf, String)>,
+}
+
+impl Inventory {
+    /// Disks at the tip of a chain, the ones a VM writes to
+    pub fn leaves(&self) -> impl Iterator<Item = &ProbedDisk> {
+        self.chains.iter().filter_map(move |chain| chain.last().map(|&i| &self.disks[i]))
+    }
+
+    /// Bytes taken by every file found
+    pub fn allocated(&self) -> u64 {
+        self.disks.iter().map(|d| d.allocated).sum()
+    }
+}
+
+impl fmt::Display for Inventory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} disks in {} chains", self.disks.len(), self.chains.len())?;
+        for chain in &self.chains {
+            let names: Vec<String> = chain.iter().map(|&i| self.disks[i].path.display().to_string()).collect();
+            write!(f, "\n    {}", names.join(" <- "))?;
+        }
+        for &i in &self.broken {
+            write!(f, "\nmissing parent: {}", self.disks[i].path.display())?;
+        }
+        for (path, format) in &self.orphans {
+            write!(f, "\norphan: {} ({})", path.display(), format)?;
+        }
+        for (path, reason) in &self.unreadable {
+            write!(f, "\nunreadable: {}: {}", path.display(), reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// One `.vmdk` file, as probed by a worker of `probe_dir`
+struct Probed {
+    path: PathBuf,
+    format: Format,
+    len: u64,
+    descriptor: Option<Descriptor>,
+}
+
+/// Probe every `.vmdk` file in the datastore directory `dir`, several at
+/// once, and group them into disks and snapshot chains. Files that fail to
+/// probe are listed in the inventory rather than failing the whole walk.
+pub fn probe_dir<P: AsRef<Path>>(dir: P, options: &ProbeOptions) -> Result<Inventory, Error> {
+    let mut paths = Vec::new();
+    walk(dir.as_ref(), options.recursive, &mut paths)?;
+    paths.sort();
+
+    let next = AtomicUsize::new(0);
+    let workers = options.threads.clamp(1, std::cmp::max(paths.len(), 1));
+    let mut results: Vec<(usize, Result<Probed, Error>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        match paths.get(i) {
+                            Some(path) => done.push((i, probe_file(path))),
+                            None => return done,
+                        }
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().expect("probe worker panicked")).collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+
+    let mut inventory = Inventory::default();
+    let mut others = Vec::new();
+    for (i, result) in results {
+        match result {
+            Ok(Probed { path, format, len, descriptor: Some(descriptor) }) => inventory.disks.push(ProbedDisk {
+                path,
+                format,
+                descriptor,
+                extents: Vec::new(),
+                missing_extents: Vec::new(),
+                parent: None,
+                allocated: len,
+            }),
+            Ok(probed) => others.push(probed),
+            Err(e) => {
+                warn!("Could not probe {}: {}", paths[i].display(), e);
+                inventory.unreadable.push((paths[i].clone(), e.to_string()));
+            }
+        }
+    }
+
+    // Extent files, claimed by the disks naming them
+    let mut extent_files: HashMap<PathBuf, &Probed> = others.iter().map(|p| (identity(&p.path), p)).collect();
+    let disk_index: HashMap<PathBuf, usize> =
+        inventory.disks.iter().enumerate().map(|(i, d)| (identity(&d.path), i)).collect();
+    for disk in &mut inventory.disks {
+        // Monolithic sparse disks are their own extent, whatever it is named
+        let names: Vec<&str> = match disk.format {
+            Format::Sparse => Vec::new(),
+            _ => disk.descriptor.extents.iter().filter_map(|e| e.filename.as_deref()).collect(),
+        };
+        for name in names {
+            let path = DefaultResolver.resolve(&disk.path, name);
+            match extent_files.remove(&identity(&path)) {
+                Some(probed) => {
+                    disk.allocated += probed.len;
+                    disk.extents.push(probed.path.clone());
+                }
+                None if path.exists() => disk.extents.push(path),
+                None => disk.missing_extents.push(path),
+            }
+        }
+        if let Some(hint) = &disk.descriptor.parent_file_name_hint {
+            let parent = DefaultResolver.resolve(&disk.path, hint);
+            disk.parent = disk_index.get(&identity(&parent)).map(|_| parent);
+        }
+    }
+    let mut orphans: Vec<_> = extent_files.into_values().map(|p| (p.path.clone(), p.format)).collect();
+    orphans.sort_by(|a, b| a.0.cmp(&b.0));
+    inventory.orphans = orphans;
+
+    // Chains, walked from each leaf to its base
+    let parents: Vec<Option<usize>> = inventory
+        .disks
+        .iter()
+        .map(|d| d.parent.as_ref().and_then(|p| disk_index.get(&identity(p)).copied()))
+        .collect();
+    let mut has_children = vec![false; parents.len()];
+    for &parent in parents.iter().flatten() {
+        has_children[parent] = true;
+    }
+    for leaf in (0..parents.len()).filter(|&i| !has_children[i]) {
+        let mut chain = vec![leaf];
+        let mut at = leaf;
+        while let Some(parent) = parents[at] {
+            if chain.contains(&parent) {
+                break;
+            }
+            chain.push(parent);
+            at = parent;
+        }
+        chain.reverse();
+        let base = chain[0];
+        if inventory.disks[base].has_parent() && !inventory.broken.contains(&base) {
+            inventory.broken.push(base);
+        }
+        inventory.chains.push(chain);
+    }
+    inventory.broken.sort();
+    info!("Probed {} files: {}", paths.len(), inventory);
+    Ok(inventory)
+}
+
+/// `.vmdk` files in `dir`, and its subdirectories if `recursive`
+fn walk(dir: &Path, recursive: bool, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            if recursive {
+                walk(&path, recursive, paths)?;
+            }
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vmdk")) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The format of the file at `path` and, for disks, their descriptor
+fn probe_file(path: &Path) -> Result<Probed, Error> {
+    let format = detect(path)?;
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let descriptor = match format {
+        Format::Sparse | Format::Descriptor => match crate::read_descriptor(&mut file, Strictness::Lenient) {
+            Ok((_, bytes, _)) => Some(Descriptor::new(descriptor::decode(&bytes).trim_end_matches(char::from(0)))?),
+            Err(e) if matches!(e.downcast_ref(), Some(VmdkError::MissingDescriptor(_))) => None,
+            Err(e) => return Err(e),
+        },
+        _ => None,
+    };
+    Ok(Probed { path: path.to_owned(), format, len, descriptor })
+}
+
+/// Key telling whether two paths name the same file
+fn identity(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+
+    #[test]
+    fn test_detect() {
+        let dir = scratch_dir("probe");
+        let sparse = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        std::fs::write(dir.join("disk.vmdk"), &sparse).unwrap();
+        std::fs::write(dir.join("flat.vmdk"), "# Disk DescriptorFile\nversion=1\nCID=fffffffe\n\
+            parentCID=ffffffff\ncreateType=\"monolithicFlat\"\nRW 1024 FLAT \"disk.raw\" 0\n").unwrap();
+        let mut raw = vec![0u8; 1024 * 512];
+        raw[512..1024].fill(0xcd);
+        std::fs::write(dir.join("disk.raw"), &raw).unwrap();
+        let mut qcow = b"QFI\xfb\0\0\0\x03".to_vec();
+        qcow.resize(4096, 0);
+        std::fs::write(dir.join("disk.qcow2"), &qcow).unwrap();
+        let mut vhd = vec![0u8; 2048];
+        vhd[1536..1544].copy_from_slice(VHD_COOKIE);
+        std::fs::write(dir.join("disk.vhd"), &vhd).unwrap();
+        std::fs::write(dir.join("disk.cowd"), b"COWD\x01\0\0\0").unwrap();
+
+        assert_eq!(detect(dir.join("disk.vmdk")).unwrap(), Format::Sparse);
+        assert_eq!(detect(dir.join("flat.vmdk")).unwrap(), Format::Descriptor);
+        assert_eq!(detect(dir.join("disk.raw")).unwrap(), Format::Raw);
+        assert_eq!(detect(dir.join("disk.qcow2")).unwrap(), Format::Qcow);
+        assert_eq!(detect(dir.join("disk.vhd")).unwrap(), Format::Vhd);
+        assert_eq!(detect(dir.join("disk.cowd")).unwrap(), Format::Cowd);
+
+        let mut buf = [0u8; 512];
+        for name in &["disk.vmdk", "disk.raw", "flat.vmdk"] {
+            let mut image = open_any(dir.join(name)).unwrap();
+            assert_eq!(image.size().unwrap(), 1024 * 512);
+            assert_eq!(image.read_at(1024 * 512, &mut buf).unwrap(), 0);
+        }
+        let mut image = open_any(dir.join("disk.raw")).unwrap();
+        assert!(matches!(image, AnyImage::Raw(_)));
+        image.read_at(512, &mut buf).unwrap();
+        assert_eq!(buf, [0xcd; 512]);
+
+        let err = open_any(dir.join("disk.qcow2")).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(VmdkError::UnsupportedFormat(_))));
+        assert!(err.to_string().contains("qcow"));
+    }
+
+    #[test]
+    fn test_probe_dir() {
+        let dir = scratch_dir("probe-dir");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").build();
+        std::fs::write(dir.join("base.vmdk"), &base).unwrap();
+        let cid = 0x12345678;
+        for (name, parent) in &[("snap1.vmdk", "base.vmdk"), ("snap2.vmdk", "snap1.vmdk"), ("other.vmdk", "base.vmdk")] {
+            let image = SparseImage::new(1024, 128).child(parent, cid).build();
+            std::fs::write(dir.join(name), &image).unwrap();
+        }
+        std::fs::write(dir.join("flat.vmdk"), "# Disk DescriptorFile\nversion=1\nCID=fffffffe\n\
+            parentCID=ffffffff\ncreateType=\"monolithicFlat\"\nRW 1024 FLAT \"flat-flat.vmdk\" 0\n\
+            RW 1024 FLAT \"gone-flat.vmdk\" 0\n").unwrap();
+        std::fs::write(dir.join("flat-flat.vmdk"), vec![0u8; 1024 * 512]).unwrap();
+        std::fs::write(dir.join("stray-flat.vmdk"), vec![0u8; 4096]).unwrap();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let orphan = SparseImage::new(1024, 128).child("nowhere.vmdk", 1).build();
+        std::fs::write(dir.join("sub").join("lost.vmdk"), &orphan).unwrap();
+
+        let inventory = probe_dir(&dir, ProbeOptions::new().threads(3)).unwrap();
+        let names: Vec<_> = inventory.disks.iter().map(|d| d.path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["base.vmdk", "flat.vmdk", "other.vmdk", "snap1.vmdk", "snap2.vmdk"]);
+        assert_eq!(inventory.chains, [vec![1], vec![0, 2], vec![0, 3, 4]]);
+        assert_eq!(inventory.leaves().count(), 3);
+        let flat = &inventory.disks[1];
+        assert_eq!(flat.extents, [dir.join("flat-flat.vmdk")]);
+        assert_eq!(flat.missing_extents, [dir.join("gone-flat.vmdk")]);
+        assert_eq!(flat.size(), 2048 * 512);
+        assert_eq!(inventory.disks[4].parent, Some(dir.join("snap1.vmdk")));
+        assert_eq!(inventory.orphans, [(dir.join("stray-flat.vmdk"), Format::Raw)]);
+        assert!(inventory.broken.is_empty() && inventory.unreadable.is_empty());
+
+        let inventory = probe_dir(&dir, ProbeOptions::new().threads(1).recursive(true)).unwrap();
+        assert_eq!(inventory.disks.len(), 6);
+        assert_eq!(inventory.broken, [5]);
+    }
+}
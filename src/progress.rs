@@ -0,0 +1,93 @@
+//! Progress reporting and cancellation of long-running operations such as
+//! cloning.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use failure::Error;
+
+use crate::VmdkError;
+
+/// Receives progress of an operation. Called from the thread running it.
+pub trait Progress: Debug + Send + Sync {
+    /// A new phase of the operation started, e.g. "copying"
+    fn phase(&self, _name: &str) {}
+
+    /// `done` of `total` bytes have been processed
+    fn update(&self, done: u64, total: u64);
+
+    /// Polled between steps; returning `true` stops the operation with
+    /// `VmdkError::Cancelled`
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Draws a progress bar with `indicatif`
+#[cfg(feature = "indicatif")]
+impl Progress for indicatif::ProgressBar {
+    fn phase(&self, name: &str) {
+        self.set_message(name.to_owned());
+    }
+
+    fn update(&self, done: u64, total: u64) {
+        self.set_length(total);
+        self.set_position(done);
+    }
+}
+
+/// Asks running operations to stop. Clones share the same flag, so one
+/// can be handed to the operation and another kept to cancel it from any
+/// thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop operations watching this token at their next safe point
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Reports to the optional progress receiver and cancel token of one
+/// operation
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Monitor<'a> {
+    pub(crate) progress: Option<&'a dyn Progress>,
+    pub(crate) cancel: Option<&'a CancelToken>,
+}
+
+impl<'a> Monitor<'a> {
+    pub(crate) fn phase(&self, name: &str) {
+        if let Some(progress) = self.progress {
+            progress.phase(name);
+        }
+    }
+
+    /// Report progress, failing with `VmdkError::Cancelled` if asked to stop
+    pub(crate) fn step(&self, done: u64, total: u64) -> Result<(), Error> {
+        let mut cancelled = self.cancel.map(|c| c.is_cancelled()).unwrap_or(false);
+        if let Some(progress) = self.progress {
+            progress.update(done, total);
+            cancelled |= progress.cancelled();
+        }
+        if cancelled {
+            return Err(VmdkError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn done(&self, total: u64) {
+        if let Some(progress) = self.progress {
+            progress.update(total, total);
+        }
+    }
+}
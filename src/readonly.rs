@@ -0,0 +1,332 @@
+//! A disk handle that cannot write.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use byteorder::{LittleEndian, ReadBytesExt};
+use failure::Error;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::analysis::{EntropyReport, Fragmentation, UnreferencedRegion};
+use crate::check::CheckReport;
+use crate::clone::CloneOptions;
+use crate::descriptor::Descriptor;
+use crate::extent::GD_AT_END;
+use crate::scan::{Hit, Pattern, ScanOptions};
+use crate::timeline::Timeline;
+use crate::{ExtentHeader, MapEntry, Vmdk, VmdkError, VmdkOpenOptions, EXTENT_MAGIC, MAX_TEXT_DESCRIPTOR, SECTOR_SIZE};
+
+/// Metadata hashes of one file of a disk opened with
+/// `VmdkOpenOptions::write_blocker`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvidenceRecord {
+    pub path: PathBuf,
+    /// SHA-256 of the metadata when the disk was opened, in hex
+    pub opened_hash: String,
+    pub opened_at: SystemTime,
+    /// SHA-256 of the metadata when the disk was closed, in hex
+    pub closed_hash: Option<String>,
+    pub closed_at: Option<SystemTime>,
+}
+
+impl EvidenceRecord {
+    /// Whether the metadata was verified unchanged at close
+    pub fn unchanged(&self) -> bool {
+        self.closed_hash.as_ref() == Some(&self.opened_hash)
+    }
+}
+
+/// Record of the metadata hashes of every file of a disk, taken when it
+/// was opened and again when it was closed, for the caller to persist
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EvidenceLog {
+    pub records: Vec<EvidenceRecord>,
+}
+
+/// A disk opened read-only, offering only operations that leave its files
+/// untouched: no writes, no content ID changes, no locks or lock files.
+/// Meant for evidence that must not change while it is examined.
+pub struct VmdkReadOnly(Vmdk, Option<EvidenceLog>);
+
+impl VmdkReadOnly {
+    /// Open the disk at `path` with default options
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        VmdkOpenOptions::new().open_read_only(path)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    /// Hashes taken at open, with `VmdkOpenOptions::write_blocker`
+    pub fn evidence_log(&self) -> Option<&EvidenceLog> {
+        self.1.as_ref()
+    }
+
+    /// Close the disk. With `VmdkOpenOptions::write_blocker`, hash the
+    /// metadata of its files again and fail if any of them changed since
+    /// the disk was opened; either way the log is returned, with the
+    /// hashes at close filled in, on failure in
+    /// `VmdkError::EvidenceModified`. Files that can no longer be read are
+    /// logged without a hash at close.
+    pub fn close(self) -> Result<Option<EvidenceLog>, Error> {
+        drop(self.0);
+        let mut log = match self.1 {
+            Some(log) => log,
+            None => return Ok(None),
+        };
+        for record in log.records.iter_mut() {
+            record.closed_hash = match metadata_hash(&record.path) {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    warn!("Failed to hash {} at close: {}", record.path.display(), e);
+                    None
+                }
+            };
+            record.closed_at = Some(SystemTime::now());
+        }
+        if let Some(record) = log.records.iter().find(|r| !r.unchanged()) {
+            let path = record.path.display().to_string();
+            return Err(VmdkError::EvidenceModified { path, log }.into());
+        }
+        info!("Verified {} files unchanged", log.records.len());
+        Ok(Some(log))
+    }
+
+    /// See `Vmdk::read_at`
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        self.0.read_at(offset, buf)
+    }
+
+    pub fn extent_header(&self) -> Option<&ExtentHeader> {
+        self.0.extent_header.as_ref()
+    }
+
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.0.descriptor
+    }
+
+    /// See `Vmdk::raw_descriptor`
+    pub fn raw_descriptor(&self) -> &str {
+        self.0.raw_descriptor()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+
+    pub fn cid(&self) -> u32 {
+        self.0.cid()
+    }
+
+    /// See `Vmdk::component_files`
+    pub fn component_files(&self) -> Vec<PathBuf> {
+        self.0.component_files()
+    }
+
+    /// See `Vmdk::map`
+    pub fn map(&mut self) -> Result<Vec<MapEntry>, Error> {
+        self.0.map()
+    }
+
+    /// See `Vmdk::locate`
+    pub fn locate(&mut self, offset: u64) -> Result<Option<(PathBuf, u64)>, Error> {
+        self.0.locate(offset)
+    }
+
+    /// See `Vmdk::physical_to_logical`
+    pub fn physical_to_logical<P: AsRef<Path>>(&mut self, file: P, offset: u64) -> Result<Option<u64>, Error> {
+        self.0.physical_to_logical(file, offset)
+    }
+
+    /// See `Vmdk::check`
+    pub fn check(&mut self) -> Result<CheckReport, Error> {
+        self.0.check()
+    }
+
+    /// See `Vmdk::scan`
+    pub fn scan(&mut self, patterns: &[Pattern], options: &ScanOptions) -> Result<Vec<Hit>, Error> {
+        self.0.scan(patterns, options)
+    }
+
+    /// See `Vmdk::timeline`
+    pub fn timeline(&self) -> Result<Timeline, Error> {
+        self.0.timeline()
+    }
+
+    /// See `Vmdk::entropy`
+    pub fn entropy(&mut self, threshold: f64) -> Result<EntropyReport, Error> {
+        self.0.entropy(threshold)
+    }
+
+    /// See `Vmdk::fragmentation`
+    pub fn fragmentation(&mut self) -> Result<Fragmentation, Error> {
+        self.0.fragmentation()
+    }
+
+    /// See `Vmdk::unreferenced_regions`
+    pub fn unreferenced_regions(&mut self) -> Result<Vec<UnreferencedRegion>, Error> {
+        self.0.unreferenced_regions()
+    }
+
+    /// Copy the disk into a new image at `path`, see `Vmdk::clone_to`
+    pub fn clone_to<P: AsRef<Path>>(&mut self, path: P, options: &CloneOptions) -> Result<(), Error> {
+        self.0.clone_to(path, options)
+    }
+
+    /// Copy the contents of the disk into a raw image, see
+    /// `Vmdk::export_raw`
+    pub fn export_raw<P: AsRef<Path>>(&mut self, path: P, options: &CloneOptions) -> Result<(), Error> {
+        self.0.export_raw(path, options)
+    }
+}
+
+impl VmdkOpenOptions {
+    /// Open the disk at `path` as a `VmdkReadOnly`, whatever `write` is set
+    /// to
+    pub fn open_read_only<P: AsRef<Path>>(&self, path: P) -> Result<VmdkReadOnly, Error> {
+        let path = path.as_ref();
+        let mut options = self.clone();
+        options.write(false);
+        let vmdk = options.open(path)?;
+        if !self.write_blocker {
+            return Ok(VmdkReadOnly(vmdk, None));
+        }
+
+        let mut log = EvidenceLog::default();
+        for file in vmdk.component_files() {
+            log.records.push(EvidenceRecord {
+                opened_hash: metadata_hash(&file)?,
+                opened_at: SystemTime::now(),
+                path: file,
+                closed_hash: None,
+                closed_at: None,
+            });
+        }
+        Ok(VmdkReadOnly(vmdk, Some(log)))
+    }
+}
+
+/// SHA-256 over the length of the file at `path` and its metadata: the
+/// header, embedded descriptor and grain directories of a sparse extent,
+/// or all of a text descriptor. Flat extents are only covered by their
+/// length, as hashing them would mean reading the whole disk.
+fn metadata_hash(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut hasher = Sha256::new();
+    hasher.update(len.to_le_bytes());
+
+    file.seek(SeekFrom::Start(0))?;
+    let magic = if len >= 4 { file.read_u32::<LittleEndian>()? } else { 0 };
+    if magic == EXTENT_MAGIC {
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = ExtentHeader::new(&mut file)?;
+        hash_sectors(&mut hasher, &mut file, 0, 1)?;
+        if header.gd_offset.0 == GD_AT_END && len >= 3 * SECTOR_SIZE {
+            // Footer marker, footer and end-of-stream marker
+            hash_sectors(&mut hasher, &mut file, len / SECTOR_SIZE - 3, 3)?;
+            file.seek(SeekFrom::Start(len - 2 * SECTOR_SIZE))?;
+            header = ExtentHeader::new(&mut file)?;
+        }
+        hash_sectors(&mut hasher, &mut file, header.desc_offset.0, header.desc_size.0)?;
+        let num_gts = header.capacity.0.div_ceil(header.grain_size.0).div_ceil(u64::from(header.gtes_per_gt));
+        let gd_sectors = (num_gts * 4).div_ceil(SECTOR_SIZE);
+        for &gd in &[header.gd_offset.0, header.rgd_offset.0] {
+            if gd != 0 && gd != GD_AT_END {
+                hash_sectors(&mut hasher, &mut file, gd, gd_sectors)?;
+            }
+        }
+    } else if len <= MAX_TEXT_DESCRIPTOR {
+        let mut text = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut text)?;
+        hasher.update(&text);
+    }
+
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn hash_sectors(hasher: &mut Sha256, file: &mut File, sector: u64, sectors: u64) -> Result<(), Error> {
+    let mut buf = vec![0u8; (sectors * SECTOR_SIZE).try_into()?];
+    file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    file.read_exact(&mut buf)?;
+    hasher.update(&buf);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock;
+    use crate::testutil::{scratch_dir, SparseImage};
+
+    #[test]
+    fn test_read_only() {
+        let dir = scratch_dir("read-only");
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        std::fs::write(&path, &image).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+        let mut options = VmdkOpenOptions::new();
+        options.write(true).vmware_lock(true);
+        let mut vmdk = options.open_read_only(&path).unwrap();
+        assert!(!lock::is_locked(&path));
+        let mut buf = [0u8; 512];
+        vmdk.read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+        assert!(vmdk.check().unwrap().problems.is_empty());
+        drop(vmdk);
+
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), modified);
+    }
+
+    #[test]
+    fn test_write_blocker() {
+        let dir = scratch_dir("write-blocker");
+        let path = dir.join("disk.vmdk");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build();
+        std::fs::write(&path, &image).unwrap();
+        let mut options = VmdkOpenOptions::new();
+        options.write_blocker(true);
+
+        let vmdk = options.open_read_only(&path).unwrap();
+        let log = vmdk.evidence_log().unwrap().clone();
+        assert_eq!(log.records.len(), 1);
+        assert_eq!(log.records[0].opened_hash.len(), 64);
+        let log = vmdk.close().unwrap().unwrap();
+        assert!(log.records[0].unchanged());
+
+        // Grain data is not covered, grain directories are
+        let vmdk = options.open_read_only(&path).unwrap();
+        let mut changed = image.clone();
+        changed[128 * 512] = 0;
+        std::fs::write(&path, &changed).unwrap();
+        assert!(vmdk.close().is_ok());
+        let vmdk = options.open_read_only(&path).unwrap();
+        let header = ExtentHeader::new(&image[..]).unwrap();
+        changed[header.gd_offset.bytes() as usize] ^= 1;
+        std::fs::write(&path, &changed).unwrap();
+        match vmdk.close().err().unwrap().downcast::<VmdkError>() {
+            Ok(VmdkError::EvidenceModified { path: modified, log }) => {
+                assert_eq!(modified, path.display().to_string());
+                assert!(!log.records[0].unchanged());
+                assert!(log.records[0].closed_hash.is_some() && log.records[0].closed_at.is_some());
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // A file gone at close is reported as changed, the log kept
+        let vmdk = options.open_read_only(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        match vmdk.close().err().unwrap().downcast::<VmdkError>() {
+            Ok(VmdkError::EvidenceModified { log, .. }) => assert_eq!(log.records[0].closed_hash, None),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Copying whole files as cheaply as the filesystem allows.
+//!
+//! On Linux a copy is first attempted as a reflink (`FICLONE`), which on
+//! Btrfs, XFS and other copy-on-write filesystems shares the data of the
+//! source instead of copying it and completes at once. Otherwise the
+//! ranges holding data are copied with `copy_file_range`, which such
+//! filesystems may still share and others copy in the kernel, and holes
+//! stay holes. Where neither works, data is read and written, skipping
+//! zeros so sparse files stay sparse.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use failure::Error;
+
+use crate::extent::is_zero;
+use crate::progress::Monitor;
+
+/// Bytes copied between progress reports
+const CHUNK: usize = 1 << 20;
+
+/// How `copy_file` copied a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CopyMethod {
+    /// The copy shares the data of the source
+    Reflink,
+    /// Ranges holding data were copied by the kernel
+    CopyFileRange,
+    /// Data was read and written
+    ReadWrite,
+}
+
+/// Copy all of `src` into the empty file `dst`, reporting progress to
+/// `monitor` as `done` plus the bytes copied of `total`, and adding them
+/// to `done`
+pub(crate) fn copy_file(src: &mut File, dst: &mut File, monitor: Monitor, done: &mut u64, total: u64) -> Result<CopyMethod, Error> {
+    let len = src.metadata()?.len();
+    monitor.step(*done, total)?;
+    let method = copy_data(src, dst, monitor, done, total)?;
+    // Trailing holes have no data range to extend the copy
+    dst.set_len(len)?;
+    Ok(method)
+}
+
+#[cfg(target_os = "linux")]
+fn copy_data(src: &mut File, dst: &mut File, monitor: Monitor, done: &mut u64, total: u64) -> Result<CopyMethod, Error> {
+    use std::os::unix::io::AsRawFd;
+
+    /// `_IOW(0x94, 9, int)`
+    const FICLONE: u64 = 0x4004_9409;
+
+    let len = src.metadata()?.len();
+    // SAFETY: FICLONE only reads the file descriptor passed as argument
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } == 0 {
+        *done += len;
+        return Ok(CopyMethod::Reflink);
+    }
+
+    let mut method = CopyMethod::CopyFileRange;
+    let mut pos = 0;
+    while let Some((start, end)) = data_range(src, pos, len) {
+        let mut offset = start;
+        while offset < end {
+            monitor.step(*done, total)?;
+            let n = std::cmp::min(end - offset, CHUNK as u64) as usize;
+            let (mut from, mut to) = (offset as libc::loff_t, offset as libc::loff_t);
+            // SAFETY: both offsets point at locals living across the call
+            let copied = unsafe { libc::copy_file_range(src.as_raw_fd(), &mut from, dst.as_raw_fd(), &mut to, n, 0) };
+            if copied > 0 {
+                offset += copied as u64;
+                *done += copied as u64;
+            } else {
+                // Not supported between these files, or the source shrank
+                method = CopyMethod::ReadWrite;
+                read_write(src, dst, offset, end, monitor, done, total)?;
+                offset = end;
+            }
+        }
+        pos = end;
+    }
+    Ok(method)
+}
+
+/// The next range of `file` from `pos` holding data, as `SEEK_DATA` and
+/// `SEEK_HOLE` find it, or everything left where they are not supported
+#[cfg(target_os = "linux")]
+fn data_range(file: &File, pos: u64, len: u64) -> Option<(u64, u64)> {
+    use std::os::unix::io::AsRawFd;
+
+    if pos >= len {
+        return None;
+    }
+    // SAFETY: lseek only moves the offset of the file descriptor
+    let start = unsafe { libc::lseek(file.as_raw_fd(), pos as libc::off_t, libc::SEEK_DATA) };
+    if start < 0 {
+        // ENXIO: no data past `pos`
+        return match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENXIO) => None,
+            _ => Some((pos, len)),
+        };
+    }
+    // SAFETY: as above
+    let end = unsafe { libc::lseek(file.as_raw_fd(), start, libc::SEEK_HOLE) };
+    let end = if end < 0 { len } else { std::cmp::min(end as u64, len) };
+    Some((start as u64, end))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_data(src: &mut File, dst: &mut File, monitor: Monitor, done: &mut u64, total: u64) -> Result<CopyMethod, Error> {
+    let len = src.metadata()?.len();
+    read_write(src, dst, 0, len, monitor, done, total)?;
+    Ok(CopyMethod::ReadWrite)
+}
+
+/// Copy bytes `start..end` of `src` by reading and writing them, leaving
+/// holes in `dst` where `src` holds zeros
+fn read_write(src: &mut File, dst: &mut File, start: u64, end: u64, monitor: Monitor, done: &mut u64, total: u64) -> Result<(), Error> {
+    let mut buf = vec![0u8; CHUNK];
+    src.seek(SeekFrom::Start(start))?;
+    let mut pos = start;
+    while pos < end {
+        monitor.step(*done, total)?;
+        let n = std::cmp::min(end - pos, CHUNK as u64) as usize;
+        src.read_exact(&mut buf[..n])?;
+        if !is_zero(&buf[..n]) {
+            dst.seek(SeekFrom::Start(pos))?;
+            dst.write_all(&buf[..n])?;
+        }
+        pos += n as u64;
+        *done += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use crate::testutil::scratch_dir;
+
+    #[test]
+    fn test_copy_file() {
+        let dir = scratch_dir("reflink");
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        let mut file = File::create(&src).unwrap();
+        file.set_len(8 << 20).unwrap();
+        file.seek(SeekFrom::Start(3 << 20)).unwrap();
+        file.write_all(&[0xb1; 5000]).unwrap();
+        drop(file);
+
+        let mut input = File::open(&src).unwrap();
+        let mut output = OpenOptions::new().read(true).write(true).create_new(true).open(&dst).unwrap();
+        let mut done = 0;
+        copy_file(&mut input, &mut output, Monitor::default(), &mut done, 8 << 20).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), std::fs::read(&src).unwrap());
+        assert!(done <= 8 << 20);
+
+        // Reading and writing leaves holes where the source holds zeros
+        let mut data = vec![0u8; 3 * CHUNK];
+        data[CHUNK + 7] = 1;
+        std::fs::write(&src, &data).unwrap();
+        let mut input = File::open(&src).unwrap();
+        let mut output = File::create(&dst).unwrap();
+        let mut done = 0;
+        read_write(&mut input, &mut output, 0, data.len() as u64, Monitor::default(), &mut done, 0).unwrap();
+        output.set_len(data.len() as u64).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert_eq!(done, data.len() as u64);
+    }
+}
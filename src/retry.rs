@@ -0,0 +1,143 @@
+//! Retrying reads that fail transiently, as happens on network-backed
+//! storage such as NFS.
+
+use std::io::{self, ErrorKind};
+use std::thread;
+use std::time::Duration;
+use failure::Error;
+use log::warn;
+
+/// `EIO` on Unix, which the standard library reports without an
+/// `ErrorKind` of its own
+const EIO: i32 = 5;
+
+/// How often and how patiently to retry failed reads
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    kinds: Vec<ErrorKind>,
+    os_errors: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            kinds: vec![
+                ErrorKind::Interrupted,
+                ErrorKind::TimedOut,
+                ErrorKind::ConnectionReset,
+                ErrorKind::ConnectionAborted,
+            ],
+            os_errors: if cfg!(unix) { vec![EIO] } else { Vec::new() },
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retry up to 3 times on interrupted, timed out or reset I/O and `EIO`,
+    /// waiting 100ms before the first retry and doubling the wait each time
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up after `retries` retries
+    pub fn retries(&mut self, retries: u32) -> &mut Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait `backoff` before the first retry, doubling it for each further
+    /// retry up to `max_backoff`
+    pub fn backoff(&mut self, backoff: Duration, max_backoff: Duration) -> &mut Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Retry I/O errors of these kinds, replacing the defaults
+    pub fn error_kinds(&mut self, kinds: &[ErrorKind]) -> &mut Self {
+        self.kinds = kinds.to_vec();
+        self
+    }
+
+    /// Retry I/O errors with these raw OS error codes, replacing the
+    /// defaults
+    pub fn os_errors(&mut self, codes: &[i32]) -> &mut Self {
+        self.os_errors = codes.to_vec();
+        self
+    }
+
+    /// Whether `err` is worth retrying
+    pub fn is_retryable(&self, err: &Error) -> bool {
+        match err.downcast_ref::<io::Error>() {
+            Some(e) => {
+                self.kinds.contains(&e.kind())
+                    || e.raw_os_error().map(|c| self.os_errors.contains(&c)).unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    /// Run `op` until it succeeds, fails with an error that is not
+    /// retryable, or runs out of retries
+    pub(crate) fn run<T, F>(&self, mut op: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(ref e) if attempt < self.retries && self.is_retryable(e) => {
+                    attempt += 1;
+                    warn!("Retrying after {} (attempt {} of {})", e, attempt, self.retries);
+                    thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, self.max_backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let mut policy = RetryPolicy::new();
+        policy.retries(2).backoff(Duration::from_millis(1), Duration::from_millis(2));
+
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            if calls < 3 {
+                return Err(io::Error::from_raw_os_error(EIO).into());
+            }
+            Ok(calls)
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        calls = 0;
+        let result: Result<(), Error> = policy.run(|| {
+            calls += 1;
+            Err(io::Error::from(ErrorKind::TimedOut).into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        calls = 0;
+        let result: Result<(), Error> = policy.run(|| {
+            calls += 1;
+            Err(io::Error::from(ErrorKind::NotFound).into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,326 @@
+//! Searching the contents of a disk for byte signatures.
+
+use std::path::PathBuf;
+use failure::Error;
+use log::info;
+
+use crate::analysis::RegionKind;
+use crate::{Vmdk, VmdkError};
+
+/// Bytes read at a time by default
+const DEFAULT_CHUNK: usize = 1 << 20;
+
+/// Longest regex match found across chunk boundaries by default
+const DEFAULT_MAX_MATCH_LEN: usize = 4096;
+
+/// What `Vmdk::scan` searches for
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// An exact byte string
+    Bytes(Vec<u8>),
+    /// A regular expression over bytes
+    #[cfg(feature = "regex")]
+    Regex(regex::bytes::Regex),
+}
+
+impl Pattern {
+    /// Bytes a match can span, as far as the scan keeps track
+    #[cfg_attr(not(feature = "regex"), allow(unused_variables))]
+    fn max_len(&self, options: &ScanOptions) -> usize {
+        match self {
+            Pattern::Bytes(bytes) => bytes.len(),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(_) => options.max_match_len,
+        }
+    }
+
+    /// Start and end of every match in `buf`
+    fn find_all(&self, buf: &[u8], found: &mut dyn FnMut(usize, usize)) {
+        match self {
+            Pattern::Bytes(bytes) => {
+                for (i, window) in buf.windows(bytes.len()).enumerate() {
+                    if window == &bytes[..] {
+                        found(i, i + bytes.len());
+                    }
+                }
+            }
+            #[cfg(feature = "regex")]
+            Pattern::Regex(regex) => {
+                for m in regex.find_iter(buf) {
+                    found(m.start(), m.end());
+                }
+            }
+        }
+    }
+}
+
+/// Options for `Vmdk::scan`
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    include_parents: bool,
+    include_orphans: bool,
+    chunk_size: usize,
+    max_match_len: usize,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            include_parents: true,
+            include_orphans: false,
+            chunk_size: DEFAULT_CHUNK,
+            max_match_len: DEFAULT_MAX_MATCH_LEN,
+        }
+    }
+}
+
+impl ScanOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also search data the disk reads from its parents. Defaults to true.
+    pub fn include_parents(&mut self, include: bool) -> &mut Self {
+        self.include_parents = include;
+        self
+    }
+
+    /// Also search grains of this disk no grain table points to anymore
+    pub fn include_orphans(&mut self, include: bool) -> &mut Self {
+        self.include_orphans = include;
+        self
+    }
+
+    /// Bytes read at a time. Defaults to 1 MiB.
+    pub fn chunk_size(&mut self, size: usize) -> &mut Self {
+        self.chunk_size = size;
+        self
+    }
+
+    /// Longest regex match found across chunk boundaries. Defaults to
+    /// 4 KiB.
+    pub fn max_match_len(&mut self, len: usize) -> &mut Self {
+        self.max_match_len = len;
+        self
+    }
+}
+
+/// A match found by `Vmdk::scan`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    /// Index of the pattern that matched
+    pub pattern: usize,
+    /// Byte offset in the disk, `None` for orphaned grains
+    pub logical: Option<u64>,
+    /// Extent file and byte offset in it, `None` inside compressed grains
+    pub physical: Option<(PathBuf, u64)>,
+    /// Length of the match in bytes
+    pub length: usize,
+}
+
+impl Vmdk {
+    /// Search the data of the disk for `patterns`, reading it a chunk at a
+    /// time. Ranges reading as zeros are skipped. Matches are returned in
+    /// the order they are found.
+    pub fn scan(&mut self, patterns: &[Pattern], options: &ScanOptions) -> Result<Vec<Hit>, Error> {
+        if patterns.iter().any(|p| p.max_len(options) == 0) || options.chunk_size == 0 {
+            return Err(VmdkError::InvalidArgument("empty pattern or chunk size".to_owned()).into());
+        }
+        let overlap = patterns.iter().map(|p| p.max_len(options)).max().unwrap_or(1);
+        let mut hits = Vec::new();
+
+        // Adjacent ranges are searched as one, wherever they are stored, so
+        // matches spanning them are found
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for entry in self.map()?.iter().filter(|e| e.data && (e.depth == 0 || options.include_parents)) {
+            match ranges.last_mut() {
+                Some(last) if last.1 == entry.offset => last.1 += entry.length,
+                _ => ranges.push((entry.offset, entry.offset + entry.length)),
+            }
+        }
+        for (start, end) in ranges {
+            let mut found = Vec::new();
+            let mut offset = start;
+            scan_stream(patterns, options.chunk_size, overlap, start, &mut found, |buf| {
+                let n = std::cmp::min(buf.len() as u64, end - offset) as usize;
+                self.read_at(offset, &mut buf[..n])?;
+                offset += n as u64;
+                Ok(n)
+            })?;
+            for (pattern, logical, length) in found {
+                let physical = self.locate(logical)?;
+                hits.push(Hit { pattern, logical: Some(logical), physical, length });
+            }
+        }
+
+        if options.include_orphans {
+            for region in self.unreferenced_regions()? {
+                if region.kind != RegionKind::OrphanedGrain {
+                    continue;
+                }
+                let file = self.open_component(&region.path)?;
+                let mut found = Vec::new();
+                let (mut offset, end) = (region.offset, region.offset + region.length);
+                scan_stream(patterns, options.chunk_size, overlap, region.offset, &mut found, |buf| {
+                    let n = std::cmp::min(buf.len() as u64, end - offset) as usize;
+                    file.read_exact_at(&mut buf[..n], offset)?;
+                    offset += n as u64;
+                    Ok(n)
+                })?;
+                for (pattern, offset, length) in found {
+                    let physical = Some((region.path.clone(), offset));
+                    hits.push(Hit { pattern, logical: None, physical, length });
+                }
+            }
+        }
+        info!("Scanned {}: {} hits", self.path.display(), hits.len());
+        Ok(hits)
+    }
+}
+
+/// Search the bytes `read` yields, starting at offset `base`, carrying the
+/// last `overlap` bytes of each window over into the next. Matches starting
+/// in those bytes are left to the next window, which sees them whole, so
+/// each match is found once. Matches go to `found` as (pattern, offset,
+/// length).
+fn scan_stream<F>(
+    patterns: &[Pattern],
+    chunk_size: usize,
+    overlap: usize,
+    base: u64,
+    found: &mut Vec<(usize, u64, usize)>,
+    mut read: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&mut [u8]) -> Result<usize, Error>,
+{
+    let mut buf = vec![0u8; overlap + chunk_size];
+    // Bytes kept from the previous window, and the offset of the first one
+    let mut kept = 0;
+    let mut start = base;
+    // Where the next match of each pattern may start: past the start of
+    // the last byte string match, past the end of the last regex match
+    let mut next = vec![base; patterns.len()];
+    loop {
+        let n = read(&mut buf[kept..])?;
+        let last = n == 0;
+        let len = kept + n;
+        let keep = if last { 0 } else { std::cmp::min(overlap, len) };
+        let limit = start + (len - keep) as u64;
+        for (i, pattern) in patterns.iter().enumerate() {
+            let overlapping = matches!(pattern, Pattern::Bytes(_));
+            pattern.find_all(&buf[..len], &mut |s, e| {
+                let at = start + s as u64;
+                if at >= next[i] && (last || at < limit) {
+                    found.push((i, at, e - s));
+                    next[i] = if overlapping { at + 1 } else { start + e as u64 };
+                }
+            });
+        }
+        if last {
+            return Ok(());
+        }
+        buf.copy_within(len - keep..len, 0);
+        start = limit;
+        kept = keep;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+    use crate::VmdkOpenOptions;
+
+    #[test]
+    fn test_scan() {
+        let dir = scratch_dir("scan");
+        let grain = 128 * 512;
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0);
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(dir.join("base.vmdk")).unwrap();
+        // Spans two chunks of the scan below
+        vmdk.write_at(grain + 1000, b"secret").unwrap();
+        vmdk.close().unwrap();
+
+        let mut delta = Vmdk::new(dir.join("base.vmdk")).unwrap().snapshot(dir.join("delta.vmdk")).unwrap();
+        delta.write_at(3 * grain, b"a secret here").unwrap();
+        delta.close().unwrap();
+
+        let mut delta = Vmdk::new(dir.join("delta.vmdk")).unwrap();
+        let patterns = [Pattern::Bytes(b"secret".to_vec()), Pattern::Bytes(b"here".to_vec())];
+        let mut options = ScanOptions::new();
+        options.chunk_size(1003);
+        let hits = delta.scan(&patterns, &options).unwrap();
+        assert_eq!(hits.len(), 3);
+        let hit = hits.iter().find(|h| h.logical == Some(grain + 1000)).unwrap();
+        assert_eq!(hit.physical, Some((dir.join("base.vmdk"), grain + 1000)));
+        let hit = hits.iter().find(|h| h.pattern == 1).unwrap();
+        assert_eq!(hit.logical, Some(3 * grain + 9));
+        assert_eq!(hit.physical.as_ref().unwrap().0, dir.join("delta.vmdk"));
+
+        options.include_parents(false);
+        assert_eq!(delta.scan(&patterns, &options).unwrap().len(), 2);
+    }
+
+    /// Matches of `patterns` in `data` read `chunk_size` bytes at a time
+    fn scan_bytes(patterns: &[Pattern], data: &[u8], chunk_size: usize, overlap: usize) -> Vec<(usize, u64, usize)> {
+        let mut found = Vec::new();
+        let mut rest = data;
+        scan_stream(patterns, chunk_size, overlap, 1000, &mut found, |buf| Ok(rest.read(buf)?)).unwrap();
+        found
+    }
+
+    #[test]
+    fn test_scan_window_boundaries() {
+        let pattern = [Pattern::Bytes(b"abc".to_vec())];
+        for chunk_size in 1..8 {
+            for at in 0..20 {
+                let mut data = vec![b'x'; 23];
+                data[at..at + 3].copy_from_slice(b"abc");
+                assert_eq!(scan_bytes(&pattern, &data, chunk_size, 3), [(0, 1000 + at as u64, 3)], "chunk {} at {}", chunk_size, at);
+            }
+            // Overlapping matches are all found, once
+            let found = scan_bytes(&[Pattern::Bytes(b"aa".to_vec())], b"aaaa", chunk_size, 2);
+            assert_eq!(found, [(0, 1000, 2), (0, 1001, 2), (0, 1002, 2)]);
+        }
+        #[cfg(feature = "regex")]
+        let pattern = [Pattern::Regex(regex::bytes::Regex::new("key=[0-9]+").unwrap())];
+        #[cfg(feature = "regex")]
+        for chunk_size in 1..20 {
+            let found = scan_bytes(&pattern, b"xxkey=0123456yykey=7", chunk_size, 16);
+            assert_eq!(found, [(0, 1002, 11), (0, 1015, 5)], "chunk {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn test_scan_across_parent() {
+        let dir = scratch_dir("scan-parent");
+        let grain = 128 * 512;
+        let mut base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0);
+        base.grains[0].1[grain as usize - 3..].copy_from_slice(b"sec");
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let mut delta = Vmdk::new(dir.join("base.vmdk")).unwrap().snapshot(dir.join("delta.vmdk")).unwrap();
+        delta.write_at(2 * grain, b"ret").unwrap();
+
+        let hits = delta.scan(&[Pattern::Bytes(b"secret".to_vec())], &ScanOptions::new()).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].logical, Some(2 * grain - 3));
+        assert_eq!(hits[0].physical, Some((dir.join("base.vmdk"), 2 * grain - 3)));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_scan_regex() {
+        let dir = scratch_dir("scan-regex");
+        let image = SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(0, 0);
+        std::fs::write(dir.join("disk.vmdk"), image.build()).unwrap();
+        let mut vmdk = VmdkOpenOptions::new().write(true).open(dir.join("disk.vmdk")).unwrap();
+        vmdk.write_at(100, b"key=0123456").unwrap();
+        let pattern = Pattern::Regex(regex::bytes::Regex::new("key=[0-9]+").unwrap());
+        let hits = vmdk.scan(&[pattern], ScanOptions::new().chunk_size(105)).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].logical, hits[0].length), (Some(100), 11));
+    }
+}
@@ -0,0 +1,137 @@
+//! Reads that detect a disk changing under them.
+//!
+//! Backups read disks they believe quiesced, such as the base of a chain
+//! whose VM writes to a fresh snapshot. A disk that is written anyway gets
+//! a new CID, and a new `uuid.modification` from hosts that keep one, the
+//! first time its descriptor is updated, so a read session compares both
+//! with their values at the start after every read. The descriptor is only
+//! parsed again when its bytes change.
+
+use std::path::Path;
+use failure::Error;
+use log::warn;
+
+use crate::descriptor::{self, Descriptor};
+use crate::diagnostics::Strictness;
+use crate::storage::Storage;
+use crate::{Vmdk, VmdkError};
+
+/// A disk read by a backup, failing with `VmdkError::ConcurrentModification`
+/// as soon as a read finds it modified since the session started
+pub struct ReadSession {
+    vmdk: Vmdk,
+    cid: u32,
+    modification: Option<[u8; 16]>,
+    /// The descriptor file, kept open to read the descriptor again
+    file: Box<dyn Storage>,
+    /// Descriptor bytes last found to hold `cid` and `modification`
+    verified: Vec<u8>,
+}
+
+impl ReadSession {
+    /// Open the disk at `path` with default options
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        ReadSession::new(Vmdk::new(path)?)
+    }
+
+    /// Start a session on an open disk, taking the CID and modification
+    /// UUID its descriptor holds now
+    pub fn new(vmdk: Vmdk) -> Result<Self, Error> {
+        let mut file = vmdk.open_component(vmdk.path())?;
+        let bytes = descriptor_bytes(&mut *file)?;
+        let current = parse(&bytes)?;
+        let (cid, modification) = (current.cid, current.ddb.get_uuid("uuid.modification"));
+        let session = ReadSession { vmdk, cid, modification, file, verified: bytes };
+        session.verify_against(&current)?;
+        Ok(session)
+    }
+
+    /// CID of the disk when the session started
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    /// `uuid.modification` of the disk when the session started
+    pub fn modification_uuid(&self) -> Option<[u8; 16]> {
+        self.modification
+    }
+
+    pub fn vmdk(&self) -> &Vmdk {
+        &self.vmdk
+    }
+
+    /// End the session, returning the disk
+    pub fn into_inner(self) -> Vmdk {
+        self.vmdk
+    }
+
+    /// See `Vmdk::read_at`. The data read is only returned if the disk is
+    /// unchanged once it has been read.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.vmdk.read_at(offset, buf)?;
+        self.verify()?;
+        Ok(n)
+    }
+
+    /// Fail with `VmdkError::ConcurrentModification` if the descriptor of
+    /// the disk no longer holds the CID and modification UUID it held when
+    /// the session started
+    pub fn verify(&mut self) -> Result<(), Error> {
+        let bytes = descriptor_bytes(&mut *self.file)?;
+        if bytes != self.verified {
+            self.verify_against(&parse(&bytes)?)?;
+            self.verified = bytes;
+        }
+        Ok(())
+    }
+
+    fn verify_against(&self, current: &Descriptor) -> Result<(), Error> {
+        // A handle opened before the session may already be out of date
+        let cids = [self.vmdk.cid(), self.cid];
+        if cids.iter().any(|&cid| cid != current.cid) || current.ddb.get_uuid("uuid.modification") != self.modification {
+            warn!("{} changed during a read session: CID {:08x} is now {:08x}", self.vmdk.path().display(), self.cid, current.cid);
+            return Err(VmdkError::ConcurrentModification(self.vmdk.path().display().to_string()).into());
+        }
+        Ok(())
+    }
+}
+
+/// The descriptor bytes `file` holds now
+fn descriptor_bytes(file: &mut dyn Storage) -> Result<Vec<u8>, Error> {
+    Ok(crate::read_descriptor(file, Strictness::Lenient)?.1)
+}
+
+fn parse(bytes: &[u8]) -> Result<Descriptor, Error> {
+    Descriptor::new(descriptor::decode(bytes).trim_end_matches(char::from(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+    use crate::VmdkOpenOptions;
+
+    #[test]
+    fn test_read_session() {
+        let dir = scratch_dir("read-session");
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, SparseImage::new(1024, 128).monolithic("disk.vmdk").grain(1, 0xb1).build()).unwrap();
+
+        let mut session = ReadSession::open(&path).unwrap();
+        assert_eq!(session.cid(), 0x12345678);
+        let mut buf = [0u8; 512];
+        session.read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb1; 512]);
+
+        // A writer opening the disk behind the session's back
+        let mut writer = VmdkOpenOptions::new().write(true).open(&path).unwrap();
+        writer.write_at(0, &[1u8; 512]).unwrap();
+        drop(writer);
+
+        let err = session.read_at(0, &mut buf).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(VmdkError::ConcurrentModification(_))), "{}", err);
+        assert!(session.verify().is_err());
+        assert!(ReadSession::new(session.into_inner()).is_err());
+        assert!(ReadSession::open(&path).is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+//! Writing images straight to multipart uploads.
+//!
+//! `MultipartWriter` turns the bytes written to it into numbered parts of a
+//! fixed size, handing each to a `PartUpload` as soon as it is full, so an
+//! image produced sequentially, such as by `StreamOptimizedWriter` or
+//! `Vmdk::export_stream_optimized`, can be uploaded while it is produced
+//! without a temporary file. At most one part is buffered.
+
+use std::io::{self, Write};
+use failure::Error;
+use log::info;
+
+use crate::VmdkError;
+
+/// Receives the parts of an upload, such as a multipart upload to object
+/// storage
+pub trait PartUpload {
+    /// Upload part `number`, counting from 1. Every part but the last has
+    /// the part size of the writer.
+    fn upload_part(&mut self, number: u32, data: &[u8]) -> Result<(), Error>;
+
+    /// Assemble the uploaded parts, in order, into the final object
+    fn complete(&mut self) -> Result<(), Error>;
+
+    /// Discard the parts uploaded so far
+    fn abort(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Buffers written bytes into parts of `part_size` for a `PartUpload`.
+///
+/// `finish` uploads the last part and completes the upload; writers
+/// dropped or failing before then abort it.
+pub struct MultipartWriter<U: PartUpload> {
+    upload: Option<U>,
+    part_size: usize,
+    part: Vec<u8>,
+    /// Number of the part being filled
+    number: u32,
+    written: u64,
+}
+
+impl<U: PartUpload> MultipartWriter<U> {
+    /// Upload through `upload` in parts of `part_size` bytes, which must not
+    /// be zero
+    pub fn new(upload: U, part_size: usize) -> Result<Self, Error> {
+        if part_size == 0 {
+            return Err(VmdkError::InvalidArgument("zero part size".to_owned()).into());
+        }
+        Ok(MultipartWriter { upload: Some(upload), part_size, part: Vec::with_capacity(part_size), number: 1, written: 0 })
+    }
+
+    /// Bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Upload the last part, even if empty when nothing was written, and
+    /// complete the upload, returning it
+    pub fn finish(mut self) -> Result<U, Error> {
+        if !self.part.is_empty() || self.number == 1 {
+            self.upload_part()?;
+        }
+        let mut upload = self.upload.take().expect("upload taken only by finish");
+        if let Err(e) = upload.complete() {
+            let _ = upload.abort();
+            return Err(e);
+        }
+        info!("Uploaded {} bytes in {} parts", self.written, self.number - 1);
+        Ok(upload)
+    }
+
+    /// Upload the buffered part, aborting the upload if that fails
+    fn upload_part(&mut self) -> Result<(), Error> {
+        let upload = self.upload.as_mut().ok_or_else(|| VmdkError::InvalidArgument("upload aborted".to_owned()))?;
+        if let Err(e) = upload.upload_part(self.number, &self.part) {
+            let _ = upload.abort();
+            self.upload = None;
+            return Err(e);
+        }
+        self.number += 1;
+        self.part.clear();
+        Ok(())
+    }
+}
+
+impl<U: PartUpload> Write for MultipartWriter<U> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(self.part_size - self.part.len(), buf.len());
+        self.part.extend_from_slice(&buf[..n]);
+        if self.part.len() == self.part_size {
+            self.upload_part().map_err(io::Error::other)?;
+        }
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    /// Parts have a fixed size, so nothing is uploaded before the part is
+    /// full
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<U: PartUpload> Drop for MultipartWriter<U> {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.as_mut() {
+            let _ = upload.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::clone::CloneOptions;
+    use crate::testutil::{scratch_dir, SparseImage};
+    use crate::Vmdk;
+
+    #[derive(Default, Clone)]
+    struct Uploaded {
+        parts: Vec<(u32, Vec<u8>)>,
+        completed: bool,
+        aborted: bool,
+    }
+
+    /// Collects the parts in memory
+    #[derive(Default, Clone)]
+    struct Parts(Arc<Mutex<Uploaded>>);
+
+    impl PartUpload for Parts {
+        fn upload_part(&mut self, number: u32, data: &[u8]) -> Result<(), Error> {
+            let mut uploaded = self.0.lock().unwrap();
+            if uploaded.parts.len() == 3 && data[0] == 0xee {
+                return Err(VmdkError::InvalidArgument("upload refused".to_owned()).into());
+            }
+            uploaded.parts.push((number, data.to_vec()));
+            Ok(())
+        }
+
+        fn complete(&mut self) -> Result<(), Error> {
+            self.0.lock().unwrap().completed = true;
+            Ok(())
+        }
+
+        fn abort(&mut self) -> Result<(), Error> {
+            self.0.lock().unwrap().aborted = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_multipart_writer() {
+        let dir = scratch_dir("multipart");
+        let path = dir.join("disk.vmdk");
+        std::fs::write(&path, SparseImage::new(8192, 128).monolithic("disk.vmdk").grain(2, 0xb1).grain(60, 0xb2).build())
+            .unwrap();
+
+        let parts = Parts::default();
+        let writer = MultipartWriter::new(parts.clone(), 4096).unwrap();
+        let mut vmdk = Vmdk::new(&path).unwrap();
+        let writer = vmdk.export_stream_optimized(writer, "copy.vmdk", &CloneOptions::new()).unwrap();
+        let written = writer.written();
+        writer.finish().unwrap();
+
+        let Uploaded { parts: uploaded, completed, aborted } = parts.0.lock().unwrap().clone();
+        assert!(completed && !aborted);
+        assert!(uploaded.iter().enumerate().all(|(i, (n, _))| *n as usize == i + 1));
+        assert!(uploaded[..uploaded.len() - 1].iter().all(|(_, data)| data.len() == 4096));
+        let image: Vec<u8> = uploaded.into_iter().flat_map(|(_, data)| data).collect();
+        assert_eq!(image.len() as u64, written);
+        std::fs::write(dir.join("copy.vmdk"), &image).unwrap();
+        let mut copy = Vmdk::new(dir.join("copy.vmdk")).unwrap();
+        let mut buf = [0u8; 512];
+        copy.read_at(60 * 128 * 512, &mut buf).unwrap();
+        assert_eq!(buf, [0xb2; 512]);
+
+        // A failing part aborts the upload
+        let parts = Parts::default();
+        let mut writer = MultipartWriter::new(parts.clone(), 512).unwrap();
+        writer.write_all(&[0; 3 * 512]).unwrap();
+        assert!(writer.write_all(&[0xee; 512]).is_err());
+        assert!(writer.finish().is_err());
+        assert!(parts.0.lock().unwrap().aborted);
+
+        let parts = Parts::default();
+        drop(MultipartWriter::new(parts.clone(), 512).unwrap());
+        assert!(parts.0.lock().unwrap().aborted);
+    }
+}
@@ -0,0 +1,212 @@
+//! Snapshots: delta disks stacked on a disk, and folding them back into it.
+
+use std::path::{Path, PathBuf};
+use failure::Error;
+use log::info;
+
+use crate::create::VmdkBuilder;
+use crate::descriptor::{self, new_uuid, DiskType, NO_PARENT_CID, NULL_UUID};
+use crate::{Vmdk, VmdkError, VmdkOpenOptions};
+
+/// Bytes copied at a time by `commit` and `flatten`
+const CHUNK: u64 = 1 << 20;
+
+impl Vmdk {
+    /// Create a delta disk at `path` on top of this disk and open it for
+    /// writing. From then on this disk must not change, which opening it
+    /// for writing enforces as long as the delta exists next to it. Flush
+    /// this disk first if it was written.
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<Vmdk, Error> {
+        let path = path.as_ref();
+        let hint = parent_hint(path, &self.path)?;
+
+        let mut ddb = self.descriptor.ddb.clone();
+        ddb.set("uuid.image", &new_uuid());
+        ddb.set("uuid.parent", self.descriptor.ddb.get("uuid.image").unwrap_or(NULL_UUID));
+        if ddb.get("uuid.modification").is_some() {
+            ddb.set("uuid.modification", &new_uuid());
+        }
+        if let Some(modification) = self.descriptor.ddb.get("uuid.modification") {
+            ddb.set("uuid.parentmodification", modification);
+        }
+
+        let mut builder = VmdkBuilder::new(self.size());
+        builder.create_type(DiskType::MonolithicSparse).parent(&hint, self.descriptor.cid).ddb(ddb);
+        let delta = builder.create(path)?;
+        info!("Created snapshot {} of {}", path.display(), self.path.display());
+        Ok(delta)
+    }
+
+    /// Write the data this delta disk holds into its parent, so the parent
+    /// alone has the contents the delta shows. The delta stays usable and
+    /// is pointed at the new content ID of the parent. This disk must be
+    /// open for writing.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        self.check_all_writable()?;
+        let parent_path = match &self.parent {
+            Some(parent) => parent.path.clone(),
+            None => return Err(VmdkError::InvalidArgument("disk has no parent to commit to".to_owned()).into()),
+        };
+        let mut options = VmdkOpenOptions::new();
+        options.write(true).ignore_children(true);
+        options.audit = self.audit.clone();
+        let mut parent = options.open(&parent_path)?;
+
+        // Zero grains of the delta hide parent data, so they are copied too
+        for entry in self.map()?.into_iter().filter(|e| e.depth == 0) {
+            let mut buf = vec![0u8; std::cmp::min(entry.length, CHUNK) as usize];
+            let mut offset = entry.offset;
+            while offset < entry.offset + entry.length {
+                let n = std::cmp::min(entry.offset + entry.length - offset, CHUNK) as usize;
+                self.read_at(offset, &mut buf[..n])?;
+                parent.write_at(offset, &buf[..n])?;
+                offset += n as u64;
+            }
+        }
+        let cid = parent.cid();
+        parent.close()?;
+        info!("Committed {} into {}", self.path.display(), parent_path.display());
+
+        let text = descriptor::set_value(self.descriptor_text(), "parentCID", &format!("{:08x}", cid))?;
+        self.write_descriptor(text)?;
+        self.descriptor.parent_cid = cid;
+        self.parent = Some(Box::new(VmdkOpenOptions::new().open(&parent_path)?));
+        Ok(())
+    }
+
+    /// Copy all data this delta disk reads from its parents into itself and
+    /// detach it from them, leaving a standalone disk. This disk must be
+    /// open for writing.
+    pub fn flatten(&mut self) -> Result<(), Error> {
+        self.check_all_writable()?;
+        if self.parent.is_none() {
+            return Ok(());
+        }
+
+        for entry in self.map()?.into_iter().filter(|e| e.data && e.depth > 0) {
+            let mut buf = vec![0u8; std::cmp::min(entry.length, CHUNK) as usize];
+            let mut offset = entry.offset;
+            while offset < entry.offset + entry.length {
+                let n = std::cmp::min(entry.offset + entry.length - offset, CHUNK) as usize;
+                self.read_at(offset, &mut buf[..n])?;
+                self.write_at(offset, &buf[..n])?;
+                offset += n as u64;
+            }
+        }
+
+        let mut text = descriptor::set_value(self.descriptor_text(), "parentCID", &format!("{:08x}", NO_PARENT_CID))?;
+        text = descriptor::remove_value(&text, "parentFileNameHint");
+        for key in &["ddb.uuid.parent", "ddb.uuid.parentmodification"] {
+            if let Ok(updated) = descriptor::set_value(&text, key, &format!("\"{}\"", NULL_UUID)) {
+                text = updated;
+            }
+        }
+        self.write_descriptor(text)?;
+        self.descriptor.parent_cid = NO_PARENT_CID;
+        self.descriptor.parent_file_name_hint = None;
+        self.parent = None;
+        info!("Flattened {}", self.path.display());
+        Ok(())
+    }
+
+    fn check_all_writable(&self) -> Result<(), Error> {
+        for extent in &self.extents {
+            extent.check_writable()?;
+        }
+        Ok(())
+    }
+}
+
+/// Create a linked clone of the template disk at `base`: a new delta disk
+/// at `dst` on top of it, opened for writing. Any number of clones can
+/// share one base, which is only ever read and from then on must not
+/// change.
+pub fn linked_clone<P: AsRef<Path>, Q: AsRef<Path>>(base: P, dst: Q) -> Result<Vmdk, Error> {
+    let base = VmdkOpenOptions::new().open(base)?;
+    if base.descriptor.encryption.is_some() {
+        return Err(VmdkError::Encrypted.into());
+    }
+    base.snapshot(dst)
+}
+
+/// How a delta at `path` refers to `parent`: by file name when both are
+/// in the same directory, by absolute path otherwise
+fn parent_hint(path: &Path, parent: &Path) -> Result<String, Error> {
+    let dir = |p: &Path| p.parent().map(|d| d.to_owned()).unwrap_or_default();
+    let same_dir = match (dir(path).canonicalize(), dir(parent).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    };
+    let hint: PathBuf = match parent.file_name() {
+        Some(name) if same_dir => PathBuf::from(name),
+        _ => parent.canonicalize()?,
+    };
+    Ok(hint.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{contents, scratch_dir, SparseImage};
+
+    #[test]
+    fn test_snapshot_commit_flatten() {
+        let dir = scratch_dir("snapshot");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0xb1).grain(2, 0xb2);
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let grain = 128 * 512;
+
+        let base = Vmdk::new(dir.join("base.vmdk")).unwrap();
+        let mut delta = base.snapshot(dir.join("base-000001.vmdk")).unwrap();
+        drop(base);
+        assert_eq!(delta.descriptor.parent_file_name_hint.as_deref(), Some("base.vmdk"));
+        assert!(VmdkOpenOptions::new().write(true).open(dir.join("base.vmdk")).is_err());
+        delta.write_at(grain, &vec![0u8; grain as usize]).unwrap();
+        delta.write_at(3 * grain, &[0xd3; 10]).unwrap();
+        let expected = contents(&mut delta);
+
+        delta.commit().unwrap();
+        delta.close().unwrap();
+        let mut base = Vmdk::new(dir.join("base.vmdk")).unwrap();
+        assert_eq!(contents(&mut base), expected);
+        // The delta follows the new CID of its parent
+        let mut delta = VmdkOpenOptions::new().write(true).open(dir.join("base-000001.vmdk")).unwrap();
+        assert_eq!(delta.descriptor.parent_cid, base.cid());
+        assert_eq!(contents(&mut delta), expected);
+
+        delta.flatten().unwrap();
+        delta.close().unwrap();
+        std::fs::remove_file(dir.join("base.vmdk")).unwrap();
+        let mut flat = Vmdk::new(dir.join("base-000001.vmdk")).unwrap();
+        assert!(flat.parent().is_none());
+        assert_eq!(contents(&mut flat), expected);
+    }
+
+    #[test]
+    fn test_linked_clone() {
+        let dir = scratch_dir("linked-clone");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0xb1);
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let before = std::fs::read(dir.join("base.vmdk")).unwrap();
+        std::fs::create_dir_all(dir.join("pool")).unwrap();
+
+        let mut a = linked_clone(dir.join("base.vmdk"), dir.join("a.vmdk")).unwrap();
+        let mut b = linked_clone(dir.join("base.vmdk"), dir.join("pool/b.vmdk")).unwrap();
+        assert!(linked_clone(dir.join("base.vmdk"), dir.join("a.vmdk")).is_err());
+        assert_eq!(a.descriptor.parent_file_name_hint.as_deref(), Some("base.vmdk"));
+        assert!(Path::new(b.descriptor.parent_file_name_hint.as_deref().unwrap()).is_absolute());
+        assert_ne!(a.descriptor.ddb.get("uuid.image"), b.descriptor.ddb.get("uuid.image"));
+        a.write_at(128 * 512 + 10, &[0xaa; 10]).unwrap();
+        b.write_at(0, &[0xbb; 10]).unwrap();
+        a.close().unwrap();
+        b.close().unwrap();
+
+        assert_eq!(std::fs::read(dir.join("base.vmdk")).unwrap(), before);
+        assert!(VmdkOpenOptions::new().write(true).open(dir.join("base.vmdk")).is_err());
+        let mut buf = [0u8; 30];
+        Vmdk::new(dir.join("a.vmdk")).unwrap().read_at(128 * 512, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[10], buf[20]), (0xb1, 0xaa, 0xb1));
+        Vmdk::new(dir.join("pool/b.vmdk")).unwrap().read_at(0, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[10]), (0xbb, 0));
+    }
+}
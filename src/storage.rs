@@ -0,0 +1,158 @@
+//! Where the bytes of descriptor and extent files come from.
+//!
+//! Disks are read from local files unless opened with
+//! `VmdkOpenOptions::storage`, whose `StorageProvider` then opens every file
+//! of the disk and its parents by path, such as objects of a bucket or the
+//! in-memory files of `memory::MemoryFiles`.
+//! Locks and memory maps still need local files.
+
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use failure::Error;
+
+use crate::positioned;
+use crate::VmdkError;
+
+/// Random access to the contents of one file of a disk
+pub trait Storage: Read + Write + Seek + Debug + Send + Sync {
+    /// Fill `buf` from byte `offset`. The position for `Read` and `Seek`
+    /// may be left anywhere.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Write all of `buf` at byte `offset`. The position may be left
+    /// anywhere.
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Size in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    /// Truncate or extend to `len` bytes
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Make written data durable
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Make written data and all metadata durable
+    fn sync_all(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    /// Deallocate the `len` bytes at `offset`, which then read as zeros,
+    /// keeping the size. Returns whether the storage supports it.
+    fn punch_hole(&self, _offset: u64, _len: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// The local file, for what only files support, such as locks
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl Storage for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        positioned::read_exact_at(self, buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        positioned::write_all_at(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        // SAFETY: fallocate only acts on the file descriptor passed
+        if unsafe { libc::fallocate(self.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) } == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+            _ => Err(e),
+        }
+    }
+
+    /// Marks the file sparse first, as zeroed ranges of other files stay
+    /// allocated
+    #[cfg(windows)]
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<bool> {
+        use std::os::windows::io::AsRawHandle;
+        use std::ptr::{null, null_mut};
+        use windows_sys::Win32::Foundation::{ERROR_INVALID_FUNCTION, ERROR_NOT_SUPPORTED};
+        use windows_sys::Win32::System::Ioctl::{FILE_ZERO_DATA_INFORMATION, FSCTL_SET_SPARSE, FSCTL_SET_ZERO_DATA};
+        use windows_sys::Win32::System::IO::DeviceIoControl;
+
+        let handle = self.as_raw_handle();
+        let range = FILE_ZERO_DATA_INFORMATION {
+            FileOffset: offset as i64,
+            BeyondFinalZero: (offset + len) as i64,
+        };
+        let mut returned = 0;
+        // SAFETY: both controls only act on the handle passed, reading no
+        // more than the size given of `range`
+        let punched = unsafe {
+            DeviceIoControl(handle, FSCTL_SET_SPARSE, null(), 0, null_mut(), 0, &mut returned, null_mut()) != 0
+                && DeviceIoControl(
+                    handle,
+                    FSCTL_SET_ZERO_DATA,
+                    &range as *const FILE_ZERO_DATA_INFORMATION as *const _,
+                    std::mem::size_of::<FILE_ZERO_DATA_INFORMATION>() as u32,
+                    null_mut(),
+                    0,
+                    &mut returned,
+                    null_mut(),
+                ) != 0
+        };
+        if punched {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error().map(|code| code as u32) {
+            Some(ERROR_INVALID_FUNCTION) | Some(ERROR_NOT_SUPPORTED) => Ok(false),
+            _ => Err(e),
+        }
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// Opens the files of disks opened with `VmdkOpenOptions::storage`
+pub trait StorageProvider: Debug + Send + Sync {
+    /// Open the file at `path`, as resolved by the `PathResolver` of the
+    /// disk, for writing too if `write`
+    fn open(&self, path: &Path, write: bool) -> Result<Box<dyn Storage>, Error>;
+
+    /// Create the empty file at `path`, failing if it exists, and open it
+    /// for writing. Needed to create disks with `VmdkBuilder::storage`.
+    fn create(&self, path: &Path) -> Result<Box<dyn Storage>, Error> {
+        Err(VmdkError::NotWritable(format!("cannot create {}", path.display())).into())
+    }
+
+    /// Delete the file at `path`, as done to clean up disks whose creation
+    /// failed
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        Err(VmdkError::NotWritable(format!("cannot remove {}", path.display())).into())
+    }
+}
@@ -0,0 +1,756 @@
+//! Stream-optimized extents.
+//!
+//! After the metadata overhead a stream-optimized extent is a sequence of
+//! markers: compressed grains, followed by the grain tables, the grain
+//! directory, a footer and an end-of-stream marker. Every marker starts on a
+//! sector boundary.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Error;
+use log::info;
+
+use crate::compress::{deflate_grain, inflate_grain, COMPRESSION_DEFLATE};
+use crate::descriptor::{self, AccessMode, DescriptorBuilder, DiskType, ExtentType};
+use crate::extent::{is_zero, GD_AT_END};
+use crate::{
+    ExtentHeader, SectorType, VmdkError, EXTENT_MAGIC, FLAG_COMPRESSED, FLAG_MARKERS,
+    FLAG_VALID_NEWLINE_DETECTION, SECTOR_SIZE,
+};
+
+/// Default grain size of newly written extents, in sectors
+pub(crate) const DEFAULT_GRAIN_SIZE: u64 = 128;
+/// Grain table entries per grain table of newly written extents
+pub(crate) const DEFAULT_GTES_PER_GT: u32 = 512;
+
+/// Marker type of the end-of-stream marker
+pub const MARKER_EOS: u32 = 0;
+/// Marker type of a grain table marker
+pub const MARKER_GT: u32 = 1;
+/// Marker type of a grain directory marker
+pub const MARKER_GD: u32 = 2;
+/// Marker type of a footer marker
+pub const MARKER_FOOTER: u32 = 3;
+
+/// A single marker and the data attached to it
+#[derive(Debug)]
+pub enum Marker {
+    /// A compressed grain for the given logical sector
+    Grain { lba: u64, data: Vec<u8> },
+    /// A grain table, as sector offsets of its grains
+    GrainTable(Vec<u32>),
+    /// The grain directory, as sector offsets of the grain tables
+    GrainDirectory(Vec<u32>),
+    /// The footer, a copy of the header with the real `gd_offset`
+    Footer(ExtentHeader),
+    /// End of the stream
+    EndOfStream,
+}
+
+/// Iterator over the markers of a stream-optimized extent
+pub struct MarkerStream<R> {
+    reader: R,
+    pos: u64,
+    done: bool,
+}
+
+impl<R: Read> MarkerStream<R> {
+    /// `reader` must be positioned at the first marker. Positions reported
+    /// by the stream are relative to that point.
+    pub fn new(reader: R) -> Self {
+        MarkerStream {
+            reader,
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Number of bytes consumed from the reader so far
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.reader.read_exact(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Skip the padding up to the next sector boundary
+    fn align(&mut self) -> Result<(), Error> {
+        let rem = self.pos % SECTOR_SIZE;
+        if rem != 0 {
+            let mut pad = vec![0u8; (SECTOR_SIZE - rem) as usize];
+            self.read_exact(&mut pad)?;
+        }
+        Ok(())
+    }
+
+    fn read_sectors(&mut self, sectors: u64) -> Result<Vec<u8>, Error> {
+        let len = sectors.checked_mul(SECTOR_SIZE).ok_or(VmdkError::ParseError)?;
+        let mut buf = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut buf)?;
+        self.pos += buf.len() as u64;
+        if (buf.len() as u64) != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(buf)
+    }
+
+    fn read_marker(&mut self) -> Result<Option<Marker>, Error> {
+        // Tell a clean end of input apart from a truncated marker
+        let mut head = [0u8; 12];
+        let mut got = 0;
+        while got < head.len() {
+            match self.reader.read(&mut head[got..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => got += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.pos += head.len() as u64;
+
+        let mut head = &head[..];
+        let val = head.read_u64::<LittleEndian>()?;
+        let size = head.read_u32::<LittleEndian>()?;
+
+        if size != 0 {
+            let mut data = vec![0u8; size as usize];
+            self.read_exact(&mut data)?;
+            self.align()?;
+            return Ok(Some(Marker::Grain { lba: val, data }));
+        }
+
+        let marker_type = self.reader.read_u32::<LittleEndian>()?;
+        self.pos += 4;
+        self.align()?;
+        info!("Marker type {} with {} sectors at 0x{:x}", marker_type, val, self.pos);
+
+        let marker = match marker_type {
+            MARKER_EOS => Marker::EndOfStream,
+            MARKER_GT => Marker::GrainTable(entries(&self.read_sectors(val)?)),
+            MARKER_GD => Marker::GrainDirectory(entries(&self.read_sectors(val)?)),
+            MARKER_FOOTER => Marker::Footer(ExtentHeader::new(&self.read_sectors(val)?[..])?),
+            _ => return Err(VmdkError::ParseError.into()),
+        };
+
+        Ok(Some(marker))
+    }
+}
+
+fn entries(buf: &[u8]) -> Vec<u32> {
+    buf.chunks_exact(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect()
+}
+
+impl<R: Read> Iterator for MarkerStream<R> {
+    type Item = Result<Marker, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let marker = self.read_marker();
+        match marker {
+            Ok(Some(Marker::EndOfStream)) | Ok(None) | Err(_) => self.done = true,
+            _ => (),
+        }
+        marker.transpose()
+    }
+}
+
+/// Sequential reader over the logical contents of a stream-optimized disk.
+///
+/// Only `Read` is required of the input, so images can be consumed straight
+/// from a pipe or socket. Grains must appear in ascending order, which is how
+/// stream-optimized images are produced.
+pub struct StreamReader<R> {
+    /// Header at the start of the stream
+    pub header: ExtentHeader,
+    /// Embedded descriptor text, if present
+    pub descriptor: Option<String>,
+    markers: MarkerStream<R>,
+    /// Logical bytes returned so far
+    pos: u64,
+    /// Decompressed grain and the logical offset it starts at
+    pending: Option<(u64, Vec<u8>)>,
+    finished: bool,
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        reader.read_exact(&mut sector)?;
+        let header = ExtentHeader::new(&sector[..])?;
+        let mut consumed = SECTOR_SIZE;
+
+        let mut descriptor = None;
+        if header.desc_offset.0 != 0 {
+            let start = header.desc_offset.0 * SECTOR_SIZE;
+            consumed += skip(&mut reader, start.saturating_sub(consumed))?;
+            let len = header.desc_size.0 * SECTOR_SIZE;
+            let mut buf = vec![0u8; len.try_into()?];
+            reader.read_exact(&mut buf)?;
+            consumed += len;
+            let text = descriptor::decode(&buf);
+            descriptor = Some(text.trim_matches(char::from(0)).to_owned());
+        }
+
+        // Markers start after the metadata overhead
+        skip(&mut reader, (header.overhead.0 * SECTOR_SIZE).saturating_sub(consumed))?;
+
+        Ok(StreamReader {
+            header,
+            descriptor,
+            markers: MarkerStream::new(reader),
+            pos: 0,
+            pending: None,
+            finished: false,
+        })
+    }
+
+    /// Size of the virtual disk in bytes
+    pub fn size(&self) -> u64 {
+        self.header.capacity.0 * SECTOR_SIZE
+    }
+
+    /// Fetch the next grain from the stream, `None` once the stream ends
+    fn next_grain(&mut self) -> Result<Option<(u64, Vec<u8>)>, Error> {
+        loop {
+            match self.markers.next().transpose()? {
+                Some(Marker::Grain { lba, data }) => {
+                    let offset = lba.checked_mul(SECTOR_SIZE).ok_or(VmdkError::ParseError)?;
+                    if offset < self.pos {
+                        return Err(VmdkError::ParseError.into());
+                    }
+                    let grain = inflate_grain(&data, self.header.grain_size.0 * SECTOR_SIZE)?;
+                    return Ok(Some((offset, grain)));
+                }
+                Some(Marker::EndOfStream) | None => return Ok(None),
+                Some(_) => continue,
+            }
+        }
+    }
+
+    fn read_inner(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let size = self.size();
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let want = std::cmp::min(buf.len() as u64, size - self.pos) as usize;
+
+        if self.pending.is_none() && !self.finished {
+            self.pending = self.next_grain()?;
+            self.finished = self.pending.is_none();
+        }
+
+        let n = match &self.pending {
+            Some((start, grain)) if *start <= self.pos => {
+                let within = (self.pos - start) as usize;
+                let n = std::cmp::min(want, grain.len() - within);
+                buf[..n].copy_from_slice(&grain[within..within + n]);
+                n
+            }
+            // Unallocated space before the next grain, or after the last one
+            Some((start, _)) => {
+                let n = std::cmp::min(want as u64, start - self.pos) as usize;
+                zero(&mut buf[..n]);
+                n
+            }
+            None => {
+                zero(&mut buf[..want]);
+                want
+            }
+        };
+
+        self.pos += n as u64;
+        if let Some((start, grain)) = &self.pending {
+            if self.pos >= start + grain.len() as u64 {
+                self.pending = None;
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_inner(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/// How grains are compressed by `StreamOptimizedWriter`
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    level: u32,
+    store_incompressible: bool,
+    threads: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            level: 6,
+            store_incompressible: false,
+            threads: 1,
+        }
+    }
+}
+
+impl CompressionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// zlib compression level from 0 (store) to 9 (best), 6 by default
+    pub fn level(&mut self, level: u32) -> &mut Self {
+        self.level = level;
+        self
+    }
+
+    /// Store grains that do not shrink when compressed as uncompressed
+    /// DEFLATE blocks
+    pub fn store_incompressible(&mut self, store: bool) -> &mut Self {
+        self.store_incompressible = store;
+        self
+    }
+
+    /// Number of threads compressing independent grains in parallel
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads;
+        self
+    }
+}
+
+/// Writes a monolithic stream-optimized extent to any `Write` sink.
+///
+/// The logical disk contents are written sequentially through `Write`;
+/// all-zero grains are left unallocated. `finish` must be called to write the
+/// grain tables, directory and footer.
+///
+/// Nothing is ever written twice, so the sink can be a pipe or an upload
+/// such as `sink::MultipartWriter`. Besides the grain being filled, up to
+/// `threads * 8` full grains wait to be compressed, and 4 bytes per grain
+/// of the disk are kept for the grain tables.
+pub struct StreamOptimizedWriter<W: Write> {
+    writer: W,
+    /// Bytes written to `writer` so far
+    pos: u64,
+    header: ExtentHeader,
+    options: CompressionOptions,
+    gtes: Vec<u32>,
+    /// Partially filled grain at `grain_index`
+    grain: Vec<u8>,
+    grain_index: u64,
+    /// Full grains waiting to be compressed
+    batch: Vec<(u64, Vec<u8>)>,
+}
+
+impl<W: Write> StreamOptimizedWriter<W> {
+    /// Start a disk of `capacity` bytes, rounded up to whole sectors
+    pub fn new(writer: W, capacity: u64, options: &CompressionOptions) -> Result<Self, Error> {
+        let descriptor = descriptor_text(capacity.div_ceil(SECTOR_SIZE));
+        Self::with_descriptor(writer, capacity, &descriptor, options)
+    }
+
+    /// Start a disk of `capacity` bytes with `descriptor` embedded instead
+    /// of a minimal generated one
+    pub fn with_descriptor(
+        writer: W,
+        capacity: u64,
+        descriptor: &str,
+        options: &CompressionOptions,
+    ) -> Result<Self, Error> {
+        if options.level > 9 {
+            return Err(VmdkError::InvalidArgument(format!("compression level {}", options.level)).into());
+        }
+        if options.threads == 0 {
+            return Err(VmdkError::InvalidArgument("zero compression threads".to_owned()).into());
+        }
+
+        let capacity = capacity.div_ceil(SECTOR_SIZE);
+        let grain_size = DEFAULT_GRAIN_SIZE;
+        let num_grains = capacity.div_ceil(grain_size);
+        let desc_size = (descriptor.len() as u64).div_ceil(SECTOR_SIZE);
+        let overhead = (1 + desc_size).div_ceil(grain_size) * grain_size;
+
+        let header = ExtentHeader {
+            magic_number: EXTENT_MAGIC,
+            version: 3,
+            flags: FLAG_VALID_NEWLINE_DETECTION | FLAG_COMPRESSED | FLAG_MARKERS,
+            capacity: SectorType(capacity),
+            grain_size: SectorType(grain_size),
+            desc_offset: SectorType(1),
+            desc_size: SectorType(desc_size),
+            gtes_per_gt: DEFAULT_GTES_PER_GT,
+            rgd_offset: SectorType(0),
+            gd_offset: SectorType(GD_AT_END),
+            overhead: SectorType(overhead),
+            dirty_shutdown: 0,
+            single_eol_char: b'\n',
+            non_eol_char: b' ',
+            dbl_eol_char: b'\r',
+            dbl_eol_char2: b'\n',
+            compress_method: COMPRESSION_DEFLATE,
+        };
+
+        let mut w = StreamOptimizedWriter {
+            writer,
+            pos: 0,
+            header,
+            options: options.clone(),
+            gtes: vec![0; num_grains.try_into()?],
+            grain: Vec::new(),
+            grain_index: 0,
+            batch: Vec::new(),
+        };
+
+        let mut head = Vec::new();
+        w.header.write(&mut head)?;
+        head.extend_from_slice(descriptor.as_bytes());
+        head.resize((overhead * SECTOR_SIZE).try_into()?, 0);
+        w.emit(&head)?;
+
+        Ok(w)
+    }
+
+    fn emit(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    fn pad_to_sector(&mut self) -> Result<(), Error> {
+        let rem = self.pos % SECTOR_SIZE;
+        if rem != 0 {
+            self.emit(&vec![0u8; (SECTOR_SIZE - rem) as usize])?;
+        }
+        Ok(())
+    }
+
+    fn sector(&self) -> Result<u32, Error> {
+        (self.pos / SECTOR_SIZE)
+            .try_into()
+            .map_err(|_| VmdkError::InvalidArgument("extent too large for 32-bit grain offsets".to_owned()).into())
+    }
+
+    fn grain_bytes(&self) -> usize {
+        (self.header.grain_size.0 * SECTOR_SIZE) as usize
+    }
+
+    /// Queue the filled grain, skipping it if it is all zeros
+    fn end_grain(&mut self) -> Result<(), Error> {
+        let grain = std::mem::take(&mut self.grain);
+        if !is_zero(&grain) {
+            self.batch.push((self.grain_index, grain));
+            if self.batch.len() >= self.options.threads * 8 {
+                self.flush_batch()?;
+            }
+        }
+        self.grain_index += 1;
+        Ok(())
+    }
+
+    fn flush_batch(&mut self) -> Result<(), Error> {
+        let batch = std::mem::take(&mut self.batch);
+        let level = self.options.level;
+        let store = self.options.store_incompressible;
+
+        let compressed: Vec<Result<Vec<u8>, Error>> = if self.options.threads <= 1 || batch.len() <= 1 {
+            batch.iter().map(|(_, g)| deflate_grain(g, level, store)).collect()
+        } else {
+            let per_thread = batch.len().div_ceil(self.options.threads);
+            std::thread::scope(|scope| {
+                let workers: Vec<_> = batch
+                    .chunks(per_thread)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|(_, g)| deflate_grain(g, level, store))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .flat_map(|w| w.join().expect("compression worker panicked"))
+                    .collect()
+            })
+        };
+
+        for ((index, _), data) in batch.iter().zip(compressed) {
+            let data = data?;
+            let slot: usize = (*index).try_into()?;
+            self.gtes[slot] = self.sector()?;
+            let mut marker = Vec::with_capacity(12 + data.len());
+            marker.write_u64::<LittleEndian>(index * self.header.grain_size.0)?;
+            marker.write_u32::<LittleEndian>(data.len().try_into()?)?;
+            marker.extend_from_slice(&data);
+            self.emit(&marker)?;
+            self.pad_to_sector()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_marker(&mut self, marker_type: u32, data: &[u8]) -> Result<u32, Error> {
+        let mut marker = Vec::with_capacity(SECTOR_SIZE as usize);
+        marker.write_u64::<LittleEndian>(data.len() as u64 / SECTOR_SIZE)?;
+        marker.write_u32::<LittleEndian>(0)?;
+        marker.write_u32::<LittleEndian>(marker_type)?;
+        marker.resize(SECTOR_SIZE as usize, 0);
+        self.emit(&marker)?;
+        let sector = self.sector()?;
+        self.emit(data)?;
+        Ok(sector)
+    }
+
+    /// Write the remaining grains and the trailing metadata, returning the
+    /// underlying writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        if !self.grain.is_empty() {
+            let len = self.grain_bytes();
+            self.grain.resize(len, 0);
+            self.end_grain()?;
+        }
+        self.flush_batch()?;
+
+        let gtes = std::mem::take(&mut self.gtes);
+        let mut gd = Vec::new();
+        for table in gtes.chunks(self.header.gtes_per_gt as usize) {
+            if table.iter().all(|&gte| gte == 0) {
+                gd.write_u32::<LittleEndian>(0)?;
+                continue;
+            }
+            let mut data = Vec::with_capacity(self.header.gtes_per_gt as usize * 4);
+            for gte in table {
+                data.write_u32::<LittleEndian>(*gte)?;
+            }
+            data.resize(data.len().div_ceil(SECTOR_SIZE as usize) * SECTOR_SIZE as usize, 0);
+            let sector = self.write_marker(MARKER_GT, &data)?;
+            gd.write_u32::<LittleEndian>(sector)?;
+        }
+        gd.resize(std::cmp::max(gd.len(), 1).div_ceil(SECTOR_SIZE as usize) * SECTOR_SIZE as usize, 0);
+        let gd_sector = self.write_marker(MARKER_GD, &gd)?;
+
+        let mut footer = Vec::new();
+        let mut header = self.header.clone();
+        header.gd_offset = SectorType(u64::from(gd_sector));
+        header.write(&mut footer)?;
+        self.write_marker(MARKER_FOOTER, &footer)?;
+        self.write_marker(MARKER_EOS, &[])?;
+
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_inner(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let capacity = self.header.capacity.0 * SECTOR_SIZE;
+        let written = self.grain_index * self.grain_bytes() as u64 + self.grain.len() as u64;
+        if written + buf.len() as u64 > capacity {
+            return Err(VmdkError::InvalidArgument("write past the end of the disk".to_owned()).into());
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let n = std::cmp::min(self.grain_bytes() - self.grain.len(), buf.len() - done);
+            self.grain.extend_from_slice(&buf[done..done + n]);
+            done += n;
+            if self.grain.len() == self.grain_bytes() {
+                self.end_grain()?;
+            }
+        }
+
+        Ok(done)
+    }
+}
+
+impl<W: Write> Write for StreamOptimizedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_inner(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Minimal embedded descriptor of a monolithic stream-optimized disk
+fn descriptor_text(capacity: u64) -> String {
+    let adapter = "lsilogic";
+    let mut builder = DescriptorBuilder::new();
+    builder
+        .create_type(DiskType::StreamOptimized)
+        .add_extent(AccessMode::Rw, capacity, ExtentType::Sparse, "disk.vmdk")
+        .ddb("virtualHWVersion", "4")
+        .ddb("adapterType", adapter)
+        .geometry_for_capacity(capacity * SECTOR_SIZE, adapter);
+    builder.to_text()
+}
+
+fn skip<R: Read>(reader: &mut R, len: u64) -> Result<u64, Error> {
+    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(skipped)
+}
+
+fn zero(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use crate::testutil::{metadata_marker, scratch_dir, SparseImage, StreamImage};
+
+    #[test]
+    fn test_marker_stream() {
+        let mut stream = Vec::new();
+        stream.write_u64::<LittleEndian>(128).unwrap();
+        stream.write_u32::<LittleEndian>(5).unwrap();
+        stream.extend_from_slice(b"hello");
+        stream.resize(512, 0);
+
+        let mut gt = vec![0u8; 2048];
+        gt[4..8].copy_from_slice(&3u32.to_le_bytes());
+        metadata_marker(&mut stream, MARKER_GT, &gt);
+        let mut gd = vec![0u8; 512];
+        gd[..4].copy_from_slice(&5u32.to_le_bytes());
+        metadata_marker(&mut stream, MARKER_GD, &gd);
+        let footer = SparseImage::new(1024, 128).build();
+        metadata_marker(&mut stream, MARKER_FOOTER, &footer[..512]);
+        metadata_marker(&mut stream, MARKER_EOS, &[]);
+        // Anything after the end-of-stream marker is not parsed
+        stream.extend_from_slice(&[0xff; 512]);
+
+        let markers = MarkerStream::new(&stream[..]).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(markers.len(), 5);
+        match &markers[0] {
+            Marker::Grain { lba, data } => {
+                assert_eq!(*lba, 128);
+                assert_eq!(&data[..], b"hello");
+            }
+            m => panic!("unexpected marker {:?}", m),
+        }
+        match &markers[1] {
+            Marker::GrainTable(entries) => {
+                assert_eq!(entries.len(), 512);
+                assert_eq!(entries[1], 3);
+            }
+            m => panic!("unexpected marker {:?}", m),
+        }
+        match &markers[2] {
+            Marker::GrainDirectory(entries) => assert_eq!(entries[0], 5),
+            m => panic!("unexpected marker {:?}", m),
+        }
+        match &markers[3] {
+            Marker::Footer(header) => assert_eq!(header.capacity.0, 1024),
+            m => panic!("unexpected marker {:?}", m),
+        }
+        assert!(matches!(markers[4], Marker::EndOfStream));
+    }
+
+    #[test]
+    fn test_truncated_marker() {
+        let mut stream = Vec::new();
+        stream.write_u64::<LittleEndian>(0).unwrap();
+        stream.write_u32::<LittleEndian>(100).unwrap();
+        stream.extend_from_slice(&[1u8; 50]);
+
+        let mut markers = MarkerStream::new(&stream[..]);
+        assert!(markers.next().unwrap().is_err());
+        assert!(markers.next().is_none());
+
+        assert!(MarkerStream::new(&[][..]).next().is_none());
+    }
+
+    #[test]
+    fn test_stream_reader_from_pipe() {
+        // A plain `Read` with no `Seek`, like a pipe
+        struct Pipe(io::Cursor<Vec<u8>>);
+        impl Read for Pipe {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = std::cmp::min(buf.len(), 700);
+                self.0.read(&mut buf[..n])
+            }
+        }
+
+        let image = StreamImage::new(1000, 128).grain(1, 0x11).grain(3, 0x33).build();
+        let mut reader = StreamReader::new(Pipe(io::Cursor::new(image))).unwrap();
+        assert!(reader.descriptor.as_ref().unwrap().contains("streamOptimized"));
+
+        let mut disk = Vec::new();
+        reader.read_to_end(&mut disk).unwrap();
+        assert_eq!(disk.len(), 1000 * 512);
+        let grain = 128 * 512;
+        assert!(disk[..grain].iter().all(|&b| b == 0));
+        assert!(disk[grain..2 * grain].iter().all(|&b| b == 0x11));
+        assert!(disk[2 * grain..3 * grain].iter().all(|&b| b == 0));
+        assert!(disk[3 * grain..4 * grain].iter().all(|&b| b == 0x33));
+        assert!(disk[4 * grain..].iter().all(|&b| b == 0));
+    }
+
+    fn roundtrip(options: &CompressionOptions) {
+        let capacity = 1000 * 512 + 100;
+        let mut disk = vec![0u8; capacity];
+        for (i, b) in disk[70000..300000].iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        disk[capacity - 1] = 0xee;
+
+        let mut writer = StreamOptimizedWriter::new(Vec::new(), capacity as u64, options).unwrap();
+        for chunk in disk.chunks(10000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let image = writer.finish().unwrap();
+
+        let mut reader = StreamReader::new(&image[..]).unwrap();
+        assert_eq!(reader.size(), 1001 * 512);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..capacity], &disk[..]);
+        assert!(out[capacity..].iter().all(|&b| b == 0));
+
+        // The grain directory from the footer must agree with the stream
+        let path = scratch_dir("stream-writer").join(format!("disk-{}.vmdk", options.level));
+        std::fs::write(&path, &image).unwrap();
+        let mut vmdk = crate::Vmdk::new(&path).unwrap();
+        let mut out = vec![0u8; capacity];
+        vmdk.read_at(0, &mut out).unwrap();
+        assert_eq!(out, disk);
+    }
+
+    #[test]
+    fn test_stream_writer_roundtrip() {
+        roundtrip(&CompressionOptions::new());
+        roundtrip(CompressionOptions::new().level(0));
+        roundtrip(CompressionOptions::new().level(9).store_incompressible(true).threads(4));
+    }
+
+    #[test]
+    fn test_stream_writer_options() {
+        assert!(StreamOptimizedWriter::new(Vec::new(), 512, CompressionOptions::new().level(10)).is_err());
+        assert!(StreamOptimizedWriter::new(Vec::new(), 512, CompressionOptions::new().threads(0)).is_err());
+
+        let mut writer = StreamOptimizedWriter::new(Vec::new(), 512, &CompressionOptions::new()).unwrap();
+        assert!(writer.write_all(&[1u8; 513]).is_err());
+    }
+}
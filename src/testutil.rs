@@ -0,0 +1,283 @@
+//! Helpers for building synthetic images in tests.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::stream::{MARKER_EOS, MARKER_FOOTER, MARKER_GD, MARKER_GT};
+use crate::{ExtentHeader, SectorType, Vmdk, EXTENT_MAGIC, SECTOR_SIZE};
+
+/// Create an empty scratch directory unique to `name`
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("vmdk-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A version 1 hosted sparse header without grain directories
+pub fn header(capacity: u64, grain_size: u64, desc_offset: u64, desc_size: u64) -> ExtentHeader {
+    ExtentHeader {
+        magic_number: EXTENT_MAGIC,
+        version: 1,
+        flags: 0x3,
+        capacity: SectorType(capacity),
+        grain_size: SectorType(grain_size),
+        desc_offset: SectorType(desc_offset),
+        desc_size: SectorType(desc_size),
+        gtes_per_gt: 512,
+        rgd_offset: SectorType(0),
+        gd_offset: SectorType(0),
+        overhead: SectorType(0),
+        dirty_shutdown: 0,
+        single_eol_char: b'\n',
+        non_eol_char: b' ',
+        dbl_eol_char: b'\r',
+        dbl_eol_char2: b'\n',
+        compress_method: 0,
+    }
+}
+
+/// Serialize `h` into a 512 byte header sector
+pub fn header_bytes(h: &ExtentHeader) -> Vec<u8> {
+    let mut out = Vec::new();
+    h.write(&mut out).unwrap();
+    out
+}
+
+/// Write `base.vmdk`, a disk of `capacity` sectors in 128 sector grains
+/// with grains 1 and 2 stored and grain 3 zero, and its child
+/// `child.vmdk` with grain 2 stored, into `dir`
+pub fn write_chain(dir: &Path, capacity: u64) {
+    let base = SparseImage::new(capacity, 128).monolithic("base.vmdk").grain(1, 0xb1).grain(2, 0xb2).grain(3, 0);
+    std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+    let child = SparseImage::new(capacity, 128).child("base.vmdk", 0x12345678).grain(2, 0xc2);
+    std::fs::write(dir.join("child.vmdk"), child.build()).unwrap();
+}
+
+/// Every byte of `vmdk`
+pub fn contents(vmdk: &mut Vmdk) -> Vec<u8> {
+    let mut buf = vec![0u8; vmdk.size() as usize];
+    vmdk.read_at(0, &mut buf).unwrap();
+    buf
+}
+
+/// Disk database section of a VirtualBox descriptor, heading and all
+pub const VIRTUALBOX_DDB: &str = "# The disk Data Base \n#DDB\n\n\
+    ddb.virtualHWVersion = \"4\"\nddb.adapterType=\"ide\"\n\
+    ddb.geometry.cylinders=\"16383\"\nddb.geometry.heads=\"16\"\nddb.geometry.sectors=\"63\"\n\
+    ddb.geometry.biosCylinders=\"1024\"\nddb.geometry.biosHeads=\"255\"\nddb.geometry.biosSectors=\"63\"\n\
+    ddb.uuid.image=\"2ebfd8e9-9868-4688-8f3f-97e3f9def370\"\n\
+    ddb.uuid.parent=\"00000000-0000-0000-0000-000000000000\"\n\
+    ddb.uuid.modification=\"e2b662bc-16ff-478e-8b7f-3323b027087e\"\n\
+    ddb.uuid.parentmodification=\"00000000-0000-0000-0000-000000000000\"\n\
+    ddb.comment=\"\"\n";
+
+/// Layout of a synthetic hosted sparse extent
+pub struct SparseImage {
+    pub capacity: u64,
+    pub grain_size: u64,
+    pub gtes_per_gt: u32,
+    pub descriptor: Option<String>,
+    /// Header overhead to record instead of where the grains start
+    pub overhead: Option<u64>,
+    /// Allocated grains as (grain index, data)
+    pub grains: Vec<(u64, Vec<u8>)>,
+}
+
+impl SparseImage {
+    pub fn new(capacity: u64, grain_size: u64) -> Self {
+        SparseImage {
+            capacity,
+            grain_size,
+            gtes_per_gt: 512,
+            descriptor: None,
+            overhead: None,
+            grains: Vec::new(),
+        }
+    }
+
+    /// Embed a monolithicSparse descriptor whose extent is named `name`,
+    /// laid out and spaced the way VirtualBox writes them
+    pub fn virtualbox(mut self, name: &str) -> Self {
+        self.descriptor = Some(format!(
+            "# Disk DescriptorFile\nversion=1\nCID=def0d352\nparentCID=ffffffff\n\
+             createType=\"monolithicSparse\"\n\n# Extent description\n\
+             RW {} SPARSE \"{}\"\n\n{}",
+            self.capacity, name, VIRTUALBOX_DDB
+        ));
+        self
+    }
+
+    /// Embed a monolithicSparse descriptor whose extent is named `name`
+    pub fn monolithic(mut self, name: &str) -> Self {
+        self.descriptor = Some(format!(
+            "# Disk DescriptorFile\nversion=1\nCID=12345678\nparentCID=ffffffff\n\
+             createType=\"monolithicSparse\"\n\n# Extent description\n\
+             RW {} SPARSE \"{}\"\n\n# The Disk Data Base\n#DDB\n\n\
+             ddb.adapterType = \"ide\"\n",
+            self.capacity, name
+        ));
+        self
+    }
+
+    /// Embed a monolithicSparse delta descriptor pointing at `parent`
+    pub fn child(mut self, parent: &str, parent_cid: u32) -> Self {
+        self.descriptor = Some(format!(
+            "# Disk DescriptorFile\nversion=1\nCID=9abcdef0\nparentCID={:08x}\n\
+             createType=\"monolithicSparse\"\nparentFileNameHint=\"{}\"\n\n\
+             # Extent description\nRW {} SPARSE \"child.vmdk\"\n",
+            parent_cid, parent, self.capacity
+        ));
+        self
+    }
+
+    pub fn grain(mut self, index: u64, byte: u8) -> Self {
+        let len = (self.grain_size * SECTOR_SIZE) as usize;
+        self.grains.push((index, vec![byte; len]));
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let grain_bytes = self.grain_size * SECTOR_SIZE;
+        let num_grains = self.capacity.div_ceil(self.grain_size);
+        let num_gts = num_grains.div_ceil(u64::from(self.gtes_per_gt));
+        let gt_sectors = (u64::from(self.gtes_per_gt) * 4).div_ceil(SECTOR_SIZE);
+        let gd_sectors = (num_gts * 4).div_ceil(SECTOR_SIZE);
+
+        let desc_offset = if self.descriptor.is_some() { 1 } else { 0 };
+        let desc_size = match &self.descriptor {
+            Some(d) => (d.len() as u64).div_ceil(SECTOR_SIZE),
+            None => 0,
+        };
+        let rgd_offset = 1 + desc_size;
+        let gd_offset = rgd_offset + gd_sectors + num_gts * gt_sectors;
+        let meta_end = gd_offset + gd_sectors + num_gts * gt_sectors;
+        let overhead = meta_end.div_ceil(self.grain_size) * self.grain_size;
+
+        let mut out = header_bytes(&ExtentHeader {
+            rgd_offset: SectorType(rgd_offset),
+            gd_offset: SectorType(gd_offset),
+            overhead: SectorType(self.overhead.unwrap_or(overhead)),
+            gtes_per_gt: self.gtes_per_gt,
+            ..header(self.capacity, self.grain_size, desc_offset, desc_size)
+        });
+
+        if let Some(d) = &self.descriptor {
+            out.extend_from_slice(d.as_bytes());
+        }
+        out.resize(((overhead + self.grains.len() as u64 * self.grain_size) * SECTOR_SIZE) as usize, 0);
+
+        // Grains are laid out in the order they were added, after the metadata
+        let mut gtes = vec![0u32; (num_gts * u64::from(self.gtes_per_gt)) as usize];
+        for (n, (index, data)) in self.grains.iter().enumerate() {
+            let sector = overhead + n as u64 * self.grain_size;
+            gtes[*index as usize] = sector as u32;
+            let start = (sector * SECTOR_SIZE) as usize;
+            out[start..start + data.len()].copy_from_slice(data);
+        }
+        debug_assert!((out.len() as u64).is_multiple_of(grain_bytes));
+
+        for gd in &[rgd_offset, gd_offset] {
+            let gt_start = gd + gd_sectors;
+            for t in 0..num_gts {
+                let entry = (gd * SECTOR_SIZE + t * 4) as usize;
+                let gt = (gt_start + t * gt_sectors) as u32;
+                out[entry..entry + 4].copy_from_slice(&gt.to_le_bytes());
+                for i in 0..u64::from(self.gtes_per_gt) {
+                    let gte = gtes[(t * u64::from(self.gtes_per_gt) + i) as usize];
+                    let pos = (u64::from(gt) * SECTOR_SIZE + i * 4) as usize;
+                    out[pos..pos + 4].copy_from_slice(&gte.to_le_bytes());
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Layout of a synthetic stream-optimized extent
+pub struct StreamImage {
+    pub capacity: u64,
+    pub grain_size: u64,
+    /// Grains as (logical sector, uncompressed data), in stream order
+    pub grains: Vec<(u64, Vec<u8>)>,
+}
+
+impl StreamImage {
+    pub fn new(capacity: u64, grain_size: u64) -> Self {
+        StreamImage {
+            capacity,
+            grain_size,
+            grains: Vec::new(),
+        }
+    }
+
+    pub fn grain(mut self, index: u64, byte: u8) -> Self {
+        let len = (self.grain_size * SECTOR_SIZE) as usize;
+        self.grains.push((index * self.grain_size, vec![byte; len]));
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let descriptor = format!(
+            "# Disk DescriptorFile\nversion=1\nCID=12345678\nparentCID=ffffffff\n\
+             createType=\"streamOptimized\"\n\n# Extent description\n\
+             RW {} SPARSE \"disk.vmdk\"\n",
+            self.capacity
+        );
+        let mut h = ExtentHeader {
+            version: 3,
+            flags: 0x30001,
+            gd_offset: SectorType(u64::MAX),
+            overhead: SectorType(self.grain_size),
+            compress_method: 1,
+            ..header(self.capacity, self.grain_size, 1, 1)
+        };
+        let mut out = header_bytes(&h);
+        out.extend_from_slice(descriptor.as_bytes());
+        out.resize((self.grain_size * SECTOR_SIZE) as usize, 0);
+
+        let num_grains = self.capacity.div_ceil(self.grain_size);
+        let num_gts = num_grains.div_ceil(512);
+        let mut gtes = vec![0u32; (num_gts * 512) as usize];
+        for (lba, data) in &self.grains {
+            gtes[(lba / self.grain_size) as usize] = (out.len() as u64 / SECTOR_SIZE) as u32;
+            let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+            enc.write_all(data).unwrap();
+            let compressed = enc.finish().unwrap();
+            out.write_u64::<LittleEndian>(*lba).unwrap();
+            out.write_u32::<LittleEndian>(compressed.len() as u32).unwrap();
+            out.extend_from_slice(&compressed);
+            out.resize((out.len() as u64).div_ceil(SECTOR_SIZE) as usize * SECTOR_SIZE as usize, 0);
+        }
+
+        let mut gd = Vec::new();
+        for table in gtes.chunks(512) {
+            let mut data = Vec::new();
+            for gte in table {
+                data.write_u32::<LittleEndian>(*gte).unwrap();
+            }
+            gd.write_u32::<LittleEndian>((out.len() as u64 / SECTOR_SIZE + 1) as u32).unwrap();
+            metadata_marker(&mut out, MARKER_GT, &data);
+        }
+        gd.resize((gd.len() as u64).div_ceil(SECTOR_SIZE) as usize * SECTOR_SIZE as usize, 0);
+        h.gd_offset = SectorType(out.len() as u64 / SECTOR_SIZE + 1);
+        metadata_marker(&mut out, MARKER_GD, &gd);
+        metadata_marker(&mut out, MARKER_FOOTER, &header_bytes(&h));
+        metadata_marker(&mut out, MARKER_EOS, &[]);
+        out
+    }
+}
+
+/// Append a metadata marker of `marker_type` and the sectors of `data`
+pub fn metadata_marker(out: &mut Vec<u8>, marker_type: u32, data: &[u8]) {
+    out.write_u64::<LittleEndian>(data.len() as u64 / SECTOR_SIZE).unwrap();
+    out.write_u32::<LittleEndian>(0).unwrap();
+    out.write_u32::<LittleEndian>(marker_type).unwrap();
+    out.resize(out.len() + SECTOR_SIZE as usize - 16, 0);
+    out.extend_from_slice(data);
+}
@@ -0,0 +1,98 @@
+//! Throttling of disk I/O, so background jobs such as clones or scrubs do
+//! not starve other users of the datastore.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket limit on bytes and requests per second. Clones share
+/// their budget, so one throttle can pace several disks at once.
+///
+/// Each bucket holds at most one second's worth of tokens, allowing short
+/// bursts. Requests larger than that still pass, but run the bucket into
+/// debt that later requests wait out.
+#[derive(Debug, Clone)]
+pub struct Throttle(Arc<Mutex<Buckets>>);
+
+#[derive(Debug)]
+struct Buckets {
+    bytes: Option<Bucket>,
+    ops: Option<Bucket>,
+    last: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket { rate: rate as f64, tokens: rate as f64 }
+    }
+
+    /// Refill for `elapsed` seconds, take `amount` and return how many
+    /// seconds to wait until the bucket is out of debt
+    fn take(&mut self, elapsed: f64, amount: f64) -> f64 {
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - amount;
+        if self.tokens < 0.0 {
+            -self.tokens / self.rate
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Throttle {
+    /// Limit I/O to `bytes_per_sec` bytes and `iops` requests per second.
+    /// `None` leaves that dimension unlimited.
+    pub fn new(bytes_per_sec: Option<u64>, iops: Option<u64>) -> Self {
+        let buckets = Buckets {
+            bytes: bytes_per_sec.filter(|&r| r > 0).map(Bucket::new),
+            ops: iops.filter(|&r| r > 0).map(Bucket::new),
+            last: Instant::now(),
+        };
+        Throttle(Arc::new(Mutex::new(buckets)))
+    }
+
+    /// Account for one request of `bytes` bytes, sleeping as long as the
+    /// limits require
+    pub fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut buckets = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let elapsed = now.duration_since(buckets.last).as_secs_f64();
+            buckets.last = now;
+            let bytes = buckets.bytes.as_mut().map(|b| b.take(elapsed, bytes as f64)).unwrap_or(0.0);
+            let ops = buckets.ops.as_mut().map(|b| b.take(elapsed, 1.0)).unwrap_or(0.0);
+            bytes.max(ops)
+        };
+        if wait > 0.0 {
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(Some(100_000), None);
+        let start = Instant::now();
+        // The first second's worth passes as a burst
+        throttle.acquire(100_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        throttle.clone().acquire(20_000);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        let throttle = Throttle::new(None, Some(10));
+        let start = Instant::now();
+        for _ in 0..12 {
+            throttle.acquire(1 << 20);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,138 @@
+//! Reconstructing the history of a snapshot chain.
+
+use std::path::PathBuf;
+use std::time::SystemTime;
+use failure::Error;
+
+use crate::descriptor::NULL_UUID;
+use crate::Vmdk;
+
+/// A disk of a chain, as listed by `Vmdk::timeline`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineEntry {
+    /// File holding the descriptor
+    pub path: PathBuf,
+    /// Distance from the disk `timeline` was called on
+    pub depth: usize,
+    pub cid: u32,
+    pub parent_cid: u32,
+    /// `ddb.uuid.image`, if set and not null
+    pub image_uuid: Option<String>,
+    /// `ddb.uuid.modification`, which changes whenever the disk is written
+    pub modification_uuid: Option<String>,
+    /// `ddb.uuid.parent`
+    pub parent_uuid: Option<String>,
+    /// `ddb.uuid.parentmodification`, the modification UUID of the parent
+    /// when this disk was created
+    pub parent_modification_uuid: Option<String>,
+    /// Latest modification time of the files of this disk
+    pub modified: Option<SystemTime>,
+    /// Whether the parent was written after this disk was created, as told
+    /// by its CID or modification UUID. `None` for the base disk.
+    pub parent_changed: Option<bool>,
+}
+
+/// History of a chain, from `Vmdk::timeline`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timeline {
+    /// The disks of the chain, base disk first
+    pub entries: Vec<TimelineEntry>,
+}
+
+impl Timeline {
+    /// Entries ordered by modification time, oldest first. Entries without
+    /// one come first.
+    pub fn by_time(&self) -> Vec<&TimelineEntry> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|e| e.modified);
+        entries
+    }
+
+    /// Entries whose parent was written after they were created, which
+    /// usually means the chain is corrupt
+    pub fn inconsistencies(&self) -> Vec<&TimelineEntry> {
+        self.entries.iter().filter(|e| e.parent_changed == Some(true)).collect()
+    }
+}
+
+impl Vmdk {
+    /// Walk the chain of this disk and collect when each disk was created
+    /// from and modified relative to its parent, from the content IDs,
+    /// UUIDs and file modification times
+    pub fn timeline(&self) -> Result<Timeline, Error> {
+        let mut entries = Vec::new();
+        let mut disk = Some(self);
+        let mut depth = 0;
+        while let Some(d) = disk {
+            let uuid = |key: &str| d.descriptor.ddb.get(key).filter(|&v| v != NULL_UUID).map(str::to_owned);
+            let mut modified = None;
+            for file in d.own_files() {
+                let mtime = std::fs::metadata(&file)?.modified().ok();
+                modified = std::cmp::max(modified, mtime);
+            }
+            entries.push(TimelineEntry {
+                path: d.path.clone(),
+                depth,
+                cid: d.descriptor.cid,
+                parent_cid: d.descriptor.parent_cid,
+                image_uuid: uuid("uuid.image"),
+                modification_uuid: uuid("uuid.modification"),
+                parent_uuid: uuid("uuid.parent"),
+                parent_modification_uuid: uuid("uuid.parentmodification"),
+                modified,
+                parent_changed: None,
+            });
+            disk = d.parent();
+            depth += 1;
+        }
+        entries.reverse();
+
+        for i in 1..entries.len() {
+            let (parent, child) = (&entries[i - 1], &entries[i]);
+            let uuid_changed = match (&parent.modification_uuid, &child.parent_modification_uuid) {
+                (Some(current), Some(recorded)) => current != recorded,
+                _ => false,
+            };
+            entries[i].parent_changed = Some(uuid_changed || parent.cid != child.parent_cid);
+        }
+        Ok(Timeline { entries })
+    }
+
+    /// Files of this disk, without those of its parents
+    fn own_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.path.clone()];
+        for file in self.extents.iter().filter_map(|e| e.path.as_ref()) {
+            if !files.contains(file) {
+                files.push(file.clone());
+            }
+        }
+        files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch_dir, SparseImage};
+
+    #[test]
+    fn test_timeline() {
+        let dir = scratch_dir("timeline");
+        let base = SparseImage::new(1024, 128).monolithic("base.vmdk").grain(1, 0xb1);
+        std::fs::write(dir.join("base.vmdk"), base.build()).unwrap();
+        let mut delta = Vmdk::new(dir.join("base.vmdk")).unwrap().snapshot(dir.join("delta.vmdk")).unwrap();
+        delta.write_at(0, &[1; 512]).unwrap();
+        delta.close().unwrap();
+
+        let delta = Vmdk::new(dir.join("delta.vmdk")).unwrap();
+        let timeline = delta.timeline().unwrap();
+        assert_eq!(timeline.entries.len(), 2);
+        assert_eq!(timeline.entries[0].path, dir.join("base.vmdk"));
+        assert_eq!(timeline.entries[0].depth, 1);
+        assert_eq!(timeline.entries[0].parent_changed, None);
+        assert_eq!(timeline.entries[1].parent_cid, timeline.entries[0].cid);
+        assert!(timeline.entries[1].image_uuid.is_some());
+        assert!(timeline.inconsistencies().is_empty());
+        assert_eq!(timeline.by_time().last().unwrap().path, dir.join("delta.vmdk"));
+    }
+}